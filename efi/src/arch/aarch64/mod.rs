@@ -1,5 +1,7 @@
 use core::arch::asm;
 
+pub const ELF_MACHINE: u16 = 183; // EM_AARCH64
+
 pub const R_REL: usize    = 1027; // R_RELATIVE
 pub const R_SYM: &[usize] = &[
     257,  // R_64:        S + A
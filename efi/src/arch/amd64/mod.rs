@@ -1,5 +1,7 @@
 use core::arch::asm;
 
+pub const ELF_MACHINE: u16 = 62; // EM_X86_64
+
 pub const R_REL: usize    = 8; // R_RELATIVE
 pub const R_SYM: &[usize] = &[
     1, // R_64:        S + A
@@ -6,4 +6,5 @@ macro_rules! use_arch {
 }
 
 use_arch!("aarch64", aarch64);
+use_arch!("riscv64", riscv64);
 use_arch!("x86_64", amd64);
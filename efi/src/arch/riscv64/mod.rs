@@ -0,0 +1,13 @@
+use core::arch::asm;
+
+pub const ELF_MACHINE: u16 = 243; // EM_RISCV
+
+pub const R_REL: usize    = 3; // R_RELATIVE
+pub const R_SYM: &[usize] = &[
+    2, // R_64:        S + A
+    5  // R_JUMP_SLOT: S
+];
+
+pub fn halt() {
+    unsafe { asm!("csrci sstatus, 0b10", "wfi"); }
+}
@@ -13,7 +13,23 @@ pub struct SysInfo {
     pub layout_len: usize,
     pub acpi_ptr: usize,
     pub dtb_ptr: usize,
-    pub disk_uuid: [u8; 16]
+    pub disk_uuid: [u8; 16],
+    pub fb: FrameBuffer,
+    pub initrd_ptr: usize,
+    pub initrd_len: usize,
+    pub cmdline_ptr: usize,
+    pub cmdline_len: usize
+}
+
+// GOP mode at boot, `ptr` is 0 when there is none
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct FrameBuffer {
+    pub ptr: usize,
+    pub width: u32,
+    pub height: u32,
+    pub pitch: u32, // Bytes per scanline
+    pub format: u32
 }
 
 #[repr(C)]
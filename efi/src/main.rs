@@ -4,8 +4,10 @@
 //! Description: EFI Bootloader of UNIX Version 11
 //! Licence: Non-assertion pledge
 
-#![no_std]
-#![no_main]
+// Host `cargo test` builds get std and skip the firmware entry point
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code))]
 
 mod arch;
 mod kargs;
@@ -14,25 +16,32 @@ use crate::{arch::*, kargs::*};
 
 use core::panic::PanicInfo;
 use uefi::{
-    Identify, Status,
+    CStr16, Identify, Status,
     boot::{
         AllocateType, MemoryType, SearchType,
         allocate_pages, exit_boot_services,
         free_pages, get_image_file_system,
-        image_handle, locate_handle_buffer,
+        get_handle_for_protocol, image_handle, locate_handle_buffer,
         open_protocol_exclusive as open_protocol
     },
     cstr16, entry,
-    mem::memory_map::MemoryMap,
+    mem::memory_map::{MemoryMap, MemoryMapMut, MemoryMapOwned},
     println,
-    proto::media::{
-        block::BlockIO,
-        file::{File, FileAttribute, FileInfo, FileMode}
+    proto::{
+        console::gop::{GraphicsOutput, PixelFormat},
+        media::{
+            block::BlockIO,
+            file::{Directory, File, FileAttribute, FileInfo, FileMode}
+        }
     },
     system::with_config_table,
     table::cfg::ConfigTableEntry
 };
-use xmas_elf::{program::Type as PhType, ElfFile};
+use xmas_elf::{
+    ElfFile,
+    header::Class,
+    program::Type as PhType
+};
 
 const DT_NULL: usize   = 0;
 const DT_STRTAB: usize = 5;
@@ -47,27 +56,123 @@ pub fn align_up(val: usize, align: usize) -> usize {
     return val.div_ceil(align) * align;
 }
 
-#[entry]
+// Every PT_LOAD segment has to come from inside the file
+fn segments_in_bounds(elf: &ElfFile, file_len: usize) -> bool {
+    return elf.program_iter()
+        .filter(|ph| ph.get_type() == Ok(PhType::Load))
+        .all(|ph| {
+            let end = ph.offset().checked_add(ph.file_size());
+            end.is_some_and(|end| end <= file_len as u64) && ph.file_size() <= ph.mem_size()
+        });
+}
+
+fn validate_elf(binary: &[u8]) -> Result<ElfFile<'_>, &'static str> {
+    let elf = ElfFile::new(binary)?; // Checks the magic
+    if elf.header.pt1.class() != Class::SixtyFour {
+        return Err("not an ELF64 image");
+    }
+    if elf.header.pt2.machine().0 != ELF_MACHINE {
+        return Err("built for another architecture");
+    }
+    if !elf.program_iter().any(|ph| ph.get_type() == Ok(PhType::Load)) {
+        return Err("no loadable segments");
+    }
+    if !segments_in_bounds(&elf, binary.len()) {
+        return Err("segment exceeds the file");
+    }
+    return Ok(elf);
+}
+
+// Whole file in LOADER_DATA pages, None if it is absent
+fn read_file(root: &mut Directory, path: &CStr16) -> Option<&'static mut [u8]> {
+    let mut file = root.open(
+        path, FileMode::Read, FileAttribute::empty()
+    ).ok()?.into_regular_file()?;
+
+    let mut info_buf = [0u8; 512];
+    let info = file.get_info::<FileInfo>(&mut info_buf).ok()?;
+    let file_size = info.file_size() as usize;
+
+    let file_pages = align_up(file_size, PAGE_4KIB) / PAGE_4KIB;
+    let file_ptr = allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, file_pages.max(1)).ok()?;
+    let buf = unsafe { core::slice::from_raw_parts_mut(file_ptr.as_ptr(), file_size) };
+    if file.read(buf).ok()? != file_size { return None; }
+    return Some(buf);
+}
+
+fn gop_framebuffer() -> FrameBuffer {
+    let none = FrameBuffer { ptr: 0, width: 0, height: 0, pitch: 0, format: 0 };
+    let Ok(handle) = get_handle_for_protocol::<GraphicsOutput>() else { return none; };
+    let Ok(mut gop) = open_protocol::<GraphicsOutput>(handle) else { return none; };
+
+    let mode = gop.current_mode_info();
+    let format = match mode.pixel_format() {
+        PixelFormat::Rgb => 0,
+        PixelFormat::Bgr => 1,
+        _ => return none // Bitmask and BLT-only modes are not handled by the kernel
+    };
+    let (width, height) = mode.resolution();
+
+    return FrameBuffer {
+        ptr: gop.frame_buffer().as_mut_ptr() as usize,
+        width: width as u32,
+        height: height as u32,
+        pitch: (mode.stride() * 4) as u32,
+        format
+    };
+}
+
+// Sorts the map by address and merges entries of the same type and attributes that touch
+// or overlap, in place at the firmware's stride. Returns how many entries are left.
+fn coalesce(map: &mut impl MemoryMapMut) -> usize {
+    map.sort();
+    let len = map.len();
+    if len == 0 { return 0; }
+
+    let mut last = 0;
+    for idx in 1..len {
+        let desc = *map.get(idx).unwrap();
+        let prev = map.get_mut(last).unwrap();
+        let prev_end = prev.phys_start + prev.page_count * PAGE_4KIB as u64;
+        if prev.ty == desc.ty && prev.att == desc.att && desc.phys_start <= prev_end {
+            let end = prev_end.max(desc.phys_start + desc.page_count * PAGE_4KIB as u64);
+            prev.page_count = (end - prev.phys_start) / PAGE_4KIB as u64;
+        } else {
+            last += 1;
+            *map.get_mut(last).unwrap() = desc;
+        }
+    }
+    return last + 1;
+}
+
+#[cfg_attr(not(test), entry)]
 fn flint() -> Status {
     let mut file_binary: &mut [u8] = &mut [];
+    let (mut initrd_ptr, mut initrd_len) = (0, 0);
+    let (mut cmdline_ptr, mut cmdline_len) = (0, 0);
     if let Ok(mut filesys_protocol) = get_image_file_system(image_handle()) {
         let mut root = filesys_protocol.open_volume().unwrap();
+        let Some(binary) = read_file(&mut root, cstr16!("\\unix")) else {
+            println!("Failed to read \\unix");
+            return Status::NOT_FOUND;
+        };
+        file_binary = binary;
 
-        let mut file = root.open(
-            cstr16!("\\unix"), FileMode::Read, FileAttribute::empty()
-        ).unwrap().into_regular_file().unwrap();
-
-        let mut info_buf = [0u8; 512];
-        let info = file.get_info::<FileInfo>(&mut info_buf).unwrap();
-        let file_size = info.file_size() as usize;
-
-        let file_pages = align_up(file_size, PAGE_4KIB) / PAGE_4KIB;
-        let file_ptr = allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, file_pages).unwrap();
-        file_binary = unsafe { core::slice::from_raw_parts_mut(file_ptr.as_ptr(), file_size) };
-        file.read(file_binary).unwrap();
+        if let Some(initrd) = read_file(&mut root, cstr16!("\\initrd")) {
+            (initrd_ptr, initrd_len) = (initrd.as_ptr() as usize, initrd.len());
+        }
+        if let Some(cmdline) = read_file(&mut root, cstr16!("\\cmdline")) {
+            (cmdline_ptr, cmdline_len) = (cmdline.as_ptr() as usize, cmdline.len());
+        }
     }
 
-    let elf = ElfFile::new(file_binary).unwrap();
+    let elf = match validate_elf(file_binary) {
+        Ok(elf) => elf,
+        Err(err) => {
+            println!("Invalid kernel image: {}", err);
+            return Status::LOAD_ERROR;
+        }
+    };
     let ep = elf.header.pt2.entry_point() as usize;
 
     let ksize = elf.program_iter()
@@ -192,8 +297,11 @@ fn flint() -> Status {
         }
     }
 
+    let fb = gop_framebuffer();
+
     let ignite: extern "efiapi" fn(Kargs) -> ! = unsafe { core::mem::transmute(ep + kbase) };
-    let efi_ram_layout = unsafe { exit_boot_services(Some(MemoryType::LOADER_DATA)) };
+    let mut efi_ram_layout = unsafe { exit_boot_services(Some(MemoryType::LOADER_DATA)) };
+    let layout_len = coalesce(&mut efi_ram_layout);
     let sysinfo = Kargs {
         kernel: KernelInfo {
             size: ksize, ep,
@@ -202,16 +310,123 @@ fn flint() -> Status {
         },
         sys: SysInfo {
             layout_ptr: efi_ram_layout.buffer().as_ptr() as usize,
-            layout_len: efi_ram_layout.len(),
-            acpi_ptr, dtb_ptr, disk_uuid
+            layout_len,
+            acpi_ptr, dtb_ptr, disk_uuid, fb,
+            initrd_ptr, initrd_len,
+            cmdline_ptr, cmdline_len
         },
         kbase
     };
     ignite(sysinfo);
 }
 
+#[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     println!("Panic: {}", info);
     loop { arch::halt(); }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // ELF64 header for this machine followed by one PT_LOAD header
+    fn image(offset: u64, file_size: u64, mem_size: u64) -> Vec<u8> {
+        let mut elf = vec![0u8; 0x1000];
+        elf[..8].copy_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+        elf[16..18].copy_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+        elf[18..20].copy_from_slice(&ELF_MACHINE.to_le_bytes());
+        elf[20..24].copy_from_slice(&1u32.to_le_bytes());
+        elf[32..40].copy_from_slice(&64u64.to_le_bytes()); // e_phoff
+        elf[52..54].copy_from_slice(&64u16.to_le_bytes());
+        elf[54..56].copy_from_slice(&56u16.to_le_bytes());
+        elf[56..58].copy_from_slice(&1u16.to_le_bytes());
+        elf[58..60].copy_from_slice(&64u16.to_le_bytes());
+
+        let ph = &mut elf[64..120];
+        ph[0..4].copy_from_slice(&1u32.to_le_bytes()); // PT_LOAD
+        ph[8..16].copy_from_slice(&offset.to_le_bytes());
+        ph[32..40].copy_from_slice(&file_size.to_le_bytes());
+        ph[40..48].copy_from_slice(&mem_size.to_le_bytes());
+        return elf;
+    }
+
+    #[test]
+    fn segment_inside_the_file() {
+        let elf = image(0x200, 0xe00, 0x2000);
+        assert!(validate_elf(&elf).is_ok());
+        let elf = image(0, 0x1000, 0x1000);
+        assert!(validate_elf(&elf).is_ok());
+    }
+
+    #[test]
+    fn segment_past_the_file() {
+        let elf = image(0x200, 0xe01, 0x2000);
+        assert_eq!(validate_elf(&elf).err(), Some("segment exceeds the file"));
+        let elf = image(u64::MAX, 2, 2);
+        assert_eq!(validate_elf(&elf).err(), Some("segment exceeds the file"));
+        // More file bytes than memory to hold them
+        let elf = image(0x200, 0x100, 0x80);
+        assert_eq!(validate_elf(&elf).err(), Some("segment exceeds the file"));
+    }
+
+    #[test]
+    fn header_checks() {
+        let mut elf = image(0x200, 0x100, 0x100);
+        elf[4] = 1; // ELFCLASS32
+        assert!(validate_elf(&elf).is_err());
+
+        let mut elf = image(0x200, 0x100, 0x100);
+        elf[18..20].copy_from_slice(&(ELF_MACHINE ^ 1).to_le_bytes());
+        assert_eq!(validate_elf(&elf).err(), Some("built for another architecture"));
+
+        let mut elf = image(0x200, 0x100, 0x100);
+        elf[64] = 2; // PT_DYNAMIC
+        assert_eq!(validate_elf(&elf).err(), Some("no loadable segments"));
+    }
+
+    #[test]
+    fn memory_map_is_coalesced() {
+        use uefi::mem::memory_map::{MemoryAttribute, MemoryDescriptor, MemoryMapMeta, MemoryMapRefMut};
+        let desc = |ty: MemoryType, phys_start: u64, page_count: u64, att: MemoryAttribute| MemoryDescriptor {
+            ty, phys_start, page_count, att, ..Default::default()
+        };
+        let (conv, data) = (MemoryType::CONVENTIONAL, MemoryType::LOADER_DATA);
+        let wb = MemoryAttribute::WRITE_BACK;
+        let mut descs = [
+            desc(conv, 0x200000, 4, wb),
+            desc(conv, 0x110000, 0x10, wb),               // touches the one at 0x100000
+            desc(data, 0x128000, 1, wb),
+            desc(conv, 0x118000, 0x10, wb),               // overlaps the one above
+            desc(conv, 0x204000, 4, wb | MemoryAttribute::RUNTIME),
+            desc(conv, 0x100000, 0x10, wb),
+            desc(conv, 0x129000, 1, wb),                  // same type, but past the LOADER_DATA page
+            desc(conv, 0x200000, 4, wb)                   // a duplicate sliver
+        ];
+
+        let bytes = unsafe {
+            core::slice::from_raw_parts_mut(descs.as_mut_ptr() as *mut u8, size_of_val(&descs))
+        };
+        let meta = MemoryMapMeta {
+            map_size: bytes.len(),
+            desc_size: size_of::<MemoryDescriptor>(),
+            map_key: Default::default(),
+            desc_version: MemoryDescriptor::VERSION
+        };
+        let mut map = MemoryMapRefMut::new(bytes, meta).unwrap();
+
+        let len = coalesce(&mut map);
+        let got: Vec<_> = (0..len).map(|idx| {
+            let d = map.get(idx).unwrap();
+            (d.ty, d.phys_start, d.page_count, d.att)
+        }).collect();
+        assert_eq!(got, [
+            (conv, 0x100000, 0x28, wb),
+            (data, 0x128000, 1, wb),
+            (conv, 0x129000, 1, wb),
+            (conv, 0x200000, 4, wb),
+            (conv, 0x204000, 4, wb | MemoryAttribute::RUNTIME)
+        ]);
+    }
+}
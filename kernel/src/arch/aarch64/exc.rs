@@ -1,9 +1,13 @@
 use crate::{
-    arch::intc,
+    arch::{intc, percpu::{self, PerCpu}},
+    kargs::AP_LIST,
     kreq::kernel_requestee,
-    printlnk, ram::stack_top
+    printlnk,
+    proc::{fault::{PageFault, handle_page_fault}, preempt, save_ctxt},
+    ram::{check_stack, stack_top, tlb}, time, trace, warn
 };
 
+use alloc::boxed::Box;
 use core::arch::{asm, global_asm};
 
 unsafe extern "C" {
@@ -45,6 +49,7 @@ macro_rules! exc_stub_el0 {
             "stp x2, x3, [sp, #16]\n",
             "mrs x0, sp_el0\n",
             "mrs x1, tpidr_el1\n",
+            "ldr x1, [x1]\n",         // x1 = this_cpu().kstack_top
             "sub x1, x1, #816\n",
             "str x0, [x1, #248]\n",
             "ldp x2, x3, [sp, #0]\n",
@@ -204,6 +209,24 @@ pub struct ExcFrame {
     pub fpsr: u64      // fp status reg
 }
 
+// Instruction and data aborts from either EL
+fn decode_abort(frame: &ExcFrame, user: bool) -> Option<PageFault> {
+    let ec = (frame.esr >> 26) & 0x3f;
+    let iss = frame.esr & 0x1ffffff;
+    let exec = match ec {
+        0x20 | 0x21 => true,
+        0x24 | 0x25 => false,
+        _ => return None
+    };
+
+    return Some(PageFault {
+        addr: frame.far as usize,
+        present: iss & 0x3c != 0x04, // Not a translation fault
+        write: !exec && iss & (1 << 6) != 0, // WnR
+        exec, user
+    });
+}
+
 #[unsafe(no_mangle)]
 extern "C" fn exc_handler(exc_type: u64, frame: *mut ExcFrame) {
     macro_rules! ref_frame {
@@ -212,6 +235,9 @@ extern "C" fn exc_handler(exc_type: u64, frame: *mut ExcFrame) {
 
     match exc_type {
         0 => { /* sync el1t */
+            if let Some(fault) = decode_abort(&ref_frame!(), false) {
+                if handle_page_fault(&fault) { return; }
+            }
             printlnk!("Kernel sync exception (EL1t)");
             printlnk!("Exception frame: {:#x?}", frame);
             panic!("Unhandled kernel exception");
@@ -219,12 +245,14 @@ extern "C" fn exc_handler(exc_type: u64, frame: *mut ExcFrame) {
         1 => { /* irq el1t */
             let intid = intc::ack();
             match intid {
+                1 => tlb::handle_ipi(), // TLB shootdown
                 27 => { // timer
-                    printlnk!("Timer IRQ");
-                    intc::timer_set_ms(1000);
+                    trace!("Timer IRQ");
+                    check_stack();
+                    time::handle_irq();
                 }
                 _ => {
-                    printlnk!("Unhandled IRQ: {}", intid);
+                    warn!("Unhandled IRQ: {}", intid);
                 }
             }
             intc::eoi(intid);
@@ -234,11 +262,18 @@ extern "C" fn exc_handler(exc_type: u64, frame: *mut ExcFrame) {
         4..8 => unreachable!(),
         8  | 12 => { /* sync el0 */
             if (ref_frame!().esr >> 26) & 0x3f == 0x15 { // supervisor call
-                ref_frame!().x[0] = kernel_requestee(
+                save_ctxt(&ref_frame!());
+                let ret = kernel_requestee(
                     ref_frame!().x[0] as *const u8,
                     ref_frame!().x[1] as usize, ref_frame!().x[2] as usize, ref_frame!().x[3] as usize,
                     ref_frame!().x[4] as usize, ref_frame!().x[5] as usize, ref_frame!().x[6] as usize
-                ) as u64;
+                );
+                unsafe { (*frame).x[0] = ret as u64; }
+            } else if let Some(fault) = decode_abort(&ref_frame!(), true) {
+                if !handle_page_fault(&fault) {
+                    printlnk!("Exception frame: {:#x?}", ref_frame!());
+                    panic!("Unhandled page fault");
+                }
             } else {
                 printlnk!("Exception type: {}", exc_type);
                 printlnk!("Exception frame: {:#x?}", ref_frame!());
@@ -248,11 +283,18 @@ extern "C" fn exc_handler(exc_type: u64, frame: *mut ExcFrame) {
         9  | 13 => { /* irq el0 */
             let intid = intc::ack();
             match intid {
+                1 => tlb::handle_ipi(), // TLB shootdown
                 27 => { // timer
-                    printlnk!("Timer IRQ");
+                    trace!("Timer IRQ");
+                    check_stack();
+                    time::handle_irq();
+                    if percpu::this_cpu().take_resched() {
+                        intc::eoi(intid);
+                        preempt(&ref_frame!());
+                    }
                 }
                 _ => {
-                    printlnk!("Unhandled IRQ: {}", intid);
+                    warn!("Unhandled IRQ: {}", intid);
                 }
             }
             intc::eoi(intid);
@@ -288,10 +330,11 @@ pub fn set(enabled: bool) {
 }
 
 pub fn init() {
+    percpu::set(Box::leak(Box::new(PerCpu::new(AP_LIST.virtid_self()))));
+
     unsafe {
         asm!(
             "msr vbar_el1, {vbar}",
-            "msr tpidr_el1, xzr",
 
             "mov {tmp}, sp",
             "msr sp_el0, {tmp}",
@@ -306,7 +349,35 @@ pub fn init() {
 }
 
 pub fn set_kstk(kstk_top: usize) {
-    unsafe {
-        asm!("msr tpidr_el1, {}", in(reg) kstk_top, options(nomem, nostack));
+    percpu::this_cpu().set_kstack_top(kstk_top);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(ec: u64, iss: u64, far: u64) -> ExcFrame {
+        let mut frame: ExcFrame = unsafe { core::mem::zeroed() };
+        (frame.esr, frame.far) = (ec << 26 | 1 << 25 | iss, far);
+        return frame;
+    }
+
+    #[test]
+    fn abort_syndrome() {
+        // Data abort from EL0, level 3 translation fault on a write
+        let fault = decode_abort(&frame(0x24, 1 << 6 | 0x07, 0x1000), true).unwrap();
+        assert!(!fault.present && fault.write && !fault.exec && fault.user);
+        assert_eq!(fault.addr, 0x1000);
+
+        // Level 2 permission fault on a read from EL1
+        let fault = decode_abort(&frame(0x25, 0x0e, 0x2000), false).unwrap();
+        assert!(fault.present && !fault.write && !fault.exec && !fault.user);
+
+        // Instruction aborts are never writes, whatever bit 6 says
+        let fault = decode_abort(&frame(0x20, 1 << 6 | 0x0f, 0x40_0000), true).unwrap();
+        assert!(fault.present && !fault.write && fault.exec);
+
+        // SVC and friends are not aborts
+        assert!(decode_abort(&frame(0x15, 0, 0), true).is_none());
     }
 }
@@ -1,5 +1,5 @@
 use crate::{
-    device::cpu::{GICC_BASE, GICD_BASE, GICR_BASE, ic_va},
+    device::cpu::{GICC_BASE, GICD_BASE, GICM_BASE, GICR_BASE, ic_va},
     kargs::AP_LIST
 };
 
@@ -56,10 +56,11 @@ pub fn init() {
     match v {
         2 => init_v2(),
         3 => init_v3(),
-        _ => crate::printlnk!("Unknown GIC version: {}", v)
+        _ => crate::error!("Unknown GIC version: {}", v)
     }
 
     enable(27); // CNTV virtual timer
+    enable(IPI_TLB);
 }
 
 fn init_v2() {
@@ -181,6 +182,8 @@ pub fn disable(intid: u32) {
     }
 }
 
+pub const IPI_TLB: u32 = 1; // TLB shootdown
+
 pub fn send_ipi_others(intid: u32) {
     match gic_ver() {
         2 => unsafe {
@@ -218,6 +221,13 @@ pub fn send_ipi(intid: u32, target: u32) {
     }
 }
 
+// Writes to the GICv2m frame's SETSPI_NSR raise the SPI in the data word.
+// SPIs are routed by the distributor, so `target` is not encoded.
+pub fn msi_msg(intid: u32, _target: u32) -> Option<(u64, u32)> {
+    let base = *GICM_BASE.get()?;
+    return Some(((base + 0x040) as u64, intid));
+}
+
 #[inline(always)]
 pub fn timer_freq() -> u64 {
     let freq: u64;
@@ -225,6 +235,19 @@ pub fn timer_freq() -> u64 {
     return freq;
 }
 
+// Monotonic counter ticking at `counter_freq()`
+#[inline(always)]
+pub fn counter() -> u64 {
+    let cnt: u64;
+    unsafe { asm!("isb", "mrs {}, CNTPCT_EL0", out(reg) cnt); }
+    return cnt;
+}
+
+#[inline(always)]
+pub fn counter_freq() -> u64 {
+    return timer_freq();
+}
+
 #[inline(always)]
 pub fn timer_enable() {
     unsafe {
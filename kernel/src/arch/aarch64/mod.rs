@@ -1,14 +1,18 @@
 pub mod exc;
 pub mod intc;
+pub mod percpu;
 pub mod proc;
 pub mod rvm;
+pub mod smp;
 
 use crate::{
     arch::rvm::flags,
+    kargs::SYSINFO,
     ram::glacier::{GLACIER, page_size}
 };
 
 use core::{arch::asm, fmt::{Result, Write}, hint::spin_loop};
+use fdt::Fdt;
 
 pub fn wfi() {
     exc::set(true);
@@ -27,7 +31,10 @@ pub const R_SYM: &[usize] = &[
     1026  // R_JUMP_SLOT: S
 ];
 
-const UART0_BASE: usize = 0x0900_0000; // QEMU virt PL011 UART
+const UART0_BASE: usize = 0x0900_0000; // QEMU virt PL011 UART, without a device tree
+const UART0_CLOCK: u32 = 24_000_000;
+const PL011: &str = "arm,pl011";
+pub const DEFAULT_BAUD: u32 = 115200;
 
 #[inline(always)]
 fn serial_io() -> usize {
@@ -41,15 +48,72 @@ pub fn phys_id() -> usize {
     return mpidr & 0xffff;
 }
 
+// PSCI firmware call over the HVC conduit
+pub fn psci(func: u32, a1: usize, a2: usize, a3: usize) -> isize {
+    let ret: isize;
+    unsafe {
+        asm!(
+            "hvc #0",
+            inout("x0") func as usize => ret,
+            in("x1") a1, in("x2") a2, in("x3") a3,
+            options(nomem, nostack)
+        );
+    }
+    return ret;
+}
+
+// Runs before DEVICETREE is set up, so the blob is parsed here on its own.
+// The console named by /chosen stdout-path wins over the first PL011 node.
+// Returns the register base and UARTCLK, the first entry of its clocks.
+fn find_uart() -> (usize, u32) {
+    let ptr = SYSINFO.read().dtb_ptr;
+    if ptr == 0 { return (UART0_BASE, UART0_CLOCK); }
+    let Ok(fdt) = (unsafe { Fdt::from_ptr(ptr as *const u8) }) else { return (UART0_BASE, UART0_CLOCK); };
+    return uart_in(&fdt);
+}
+
+fn uart_in(fdt: &Fdt) -> (usize, u32) {
+    let Some(node) = fdt.chosen().stdout()
+        .filter(|node| node.compatible().is_some_and(|c| c.all().any(|s| s == PL011)))
+        .or_else(|| fdt.find_compatible(&[PL011])) else { return (UART0_BASE, UART0_CLOCK); };
+
+    let base = node.reg().and_then(|mut reg| reg.next())
+        .map(|reg| reg.starting_address as usize)
+        .unwrap_or(UART0_BASE);
+    let clock = node.property("clocks")
+        .and_then(|prop| prop.value.get(..4))
+        .and_then(|phandle| fdt.find_phandle(u32::from_be_bytes(phandle.try_into().ok()?)))
+        .and_then(|clk| clk.property("clock-frequency")?.as_usize())
+        .map(|freq| freq as u32)
+        .unwrap_or(UART0_CLOCK);
+    return (base, clock);
+}
+
+// UARTCLK / (16 * baud) in 16.6 fixed point, rounded
+fn baud_divisor(clock: u32, baud: u32) -> (u32, u32) {
+    let div = (clock as u64 * 4 + baud as u64 / 2) / baud.max(1) as u64;
+    return (((div >> 6) as u32).clamp(1, 0xffff), (div & 0x3f) as u32);
+}
+
 pub fn init_serial() {
+    init_serial_with(DEFAULT_BAUD);
+}
+
+pub fn init_serial_with(baud: u32) {
     let sio = serial_io();
-    GLACIER.write().map_page(sio, UART0_BASE, flags::D_RW);
+    let (base, clock) = find_uart();
+    let _ = GLACIER.write().map_page(sio, base, flags::D_RW);
+    let (ibrd, fbrd) = baud_divisor(clock, baud);
 
     unsafe {
         // Disable UART
         ((sio + 0x30) as *mut u32).write_volatile(0x0);
         // Clear all pending interrupts
         ((sio + 0x44) as *mut u32).write_volatile(0x7ff);
+        // Baud rate divisor, latched by the UARTLCR_H write
+        ((sio + 0x24) as *mut u32).write_volatile(ibrd);
+        ((sio + 0x28) as *mut u32).write_volatile(fbrd);
+        ((sio + 0x2c) as *mut u32).write_volatile(0x70); // 8N1, FIFOs enabled
         // Enable UART, TX, RX
         ((sio + 0x30) as *mut u32).write_volatile(0x301); // UARTCR: UARTEN|TXE|RXE
     }
@@ -63,6 +127,14 @@ pub fn serial_putchar(c: u8) {
     }
 }
 
+pub fn serial_getchar() -> Option<u8> {
+    let sio = serial_io();
+    unsafe {
+        if ((sio + 0x18) as *const u32).read_volatile() & (1 << 4) != 0 { return None; } // RXFE
+        return Some(((sio + 0x00) as *const u32).read_volatile() as u8);
+    }
+}
+
 pub struct SerialWriter;
 
 impl Write for SerialWriter {
@@ -72,6 +144,16 @@ impl Write for SerialWriter {
     }
 }
 
+// x29 points at the {x29, x30} frame record
+pub const FRAME_RECORD: isize = 0;
+
+#[inline(always)]
+pub fn frame_ptr() -> usize {
+    let fp: usize;
+    unsafe { asm!("mov {}, x29", out(reg) fp); }
+    return fp;
+}
+
 #[inline(always)]
 pub fn stack_ptr() -> *const u8 {
     let sp: usize;
@@ -85,3 +167,144 @@ pub unsafe fn move_stack(addr: usize) {
         asm!("mov sp, {}", in(reg) addr);
     }
 }
+
+// Nothing on the old stack can be touched once sp moves, so both happen here
+pub unsafe fn jump_with_stack(stack: usize, entry: usize) -> ! {
+    unsafe {
+        asm!(
+            "mov sp, {stack}",
+            "mov x29, xzr",
+            "mov x30, xzr",
+            "br {entry}",
+            stack = in(reg) stack,
+            entry = in(reg) entry,
+            options(noreturn)
+        );
+    }
+}
+
+// Smallest data and instruction cache lines in bytes, from CTR_EL0 DminLine and IminLine
+pub fn cache_lines() -> (usize, usize) {
+    let ctr: u64;
+    unsafe { asm!("mrs {}, ctr_el0", out(reg) ctr, options(nomem, nostack)); }
+    return (4 << ((ctr >> 16) & 0xf), 4 << (ctr & 0xf));
+}
+
+// Code written through the data cache has to reach the point of unification first
+pub fn sync_icache(addr: usize, len: usize) {
+    let (dline, iline) = cache_lines();
+    for line in (addr & !(dline - 1)..addr + len).step_by(dline) {
+        unsafe { asm!("dc cvau, {}", in(reg) line, options(nostack)); }
+    }
+    unsafe { asm!("dsb ish", options(nostack)); }
+    for line in (addr & !(iline - 1)..addr + len).step_by(iline) {
+        unsafe { asm!("ic ivau, {}", in(reg) line, options(nostack)); }
+    }
+    unsafe { asm!("dsb ish", "isb", options(nostack)); }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    // Just enough of a flattened device tree writer for the console lookup
+    #[derive(Default)]
+    struct Dtb { structs: Vec<u8>, strings: Vec<u8> }
+
+    impl Dtb {
+        fn token(&mut self, token: u32, data: &[u8]) -> &mut Self {
+            self.structs.extend_from_slice(&token.to_be_bytes());
+            self.structs.extend_from_slice(data);
+            while self.structs.len() % 4 != 0 { self.structs.push(0); }
+            return self;
+        }
+
+        fn begin(&mut self, name: &str) -> &mut Self {
+            return self.token(1, &[name.as_bytes(), &[0]].concat());
+        }
+
+        fn end(&mut self) -> &mut Self { self.token(2, &[]) }
+
+        fn prop(&mut self, name: &str, value: &[u8]) -> &mut Self {
+            let off = self.strings.len() as u32;
+            self.strings.extend_from_slice(name.as_bytes());
+            self.strings.push(0);
+            let head = [(value.len() as u32).to_be_bytes(), off.to_be_bytes()].concat();
+            return self.token(3, &[&head[..], value].concat());
+        }
+
+        fn cells(&mut self, name: &str, cells: &[u32]) -> &mut Self {
+            let value: Vec<u8> = cells.iter().flat_map(|c| c.to_be_bytes()).collect();
+            return self.prop(name, &value);
+        }
+
+        fn finish(&mut self) -> Vec<u8> {
+            self.token(9, &[]);
+            let rsv = 40;
+            let structs = rsv + 16;
+            let strings = structs + self.structs.len();
+            let total = strings + self.strings.len();
+            let header = [
+                0xd00dfeed, total, structs, strings, rsv,
+                17, 16, 0, self.strings.len(), self.structs.len()
+            ];
+            let mut blob: Vec<u8> = header.iter().flat_map(|&w| (w as u32).to_be_bytes()).collect();
+            blob.extend_from_slice(&[0; 16]); // Empty reservation map
+            blob.extend_from_slice(&self.structs);
+            blob.extend_from_slice(&self.strings);
+            return blob;
+        }
+    }
+
+    // QEMU virt style tree with a second PL011 and an optional stdout-path
+    fn virt(stdout: Option<&str>, clocks: bool) -> Vec<u8> {
+        let mut dtb = Dtb::default();
+        dtb.begin("").cells("#address-cells", &[2]).cells("#size-cells", &[2]);
+        dtb.begin("chosen");
+        if let Some(path) = stdout { dtb.prop("stdout-path", &[path.as_bytes(), &[0]].concat()); }
+        dtb.end();
+
+        dtb.begin("apb-pclk").prop("compatible", b"fixed-clock\0")
+            .cells("clock-frequency", &[48_000_000]).cells("phandle", &[0x8000]).end();
+        dtb.begin("serial@10000000").prop("compatible", b"ns16550a\0")
+            .cells("reg", &[0, 0x1000_0000, 0, 0x100]).end();
+        for base in [0x0900_0000, 0x0904_0000] {
+            dtb.begin(if base == 0x0900_0000 { "pl011@9000000" } else { "pl011@9040000" })
+                .prop("compatible", b"arm,pl011\0arm,primecell\0")
+                .cells("reg", &[0, base, 0, 0x1000]);
+            if clocks { dtb.cells("clocks", &[0x8000, 0x8000]); }
+            dtb.end();
+        }
+        dtb.end();
+        return dtb.finish();
+    }
+
+    #[test]
+    fn stdout_path_names_the_console() {
+        let blob = virt(Some("/pl011@9040000"), true);
+        let fdt = Fdt::new(&blob).unwrap();
+        assert_eq!(uart_in(&fdt), (0x0904_0000, 48_000_000));
+    }
+
+    #[test]
+    fn first_pl011_without_a_usable_stdout() {
+        let blob = virt(None, true);
+        assert_eq!(uart_in(&Fdt::new(&blob).unwrap()), (0x0900_0000, 48_000_000));
+
+        // A 16550 console is not something this driver can speak to
+        let blob = virt(Some("/serial@10000000"), true);
+        assert_eq!(uart_in(&Fdt::new(&blob).unwrap()), (0x0900_0000, 48_000_000));
+
+        let blob = virt(Some("/pl011@9040000"), false);
+        assert_eq!(uart_in(&Fdt::new(&blob).unwrap()), (0x0904_0000, UART0_CLOCK));
+    }
+
+    #[test]
+    fn divisors_for_common_rates() {
+        // 24 MHz UARTCLK, IBRD and FBRD in 1/64ths
+        assert_eq!(baud_divisor(24_000_000, 115200), (13, 1));
+        assert_eq!(baud_divisor(24_000_000, 38400), (39, 4));
+        assert_eq!(baud_divisor(24_000_000, 9600), (156, 16));
+    }
+}
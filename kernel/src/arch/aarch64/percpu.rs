@@ -0,0 +1,107 @@
+use crate::arch::CliState;
+
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering as AtomOrd}
+};
+
+const NO_PID: usize = usize::MAX;
+
+// Reached through tpidr_el1. The EL0 stubs read the kernel stack top at [tpidr_el1, #0].
+#[repr(C)]
+pub struct PerCpu {
+    kstack_top: AtomicUsize, // [tpidr_el1, #0]
+    virtid: usize,
+    pid: AtomicUsize,
+    cli: CliState,
+    slice: AtomicU64, // Time slice timer, 0 for none
+    resched: AtomicBool
+}
+
+impl PerCpu {
+    pub const fn new(virtid: usize) -> Self {
+        return Self {
+            kstack_top: AtomicUsize::new(0),
+            virtid,
+            pid: AtomicUsize::new(NO_PID),
+            cli: CliState::new(),
+            slice: AtomicU64::new(0),
+            resched: AtomicBool::new(false)
+        };
+    }
+
+    pub fn virtid(&self) -> usize {
+        return self.virtid;
+    }
+
+    pub fn kstack_top(&self) -> usize {
+        return self.kstack_top.load(AtomOrd::Relaxed);
+    }
+
+    pub fn set_kstack_top(&self, top: usize) {
+        self.kstack_top.store(top, AtomOrd::Relaxed);
+    }
+
+    pub fn pid(&self) -> Option<usize> {
+        let pid = self.pid.load(AtomOrd::Relaxed);
+        return (pid != NO_PID).then_some(pid);
+    }
+
+    pub fn swap_pid(&self, pid: Option<usize>) -> Option<usize> {
+        let old = self.pid.swap(pid.unwrap_or(NO_PID), AtomOrd::Relaxed);
+        return (old != NO_PID).then_some(old);
+    }
+
+    pub fn cli(&self) -> &CliState {
+        return &self.cli;
+    }
+
+    pub fn swap_slice(&self, id: u64) -> u64 {
+        return self.slice.swap(id, AtomOrd::Relaxed);
+    }
+
+    pub fn set_resched(&self, resched: bool) {
+        self.resched.store(resched, AtomOrd::Relaxed);
+    }
+
+    pub fn take_resched(&self) -> bool {
+        return self.resched.swap(false, AtomOrd::Relaxed);
+    }
+}
+
+pub fn set(cpu: &'static PerCpu) {
+    unsafe {
+        asm!("msr tpidr_el1, {}", in(reg) cpu as *const PerCpu as usize, options(nomem, nostack));
+    }
+}
+
+// tpidr_el1 resets to an unknown value, ap_entry zeroes it on the APs
+pub fn clear() {
+    unsafe { asm!("msr tpidr_el1, xzr", options(nomem, nostack)); }
+}
+
+pub fn try_this_cpu() -> Option<&'static PerCpu> {
+    let ptr: usize;
+    unsafe { asm!("mrs {}, tpidr_el1", out(reg) ptr, options(nomem, nostack, preserves_flags)); }
+    if ptr == 0 { return None; }
+    return Some(unsafe { &*(ptr as *const PerCpu) });
+}
+
+pub fn this_cpu() -> &'static PerCpu {
+    let ptr: usize;
+    unsafe {
+        asm!("mrs {}, tpidr_el1", out(reg) ptr, options(nomem, nostack, preserves_flags));
+        return &*(ptr as *const PerCpu);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::offset_of;
+
+    #[test]
+    fn kstack_top_is_first() {
+        assert_eq!(offset_of!(PerCpu, kstack_top), 0);
+    }
+}
@@ -28,6 +28,10 @@ impl ExcFrame {
         return self.x[arg_i] as usize;
     }
 
+    pub const fn set_ret(&mut self, ret: usize) {
+        self.x[0] = ret as u64;
+    }
+
     pub const fn set_pc(&mut self, pc: usize) {
         self.elr = pc as u64;
     }
@@ -46,7 +50,8 @@ pub unsafe fn rstr_ctxt(ctxt: &ExcFrame, kstk_top: usize) -> ! {
     unsafe {
         asm!(
             "mov x9, {ctxt}",
-            "msr tpidr_el1, {ksp}",
+            "mrs x10, tpidr_el1",
+            "str x11, [x10]",        // this_cpu().kstack_top = ksp
 
             "ldr x10, [x9, #256]",
             "msr elr_el1, x10",
@@ -97,8 +102,32 @@ pub unsafe fn rstr_ctxt(ctxt: &ExcFrame, kstk_top: usize) -> ! {
             "ldr x9, [x9, #72]",
             "eret",
             ctxt = in(reg) ctxt,
-            ksp = in(reg) kstk_top,
+            in("x11") kstk_top,
             options(noreturn)
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::offset_of;
+
+    #[test]
+    fn restore_reads_the_saved_pc_and_sp() {
+        // A timer tick taken at EL0, as save_ctxt copies it into the PCB
+        let mut frame = ExcFrame::new();
+        frame.elr = 0x40_1234;
+        frame.x[31] = 0x7fff_f000;
+        let ctxt = alloc::boxed::Box::new(frame);
+        assert_eq!((ctxt.pc(), ctxt.sp()), (0x40_1234, 0x7fff_f000));
+
+        // The offsets rstr_ctxt loads from, SP_EL0 sits in x[31]
+        assert_eq!(offset_of!(ExcFrame, x) + 31 * 8, 248);
+        assert_eq!(offset_of!(ExcFrame, elr), 256);
+        assert_eq!(offset_of!(ExcFrame, spsr), 264);
+        assert_eq!(offset_of!(ExcFrame, q), 288);
+        assert_eq!(offset_of!(ExcFrame, fpcr), 800);
+        assert_eq!(offset_of!(ExcFrame, fpsr), 808);
+    }
+}
@@ -1,9 +1,10 @@
-use crate::ram::glacier::{Glacier, BPage, RvmCfg};
+use crate::ram::glacier::{Glacier, BPage, G_CFG, RvmCfg};
 
 use core::arch::asm;
 
 #[allow(dead_code)]
 pub mod flags {
+    pub const PPN_SHIFT: u32 = 0;
     pub const VALID: usize = 0b1;
     pub const NEXT: usize  = 0b100_0000_0011;
 
@@ -19,13 +20,37 @@ pub mod flags {
     pub const U_RWO: usize = 0b111_0100_0011 | 0b11 << 53;
     pub const U_ROX: usize = 0b111_1100_0011;
     pub const U_RWX: usize = 0b111_0100_0011;
+
+    pub const COW: usize   = 1 << 55; // Software bit, write faults copy the page
+
+    pub const fn cow(flags: usize) -> usize { flags | 1 << 7 | COW }
+    pub const fn uncow(flags: usize) -> usize { flags & !(COW | 1 << 7) }
+}
+
+pub fn flush_local(va: usize) {
+    let tlbi_va = va >> unsafe { G_CFG.get_unchecked() }.psz.shift();
+    unsafe {
+        asm!(
+            "tlbi vale1, {va}",
+            "dsb ish",
+            "isb",
+            va = in(reg) tlbi_va
+        );
+    }
 }
 
 impl RvmCfg {
     pub fn detect() -> Self {
-        let mmfr0: usize;
-        unsafe { asm!("mrs {}, ID_AA64MMFR0_EL1", out(reg) mmfr0); }
+        let (mmfr0, mmfr2): (usize, usize);
+        unsafe {
+            asm!("mrs {}, ID_AA64MMFR0_EL1", out(reg) mmfr0);
+            asm!("mrs {}, ID_AA64MMFR2_EL1", out(reg) mmfr2);
+        }
+        return Self::decode(mmfr0, mmfr2);
+    }
 
+    // Split from detect so the ID register decoding can be checked on its own
+    pub fn decode(mmfr0: usize, mmfr2: usize) -> Self {
         let tgran4  = (mmfr0 >> 28) & 0xf;
         let tgran16 = (mmfr0 >> 20) & 0xf;
         let tgran64 = (mmfr0 >> 24) & 0xf;
@@ -40,24 +65,16 @@ impl RvmCfg {
             panic!("No supported page granule found");
         };
 
-        let mmfr2: usize;
-        unsafe { asm!("mrs {}, ID_AA64MMFR2_EL1", out(reg) mmfr2); }
+        // 52-bit VA on 4 KiB and 16 KiB granules needs LPA2 descriptors (TCR.DS),
+        // which the in-place address encoding of Glacier does not produce
         let va_range = (mmfr2 >> 16) & 0xf;
+        let va_bits = if va_range == 1 && psz == BPage::Size64kiB { 52 } else { 48 };
 
-        let va_bits = if va_range == 0 {
-            48
-        } else {
-            match psz {
-                BPage::Size4kiB  if tgran4  != 0x1 => 48,
-                BPage::Size16kiB if tgran16 != 0x2 => 48,
-                _ => 52
-            }
-        };
-
+        // Output addresses past 48 bits move into low descriptor bits, so stop there
         let ips = mmfr0 & 0xf;
         let pa_bits = match ips {
             0 => 32, 1 => 36, 2 => 40, 3 => 42,
-            4 => 44, 5 => 48, 6 => 52, _ => 48
+            4 => 44, _ => 48
         };
 
         return Self { psz, va_bits, pa_bits };
@@ -136,15 +153,7 @@ impl Glacier {
     }
 
     pub fn flush(&self, va: usize) {
-        let tlbi_va = va >> self.cfg().psz.shift();
-        unsafe {
-            asm!(
-                "tlbi vale1, {va}",
-                "dsb ish",
-                "isb",
-                va = in(reg) tlbi_va
-            );
-        }
+        flush_local(va);
     }
 
     pub fn is_active(&self) -> bool {
@@ -174,3 +183,47 @@ impl Glacier {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NO_TGRAN4: usize  = 0xf << 28;
+    const NO_TGRAN64: usize = 0xf << 24;
+    const TGRAN16: usize    = 0x1 << 20;
+    const VARANGE_52: usize = 0x1 << 16;
+
+    #[test]
+    fn granule_without_tgran4() {
+        let cfg = RvmCfg::decode(NO_TGRAN4 | TGRAN16 | 5, 0);
+        assert_eq!(cfg.psz, BPage::Size16kiB);
+        assert_eq!((cfg.va_bits, cfg.pa_bits), (48, 48));
+        assert_eq!(cfg.levels(), 4);
+
+        let cfg = RvmCfg::decode(NO_TGRAN4 | 2, 0);
+        assert_eq!(cfg.psz, BPage::Size64kiB);
+        assert_eq!(cfg.pa_bits, 40);
+
+        let cfg = RvmCfg::decode(TGRAN16 | NO_TGRAN64, 0);
+        assert_eq!(cfg.psz, BPage::Size4kiB);
+    }
+
+    #[test]
+    #[should_panic]
+    fn no_granule_at_all() {
+        RvmCfg::decode(NO_TGRAN4 | NO_TGRAN64, 0);
+    }
+
+    #[test]
+    fn va_52_bits() {
+        let cfg = RvmCfg::decode(NO_TGRAN4 | 6, VARANGE_52);
+        assert_eq!((cfg.psz, cfg.va_bits, cfg.pa_bits), (BPage::Size64kiB, 52, 48));
+        assert_eq!(cfg.levels(), 3);
+        assert_eq!(cfg.tcr_el1() & 0x3f, 12); // T0SZ
+
+        // Without LPA2 a 4 KiB granule stays at 48 bits
+        let cfg = RvmCfg::decode(6, VARANGE_52);
+        assert_eq!((cfg.psz, cfg.va_bits), (BPage::Size4kiB, 48));
+        assert_eq!(cfg.tcr_el1() & 0x3f, 16);
+    }
+}
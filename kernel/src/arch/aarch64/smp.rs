@@ -0,0 +1,116 @@
+use crate::{
+    arch::{cache_lines, psci},
+    kargs::RAMType,
+    ram::{glacier::GLACIER, physalloc::{AllocParams, PHYS_ALLOC}}
+};
+
+use alloc::string::String;
+use core::arch::{asm, global_asm};
+
+// PSCI CPU_ON entry, running at its physical address with the MMU off.
+// x0 = physical address of the ApBoot block.
+global_asm!(
+    ".global ap_entry",
+    "ap_entry:",
+        "mov x1, #(3 << 20)",      // FPEN, no FP/SIMD traps
+        "msr cpacr_el1, x1",
+        "msr tpidr_el1, xzr",      // No PerCpu yet
+        "ldp x1, x2, [x0, #0]",    // mair, tcr
+        "msr mair_el1, x1",
+        "msr tcr_el1, x2",
+        "ldp x1, x2, [x0, #16]",   // ttbr0, ttbr1
+        "msr ttbr0_el1, x1",
+        "msr ttbr1_el1, x2",
+        "isb",
+        "tlbi vmalle1",
+        "dsb sy",
+        "isb",
+
+        "ldr x1, [x0, #32]",       // sctlr
+        "msr sctlr_el1, x1",
+        "isb",
+        "ic iallu",
+        "dsb sy",
+        "isb",
+
+        "ldp x1, x2, [x0, #40]",   // stack, entry
+        "mov sp, x1",
+        "br x2"
+);
+
+unsafe extern "C" {
+    unsafe fn ap_entry();
+}
+
+#[repr(C)]
+struct ApBoot {
+    mair: u64,
+    tcr: u64,
+    ttbr0: u64,
+    ttbr1: u64,
+    sctlr: u64,
+    stack: u64,
+    entry: u64
+}
+
+const PSCI_CPU_ON: u32 = 0xc400_0003;
+
+// The AP reads these with its caches off
+fn clean_dcache(addr: usize, len: usize) {
+    let (dline, _) = cache_lines();
+    for line in (addr & !(dline - 1)..addr + len).step_by(dline) {
+        unsafe { asm!("dc cvac, {}", in(reg) line, options(nostack)); }
+    }
+    unsafe { asm!("dsb sy", options(nostack)); }
+}
+
+fn read_sysregs() -> (u64, u64, u64, u64, u64) {
+    let (mair, tcr, ttbr0, ttbr1, sctlr): (u64, u64, u64, u64, u64);
+    unsafe {
+        asm!(
+            "mrs {mair}, mair_el1",
+            "mrs {tcr}, tcr_el1",
+            "mrs {ttbr0}, ttbr0_el1",
+            "mrs {ttbr1}, ttbr1_el1",
+            "mrs {sctlr}, sctlr_el1",
+            mair = out(reg) mair, tcr = out(reg) tcr,
+            ttbr0 = out(reg) ttbr0, ttbr1 = out(reg) ttbr1,
+            sctlr = out(reg) sctlr,
+            options(nomem, nostack)
+        );
+    }
+    return (mair, tcr, ttbr0, ttbr1, sctlr);
+}
+
+// Each AP gets its own boot block and keeps it, so one that comes up late
+// still finds its own stack
+pub fn start_ap(mpidr: usize, stack_top: usize) -> Result<(), String> {
+    let boot = PHYS_ALLOC.alloc(
+        AllocParams::new(size_of::<ApBoot>()).as_type(RAMType::KernelData)
+    ).ok_or("Failed to allocate AP boot block")?.addr();
+
+    let entry_pa = GLACIER.read().get_pa(ap_entry as *const () as usize)
+        .ok_or("AP entry is not mapped")?;
+    let (mair, tcr, ttbr0, ttbr1, sctlr) = read_sysregs();
+
+    unsafe {
+        (boot as *mut ApBoot).write_volatile(ApBoot {
+            mair, tcr, ttbr0, ttbr1, sctlr,
+            stack: stack_top as u64,
+            entry: crate::ap_main as *const () as u64
+        });
+    }
+    clean_dcache(boot, size_of::<ApBoot>());
+    clean_dcache(ap_entry as *const () as usize, 0x80);
+
+    let ret = psci(PSCI_CPU_ON, mpidr & 0xff_00ff_ffff, entry_pa, boot);
+    if ret != 0 {
+        return Err(alloc::format!("PSCI CPU_ON failed: {}", ret));
+    }
+    return Ok(());
+}
+
+// Nothing to take back, the boot block is not reused
+pub fn abandon_ap(_mpidr: usize) -> bool {
+    return true;
+}
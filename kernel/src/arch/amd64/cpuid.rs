@@ -0,0 +1,117 @@
+use core::{arch::asm, fmt};
+use spin::Once;
+
+// Registers of the leaves that carry the bits below, zero when a leaf is missing
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CpuFeatures {
+    pub leaf1_ecx: u32,
+    pub leaf1_edx: u32,
+    pub leaf7_ebx: u32,
+    pub ext1_edx: u32
+}
+
+impl CpuFeatures {
+    pub fn detect() -> Self {
+        let (max_leaf, ..) = cpuid(0, 0);
+        let (max_ext, ..) = cpuid(0x8000_0000, 0);
+        let (_, _, leaf1_ecx, leaf1_edx) = cpuid(1, 0);
+        let leaf7_ebx = if max_leaf >= 7 { cpuid(7, 0).1 } else { 0 };
+        let ext1_edx = if max_ext >= 0x8000_0001 { cpuid(0x8000_0001, 0).3 } else { 0 };
+        return Self { leaf1_ecx, leaf1_edx, leaf7_ebx, ext1_edx };
+    }
+
+    pub fn has_sse(&self) -> bool    { self.leaf1_edx & (1 << 25) != 0 }
+    pub fn has_xsave(&self) -> bool  { self.leaf1_ecx & (1 << 26) != 0 }
+    pub fn has_rdrand(&self) -> bool { self.leaf1_ecx & (1 << 30) != 0 }
+    pub fn has_rdseed(&self) -> bool { self.leaf7_ebx & (1 << 18) != 0 }
+    pub fn has_nx(&self) -> bool     { self.ext1_edx & (1 << 20) != 0 }
+    pub fn has_1g_pages(&self) -> bool { self.ext1_edx & (1 << 26) != 0 }
+}
+
+impl fmt::Display for CpuFeatures {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names = [
+            (self.has_sse(), "sse"), (self.has_xsave(), "xsave"),
+            (self.has_rdrand(), "rdrand"), (self.has_rdseed(), "rdseed"),
+            (self.has_nx(), "nx"), (self.has_1g_pages(), "1g-pages")
+        ];
+        let mut first = true;
+        for (_, name) in names.iter().filter(|(has, _)| *has) {
+            if !first { f.write_str(" ")?; }
+            f.write_str(name)?;
+            first = false;
+        }
+        if first { f.write_str("none")?; }
+        return Ok(());
+    }
+}
+
+// Returns (eax, ebx, ecx, edx), rbx belongs to LLVM so it is swapped out by hand
+pub fn cpuid(leaf: u32, subleaf: u32) -> (u32, u32, u32, u32) {
+    let (eax, ebx, ecx, edx): (u32, usize, u32, u32);
+    unsafe {
+        asm!(
+            "mov {tmp}, rbx",
+            "cpuid",
+            "xchg {tmp}, rbx",
+            tmp = out(reg) ebx,
+            inout("eax") leaf => eax,
+            inout("ecx") subleaf => ecx,
+            out("edx") edx,
+            options(nomem, nostack, preserves_flags)
+        );
+    }
+    return (eax, ebx as u32, ecx, edx);
+}
+
+static FEATURES: Once<CpuFeatures> = Once::new();
+
+// Read on the BSP and assumed to be the same on every core
+pub fn features() -> &'static CpuFeatures {
+    return FEATURES.call_once(CpuFeatures::detect);
+}
+
+pub fn has_sse() -> bool      { features().has_sse() }
+pub fn has_xsave() -> bool    { features().has_xsave() }
+pub fn has_rdrand() -> bool   { features().has_rdrand() }
+pub fn has_rdseed() -> bool   { features().has_rdseed() }
+pub fn has_nx() -> bool       { features().has_nx() }
+pub fn has_1g_pages() -> bool { features().has_1g_pages() }
+
+// CR0.EM off and MP on, CR4.OSFXSR and OSXMMEXCPT on
+pub fn enable_sse() {
+    unsafe {
+        asm!(
+            "mov {tmp}, cr0",
+            "and {tmp}, -5",
+            "or {tmp}, 0x2",
+            "mov cr0, {tmp}",
+            "mov {tmp}, cr4",
+            "or {tmp}, 0x600",
+            "mov cr4, {tmp}",
+            tmp = out(reg) _,
+            options(nostack, preserves_flags)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    fn leaves_decode() {
+        // QEMU qemu64
+        let qemu = CpuFeatures { leaf1_ecx: 0x80802001, leaf1_edx: 0x078bfbfd, leaf7_ebx: 0, ext1_edx: 0x2193fbfd };
+        assert!(qemu.has_sse() && qemu.has_nx());
+        assert!(!qemu.has_xsave() && !qemu.has_rdrand() && !qemu.has_rdseed() && !qemu.has_1g_pages());
+        assert_eq!(qemu.to_string(), "sse nx");
+
+        // Skylake desktop
+        let skl = CpuFeatures { leaf1_ecx: 0x7ffafbff, leaf1_edx: 0xbfebfbff, leaf7_ebx: 0x029c67af, ext1_edx: 0x2c100800 };
+        assert_eq!(skl.to_string(), "sse xsave rdrand rdseed nx 1g-pages");
+
+        assert_eq!(CpuFeatures::default().to_string(), "none");
+    }
+}
@@ -1,7 +1,11 @@
 use crate::{
-    arch::intc,
+    arch::{cpuid, intc, percpu::{self, PerCpu}, rvm},
+    device::ps2kbd,
+    kargs::AP_LIST,
     kreq::kernel_requestee,
-    printlnk, ram::stack_top
+    printlnk,
+    proc::{fault::{PageFault, handle_page_fault}, preempt, save_ctxt},
+    ram::{VirtPageBuf, check_stack, stack_size, stack_top, tlb}, time, trace
 };
 
 use core::arch::{asm, global_asm};
@@ -123,8 +127,17 @@ global_asm!(
         "swapgs",
         "sysretq",
 
+    // Kernel GS only while in ring 0, user code may have its own or a forged one
     "isr_cmm:",
+        "test qword ptr [rsp + 24], 3", // frame.cs from user mode?
+        "jz 2f",
+        "swapgs",
+    "2:",
         call_handler!(),
+        "test qword ptr [rsp + 24], 3",
+        "jz 3f",
+        "swapgs",
+    "3:",
         "add rsp, 16",           // rsp += 16
         "iretq"
 );
@@ -186,25 +199,32 @@ impl TaskStatSeg {
     }
 }
 
-// Per-CPU data for swapgs
-#[repr(C)]
-pub struct PerCpuData {
-    pub user_rsp: u64,
-    pub kernel_rsp: u64
-}
+// IST slots, so a fault on a broken kernel stack still has somewhere to run.
+// Only for vectors that cannot nest on themselves: an IST entry starts over at
+// the top of its stack, so a #PF taken inside a #PF handler would overwrite the
+// outer frame. Page faults stay on the kernel stack.
+const IST_DF: u8  = 1;
+const IST_NMI: u8 = 2;
+const IST_MC: u8  = 3;
 
 struct CPUDesc {
     gdt: GlobDescTbl,
     tss: TaskStatSeg,
-    percpu: PerCpuData
+    percpu: PerCpu,
+    df_stack: VirtPageBuf,
+    nmi_stack: VirtPageBuf,
+    mc_stack: VirtPageBuf
 }
 
 impl CPUDesc {
-    const fn new() -> Self {
+    fn new() -> Self {
         return Self {
             gdt: GlobDescTbl::new(),
             tss: TaskStatSeg::new(),
-            percpu: PerCpuData { user_rsp: 0, kernel_rsp: 0 }
+            percpu: PerCpu::new(AP_LIST.virtid_self()),
+            df_stack: VirtPageBuf::new(stack_size()).expect("Failed to allocate IST stack"),
+            nmi_stack: VirtPageBuf::new(stack_size()).expect("Failed to allocate IST stack"),
+            mc_stack: VirtPageBuf::new(stack_size()).expect("Failed to allocate IST stack")
         };
     }
 
@@ -224,16 +244,16 @@ impl CPUDesc {
 
     fn load(&mut self, stack_top: usize) {
         self.tss.rsp0 = stack_top as u64;
-        self.tss.ist1 = stack_top as u64;
-        self.percpu.kernel_rsp = stack_top as u64;
-        self.percpu.user_rsp = 0;
+        self.tss.ist1 = self.df_stack.as_ptr_range().end as u64;
+        self.tss.ist2 = self.nmi_stack.as_ptr_range().end as u64;
+        self.tss.ist3 = self.mc_stack.as_ptr_range().end as u64;
+        self.percpu.set_kstack_top(stack_top);
         self.load_tss();
 
         let gdtr = GdtPtr {
             limit: (core::mem::size_of::<GlobDescTbl>() - 1) as u16,
             base: &raw const self.gdt as u64
         };
-        let percpu_addr = &raw const self.percpu as u64;
 
         unsafe {
             asm!(
@@ -255,13 +275,8 @@ impl CPUDesc {
                 options(nostack)
             );
 
-            asm!(
-                "wrmsr",
-                in("ecx") 0xc0000102u32,
-                in("eax") percpu_addr as u32,
-                in("edx") (percpu_addr >> 32) as u32,
-                options(nostack)
-            );
+            // Boxed in CPU_DESCS for good
+            percpu::set(&*(&raw const self.percpu));
         }
     }
 }
@@ -295,6 +310,10 @@ impl IdtEnt {
     }
 }
 
+fn ist_for(vector: usize) -> u8 {
+    return match vector { 2 => IST_NMI, 8 => IST_DF, 18 => IST_MC, _ => 0 };
+}
+
 static IDT: RwLock<[IdtEnt; 256]> = RwLock::new([IdtEnt::new(); 256]);
 
 #[repr(C, packed)]
@@ -317,6 +336,17 @@ pub struct ExcFrame {
     pub rip: u64, pub cs: u64, pub rflags: u64, pub rsp: u64, pub ss: u64
 }
 
+// #PF error code bits, the address comes from CR2
+fn decode_pf(frame: &ExcFrame, addr: usize) -> PageFault {
+    return PageFault {
+        addr,
+        present: frame.err & (1 << 0) != 0,
+        write: frame.err & (1 << 1) != 0,
+        user: frame.err & (1 << 2) != 0,
+        exec: frame.err & (1 << 4) != 0
+    };
+}
+
 #[unsafe(no_mangle)]
 extern "C" fn exc_handler(exc_type: u64, frame: &mut ExcFrame) {
     match exc_type { // exc_type == frame.vec
@@ -350,19 +380,55 @@ extern "C" fn exc_handler(exc_type: u64, frame: &mut ExcFrame) {
         // ..32 => { /* reserved by Intel */ }
         // // END OF CPU EXCEPTIONS
 
+        8 => { // #DF, on the IST stack so this much still works after a stack overflow
+            printlnk!("Double fault on CPU {}", crate::arch::phys_id());
+            printlnk!("rip={:#018x} cs={:#x} rflags={:#x}", frame.rip, frame.cs, frame.rflags);
+            printlnk!("rsp={:#018x} rbp={:#018x} ss={:#x}", frame.rsp, frame.rbp, frame.ss);
+            printlnk!("rax={:#018x} rbx={:#018x} rcx={:#018x}", frame.rax, frame.rbx, frame.rcx);
+            printlnk!("rdx={:#018x} rsi={:#018x} rdi={:#018x}", frame.rdx, frame.rsi, frame.rdi);
+            panic!("Double fault");
+        }
+
+        14 => { // #PF
+            let cr2: usize;
+            unsafe { asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack)); }
+            let fault = decode_pf(frame, cr2);
+
+            if !handle_page_fault(&fault) {
+                printlnk!("Exception frame: {:#x?}", frame);
+                panic!("Unhandled page fault");
+            }
+        }
+
         32 => { // timer
             intc::eoi(0);
-            printlnk!("Timer IRQ");
+            trace!("Timer IRQ");
+            check_stack();
+            time::handle_irq();
+            if frame.cs & 0b11 == 0b11 && percpu::this_cpu().take_resched() { // from user mode
+                preempt(frame);
+            }
             return;
         }
 
+        34 => { // PS/2 keyboard
+            ps2kbd::handle_irq();
+            intc::eoi(0);
+        }
+
         128 => { /* syscall */
+            save_ctxt(frame);
             frame.rax = kernel_requestee(
                 frame.rax as *const u8,
                 frame.rdi as usize, frame.rsi as usize, frame.rdx as usize,
                 frame.r10 as usize, frame.r8 as usize, frame.r9 as usize
             ) as u64;
         }
+
+        240 => { // TLB shootdown
+            tlb::handle_ipi();
+            intc::eoi(0);
+        }
         ..256 => { /* reserved or IRQ */
             printlnk!("Exception type: {}", exc_type);
             printlnk!("Exception frame: {:#x?}", frame);
@@ -392,6 +458,10 @@ pub fn set(enabled: bool) {
 }
 
 pub fn init() {
+    // The ISR stubs save the XMM registers with movaps
+    if cpuid::has_sse() { cpuid::enable_sse(); }
+    rvm::enable_wp();
+
     let mut desc = Box::new(CPUDesc::new());
     desc.load(stack_top());
     CPU_DESCS.write().insert(crate::arch::phys_id(), desc);
@@ -402,8 +472,7 @@ pub fn init() {
         for i in 0..256 {
             let handler = ISR_STUBS[i] as u64;
             let attr = if [0x20, 0x80].contains(&i) { 0xee } else { 0x8e };
-            let ist = if i == 8 { 1 } else { 0 };
-            idt[i].set(handler, 0x08, ist, attr);
+            idt[i].set(handler, 0x08, ist_for(i), attr);
         }
 
         let idtr = IdtPtr {
@@ -459,6 +528,49 @@ pub fn set_kstk(kstk_top: usize) {
     let mut descs = CPU_DESCS.write();
     if let Some(desc) = descs.get_mut(&crate::arch::phys_id()) {
         desc.tss.rsp0 = kstk_top as u64;
-        desc.percpu.kernel_rsp = kstk_top as u64;
+        desc.percpu.set_kstack_top(kstk_top);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    fn frame(err: u64) -> ExcFrame {
+        let mut frame: ExcFrame = unsafe { core::mem::zeroed() };
+        (frame.vec, frame.err) = (14, err);
+        return frame;
+    }
+
+    #[test]
+    fn page_fault_error_code() {
+        let fault = decode_pf(&frame(0b00110), 0x1000);
+        assert!(!fault.present && fault.write && fault.user && !fault.exec);
+        assert_eq!(fault.to_string(), "user write at 0x1000 (not present)");
+
+        let fault = decode_pf(&frame(0b10101), 0x40_0000);
+        assert!(fault.present && !fault.write && fault.user && fault.exec);
+        assert_eq!(fault.to_string(), "user exec at 0x400000 (protection)");
+
+        let fault = decode_pf(&frame(0), 0xdead_0000);
+        assert!(!fault.present && !fault.write && !fault.user && !fault.exec);
+        assert_eq!(fault.to_string(), "kernel read at 0xdead0000 (not present)");
+    }
+
+    #[test]
+    fn fatal_vectors_use_ist_stacks() {
+        let mut idt = [IdtEnt::new(); 256];
+        for (i, ent) in idt.iter_mut().enumerate() {
+            ent.set(0xffff_8000_1234_5678 + i as u64, 0x08, ist_for(i), 0x8e);
+        }
+        assert_eq!(idt[8].ist, IST_DF);
+        assert_eq!(idt[2].ist, IST_NMI);
+        assert_eq!(idt[18].ist, IST_MC);
+        assert_eq!(idt[14].ist, 0);
+        assert_eq!(idt.iter().filter(|ent| ent.ist != 0).count(), 3);
+
+        let df = idt[8];
+        assert_eq!((df.off_lo, df.off_mid, df.off_hi), (0x5680, 0x1234, 0xffff_8000));
     }
 }
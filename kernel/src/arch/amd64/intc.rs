@@ -17,6 +17,7 @@ const LAPIC_TIMER_CCR: usize = 0x390;
 const LAPIC_TIMER_DCR: usize = 0x3e0;
 
 static TIMER_FREQ: AtomicU64 = AtomicU64::new(0);
+static TSC_FREQ: AtomicU64 = AtomicU64::new(0);
 
 #[inline(always)]
 fn lapic_read(off: usize) -> u32 {
@@ -62,6 +63,7 @@ fn calibrate_timer() {
         lapic_write(LAPIC_TIMER_ICR, 0xffffffff);
 
         asm!("out 0x61, al", in("al") 1u8);
+        let tsc_start = counter();
 
         loop {
             let status: u8;
@@ -70,8 +72,10 @@ fn calibrate_timer() {
         }
 
         let elapsed = 0xffffffffu32 - lapic_read(LAPIC_TIMER_CCR);
+        let tsc_elapsed = counter() - tsc_start;
         let freq = (elapsed as u64) * 1000 / CALIB_MS;
         TIMER_FREQ.store(freq, AtomOrd::Relaxed);
+        TSC_FREQ.store(tsc_elapsed * 1000 / CALIB_MS, AtomOrd::Relaxed);
     }
 }
 
@@ -86,6 +90,8 @@ pub fn eoi(_intid: u32) {
 pub fn enable(_intid: u32) {}
 pub fn disable(_intid: u32) {}
 
+pub const IPI_TLB: u32 = 0xf0; // TLB shootdown
+
 pub fn send_ipi_others(vector: u32) {
     lapic_write(LAPIC_ICR_HI, 0);
     lapic_write(LAPIC_ICR_LO, (3 << 18) | (vector & 0xff));
@@ -96,11 +102,41 @@ pub fn send_ipi(vector: u32, target: u32) {
     lapic_write(LAPIC_ICR_LO, vector & 0xff);
 }
 
+// INIT, level assert
+pub fn send_init(target: u32) {
+    lapic_write(LAPIC_ICR_HI, target << 24);
+    lapic_write(LAPIC_ICR_LO, 0x4500);
+}
+
+// Startup IPI, the AP starts in real mode at `page` << 12
+pub fn send_startup(target: u32, page: u32) {
+    lapic_write(LAPIC_ICR_HI, target << 24);
+    lapic_write(LAPIC_ICR_LO, 0x4600 | (page & 0xff));
+}
+
+// Fixed delivery, edge triggered, physical destination
+pub fn msi_msg(vector: u32, target: u32) -> Option<(u64, u32)> {
+    return Some((0xfee0_0000 | ((target as u64 & 0xff) << 12), vector & 0xff));
+}
+
 #[inline(always)]
 pub fn timer_freq() -> u64 {
     return TIMER_FREQ.load(AtomOrd::Relaxed);
 }
 
+// Monotonic counter ticking at `counter_freq()`, assumes an invariant TSC
+#[inline(always)]
+pub fn counter() -> u64 {
+    let (lo, hi): (u32, u32);
+    unsafe { asm!("rdtsc", out("eax") lo, out("edx") hi, options(nomem, nostack)); }
+    return ((hi as u64) << 32) | lo as u64;
+}
+
+#[inline(always)]
+pub fn counter_freq() -> u64 {
+    return TSC_FREQ.load(AtomOrd::Relaxed);
+}
+
 #[inline(always)]
 pub fn timer_enable() {
     let lvt = lapic_read(LAPIC_LVT_TIMER);
@@ -0,0 +1,141 @@
+use alloc::{string::String, vec::Vec};
+use spin::Mutex;
+
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize    = 0x10;
+
+const IOAPICVER: u32  = 0x01;
+const IOREDTBL: u32   = 0x10;
+
+const REDIR_MASKED: u32     = 1 << 16;
+const REDIR_LEVEL: u32      = 1 << 15;
+const REDIR_ACTIVE_LOW: u32 = 1 << 13;
+
+struct IoApic {
+    base: usize,
+    gsi_base: u32,
+    count: u32
+}
+
+impl IoApic {
+    fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            ((self.base + IOREGSEL) as *mut u32).write_volatile(reg);
+            return ((self.base + IOWIN) as *const u32).read_volatile();
+        }
+    }
+
+    fn write(&self, reg: u32, val: u32) {
+        unsafe {
+            ((self.base + IOREGSEL) as *mut u32).write_volatile(reg);
+            ((self.base + IOWIN) as *mut u32).write_volatile(val);
+        }
+    }
+
+    fn owns(&self, gsi: u32) -> bool {
+        return (self.gsi_base..self.gsi_base + self.count).contains(&gsi);
+    }
+
+    fn write_redir(&self, gsi: u32, (lo, hi): (u32, u32)) {
+        let reg = IOREDTBL + (gsi - self.gsi_base) * 2;
+        self.write(reg, REDIR_MASKED); // Never expose a half written entry
+        self.write(reg + 1, hi);
+        self.write(reg, lo);
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Redir {
+    pub vector: u8,
+    pub dest: u32, // LAPIC ID
+    pub level: bool,
+    pub active_low: bool,
+    pub masked: bool
+}
+
+impl Redir {
+    // Fixed delivery, physical destination
+    pub fn encode(&self) -> (u32, u32) {
+        let mut lo = self.vector as u32;
+        if self.level      { lo |= REDIR_LEVEL; }
+        if self.active_low { lo |= REDIR_ACTIVE_LOW; }
+        if self.masked     { lo |= REDIR_MASKED; }
+        return (lo, (self.dest & 0xff) << 24);
+    }
+}
+
+#[derive(Clone, Copy)]
+struct IsaOverride {
+    irq: u8,
+    gsi: u32,
+    flags: u16
+}
+
+static IOAPICS: Mutex<Vec<IoApic>> = Mutex::new(Vec::new());
+static OVERRIDES: Mutex<Vec<IsaOverride>> = Mutex::new(Vec::new());
+
+// `base` must already be mapped
+pub fn add(base: usize, gsi_base: u32) {
+    let mut ioapic = IoApic { base, gsi_base, count: 0 };
+    ioapic.count = ((ioapic.read(IOAPICVER) >> 16) & 0xff) + 1;
+
+    for gsi in gsi_base..gsi_base + ioapic.count {
+        ioapic.write_redir(gsi, (REDIR_MASKED, 0));
+    }
+    IOAPICS.lock().push(ioapic);
+}
+
+pub fn add_override(irq: u8, gsi: u32, flags: u16) {
+    OVERRIDES.lock().push(IsaOverride { irq, gsi, flags });
+}
+
+pub fn route(gsi: u32, redir: Redir) -> Result<(), String> {
+    let ioapics = IOAPICS.lock();
+    let ioapic = ioapics.iter().find(|io| io.owns(gsi)).ok_or("No IO-APIC for this GSI")?;
+    ioapic.write_redir(gsi, redir.encode());
+    return Ok(());
+}
+
+// ISA IRQs are edge triggered and active high unless the MADT says otherwise
+pub fn route_isa(irq: u8, vector: u8, apic_id: u32) -> Result<u32, String> {
+    let mut redir = Redir { vector, dest: apic_id, level: false, active_low: false, masked: false };
+    let mut gsi = irq as u32;
+
+    if let Some(ovr) = OVERRIDES.lock().iter().find(|o| o.irq == irq) {
+        gsi = ovr.gsi;
+        redir.active_low = ovr.flags & 0b11 == 0b11;
+        redir.level = (ovr.flags >> 2) & 0b11 == 0b11;
+    }
+
+    route(gsi, redir)?;
+    return Ok(gsi);
+}
+
+fn set_mask(gsi: u32, masked: bool) {
+    let ioapics = IOAPICS.lock();
+    let Some(ioapic) = ioapics.iter().find(|io| io.owns(gsi)) else { return; };
+    let reg = IOREDTBL + (gsi - ioapic.gsi_base) * 2;
+
+    let lo = ioapic.read(reg);
+    ioapic.write(reg, if masked { lo | REDIR_MASKED } else { lo & !REDIR_MASKED });
+}
+
+pub fn mask(gsi: u32) { set_mask(gsi, true); }
+pub fn unmask(gsi: u32) { set_mask(gsi, false); }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redirection_entry_encoding() {
+        let redir = Redir { vector: 0x21, dest: 3, level: false, active_low: false, masked: false };
+        assert_eq!(redir.encode(), (0x0000_0021, 0x0300_0000));
+
+        let redir = Redir { vector: 0x40, dest: 0x1ff, level: true, active_low: true, masked: true };
+        let (lo, hi) = redir.encode();
+        assert_eq!(lo, 0x0001_a040);
+        // Only an 8-bit physical APIC ID fits the destination field
+        assert_eq!(hi, 0xff00_0000);
+    }
+}
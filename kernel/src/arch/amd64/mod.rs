@@ -1,7 +1,11 @@
+pub mod cpuid;
 pub mod exc;
 pub mod intc;
+pub mod percpu;
+pub mod ioapic;
 pub mod proc;
 pub mod rvm;
+pub mod smp;
 
 use core::{arch::asm, fmt::{Result, Write}};
 
@@ -23,30 +27,25 @@ pub const R_SYM: &[usize] = &[
 ];
 
 const COM1: u16 = 0x3f8;
+pub const DEFAULT_BAUD: u32 = 115200;
 
 #[inline(always)]
 pub fn phys_id() -> usize {
-    let apic_id: u32;
-    unsafe {
-        asm!(
-            "push rax",
-            "push rbx",
-            "push rcx",
-            "push rdx",
-            "mov eax, 1",
-            "cpuid",
-            "mov {0:e}, ebx",
-            "pop rdx",
-            "pop rcx",
-            "pop rbx",
-            "pop rax",
-            out(reg) apic_id
-        );
-    }
-    return (apic_id >> 24) as usize;
+    let (_, ebx, _, _) = cpuid::cpuid(1, 0);
+    return (ebx >> 24) as usize;
+}
+
+// The 16550 divides a 1.8432 MHz clock by 16, so divisor 1 is 115200 baud
+fn baud_divisor(baud: u32) -> u16 {
+    return (115200 / baud.max(1)).clamp(1, u16::MAX as u32) as u16;
 }
 
 pub fn init_serial() {
+    init_serial_with(DEFAULT_BAUD);
+}
+
+pub fn init_serial_with(baud: u32) {
+    let [div_lo, div_hi] = baud_divisor(baud).to_le_bytes();
     unsafe {
         asm!(
             "mov dx, {com1_base}",
@@ -59,11 +58,11 @@ pub fn init_serial() {
             "out dx, al",
 
             "sub dx, 3",    // COM1 + 0
-            "mov al, 0x01", // Set divisor to 1 (lo byte) 115200 baud
+            "mov al, {div_lo}", // Set divisor (lo byte)
             "out dx, al",
 
             "inc dx",       // COM1 + 1
-            "mov al, 0x00", //                  (hi byte)
+            "mov al, {div_hi}", //       (hi byte)
             "out dx, al",
 
             "add dx, 2",    // COM1 + 3
@@ -79,6 +78,8 @@ pub fn init_serial() {
             "out dx, al",
 
             com1_base = const COM1,
+            div_lo = in(reg_byte) div_lo,
+            div_hi = in(reg_byte) div_hi,
             out("dx") _,
             out("al") _
         );
@@ -107,6 +108,16 @@ pub fn serial_putchar(byte: u8) {
     }
 }
 
+pub fn serial_getchar() -> Option<u8> {
+    let lsr: u8;
+    unsafe { asm!("in al, dx", in("dx") COM1 + 5, out("al") lsr); }
+    if lsr & 0x01 == 0 { return None; } // Data not ready
+
+    let byte: u8;
+    unsafe { asm!("in al, dx", in("dx") COM1, out("al") byte); }
+    return Some(byte);
+}
+
 pub struct SerialWriter;
 
 impl Write for SerialWriter {
@@ -116,6 +127,16 @@ impl Write for SerialWriter {
     }
 }
 
+// rbp points at the saved rbp, the return address sits right above it
+pub const FRAME_RECORD: isize = 0;
+
+#[inline(always)]
+pub fn frame_ptr() -> usize {
+    let fp: usize;
+    unsafe { asm!("mov {}, rbp", out(reg) fp); }
+    return fp;
+}
+
 #[inline(always)]
 pub fn stack_ptr() -> *const u8 {
     let rsp: usize;
@@ -129,3 +150,36 @@ pub unsafe fn move_stack(addr: usize) {
         asm!("mov rsp, {}", in(reg) addr);
     }
 }
+
+// Nothing on the old stack can be touched once rsp moves, so both happen here
+pub unsafe fn jump_with_stack(stack: usize, entry: usize) -> ! {
+    unsafe {
+        asm!(
+            "mov rsp, {stack}",
+            "xor ebp, ebp",
+            "call {entry}", // Keeps the ABI stack alignment
+            "ud2",
+            stack = in(reg) stack,
+            entry = in(reg) entry,
+            options(noreturn)
+        );
+    }
+}
+
+// Instruction fetch is coherent with stores
+pub fn sync_icache(_addr: usize, _len: usize) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn divisors_for_common_rates() {
+        assert_eq!(baud_divisor(115200), 1);
+        assert_eq!(baud_divisor(38400), 3);
+        assert_eq!(baud_divisor(9600), 12);
+        // Out of range rates clamp to what the latch can hold
+        assert_eq!(baud_divisor(230400), 1);
+        assert_eq!(baud_divisor(0), 0xffff);
+    }
+}
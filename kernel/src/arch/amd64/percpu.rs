@@ -0,0 +1,132 @@
+use crate::arch::CliState;
+
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering as AtomOrd}
+};
+
+const NO_PID: usize = usize::MAX;
+
+// Reached through the GS base. The syscall stub reads gs:[0] and gs:[8].
+#[repr(C)]
+pub struct PerCpu {
+    user_rsp: AtomicU64,   // gs:[0]
+    kernel_rsp: AtomicU64, // gs:[8]
+    this: AtomicU64,       // gs:[16]
+    virtid: usize,
+    pid: AtomicUsize,
+    cli: CliState,
+    slice: AtomicU64, // Time slice timer, 0 for none
+    resched: AtomicBool
+}
+
+impl PerCpu {
+    pub const fn new(virtid: usize) -> Self {
+        return Self {
+            user_rsp: AtomicU64::new(0),
+            kernel_rsp: AtomicU64::new(0),
+            this: AtomicU64::new(0),
+            virtid,
+            pid: AtomicUsize::new(NO_PID),
+            cli: CliState::new(),
+            slice: AtomicU64::new(0),
+            resched: AtomicBool::new(false)
+        };
+    }
+
+    pub fn virtid(&self) -> usize {
+        return self.virtid;
+    }
+
+    pub fn kstack_top(&self) -> usize {
+        return self.kernel_rsp.load(AtomOrd::Relaxed) as usize;
+    }
+
+    pub fn set_kstack_top(&self, top: usize) {
+        self.kernel_rsp.store(top as u64, AtomOrd::Relaxed);
+    }
+
+    pub fn pid(&self) -> Option<usize> {
+        let pid = self.pid.load(AtomOrd::Relaxed);
+        return (pid != NO_PID).then_some(pid);
+    }
+
+    pub fn swap_pid(&self, pid: Option<usize>) -> Option<usize> {
+        let old = self.pid.swap(pid.unwrap_or(NO_PID), AtomOrd::Relaxed);
+        return (old != NO_PID).then_some(old);
+    }
+
+    pub fn cli(&self) -> &CliState {
+        return &self.cli;
+    }
+
+    pub fn swap_slice(&self, id: u64) -> u64 {
+        return self.slice.swap(id, AtomOrd::Relaxed);
+    }
+
+    pub fn set_resched(&self, resched: bool) {
+        self.resched.store(resched, AtomOrd::Relaxed);
+    }
+
+    pub fn take_resched(&self) -> bool {
+        return self.resched.swap(false, AtomOrd::Relaxed);
+    }
+}
+
+// The kernel GS base points here while in ring 0. Every entry from user mode
+// (syscall, isr_cmm) swaps it in and every return swaps the user's back, so
+// this_cpu never sees a GS base userspace could have set.
+pub fn set(cpu: &'static PerCpu) {
+    let addr = cpu as *const PerCpu as u64;
+    cpu.this.store(addr, AtomOrd::Relaxed);
+    cpu.user_rsp.store(0, AtomOrd::Relaxed);
+
+    for (msr, val) in [(0xc0000101u32, addr), (0xc0000102, 0)] {
+        unsafe {
+            asm!(
+                "wrmsr",
+                in("ecx") msr,
+                in("eax") val as u32,
+                in("edx") (val >> 32) as u32,
+                options(nostack)
+            );
+        }
+    }
+}
+
+// GS base is 0 until set, the BSP clears whatever firmware left there
+pub fn clear() {
+    for msr in [0xc0000101u32, 0xc0000102] {
+        unsafe { asm!("wrmsr", in("ecx") msr, in("eax") 0, in("edx") 0, options(nostack)); }
+    }
+}
+
+pub fn try_this_cpu() -> Option<&'static PerCpu> {
+    let (lo, hi): (u32, u32);
+    unsafe {
+        asm!("rdmsr", in("ecx") 0xc0000101u32, out("eax") lo, out("edx") hi, options(nomem, nostack, preserves_flags));
+    }
+    if lo == 0 && hi == 0 { return None; }
+    return Some(this_cpu());
+}
+
+pub fn this_cpu() -> &'static PerCpu {
+    let ptr: usize;
+    unsafe {
+        asm!("mov {}, gs:[16]", out(reg) ptr, options(nostack, readonly, preserves_flags));
+        return &*(ptr as *const PerCpu);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::offset_of;
+
+    #[test]
+    fn offsets_match_the_gs_accesses() {
+        assert_eq!(offset_of!(PerCpu, user_rsp), 0);
+        assert_eq!(offset_of!(PerCpu, kernel_rsp), 8);
+        assert_eq!(offset_of!(PerCpu, this), 16);
+    }
+}
@@ -37,6 +37,10 @@ impl ExcFrame {
         }
     }
 
+    pub const fn set_ret(&mut self, ret: usize) {
+        self.rax = ret as u64;
+    }
+
     pub const fn set_pc(&mut self, pc: usize) {
         self.rip = pc as u64;
     }
@@ -106,9 +110,12 @@ pub unsafe fn rstr_ctxt(ctxt: &ExcFrame, kstk_top: usize) -> ! {
             "push qword ptr [r15 + 416]",
             "push qword ptr [r15 + 408]",
 
+            "test qword ptr [r15 + 416], 3", // back to user mode, see isr_cmm
             "mov r14, [r15 + 280]",
             "mov r15, [r15 + 272]",
-
+            "jz 2f",
+            "swapgs",
+            "2:",
             "iretq",
             ctxt = in(reg) ctxt,
             ksp = in(reg) kstk_top,
@@ -116,3 +123,29 @@ pub unsafe fn rstr_ctxt(ctxt: &ExcFrame, kstk_top: usize) -> ! {
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::offset_of;
+
+    #[test]
+    fn restore_reads_the_saved_pc_and_sp() {
+        // A timer tick taken in user mode, as save_ctxt copies it into the PCB
+        let mut frame = ExcFrame::new();
+        (frame.vec, frame.rip, frame.rsp) = (32, 0x40_1234, 0x7fff_f000);
+        let ctxt = alloc::boxed::Box::new(frame);
+        assert_eq!((ctxt.pc(), ctxt.sp()), (0x40_1234, 0x7fff_f000));
+        assert_eq!(ctxt.cs & 3, 3);
+
+        // The offsets rstr_ctxt loads from
+        assert_eq!(offset_of!(ExcFrame, mxcsr), 256);
+        assert_eq!(offset_of!(ExcFrame, r15), 272);
+        assert_eq!(offset_of!(ExcFrame, rax), 384);
+        assert_eq!(offset_of!(ExcFrame, rip), 408);
+        assert_eq!(offset_of!(ExcFrame, cs), 416);
+        assert_eq!(offset_of!(ExcFrame, rflags), 424);
+        assert_eq!(offset_of!(ExcFrame, rsp), 432);
+        assert_eq!(offset_of!(ExcFrame, ss), 440);
+    }
+}
@@ -4,6 +4,7 @@ use core::arch::asm;
 
 #[allow(dead_code)]
 pub mod flags {
+    pub const PPN_SHIFT: u32 = 0;
     pub const VALID: usize = 0b1;
     pub const NEXT: usize  = 0b111;
 
@@ -19,6 +20,29 @@ pub mod flags {
     pub const U_RWO: usize = 0b111 | 1 << 63;
     pub const U_ROX: usize = 0b101;
     pub const U_RWX: usize = 0b111;
+
+    pub const COW: usize   = 1 << 9; // Software bit, write faults copy the page
+
+    pub const fn cow(flags: usize) -> usize { flags & !0b10 | COW }
+    pub const fn uncow(flags: usize) -> usize { flags & !COW | 0b10 }
+}
+
+pub fn flush_local(va: usize) {
+    unsafe { asm!("invlpg [{}]", in(reg) va, options(nostack, preserves_flags)); }
+}
+
+// CR0.WP: read-only PTEs bind ring 0 as well, so kernel copies into a COW
+// page fault into cow_fault instead of writing through to the shared frame
+pub fn enable_wp() {
+    unsafe {
+        asm!(
+            "mov {tmp}, cr0",
+            "or {tmp}, 0x10000",
+            "mov cr0, {tmp}",
+            tmp = out(reg) _,
+            options(nostack, preserves_flags)
+        );
+    }
 }
 
 impl RvmCfg {
@@ -55,7 +79,9 @@ impl Glacier {
         }
     }
 
-    pub fn flush(&self, _va: usize) {}
+    pub fn flush(&self, va: usize) {
+        flush_local(va);
+    }
 
     pub fn is_active(&self) -> bool {
         let ptr: usize;
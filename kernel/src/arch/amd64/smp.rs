@@ -0,0 +1,248 @@
+use crate::{
+    arch::intc,
+    kargs::RAMType,
+    ram::{glacier::page_size, physalloc::{AllocParams, LOW_MEM_END, PHYS_ALLOC}},
+    time::busy_wait_ns
+};
+
+use alloc::string::String;
+use core::{
+    arch::{asm, global_asm},
+    sync::atomic::{AtomicU64, Ordering as AtomOrd}
+};
+use spin::Once;
+
+// Real mode entry, copied below 1 MiB and started by SIPI with CS = base >> 4.
+// Every absolute address is derived from ebx (the base) at run time.
+global_asm!(
+    ".global ap_tramp_start",
+    ".global ap_tramp_gdt",
+    ".global ap_tramp_data",
+    ".global ap_tramp_end",
+
+    // LLVM takes no label differences inside memory operands, so name them
+    ".set OFF_GDT, ap_tramp_gdt - ap_tramp_start",
+    ".set OFF_GDTR, ap_tramp_gdtr - ap_tramp_start",
+    ".set OFF_PM32, ap_tramp_pm32 - ap_tramp_start",
+    ".set OFF_PM_PTR, ap_tramp_pm_ptr - ap_tramp_start",
+    ".set OFF_DATA, ap_tramp_data - ap_tramp_start",
+    ".set OFF_LM64, ap_tramp_lm64 - ap_tramp_start",
+    ".set OFF_LM_PTR, ap_tramp_lm_ptr - ap_tramp_start",
+
+    ".code16",
+    "ap_tramp_start:",
+        "cli",
+        "cld",
+        "mov ax, cs",
+        "mov ds, ax",
+        "xor ebx, ebx",
+        "mov bx, ax",
+        "shl ebx, 4",                                           // ebx = trampoline base
+
+        "lea eax, [ebx + OFF_GDT]",
+        "mov dword ptr [OFF_GDTR + 2], eax",
+        "lea eax, [ebx + OFF_PM32]",
+        "mov dword ptr [OFF_PM_PTR], eax",
+        "lgdt [OFF_GDTR]",
+
+        "mov eax, cr0",
+        "or eax, 1",                                            // PE
+        "mov cr0, eax",
+        "jmp fword ptr [OFF_PM_PTR]",
+
+    ".code32",
+    "ap_tramp_pm32:",
+        "mov ax, 0x10",
+        "mov ds, ax",
+        "mov es, ax",
+        "mov ss, ax",
+
+        "mov eax, [ebx + OFF_DATA + 8]",                        // cr4
+        "mov cr4, eax",
+        "mov eax, [ebx + OFF_DATA]",                            // low root table
+        "mov cr3, eax",
+        "mov ecx, 0xc0000080",
+        "mov eax, [ebx + OFF_DATA + 16]",                       // efer
+        "xor edx, edx",
+        "wrmsr",
+
+        "mov eax, cr0",
+        "or eax, 0x80000000",                                   // PG
+        "mov cr0, eax",
+        "lea eax, [ebx + OFF_LM64]",
+        "mov [ebx + OFF_LM_PTR], eax",
+        "jmp fword ptr [ebx + OFF_LM_PTR]",
+
+    // Takes the stack and entry, then claims them by swapping its own APIC id
+    // in owner for CLAIMED. Halts if the BSP has given up on it meanwhile.
+    ".code64",
+    "ap_tramp_lm64:",
+        "mov esi, ebx",                                         // cpuid takes ebx
+        "mov eax, 1",
+        "cpuid",
+        "shr ebx, 24",                                          // initial APIC id
+        "mov r8, [rsi + OFF_DATA + 32]",                        // stack
+        "mov r9, [rsi + OFF_DATA + 40]",                        // entry
+        "mov eax, ebx",
+        "mov rcx, -1",                                          // CLAIMED
+        "lock cmpxchg [rsi + OFF_DATA + 48], rcx",
+        "jne ap_tramp_dead",
+        "mov rax, [rsi + OFF_DATA + 24]",                       // kernel root table
+        "mov cr3, rax",
+        "mov rsp, r8",
+        "jmp r9",
+    "ap_tramp_dead:",
+        "cli",
+        "hlt",
+        "jmp ap_tramp_dead",
+
+    ".align 8",
+    "ap_tramp_gdt:",
+        ".quad 0",
+        ".quad 0x00cf9a000000ffff", // 0x08: 32-bit code
+        ".quad 0x00cf92000000ffff", // 0x10: data
+        ".quad 0x00af9a000000ffff", // 0x18: 64-bit code
+    "ap_tramp_gdtr:",
+        ".word 31",
+        ".long 0",
+    "ap_tramp_pm_ptr:",
+        ".long 0",
+        ".word 0x08",
+    "ap_tramp_lm_ptr:",
+        ".long 0",
+        ".word 0x18",
+
+    ".align 8",
+    "ap_tramp_data:",
+        ".fill 7, 8, 0",
+    "ap_tramp_end:"
+);
+
+unsafe extern "C" {
+    static ap_tramp_start: u8;
+    static ap_tramp_gdt: u8;
+    static ap_tramp_data: u8;
+    static ap_tramp_end: u8;
+}
+
+#[repr(C)]
+struct TrampData {
+    root_low: u64,
+    cr4: u64,
+    efer: u64,
+    root: u64,
+    stack: u64,
+    entry: u64,
+    owner: AtomicU64 // APIC id of the AP the data is for, CLAIMED or ABANDONED
+}
+
+const CLAIMED: u64   = u64::MAX;
+const ABANDONED: u64 = u64::MAX - 1;
+
+// Trampoline page followed by a copy of the root table, both below 1 MiB
+static TRAMP_BASE: Once<usize> = Once::new();
+
+fn tramp_data(base: usize) -> *mut TrampData {
+    let start = &raw const ap_tramp_start as usize;
+    let data = &raw const ap_tramp_data as usize;
+    return (base + data - start) as *mut TrampData;
+}
+
+// The code is copied once, an AP given up on may still be running it
+fn alloc_tramp() -> Result<usize, String> {
+    let size = page_size() * 2;
+    for addr in (page_size()..LOW_MEM_END - size).step_by(page_size()) {
+        if let Some(ptr) = PHYS_ALLOC.alloc(
+            AllocParams::new(size)
+                .at(addr as *mut u8)
+                .from_type(RAMType::LowMemory)
+                .as_type(RAMType::KernelData)
+        ) {
+            unsafe {
+                let start = &raw const ap_tramp_start as usize;
+                let end = &raw const ap_tramp_end as usize;
+                (start as *const u8).copy_to(ptr.addr() as *mut u8, end - start);
+                (*tramp_data(ptr.addr())).owner.store(ABANDONED, AtomOrd::Release);
+            }
+            return Ok(ptr.addr());
+        }
+    }
+    return Err("No low memory for the AP trampoline".into());
+}
+
+fn read_ctrl() -> (u64, u64, u64) {
+    let (cr3, cr4): (u64, u64);
+    let (lo, hi): (u32, u32);
+    unsafe {
+        asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack));
+        asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack));
+        asm!("rdmsr", in("ecx") 0xc0000080u32, out("eax") lo, out("edx") hi, options(nomem, nostack));
+    }
+    return (cr3 & !0xfff, cr4, ((hi as u64) << 32) | lo as u64);
+}
+
+pub fn start_ap(apic_id: usize, stack_top: usize) -> Result<(), String> {
+    let base = *TRAMP_BASE.try_call_once(alloc_tramp)?;
+    let (root, cr4, efer) = read_ctrl();
+
+    // Owner is CLAIMED or ABANDONED here, a late AP reading along fails its claim
+    let data = tramp_data(base);
+    unsafe {
+        // 32-bit mode can only load a root table below 4 GiB
+        let root_low = base + page_size();
+        (root as *const u8).copy_to(root_low as *mut u8, page_size());
+
+        (&raw mut (*data).root_low).write_volatile(root_low as u64);
+        (&raw mut (*data).cr4).write_volatile(cr4 & !(1 << 17));   // PCIDE needs long mode
+        (&raw mut (*data).efer).write_volatile(efer & !(1 << 10)); // LMA is read only
+        (&raw mut (*data).root).write_volatile(root);
+        (&raw mut (*data).stack).write_volatile(stack_top as u64);
+        (&raw mut (*data).entry).write_volatile(crate::ap_main as *const () as u64);
+        (*data).owner.store(apic_id as u64, AtomOrd::Release);
+    }
+
+    intc::send_init(apic_id as u32);
+    busy_wait_ns(10_000_000);
+    for _ in 0..2 {
+        intc::send_startup(apic_id as u32, (base / page_size()) as u32);
+        busy_wait_ns(200_000);
+    }
+    return Ok(());
+}
+
+// Gives up on an AP that has not claimed its data. False when it already has,
+// it is then past the trampoline and comes up on its own.
+pub fn abandon_ap(apic_id: usize) -> bool {
+    let Some(&base) = TRAMP_BASE.get() else { return true; };
+    let owner = unsafe { &(*tramp_data(base)).owner };
+    return match owner.compare_exchange(apic_id as u64, ABANDONED, AtomOrd::AcqRel, AtomOrd::Acquire) {
+        Ok(_) => true,
+        Err(cur) => cur != CLAIMED
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Base 0, limit 0xfffff, with the given access byte and flags nibble
+    fn flat(access: u8, flags: u8) -> u64 {
+        return 0xffff | (access as u64) << 40 | 0xf << 48 | (flags as u64) << 52;
+    }
+
+    #[test]
+    fn trampoline_gdt_descriptors() {
+        let gdt = unsafe { core::slice::from_raw_parts(&raw const ap_tramp_gdt, 50) };
+        let desc = |i: usize| u64::from_le_bytes(gdt[i * 8..i * 8 + 8].try_into().unwrap());
+
+        assert_eq!(desc(0), 0);
+        assert_eq!(desc(1), flat(0x9a, 0b1100)); // Ring 0 code, 32-bit, 4 KiB granular
+        assert_eq!(desc(2), flat(0x92, 0b1100)); // Ring 0 data
+        assert_eq!(desc(3), flat(0x9a, 0b1010)); // Ring 0 code, long mode and so D clear
+
+        // GDTR limit covers the four entries, the far pointers use 0x08 and 0x18
+        assert_eq!(gdt[32..34], 31u16.to_le_bytes());
+        assert_eq!(gdt[42..44], 0x08u16.to_le_bytes());
+        assert_eq!(gdt[48..50], 0x18u16.to_le_bytes());
+    }
+}
@@ -6,4 +6,107 @@ macro_rules! use_arch {
 }
 
 use_arch!("aarch64", aarch64);
+use_arch!("riscv64", riscv64);
 use_arch!("x86_64", amd64);
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering as AtomOrd};
+
+#[cfg(not(test))]
+use exc::{get as int_enabled, set as set_int};
+#[cfg(test)]
+use host::{cli_state, get as int_enabled, set as set_int};
+
+// Nesting depth of push_cli and the interrupt state before the outermost one.
+// Only touched by its own CPU with interrupts off.
+pub struct CliState {
+    depth: AtomicUsize,
+    enabled: AtomicBool
+}
+
+impl CliState {
+    pub const fn new() -> Self {
+        return Self { depth: AtomicUsize::new(0), enabled: AtomicBool::new(false) };
+    }
+}
+
+// Stands in until a CPU has its PerCpu. Only one CPU is ever that early at a
+// time: the BSP before exc::init, then each AP in turn while it comes up.
+#[cfg(not(test))]
+static BOOT_CLI: CliState = CliState::new();
+
+#[cfg(not(test))]
+fn cli_state() -> &'static CliState {
+    return percpu::try_this_cpu().map_or(&BOOT_CLI, |cpu| cpu.cli());
+}
+
+// Host tests cannot touch the real flag, each test thread plays a CPU of its own
+#[cfg(test)]
+pub mod host {
+    use super::CliState;
+    use alloc::boxed::Box;
+    use core::cell::Cell;
+
+    std::thread_local! {
+        static ENABLED: Cell<bool> = const { Cell::new(true) };
+        static ENABLES: Cell<usize> = const { Cell::new(0) };
+        static CLI: &'static CliState = Box::leak(Box::new(CliState::new()));
+    }
+
+    pub fn cli_state() -> &'static CliState { CLI.with(|cli| *cli) }
+    pub fn get() -> bool { ENABLED.get() }
+    pub fn set(enabled: bool) {
+        if enabled { ENABLES.set(ENABLES.get() + 1); }
+        ENABLED.set(enabled);
+    }
+    // Times interrupts were turned on, to catch early or repeated restores
+    pub fn enables() -> usize { ENABLES.get() }
+}
+
+pub fn push_cli() {
+    let enabled = int_enabled();
+    set_int(false);
+    let cli = cli_state();
+    if cli.depth.fetch_add(1, AtomOrd::Relaxed) == 0 {
+        cli.enabled.store(enabled, AtomOrd::Relaxed);
+    }
+}
+
+// Interrupts come back only when the outermost push_cli is undone
+pub fn pop_sti() {
+    let cli = cli_state();
+    let depth = cli.depth.load(AtomOrd::Relaxed);
+    if depth == 0 { return; }
+    cli.depth.store(depth - 1, AtomOrd::Relaxed);
+    if depth == 1 && cli.enabled.load(AtomOrd::Relaxed) {
+        set_int(true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn three_deep_restores_once() {
+        host::set(true);
+        let before = host::enables();
+        for _ in 0..3 { push_cli(); }
+        assert!(!host::get());
+
+        pop_sti();
+        pop_sti();
+        assert!(!host::get());
+        pop_sti();
+        assert!(host::get());
+        assert_eq!(host::enables(), before + 1);
+
+        // An unbalanced pop is ignored, and off before stays off
+        pop_sti();
+        assert_eq!(host::enables(), before + 1);
+        host::set(false);
+        for _ in 0..3 { push_cli(); }
+        for _ in 0..3 { pop_sti(); }
+        assert!(!host::get());
+        assert_eq!(host::enables(), before + 1);
+    }
+}
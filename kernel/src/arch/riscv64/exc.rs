@@ -0,0 +1,38 @@
+use crate::printlnk;
+
+use core::arch::{asm, global_asm};
+
+// Nothing is handled yet, every trap ends up in exc_panic
+global_asm!(
+    ".align 4",
+    ".global exc_vector",
+    "exc_vector:",
+        "csrr a0, scause",
+        "csrr a1, sepc",
+        "csrr a2, stval",
+        "j exc_panic"
+);
+
+unsafe extern "C" {
+    unsafe fn exc_vector();
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn exc_panic(scause: usize, sepc: usize, stval: usize) -> ! {
+    printlnk!("Unhandled trap: scause={:#x} sepc={:#x} stval={:#x}", scause, sepc, stval);
+    loop { unsafe { asm!("wfi"); } }
+}
+
+pub fn init() {
+    unsafe { asm!("csrw stvec, {}", in(reg) exc_vector as *const () as usize); } // Direct mode
+}
+
+pub fn set(enable: bool) {
+    unsafe {
+        if enable {
+            asm!("csrsi sstatus, 0b10"); // SIE
+        } else {
+            asm!("csrci sstatus, 0b10");
+        }
+    }
+}
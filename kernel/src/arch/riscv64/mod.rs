@@ -0,0 +1,145 @@
+pub mod exc;
+pub mod rvm;
+
+use crate::{
+    arch::rvm::flags,
+    ram::glacier::{GLACIER, page_size}
+};
+
+use core::{arch::asm, fmt::{Result, Write}, hint::spin_loop};
+
+pub fn wfi() {
+    exc::set(true);
+    unsafe { asm!("wfi"); }
+}
+
+pub fn halt() {
+    exc::set(false);
+    unsafe { asm!("wfi"); }
+}
+
+pub const R_REL: usize    = 3; // R_RELATIVE
+pub const R_SYM: &[usize] = &[
+    2, // R_64:        S + A
+    5  // R_JUMP_SLOT: S
+];
+
+const UART0_BASE: usize = 0x1000_0000; // QEMU virt NS16550A UART
+const UART0_CLOCK: u32 = 3_686_400;
+pub const DEFAULT_BAUD: u32 = 115200;
+
+#[inline(always)]
+fn serial_io() -> usize {
+    0usize.wrapping_sub(page_size())
+}
+
+// The boot path keeps the hart id in tp, S-mode cannot read mhartid
+#[inline(always)]
+pub fn phys_id() -> usize {
+    let hartid: usize;
+    unsafe { asm!("mv {}, tp", out(reg) hartid); }
+    return hartid;
+}
+
+// SBI call, returns (error, value)
+pub fn sbi(eid: usize, fid: usize, a0: usize, a1: usize, a2: usize) -> (isize, usize) {
+    let (err, val): (isize, usize);
+    unsafe {
+        asm!(
+            "ecall",
+            inout("a0") a0 => err,
+            inout("a1") a1 => val,
+            in("a2") a2,
+            in("a6") fid, in("a7") eid,
+            options(nostack)
+        );
+    }
+    return (err, val);
+}
+
+pub fn init_serial() {
+    init_serial_with(DEFAULT_BAUD);
+}
+
+pub fn init_serial_with(baud: u32) {
+    let sio = serial_io();
+    let _ = GLACIER.write().map_page(sio, UART0_BASE, flags::D_RW);
+    let div = (UART0_CLOCK / (16 * baud.max(1))).clamp(1, u16::MAX as u32) as u16;
+
+    unsafe {
+        ((sio + 1) as *mut u8).write_volatile(0x00); // No interrupts
+        ((sio + 3) as *mut u8).write_volatile(0x80); // DLAB
+        ((sio + 0) as *mut u8).write_volatile(div as u8);
+        ((sio + 1) as *mut u8).write_volatile((div >> 8) as u8);
+        ((sio + 3) as *mut u8).write_volatile(0x03); // 8N1, DLAB off
+        ((sio + 2) as *mut u8).write_volatile(0x07); // Enable and clear FIFOs
+    }
+}
+
+pub fn serial_putchar(c: u8) {
+    let sio = serial_io();
+    unsafe {
+        while ((sio + 5) as *const u8).read_volatile() & (1 << 5) == 0 { spin_loop(); } // THRE
+        (sio as *mut u8).write_volatile(c);
+    }
+}
+
+pub fn serial_getchar() -> Option<u8> {
+    let sio = serial_io();
+    unsafe {
+        if ((sio + 5) as *const u8).read_volatile() & 1 == 0 { return None; } // DR
+        return Some((sio as *const u8).read_volatile());
+    }
+}
+
+pub struct SerialWriter;
+
+impl Write for SerialWriter {
+    fn write_str(&mut self, s: &str) -> Result {
+        for byte in s.bytes() { serial_putchar(byte); }
+        Ok(())
+    }
+}
+
+// s0 points past the saved {s0, ra} pair at the top of the frame
+pub const FRAME_RECORD: isize = -16;
+
+#[inline(always)]
+pub fn frame_ptr() -> usize {
+    let fp: usize;
+    unsafe { asm!("mv {}, s0", out(reg) fp); }
+    return fp;
+}
+
+#[inline(always)]
+pub fn stack_ptr() -> *const u8 {
+    let sp: usize;
+    unsafe { asm!("mv {}, sp", out(reg) sp); }
+    return sp as *const u8;
+}
+
+#[inline(always)]
+pub unsafe fn move_stack(addr: usize) {
+    unsafe {
+        asm!("mv sp, {}", in(reg) addr);
+    }
+}
+
+// Nothing on the old stack can be touched once sp moves, so both happen here
+pub unsafe fn jump_with_stack(stack: usize, entry: usize) -> ! {
+    unsafe {
+        asm!(
+            "mv sp, {stack}",
+            "li s0, 0",
+            "li ra, 0",
+            "jr {entry}",
+            stack = in(reg) stack,
+            entry = in(reg) entry,
+            options(noreturn)
+        );
+    }
+}
+
+pub fn sync_icache(_addr: usize, _len: usize) {
+    unsafe { asm!("fence.i", options(nostack)); }
+}
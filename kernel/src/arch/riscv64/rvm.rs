@@ -0,0 +1,82 @@
+use crate::ram::glacier::{Glacier, BPage, RvmCfg};
+
+use core::arch::asm;
+
+#[allow(dead_code)]
+pub mod flags {
+    pub const PPN_SHIFT: u32 = 2; // PPN sits at bit 10 of the entry
+    pub const VALID: usize = 0b1;
+    pub const NEXT: usize  = 0b1; // No R/W/X, points to the next level
+
+    // V, R, W, X, U, G, A, D from bit 0
+    pub const K_ROO: usize = 0b0100_0011;
+    pub const K_RWO: usize = 0b1100_0111;
+    pub const K_ROX: usize = 0b0100_1011;
+    pub const K_RWX: usize = 0b1100_1111;
+
+    // Without Svpbmt, device memory is told apart by the PMAs alone
+    pub const D_RO: usize  = 0b0100_0011;
+    pub const D_RW: usize  = 0b1100_0111;
+
+    pub const U_ROO: usize = 0b0101_0011;
+    pub const U_RWO: usize = 0b1101_0111;
+    pub const U_ROX: usize = 0b0101_1011;
+    pub const U_RWX: usize = 0b1101_1111;
+
+    pub const COW: usize   = 1 << 8; // Software bit, write faults copy the page
+
+    pub const fn cow(flags: usize) -> usize { flags & !0b100 | COW }
+    pub const fn uncow(flags: usize) -> usize { flags & !COW | 0b100 }
+}
+
+const SATP_SV39: usize = 8;
+const SATP_SV48: usize = 9;
+
+pub fn flush_local(va: usize) {
+    unsafe { asm!("sfence.vma {}, zero", in(reg) va, options(nostack)); }
+}
+
+impl RvmCfg {
+    // Sv48 is what QEMU virt and most application cores offer
+    pub fn detect() -> Self {
+        return Self {
+            psz: BPage::Size4kiB,
+            va_bits: 48, pa_bits: 56
+        };
+    }
+
+    fn satp_mode(&self) -> usize {
+        return if self.va_bits == 39 { SATP_SV39 } else { SATP_SV48 };
+    }
+}
+
+impl Glacier {
+    fn satp(&self) -> usize {
+        return self.cfg().satp_mode() << 60 | (self.root_table() as usize >> 12);
+    }
+
+    pub fn identity_map(&self) {
+        self.activate();
+    }
+
+    pub fn flush(&self, va: usize) {
+        flush_local(va);
+    }
+
+    pub fn is_active(&self) -> bool {
+        let satp: usize;
+        unsafe { asm!("csrr {}, satp", out(reg) satp); }
+        return (satp & ((1 << 44) - 1)) << 12 == self.root_table() as usize;
+    }
+
+    pub fn activate(&self) {
+        unsafe {
+            asm!(
+                "csrw satp, {satp}",
+                "sfence.vma zero, zero",
+                "fence.i",
+                satp = in(reg) self.satp()
+            );
+        }
+    }
+}
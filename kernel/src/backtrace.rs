@@ -0,0 +1,129 @@
+use crate::{arch::{FRAME_RECORD, frame_ptr, stack_ptr}, printlnk};
+
+use core::ops::Range;
+
+const MAX_DEPTH: usize = 32;
+// Farthest a caller's frame can be above sp, one whole per-CPU region
+const STACK_SPAN: usize = 0x40000;
+
+unsafe extern "C" {
+    static __text_start: u8;
+    static __text_end: u8;
+}
+
+// Runtime bounds of .text, wherever the kernel is running from
+pub fn text_range() -> Range<usize> {
+    let start = &raw const __text_start as usize;
+    let end = &raw const __text_end as usize;
+    return start..end;
+}
+
+// Follows the saved frame pointer chain from `fp`, handing each return address to `f`.
+// Stops at the first frame record outside `stack`, a return address outside `text`,
+// a link that doesn't move up the stack (so no cycles), or after MAX_DEPTH frames.
+// Returns the number of frames visited.
+pub fn walk(mut fp: usize, stack: Range<usize>, text: Range<usize>, mut f: impl FnMut(usize)) -> usize {
+    let word = size_of::<usize>();
+    for depth in 0..MAX_DEPTH {
+        let rec = fp.wrapping_add_signed(FRAME_RECORD);
+        if rec % word != 0 || rec < stack.start || rec.saturating_add(2 * word) > stack.end {
+            return depth;
+        }
+
+        let (next, ret) = unsafe {
+            let rec = rec as *const usize;
+            (rec.read_volatile(), rec.add(1).read_volatile())
+        };
+        if !text.contains(&ret) { return depth; }
+        f(ret);
+
+        if next <= fp { return depth + 1; }
+        fp = next;
+    }
+    return MAX_DEPTH;
+}
+
+// .text is linked at 0, so the offsets can go to addr2line as they are
+pub fn print() {
+    let sp = stack_ptr() as usize;
+    let text = text_range();
+    let base = text.start;
+
+    printlnk!("Backtrace:");
+    let depth = walk(frame_ptr(), sp..sp.saturating_add(STACK_SPAN), text, |ret| {
+        printlnk!("  {:#018x} (.text+{:#x})", ret, ret - base);
+    });
+    if depth == 0 { printlnk!("  <no frames>"); }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{vec, vec::Vec};
+
+    const TEXT: Range<usize> = 0x1000..0x2000;
+
+    // Frame records at the given word indices of `stack`, each linked to the next
+    // one up with the last link zeroed. Returns the frame pointer of the first.
+    fn chain(stack: &mut [usize], slots: &[usize], rets: &[usize]) -> usize {
+        let fp_of = |stack: &[usize], slot: usize| {
+            (&stack[slot] as *const usize as usize).wrapping_add_signed(-FRAME_RECORD)
+        };
+        for (i, (&slot, &ret)) in slots.iter().zip(rets).enumerate() {
+            stack[slot] = slots.get(i + 1).map_or(0, |&next| fp_of(stack, next));
+            stack[slot + 1] = ret;
+        }
+        return fp_of(stack, slots[0]);
+    }
+
+    fn bounds(stack: &[usize]) -> Range<usize> {
+        return stack.as_ptr_range().start as usize..stack.as_ptr_range().end as usize;
+    }
+
+    #[test]
+    fn synthetic_chain() {
+        let mut stack = vec![0usize; 64];
+        let fp = chain(&mut stack, &[4, 10, 30], &[0x1100, 0x1200, 0x1300]);
+
+        let mut seen = Vec::new();
+        assert_eq!(walk(fp, bounds(&stack), TEXT, |ret| seen.push(ret)), 3);
+        assert_eq!(seen, [0x1100, 0x1200, 0x1300]);
+
+        // A frame pointer off the stack walks nothing
+        assert_eq!(walk(0, bounds(&stack), TEXT, |_| panic!()), 0);
+        assert_eq!(walk(fp + 1, bounds(&stack), TEXT, |_| panic!()), 0);
+    }
+
+    #[test]
+    fn garbage_ends_the_walk() {
+        let mut stack = vec![0usize; 64];
+
+        // Return address outside .text, the bad frame is not reported
+        let fp = chain(&mut stack, &[2, 8, 16], &[0x1100, 0x5000, 0x1300]);
+        let mut seen = Vec::new();
+        assert_eq!(walk(fp, bounds(&stack), TEXT, |ret| seen.push(ret)), 1);
+        assert_eq!(seen, [0x1100]);
+
+        // Linking back down the stack would loop forever
+        let fp = chain(&mut stack, &[20, 26], &[0x1100, 0x1200]);
+        stack[26] = fp;
+        assert_eq!(walk(fp, bounds(&stack), TEXT, |_| {}), 2);
+
+        // Linking past the top of the stack
+        let fp = chain(&mut stack, &[40, 62], &[0x1100, 0x1200]);
+        stack[62] = usize::MAX - 8;
+        assert_eq!(walk(fp, bounds(&stack), TEXT, |_| {}), 2);
+    }
+
+    #[test]
+    fn depth_is_bounded() {
+        let mut stack = vec![0usize; 2 * MAX_DEPTH + 8];
+        let slots: Vec<usize> = (0..MAX_DEPTH + 4).map(|i| 2 * i).collect();
+        let rets = vec![0x1800; slots.len()];
+        let fp = chain(&mut stack, &slots, &rets);
+
+        let mut count = 0;
+        assert_eq!(walk(fp, bounds(&stack), TEXT, |_| count += 1), MAX_DEPTH);
+        assert_eq!(count, MAX_DEPTH);
+    }
+}
@@ -1,7 +1,8 @@
 use crate::{
     arch::rvm::flags,
     device::PCI_DEVICES,
-    ram::{align_down, align_up, glacier::{GLACIER, page_size}}
+    ram::{align_down, align_up, glacier::{GLACIER, page_size}},
+    time
 };
 
 #[allow(unused)]
@@ -11,10 +12,64 @@ use acpi::aml::AmlError;
 use alloc::collections::btree_map::BTreeMap;
 use spin::Mutex;
 
-#[derive(Clone, Copy, Debug)]
-pub struct KernelAcpiHandler;
+// `writable` tells unmapping which kind of mapping is going away
+#[derive(Clone, Copy, Debug, Default)]
+pub struct KernelAcpiHandler {
+    writable: bool
+}
+
+// A page stays writable while any register mapping covers it
+struct AcpiPage {
+    refs: usize,
+    writers: usize
+}
+
+impl AcpiPage {
+    fn flags(&self) -> usize {
+        return if self.writers > 0 { flags::K_RWO } else { flags::K_ROO };
+    }
+}
+
+static ACPI_MAP: Mutex<BTreeMap<usize, AcpiPage>> = Mutex::new(BTreeMap::new());
 
-static ACPI_MAP: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+// Counts a new mapping of `pages`, `map(addr, flags)` runs for each page
+// that is fresh or has to become writable
+fn take_pages(
+    acpi_map: &mut BTreeMap<usize, AcpiPage>, pages: impl Iterator<Item = usize>,
+    writable: bool, mut map: impl FnMut(usize, usize)
+) {
+    for addr in pages {
+        let page = acpi_map.entry(addr).or_insert(AcpiPage { refs: 0, writers: 0 });
+        let upgrade = writable && page.writers == 0;
+        let fresh = page.refs == 0;
+
+        page.refs += 1;
+        if writable { page.writers += 1; }
+        if fresh || upgrade {
+            map(addr, page.flags());
+        }
+    }
+}
+
+// The reverse of take_pages, `remap(addr, None)` unmaps a page nothing covers any more
+fn drop_pages(
+    acpi_map: &mut BTreeMap<usize, AcpiPage>, pages: impl Iterator<Item = usize>,
+    writable: bool, mut remap: impl FnMut(usize, Option<usize>)
+) {
+    for addr in pages {
+        let Some(page) = acpi_map.get_mut(&addr) else { continue; };
+        page.refs -= 1;
+        if writable { page.writers -= 1; }
+
+        if page.refs == 0 {
+            acpi_map.remove(&addr);
+            remap(addr, None);
+        } else if writable && page.writers == 0 {
+            // Only table mappings are left
+            remap(addr, Some(flags::K_ROO));
+        }
+    }
+}
 
 fn find_dev_ptr(addr: PciAddress) -> Option<usize> {
     return PCI_DEVICES.read().iter().find(|d| {
@@ -24,34 +79,40 @@ fn find_dev_ptr(addr: PciAddress) -> Option<usize> {
     }).map(|d| d.ptr() as usize);
 }
 
-impl Handler for KernelAcpiHandler {
-    unsafe fn map_physical_region<T>(
-        &self, phys_addr: usize, size: usize
-    ) -> PhysicalMapping<Self, T> {
+impl KernelAcpiHandler {
+    // Register windows, such as PM1 control or the reset register
+    pub unsafe fn map_registers<T>(&self, phys_addr: usize, size: usize) -> PhysicalMapping<Self, T> {
+        return unsafe { self.map_region(phys_addr, size, true) };
+    }
+
+    unsafe fn map_region<T>(&self, phys_addr: usize, size: usize, writable: bool) -> PhysicalMapping<Self, T> {
         let mut glacier = GLACIER.write();
         let mut acpi_map = ACPI_MAP.lock();
 
         let start_page = align_down(phys_addr, page_size());
         let end_page = align_up(phys_addr + size, page_size());
 
-        for addr in (start_page..end_page).step_by(page_size()) {
-            if let Some(rcnt) = acpi_map.get_mut(&addr) {
-                *rcnt += 1;
-            } else {
-                acpi_map.insert(addr, 1);
-                glacier.map_page(addr, addr, flags::K_RWO)
-                    .expect("Failed to map ACPI physical region");
-            }
-        }
+        take_pages(&mut acpi_map, (start_page..end_page).step_by(page_size()), writable, |addr, flags| {
+            glacier.map_page(addr, addr, flags).expect("Failed to map ACPI physical region");
+        });
 
         return unsafe { PhysicalMapping {
             physical_start: phys_addr,
             virtual_start: NonNull::new_unchecked(phys_addr as *mut T),
             region_length: size,
             mapped_length: size,
-            handler: *self
+            handler: KernelAcpiHandler { writable }
         } };
     }
+}
+
+impl Handler for KernelAcpiHandler {
+    // Tables are firmware data, a stray write should fault
+    unsafe fn map_physical_region<T>(
+        &self, phys_addr: usize, size: usize
+    ) -> PhysicalMapping<Self, T> {
+        return unsafe { self.map_region(phys_addr, size, false) };
+    }
 
     fn unmap_physical_region<T>(region: &PhysicalMapping<Self, T>) {
         let mut glacier = GLACIER.write();
@@ -60,15 +121,11 @@ impl Handler for KernelAcpiHandler {
         let start_page = align_down(region.physical_start, page_size());
         let end_page = align_up(region.physical_start + region.region_length, page_size());
 
-        for addr in (start_page..end_page).step_by(page_size()) {
-            if let Some(rcnt) = acpi_map.get_mut(&addr) {
-                *rcnt -= 1;
-                if *rcnt == 0 {
-                    acpi_map.remove(&addr);
-                    glacier.unmap_page(addr);
-                }
-            }
-        }
+        let pages = (start_page..end_page).step_by(page_size());
+        drop_pages(&mut acpi_map, pages, region.handler.writable, |addr, flags| match flags {
+            Some(flags) => { let _ = glacier.map_page(addr, addr, flags); }
+            None => glacier.unmap_page(addr)
+        });
     }
 
     fn read_u8(&self, addr: usize) -> u8 { unsafe { *(addr as *const u8) } }
@@ -76,10 +133,23 @@ impl Handler for KernelAcpiHandler {
     fn read_u32(&self, addr: usize) -> u32 { unsafe { *(addr as *const u32) } }
     fn read_u64(&self, addr: usize) -> u64 { unsafe { *(addr as *const u64) } }
 
-    fn write_u8(&self, addr: usize, val: u8) { unsafe { *(addr as *mut u8) = val; } }
-    fn write_u16(&self, addr: usize, val: u16) { unsafe { *(addr as *mut u16) = val; } }
-    fn write_u32(&self, addr: usize, val: u32) { unsafe { *(addr as *mut u32) = val; } }
-    fn write_u64(&self, addr: usize, val: u64) { unsafe { *(addr as *mut u64) = val; } }
+    // Writes hit operation regions and registers, mapped writable for the access
+    fn write_u8(&self, addr: usize, val: u8) {
+        let _map = unsafe { self.map_registers::<u8>(addr, 1) };
+        unsafe { *(addr as *mut u8) = val; }
+    }
+    fn write_u16(&self, addr: usize, val: u16) {
+        let _map = unsafe { self.map_registers::<u16>(addr, 2) };
+        unsafe { *(addr as *mut u16) = val; }
+    }
+    fn write_u32(&self, addr: usize, val: u32) {
+        let _map = unsafe { self.map_registers::<u32>(addr, 4) };
+        unsafe { *(addr as *mut u32) = val; }
+    }
+    fn write_u64(&self, addr: usize, val: u64) {
+        let _map = unsafe { self.map_registers::<u64>(addr, 8) };
+        unsafe { *(addr as *mut u64) = val; }
+    }
 
     fn read_io_u8(&self, port: u16) -> u8 {
         #[cfg(target_arch = "x86_64")]
@@ -173,11 +243,70 @@ impl Handler for KernelAcpiHandler {
         }
     }
 
-    fn nanos_since_boot(&self) -> u64 { 0 }
-    fn stall(&self, _us: u64) {}
-    fn sleep(&self, _ms: u64) {}
+    fn nanos_since_boot(&self) -> u64 { time::uptime_ns() }
+    fn stall(&self, us: u64) { time::busy_wait_ns(us * 1000); }
+    fn sleep(&self, ms: u64) { time::sleep_ms(ms); }
 
     fn create_mutex(&self) -> Handle { Handle(0) }
     fn acquire(&self, _mutex: Handle, _timeout: u16) -> Result<(), AmlError> { Ok(()) }
     fn release(&self, _mutex: Handle) {}
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    const PSZ: usize = 0x1000;
+
+    // Without a write permission, on every architecture
+    fn read_only(flags: usize) -> bool {
+        return flags::cow(flags) == flags | flags::COW;
+    }
+
+    fn take(acpi_map: &mut BTreeMap<usize, AcpiPage>, start: usize, end: usize, writable: bool) -> Vec<(usize, usize)> {
+        let mut mapped = Vec::new();
+        take_pages(acpi_map, (start..end).step_by(PSZ), writable, |addr, flags| mapped.push((addr, flags)));
+        return mapped;
+    }
+
+    #[test]
+    fn tables_map_read_only() {
+        let mut acpi_map = BTreeMap::new();
+        let mapped = take(&mut acpi_map, 0x7fe0_0000, 0x7fe0_2000, false);
+        assert_eq!(mapped.len(), 2);
+        assert!(mapped.iter().all(|&(_, flags)| read_only(flags)));
+
+        let mapped = take(&mut acpi_map, 0xfee0_0000, 0xfee0_1000, true);
+        assert_eq!(mapped, [(0xfee0_0000, flags::K_RWO)]);
+        assert!(!read_only(flags::K_RWO));
+    }
+
+    #[test]
+    fn overlapping_regions_share_pages() {
+        let mut acpi_map = BTreeMap::new();
+        let (table, regs) = (0x7fe0_0000, 0x7fe0_1000);
+        take(&mut acpi_map, table, table + 2 * PSZ, false);
+
+        // The register window shares the table's second page, which turns writable
+        assert_eq!(take(&mut acpi_map, regs, regs + 2 * PSZ, true), [(regs, flags::K_RWO), (regs + PSZ, flags::K_RWO)]);
+        let shared = &acpi_map[&regs];
+        assert_eq!((shared.refs, shared.writers), (2, 1));
+        assert!(take(&mut acpi_map, regs, regs + PSZ, true).is_empty()); // Already writable
+
+        let mut remapped = Vec::new();
+        let mut release = |acpi_map: &mut BTreeMap<usize, AcpiPage>, start: usize, end: usize, writable| {
+            drop_pages(acpi_map, (start..end).step_by(PSZ), writable, |addr, flags| remapped.push((addr, flags)));
+        };
+        release(&mut acpi_map, regs, regs + PSZ, true);
+        release(&mut acpi_map, regs, regs + 2 * PSZ, true);
+        release(&mut acpi_map, table, table + 2 * PSZ, false);
+        assert_eq!(remapped, [
+            (regs, Some(flags::K_ROO)), // Back to the table alone
+            (regs + PSZ, None),
+            (table, None),
+            (regs, None)
+        ]);
+        assert!(acpi_map.is_empty());
+    }
+}
@@ -0,0 +1,301 @@
+use crate::{
+    arch::rvm::flags,
+    device::{
+        PciDevice,
+        block::{BLOCK_DEVICES, BlockDevType, BlockDevice, DevId}
+    },
+    ram::{PhysPageBuf, glacier::{GLACIER, page_size}},
+    warn
+};
+
+use alloc::{string::String, sync::Arc};
+use core::hint::spin_loop;
+use spin::Mutex;
+
+// HBA registers
+const HBA_GHC: usize = 0x04;
+const HBA_PI: usize  = 0x0c;
+const HBA_SIZE: usize = 0x1100;
+
+const GHC_AE: u32 = 1 << 31;
+
+// Port registers, at 0x100 + port * 0x80
+const PX_CLB: usize  = 0x00;
+const PX_CLBU: usize = 0x04;
+const PX_FB: usize   = 0x08;
+const PX_FBU: usize  = 0x0c;
+const PX_IS: usize   = 0x10;
+const PX_CMD: usize  = 0x18;
+const PX_TFD: usize  = 0x20;
+const PX_SIG: usize  = 0x24;
+const PX_SSTS: usize = 0x28;
+const PX_SERR: usize = 0x30;
+const PX_CI: usize   = 0x38;
+
+const CMD_ST: u32  = 1 << 0;
+const CMD_FRE: u32 = 1 << 4;
+const CMD_FR: u32  = 1 << 14;
+const CMD_CR: u32  = 1 << 15;
+
+const TFD_ERR: u32 = 1 << 0;
+const TFD_DRQ: u32 = 1 << 3;
+const TFD_BSY: u32 = 1 << 7;
+const IS_TFES: u32 = 1 << 30;
+
+const SIG_SATA: u32 = 0x0000_0101;
+
+const ATA_READ_DMA_EXT: u8  = 0x25;
+const ATA_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_IDENTIFY: u8      = 0xec;
+
+const FIS_H2D: u8 = 0x27;
+
+// Layout of the per-port DMA page
+const CMD_LIST: usize  = 0x000; // 32 headers of 32 bytes, only slot 0 is used
+const RECV_FIS: usize  = 0x400;
+const CMD_TABLE: usize = 0x800; // CFIS at 0x00, PRDT at 0x80
+
+const SECTOR: usize = 512;
+const MAX_SECTORS: usize = 128; // Per command, one PRDT entry
+
+struct AhciPort {
+    regs: usize,
+    dma: PhysPageBuf
+}
+
+impl AhciPort {
+    fn read(&self, off: usize) -> u32 {
+        unsafe { return ((self.regs + off) as *const u32).read_volatile(); }
+    }
+
+    fn write(&self, off: usize, val: u32) {
+        unsafe { ((self.regs + off) as *mut u32).write_volatile(val); }
+    }
+
+    fn wait_clear(&self, off: usize, mask: u32) -> Result<(), String> {
+        for _ in 0..1_000_000 {
+            if self.read(off) & mask == 0 { return Ok(()); }
+            spin_loop();
+        }
+        return Err("AHCI port timeout".into());
+    }
+
+    fn stop(&self) -> Result<(), String> {
+        self.write(PX_CMD, self.read(PX_CMD) & !CMD_ST);
+        self.wait_clear(PX_CMD, CMD_CR)?;
+        self.write(PX_CMD, self.read(PX_CMD) & !CMD_FRE);
+        return self.wait_clear(PX_CMD, CMD_FR);
+    }
+
+    fn start(&mut self) -> Result<(), String> {
+        self.stop()?;
+        self.dma.fill(0);
+
+        let base = self.dma.ptr::<u8>() as u64;
+        self.write(PX_CLB, (base + CMD_LIST as u64) as u32);
+        self.write(PX_CLBU, ((base + CMD_LIST as u64) >> 32) as u32);
+        self.write(PX_FB, (base + RECV_FIS as u64) as u32);
+        self.write(PX_FBU, ((base + RECV_FIS as u64) >> 32) as u32);
+        self.write(PX_SERR, !0);
+        self.write(PX_IS, !0);
+
+        self.write(PX_CMD, self.read(PX_CMD) | CMD_FRE);
+        self.write(PX_CMD, self.read(PX_CMD) | CMD_ST);
+        return Ok(());
+    }
+
+    // Issues one ATA command on slot 0 with a single PRDT entry over `buf`
+    fn issue(&mut self, cmd: u8, lba: u64, count: u16, buf: usize, len: usize, write: bool) -> Result<(), String> {
+        self.wait_clear(PX_TFD, TFD_BSY | TFD_DRQ)?;
+        build_command(&mut self.dma, cmd, lba, count, buf, len, write);
+
+        self.write(PX_IS, !0);
+        self.write(PX_CI, 1);
+
+        loop {
+            if self.read(PX_CI) & 1 == 0 { break; }
+            if self.read(PX_IS) & IS_TFES != 0 { break; }
+            spin_loop();
+        }
+
+        if self.read(PX_IS) & IS_TFES != 0 || self.read(PX_TFD) & TFD_ERR != 0 {
+            return Err("AHCI command failed".into());
+        }
+        return Ok(());
+    }
+}
+
+// Fills the slot 0 header and its command table in the port's DMA page
+fn build_command(dma: &mut [u8], cmd: u8, lba: u64, count: u16, buf: usize, len: usize, write: bool) {
+    let ctba = dma.as_ptr() as u64 + CMD_TABLE as u64;
+
+    // CFL = 5 dwords, W, PRDTL = 1
+    let dw0: u32 = 5 | if write { 1 << 6 } else { 0 } | (1 << 16);
+    let hdr = &mut dma[CMD_LIST..CMD_LIST + 16];
+    hdr[0..4].copy_from_slice(&dw0.to_le_bytes());
+    hdr[4..8].fill(0);
+    hdr[8..16].copy_from_slice(&ctba.to_le_bytes());
+
+    let table = &mut dma[CMD_TABLE..CMD_TABLE + 0x90];
+    table.fill(0);
+    table[0] = FIS_H2D;
+    table[1] = 1 << 7; // Command, not control
+    table[2] = cmd;
+    table[4..7].copy_from_slice(&lba.to_le_bytes()[0..3]);
+    table[7] = 1 << 6; // LBA mode
+    table[8..11].copy_from_slice(&lba.to_le_bytes()[3..6]);
+    table[12..14].copy_from_slice(&count.to_le_bytes());
+
+    let prdt = &mut table[0x80..0x90];
+    prdt[0..8].copy_from_slice(&(buf as u64).to_le_bytes());
+    prdt[12..16].copy_from_slice(&((len - 1) as u32 & 0x3f_ffff).to_le_bytes());
+}
+
+pub struct BlockDeviceAhci {
+    port: Mutex<AhciPort>,
+    sectors: u64,
+    devid: u16,
+    port_no: u8
+}
+
+impl BlockDeviceAhci {
+    fn transfer(&self, buf: &mut [u8], lba: u64, write: bool) -> Result<(), String> {
+        let cmd = if write { ATA_WRITE_DMA_EXT } else { ATA_READ_DMA_EXT };
+        let mut dma = PhysPageBuf::new_zeroed(MAX_SECTORS * SECTOR).ok_or("Failed to allocate DMA buffer")?;
+        let mut port = self.port.lock();
+
+        for (i, ck) in buf.chunks_mut(MAX_SECTORS * SECTOR).enumerate() {
+            let lba = lba + (i * MAX_SECTORS) as u64;
+            let len = ck.len().next_multiple_of(SECTOR);
+
+            if write { dma[..ck.len()].copy_from_slice(ck); }
+            port.issue(cmd, lba, (len / SECTOR) as u16, dma.ptr::<u8>() as usize, len, write)?;
+            if !write { ck.copy_from_slice(&dma[..ck.len()]); }
+        }
+        return Ok(());
+    }
+}
+
+impl BlockDevice for BlockDeviceAhci {
+    fn block_size(&self) -> u64 {
+        return SECTOR as u64;
+    }
+
+    fn block_count(&self) -> u64 {
+        return self.sectors;
+    }
+
+    fn read_block(&self, buf: &mut [u8], lba: u64) -> Result<(), String> {
+        return self.transfer(buf, lba, false);
+    }
+
+    fn write_block(&self, buf: &[u8], lba: u64) -> Result<(), String> {
+        // Partial sectors keep whatever the disk had
+        let mut tmp = alloc::vec![0u8; buf.len().next_multiple_of(SECTOR)];
+        if buf.len() % SECTOR != 0 {
+            let last = buf.len() / SECTOR * SECTOR;
+            self.transfer(&mut tmp[last..], lba + (last / SECTOR) as u64, false)?;
+        }
+        tmp[..buf.len()].copy_from_slice(buf);
+        return self.transfer(&mut tmp, lba, true);
+    }
+
+    fn devid(&self) -> u64 {
+        return DevId::new(0)
+            .ty(BlockDevType::PCIe)
+            .loc(((self.devid as u32) << 16) | self.port_no as u32)
+            .build();
+    }
+}
+
+fn init_port(abar: usize, port_no: u8, devid: u16) -> Result<Option<BlockDeviceAhci>, String> {
+    let regs = abar + 0x100 + port_no as usize * 0x80;
+    let dma = PhysPageBuf::new_zeroed(page_size()).ok_or("Failed to allocate AHCI port memory")?;
+    let mut port = AhciPort { regs, dma };
+
+    // Device present, link up, and a plain SATA disk
+    if port.read(PX_SSTS) & 0xf != 3 || port.read(PX_SIG) != SIG_SATA {
+        return Ok(None);
+    }
+    port.start()?;
+
+    let ident = PhysPageBuf::new_zeroed(SECTOR).ok_or("Failed to allocate DMA buffer")?;
+    port.issue(ATA_IDENTIFY, 0, 0, ident.ptr::<u8>() as usize, SECTOR, false)?;
+    let words = ident.ptr::<u16>();
+    let sectors = (0..4).fold(0u64, |acc, i| {
+        acc | (unsafe { words.add(100 + i).read_volatile() } as u64) << (16 * i)
+    });
+
+    return Ok(Some(BlockDeviceAhci { port: Mutex::new(port), sectors, devid, port_no }));
+}
+
+pub fn add(dev: &mut PciDevice) {
+    if !dev.is_ahci() {
+        return;
+    }
+
+    dev.enable_pci_device();
+
+    let Some(abar) = dev.bar_addr(5) else { return; };
+    if GLACIER.write().map_range(abar, abar, HBA_SIZE, flags::D_RW).is_err() {
+        return;
+    }
+
+    unsafe {
+        let ghc = (abar + HBA_GHC) as *mut u32;
+        ghc.write_volatile(ghc.read_volatile() | GHC_AE);
+    }
+    let pi = unsafe { ((abar + HBA_PI) as *const u32).read_volatile() };
+
+    for port_no in (0..32).filter(|p| pi & (1 << p) != 0) {
+        match init_port(abar, port_no, dev.devid) {
+            Ok(Some(blk)) => BLOCK_DEVICES.write().push(Arc::new(blk)),
+            Ok(None) => {}
+            Err(e) => warn!("AHCI port {}: {}", port_no, e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn dword(buf: &[u8], off: usize) -> u32 {
+        return u32::from_le_bytes(buf[off..off + 4].try_into().unwrap());
+    }
+
+    #[test]
+    fn command_header_and_prdt_layout() {
+        let mut dma = vec![0xffu8; 0x1000];
+        let base = dma.as_ptr() as u64;
+        build_command(&mut dma, ATA_WRITE_DMA_EXT, 0x0123_4567_89ab, 8, 0x8765_4000, 8 * SECTOR, true);
+
+        assert_eq!(dword(&dma, CMD_LIST), 0x0001_0045);
+        assert_eq!(dword(&dma, CMD_LIST + 4), 0);
+        let ctba = base + CMD_TABLE as u64;
+        assert_eq!(dword(&dma, CMD_LIST + 8), ctba as u32);
+        assert_eq!(dword(&dma, CMD_LIST + 12), (ctba >> 32) as u32);
+
+        let cfis = &dma[CMD_TABLE..CMD_TABLE + 20];
+        assert_eq!(cfis[..4], [FIS_H2D, 0x80, ATA_WRITE_DMA_EXT, 0]);
+        assert_eq!(cfis[4..8], [0xab, 0x89, 0x67, 0x40]);
+        assert_eq!(cfis[8..12], [0x45, 0x23, 0x01, 0]);
+        assert_eq!(cfis[12..16], [8, 0, 0, 0]);
+
+        let prdt = CMD_TABLE + 0x80;
+        assert_eq!(dword(&dma, prdt), 0x8765_4000);
+        assert_eq!(dword(&dma, prdt + 4), 0);
+        assert_eq!(dword(&dma, prdt + 8), 0);
+        assert_eq!(dword(&dma, prdt + 12), 8 * SECTOR as u32 - 1);
+    }
+
+    #[test]
+    fn reads_clear_the_write_bit() {
+        let mut dma = vec![0u8; 0x1000];
+        build_command(&mut dma, ATA_READ_DMA_EXT, 0, 1, 0x1000, SECTOR, false);
+        assert_eq!(dword(&dma, CMD_LIST), 0x0001_0005);
+        assert_eq!(dma[CMD_TABLE + 2], ATA_READ_DMA_EXT);
+        assert_eq!(dword(&dma, CMD_TABLE + 0x80 + 12), 0x1ff);
+    }
+}
@@ -1,5 +1,8 @@
-use alloc::{string::String, sync::Arc, vec::Vec};
-use spin::RwLock;
+use crate::ram::mutex::KRwLock;
+
+use core::fmt;
+use alloc::{boxed::Box, collections::btree_map::BTreeMap, string::String, sync::Arc, vec::Vec};
+use spin::Mutex;
 
 pub trait BlockDevice: Send + Sync {
     fn block_size(&self) -> u64;
@@ -7,6 +10,17 @@ pub trait BlockDevice: Send + Sync {
     fn read_block(&self, buf: &mut [u8], lba: u64) -> Result<(), String>;
     fn write_block(&self, buf: &[u8], lba: u64) -> Result<(), String>;
     fn devid(&self) -> u64; // [Type:8][Location:32][Partition:24]
+    fn flush(&self) -> Result<(), String> { Ok(()) } // For devices with a volatile write cache
+}
+
+// A transfer of `len` bytes from `lba` must start and end inside the device
+pub fn check_lba(dev: &dyn BlockDevice, lba: u64, len: usize) -> Result<(), String> {
+    let count = dev.block_count();
+    let end = lba.checked_add((len as u64).div_ceil(dev.block_size()));
+    if lba >= count || end.is_none_or(|end| end > count) {
+        return Err("LBA out of range".into());
+    }
+    return Ok(());
 }
 
 #[repr(u8)]
@@ -46,6 +60,306 @@ impl DevId {
     pub fn build(&self) -> u64 {
         self.0
     }
+
+    pub fn dev_type(&self) -> Option<BlockDevType> {
+        return match (self.0 >> 56) as u8 {
+            0x01 => Some(BlockDevType::PCIe),
+            0x02 => Some(BlockDevType::USB),
+            0x03 => Some(BlockDevType::RamDisk),
+            0x04 => Some(BlockDevType::Legacy),
+            _ => None
+        };
+    }
+
+    pub fn location(&self) -> u32 {
+        return (self.0 >> 24) as u32;
+    }
+
+    // None for the whole device
+    pub fn partition(&self) -> Option<u32> {
+        return ((self.0 & 0xffffff) as u32).checked_sub(1);
+    }
 }
 
-pub static BLOCK_DEVICES: RwLock<Vec<Arc<dyn BlockDevice>>> = RwLock::new(Vec::new());
+// Such as pcie:0010:01/p0
+impl fmt::Display for DevId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ty = match self.dev_type() {
+            Some(BlockDevType::PCIe) => "pcie",
+            Some(BlockDevType::USB) => "usb",
+            Some(BlockDevType::RamDisk) => "ramdisk",
+            Some(BlockDevType::Legacy) => "legacy",
+            None => "unknown"
+        };
+        let loc = self.location();
+        write!(f, "{}:{:04x}:{:02x}", ty, loc >> 16, loc & 0xffff)?;
+        if let Some(part) = self.partition() {
+            write!(f, "/p{}", part)?;
+        }
+        return Ok(());
+    }
+}
+
+impl fmt::Debug for DevId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DevId({:#x}, {})", self.0, self)
+    }
+}
+
+struct CacheInner {
+    tick: u64,
+    blocks: BTreeMap<u64, (u64, Box<[u8]>)> // lba -> (last use, data)
+}
+
+// Write-through LRU cache of whole blocks
+pub struct BlockCache {
+    dev: Arc<dyn BlockDevice>,
+    inner: Mutex<CacheInner>
+}
+
+impl BlockCache {
+    const CAPACITY: usize = 256;
+
+    pub fn new(dev: Arc<dyn BlockDevice>) -> Self {
+        return Self {
+            dev,
+            inner: Mutex::new(CacheInner { tick: 0, blocks: BTreeMap::new() })
+        };
+    }
+
+    fn lookup(&self, lba: u64, out: &mut [u8]) -> bool {
+        let mut inner = self.inner.lock();
+        inner.tick += 1;
+        let tick = inner.tick;
+
+        if let Some((used, block)) = inner.blocks.get_mut(&lba) {
+            *used = tick;
+            out.copy_from_slice(&block[..out.len()]);
+            return true;
+        }
+        return false;
+    }
+
+    fn insert(&self, lba: u64, block: &[u8]) {
+        let mut inner = self.inner.lock();
+        inner.tick += 1;
+        let tick = inner.tick;
+
+        if inner.blocks.len() >= Self::CAPACITY && !inner.blocks.contains_key(&lba) {
+            let lru = inner.blocks.iter()
+                .min_by_key(|(_, (used, _))| *used)
+                .map(|(&lba, _)| lba);
+            if let Some(lru) = lru {
+                inner.blocks.remove(&lru);
+            }
+        }
+        inner.blocks.insert(lba, (tick, block.into()));
+    }
+
+    pub fn cached_read(&self, lba: u64, buf: &mut [u8]) -> Result<(), String> {
+        let bs = self.dev.block_size() as usize;
+        let blocks = buf.len().div_ceil(bs);
+
+        let mut i = 0;
+        while i < blocks {
+            let chunk_end = ((i + 1) * bs).min(buf.len());
+            if self.lookup(lba + i as u64, &mut buf[i * bs..chunk_end]) {
+                i += 1;
+                continue;
+            }
+
+            // Read the whole run of misses in one request
+            let mut run = 1;
+            {
+                let inner = self.inner.lock();
+                while i + run < blocks && !inner.blocks.contains_key(&(lba + (i + run) as u64)) {
+                    run += 1;
+                }
+            }
+
+            let mut tmp = alloc::vec![0u8; run * bs];
+            self.dev.read_block(&mut tmp, lba + i as u64)?;
+
+            let end = ((i + run) * bs).min(buf.len());
+            buf[i * bs..end].copy_from_slice(&tmp[..end - i * bs]);
+            for (j, block) in tmp.chunks_exact(bs).enumerate() {
+                self.insert(lba + (i + j) as u64, block);
+            }
+            i += run;
+        }
+
+        return Ok(());
+    }
+
+    pub fn cached_write(&self, lba: u64, buf: &[u8]) -> Result<(), String> {
+        self.dev.write_block(buf, lba)?;
+
+        let bs = self.dev.block_size() as usize;
+        for (i, block) in buf.chunks(bs).enumerate() {
+            if block.len() == bs {
+                self.insert(lba + i as u64, block);
+            } else {
+                self.inner.lock().blocks.remove(&(lba + i as u64));
+            }
+        }
+        return Ok(());
+    }
+}
+
+impl BlockDevice for BlockCache {
+    fn block_size(&self) -> u64 {
+        self.dev.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.dev.block_count()
+    }
+
+    fn read_block(&self, buf: &mut [u8], lba: u64) -> Result<(), String> {
+        self.cached_read(lba, buf)
+    }
+
+    fn write_block(&self, buf: &[u8], lba: u64) -> Result<(), String> {
+        self.cached_write(lba, buf)
+    }
+
+    fn devid(&self) -> u64 {
+        self.dev.devid()
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        self.dev.flush()
+    }
+}
+
+pub static BLOCK_DEVICES: KRwLock<Vec<Arc<dyn BlockDevice>>> = KRwLock::new(Vec::new());
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    const BS: usize = 512;
+
+    // Every block reads as its own LBA, and every device read is counted
+    struct Counting {
+        reads: AtomicUsize,
+        data: Mutex<BTreeMap<u64, Vec<u8>>>
+    }
+
+    impl Counting {
+        fn new() -> Arc<Self> {
+            return Arc::new(Self { reads: AtomicUsize::new(0), data: Mutex::new(BTreeMap::new()) });
+        }
+
+        fn reads(&self) -> usize {
+            return self.reads.load(Ordering::Relaxed);
+        }
+    }
+
+    impl BlockDevice for Counting {
+        fn block_size(&self) -> u64 { BS as u64 }
+        fn block_count(&self) -> u64 { 1024 }
+        fn devid(&self) -> u64 { 0 }
+
+        fn read_block(&self, buf: &mut [u8], lba: u64) -> Result<(), String> {
+            check_lba(self, lba, buf.len())?;
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            let data = self.data.lock();
+            for (i, block) in buf.chunks_mut(BS).enumerate() {
+                let lba = lba + i as u64;
+                match data.get(&lba) {
+                    Some(stored) => block.copy_from_slice(&stored[..block.len()]),
+                    None => block.fill(lba as u8)
+                }
+            }
+            return Ok(());
+        }
+
+        fn write_block(&self, buf: &[u8], lba: u64) -> Result<(), String> {
+            check_lba(self, lba, buf.len())?;
+            let mut data = self.data.lock();
+            for (i, block) in buf.chunks(BS).enumerate() {
+                data.insert(lba + i as u64, block.into());
+            }
+            return Ok(());
+        }
+    }
+
+    #[test]
+    fn second_read_is_a_hit() {
+        let dev = Counting::new();
+        let cache = BlockCache::new(dev.clone());
+        let mut buf = vec![0u8; BS];
+
+        cache.cached_read(7, &mut buf).unwrap();
+        assert_eq!(dev.reads(), 1);
+        cache.cached_read(7, &mut buf).unwrap();
+        assert_eq!(dev.reads(), 1);
+        assert_eq!(buf, vec![7; BS]);
+    }
+
+    #[test]
+    fn misses_around_a_hit_are_read() {
+        let dev = Counting::new();
+        let cache = BlockCache::new(dev.clone());
+        let mut buf = vec![0u8; BS];
+        cache.cached_read(5, &mut buf).unwrap();
+
+        let mut buf = vec![0u8; 3 * BS];
+        cache.cached_read(4, &mut buf).unwrap();
+        assert_eq!(dev.reads(), 3);
+        assert!(buf.chunks(BS).zip(4u8..).all(|(block, lba)| block.iter().all(|&b| b == lba)));
+
+        cache.cached_read(4, &mut buf).unwrap();
+        assert_eq!(dev.reads(), 3);
+    }
+
+    #[test]
+    fn writes_go_through_and_stay_cached() {
+        let dev = Counting::new();
+        let cache = BlockCache::new(dev.clone());
+        cache.cached_write(3, &[0xaa; BS]).unwrap();
+        assert_eq!(dev.data.lock()[&3], vec![0xaa; BS]);
+
+        let mut buf = vec![0u8; BS];
+        cache.cached_read(3, &mut buf).unwrap();
+        assert_eq!(dev.reads(), 0);
+        assert_eq!(buf, vec![0xaa; BS]);
+    }
+
+    #[test]
+    fn least_recent_block_is_evicted() {
+        let dev = Counting::new();
+        let cache = BlockCache::new(dev.clone());
+        let mut buf = vec![0u8; BS];
+        for lba in 0..BlockCache::CAPACITY as u64 {
+            cache.cached_read(lba, &mut buf).unwrap();
+        }
+        cache.cached_read(0, &mut buf).unwrap(); // Block 1 is now the oldest
+        cache.cached_read(BlockCache::CAPACITY as u64, &mut buf).unwrap();
+        let reads = dev.reads();
+
+        cache.cached_read(0, &mut buf).unwrap();
+        assert_eq!(dev.reads(), reads);
+        cache.cached_read(1, &mut buf).unwrap();
+        assert_eq!(dev.reads(), reads + 1);
+    }
+
+    #[test]
+    fn devid_fields_round_trip() {
+        let id = DevId::new(0).ty(BlockDevType::PCIe).loc(0x0010_0001).part(3);
+        assert_eq!(id.build(), 0x01_0010_0001_000004);
+        assert_eq!(id.dev_type(), Some(BlockDevType::PCIe));
+        assert_eq!((id.location(), id.partition()), (0x0010_0001, Some(3)));
+        assert_eq!(alloc::format!("{}", id), "pcie:0010:01/p3");
+
+        // Every field at its widest leaves the others alone
+        let id = DevId::new(u64::MAX).ty(BlockDevType::RamDisk).loc(0).part(0xff_fffe);
+        assert_eq!((id.dev_type(), id.location(), id.partition()), (Some(BlockDevType::RamDisk), 0, Some(0xff_fffe)));
+        let id = DevId::new(0).loc(u32::MAX);
+        assert_eq!((id.dev_type(), id.location(), id.partition()), (None, u32::MAX, None));
+        assert_eq!(alloc::format!("{}", id), "unknown:ffff:ffff");
+    }
+}
@@ -1,20 +1,29 @@
 use crate::{
-    arch::{intc, phys_id, rvm::flags},
+    arch::{self, intc, phys_id, rvm::flags},
     device::ACPI,
+    kargs::{AP_LIST, RAMType},
     ram::{
         glacier::GLACIER,
-        per_cpu_data, stack_top
-    }
+        physalloc::{AllocParams, PHYS_ALLOC},
+        gleam_base, per_cpu_data, stack_size, stack_top
+    },
+    time, warn
 };
 
-use core::sync::atomic::{AtomicUsize, Ordering as AtomOrd};
+use alloc::{string::String, vec::Vec};
+use core::{
+    hint::spin_loop,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering as AtomOrd}
+};
 use acpi::sdt::madt::{Madt, MadtEntry};
 use spin::Once;
 
 pub static GICD_BASE: Once<usize> = Once::new();
 pub static GICC_BASE: Once<usize> = Once::new(); // GICv2 GIC CPU intfce
 pub static GICR_BASE: Once<usize> = Once::new(); // GICv3 GIC redistrib
+pub static GICM_BASE: Once<usize> = Once::new(); // GICv2m MSI frame
 pub static CPU_COUNT: AtomicUsize = AtomicUsize::new(0);
+pub static CPU_ONLINE: AtomicU64 = AtomicU64::new(0); // Bitmap of running virtual CPU ids
 
 // AMD64:   LAPIC Doorbell  4KB
 // AArch64: GICD Doorbell  64KB
@@ -43,6 +52,16 @@ fn map_doorbell(phys: usize) {
 }
 
 pub fn init_cpu() {
+    init_local(true);
+}
+
+// Runs on each AP once its exception state is loaded
+pub fn init_ap() {
+    init_local(false);
+}
+
+// Global controllers are only set up by the BSP, the local one by every core
+fn init_local(bsp: bool) {
     use MadtEntry::*;
 
     let acpi_lock = ACPI.read();
@@ -66,8 +85,14 @@ pub fn init_cpu() {
             LocalApicAddressOverride(ovr) => {
                 ic_phys = Some(ovr.local_apic_address as usize);
             }
-            IoApic(io) => {
+            IoApic(io) if bsp => {
                 map_doorbell(io.io_apic_address as usize);
+                #[cfg(target_arch = "x86_64")]
+                crate::arch::ioapic::add(io.io_apic_address as usize, io.global_system_interrupt_base);
+            }
+            #[cfg(target_arch = "x86_64")]
+            InterruptSourceOverride(ovr) if bsp => {
+                crate::arch::ioapic::add_override(ovr.irq, ovr.global_system_interrupt, ovr.flags);
             }
 
             // AArch64
@@ -82,12 +107,12 @@ pub fn init_cpu() {
                     });
                 }
             }
-            Gicd(gicd) => {
+            Gicd(gicd) if bsp => {
                 let base = gicd.physical_base_address as usize;
                 GICD_BASE.call_once(|| base);
                 map_doorbell(base);
             }
-            GicRedistributor(gicr) => {
+            GicRedistributor(gicr) if bsp => {
                 let base = gicr.discovery_range_base_address as usize;
                 let len = gicr.discovery_range_length as usize;
                 GICR_BASE.call_once(|| base);
@@ -95,16 +120,69 @@ pub fn init_cpu() {
                     .map_range(base, base, len, flags::D_RW)
                     .expect("Failed to map GIC Redistributor");
             }
+            GicMsiFrame(frame) if bsp => {
+                let base = frame.physical_base_address as usize;
+                GICM_BASE.call_once(|| base);
+                map_doorbell(base);
+            }
 
             _ => {}
         }
     }
 
-    CPU_COUNT.store(cpu_count, AtomOrd::Relaxed);
+    if bsp {
+        CPU_COUNT.store(cpu_count, AtomOrd::Relaxed);
+    }
 
     if let Some(phys) = ic_phys {
         GLACIER.write().map_range(ic_va(), phys, IC_SIZE, flags::D_RW)
             .expect("Failed to map Interrupt Controller");
         intc::init();
     }
+    CPU_ONLINE.fetch_or(1 << (AP_LIST.virtid_self() % 64), AtomOrd::Release);
+}
+
+// LAPIC ids on AMD64, MPIDRs on AArch64
+fn ap_hwids() -> Vec<usize> {
+    let acpi_lock = ACPI.read();
+    let Some(acpi) = acpi_lock.as_ref() else { return Vec::new(); };
+    let Some(madt) = acpi.find_table::<Madt>() else { return Vec::new(); };
+    let madt = madt.get();
+
+    return madt.entries().filter_map(|entry| match entry {
+        MadtEntry::LocalApic(lapic) if lapic.flags & 1 != 0 => Some(lapic.apic_id as usize),
+        MadtEntry::Gicc(gicc) if gicc.flags & 1 != 0 => Some(gicc.mpidr as usize),
+        _ => None
+    }).filter(|&hwid| hwid & 0xffff != phys_id()).collect();
+}
+
+fn start_ap(hwid: usize) -> Result<(), String> {
+    let vid = AP_LIST.assign_for(hwid & 0xffff);
+    let top = gleam_base() - per_cpu_data() * vid;
+
+    let stack = PHYS_ALLOC.alloc(
+        AllocParams::new(stack_size()).as_type(RAMType::KernelData)
+    ).ok_or("Failed to allocate AP stack")?;
+    GLACIER.write().map_range(top - stack_size(), stack.addr(), stack_size(), flags::K_RWO)
+        .map_err(|_| "Failed to map AP stack")?;
+
+    arch::smp::start_ap(hwid, top)?;
+
+    let deadline = time::uptime_ns() + 100_000_000;
+    while CPU_ONLINE.load(AtomOrd::Acquire) & (1 << (vid % 64)) == 0 {
+        if time::uptime_ns() > deadline && arch::smp::abandon_ap(hwid) {
+            return Err("Timed out waiting for AP".into());
+        }
+        spin_loop();
+    }
+    return Ok(());
+}
+
+// One at a time, each AP parks in the scheduler once it is up
+pub fn start_aps() {
+    for hwid in ap_hwids() {
+        if let Err(e) = start_ap(hwid) {
+            warn!("CPU {:#x}: {}", hwid, e);
+        }
+    }
 }
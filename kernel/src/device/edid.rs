@@ -0,0 +1,131 @@
+use core::fmt;
+use alloc::{string::String, vec::Vec};
+
+pub const HEADER: [u8; 8] = [0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00];
+pub const BLOCK_LEN: usize = 128;
+
+// Four 18-byte descriptors, each a detailed timing or a display descriptor
+const DESC_START: usize = 54;
+const DESC_LEN: usize = 18;
+const DESC_CNT: usize = 4;
+
+// One detailed timing descriptor, horizontal values in pixels and vertical ones in lines
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mode {
+    pub pixel_clock: u32, // kHz
+    pub width: u32,
+    pub height: u32,
+    pub h_blank: u32,
+    pub v_blank: u32,
+    pub h_sync_off: u32,
+    pub h_sync_width: u32,
+    pub v_sync_off: u32,
+    pub v_sync_width: u32
+}
+
+impl Mode {
+    // None for display descriptors, which have a zero pixel clock
+    fn parse(desc: &[u8]) -> Option<Self> {
+        let clock = u16::from_le_bytes([desc[0], desc[1]]) as u32;
+        if clock == 0 { return None; }
+
+        let lo_hi = |lo: u8, hi: u8| lo as u32 | (hi as u32) << 8;
+        return Some(Self {
+            pixel_clock: clock * 10,
+            width: lo_hi(desc[2], desc[4] >> 4),
+            h_blank: lo_hi(desc[3], desc[4] & 0x0f),
+            height: lo_hi(desc[5], desc[7] >> 4),
+            v_blank: lo_hi(desc[6], desc[7] & 0x0f),
+            h_sync_off: desc[8] as u32 | ((desc[11] as u32 >> 6) & 0x3) << 8,
+            h_sync_width: desc[9] as u32 | ((desc[11] as u32 >> 4) & 0x3) << 8,
+            v_sync_off: (desc[10] as u32 >> 4) | ((desc[11] as u32 >> 2) & 0x3) << 4,
+            v_sync_width: (desc[10] as u32 & 0x0f) | (desc[11] as u32 & 0x3) << 4
+        });
+    }
+
+    pub fn h_total(&self) -> u32 { self.width + self.h_blank }
+    pub fn v_total(&self) -> u32 { self.height + self.v_blank }
+
+    // Rounded to the nearest Hz
+    pub fn refresh_hz(&self) -> u32 {
+        let frame = self.h_total() as u64 * self.v_total() as u64;
+        if frame == 0 { return 0; }
+        return ((self.pixel_clock as u64 * 1000 + frame / 2) / frame) as u32;
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(
+            f, "{}x{}@{}Hz, {}.{:02} MHz",
+            self.width, self.height, self.refresh_hz(),
+            self.pixel_clock / 1000, self.pixel_clock % 1000 / 10
+        );
+    }
+}
+
+// All 128 bytes of a block sum to zero
+pub fn checksum_ok(block: &[u8]) -> bool {
+    return block.len() >= BLOCK_LEN
+        && block[..BLOCK_LEN].iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0;
+}
+
+// Detailed timings of the base block in order. The first one is the preferred mode,
+// always on EDID 1.4 and whenever the feature bit says so before that.
+pub fn modes(block: &[u8]) -> Result<Vec<Mode>, String> {
+    if block.len() < BLOCK_LEN || block[..8] != HEADER {
+        return Err("No EDID header".into());
+    }
+    if !checksum_ok(block) {
+        return Err("EDID checksum mismatch".into());
+    }
+
+    return Ok(block[DESC_START..DESC_START + DESC_LEN * DESC_CNT]
+        .chunks_exact(DESC_LEN)
+        .filter_map(Mode::parse)
+        .collect());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    // A 1080p monitor base block: 1080p60 and 720p60 timings, then its name and range limits
+    const P2414H: [u8; BLOCK_LEN] = [
+        0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00, 0x10, 0xac, 0x70, 0xa0, 0x53, 0x42, 0x4c, 0x30,
+        0x1e, 0x16, 0x01, 0x03, 0x80, 0x34, 0x1d, 0x78, 0xea, 0xee, 0x95, 0xa3, 0x54, 0x4c, 0x99, 0x26,
+        0x0f, 0x50, 0x54, 0xa5, 0x4b, 0x00, 0x71, 0x4f, 0x81, 0x80, 0xa9, 0x40, 0xd1, 0xc0, 0x01, 0x01,
+        0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x02, 0x3a, 0x80, 0x18, 0x71, 0x38, 0x2d, 0x40, 0x58, 0x2c,
+        0x45, 0x00, 0x0f, 0x28, 0x21, 0x00, 0x00, 0x1e, 0x01, 0x1d, 0x00, 0x72, 0x51, 0xd0, 0x1e, 0x20,
+        0x6e, 0x28, 0x55, 0x00, 0x0f, 0x28, 0x21, 0x00, 0x00, 0x1e, 0x00, 0x00, 0x00, 0xfc, 0x00, 0x44,
+        0x45, 0x4c, 0x4c, 0x20, 0x50, 0x32, 0x34, 0x31, 0x34, 0x48, 0x0a, 0x20, 0x00, 0x00, 0x00, 0xfd,
+        0x00, 0x38, 0x4c, 0x1e, 0x53, 0x11, 0x00, 0x0a, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x00, 0x43
+    ];
+
+    #[test]
+    fn full_hd_block() {
+        let modes = modes(&P2414H).unwrap();
+        assert_eq!(modes.len(), 2);
+        assert_eq!(modes[0], Mode {
+            pixel_clock: 148_500, width: 1920, height: 1080, h_blank: 280, v_blank: 45,
+            h_sync_off: 88, h_sync_width: 44, v_sync_off: 4, v_sync_width: 5
+        });
+        assert_eq!(modes[0].to_string(), "1920x1080@60Hz, 148.50 MHz");
+        assert_eq!((modes[1].width, modes[1].height, modes[1].refresh_hz()), (1280, 720, 60));
+        assert_eq!((modes[1].h_total(), modes[1].v_total()), (1650, 750));
+    }
+
+    #[test]
+    fn bad_blocks_are_refused() {
+        let mut block = P2414H;
+        block[60] ^= 1;
+        assert!(!checksum_ok(&block));
+        assert_eq!(modes(&block), Err("EDID checksum mismatch".into()));
+
+        block = P2414H;
+        block[0] = 0xff;
+        assert_eq!(modes(&block), Err("No EDID header".into()));
+        assert!(modes(&P2414H[..100]).is_err());
+    }
+}
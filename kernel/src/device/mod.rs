@@ -1,16 +1,25 @@
 mod acpi;
+mod ahci;
 pub mod block;
 pub mod cpu;
+pub mod edid;
+mod font;
 mod nvme;
+pub mod power;
+#[cfg(target_arch = "x86_64")]
+pub mod ps2kbd;
+pub mod ringbuf;
+pub mod rng;
 mod usb;
-mod vga;
+mod virtio_blk;
+pub mod vga;
 
 use crate::{
-    arch::rvm::flags,
+    arch::{intc, rvm::flags},
     device::acpi::KernelAcpiHandler,
+    info,
     kargs::SYSINFO,
-    printk, printlnk,
-    ram::glacier::{GLACIER, page_size}
+    ram::{glacier::GLACIER, mutex::KRwLock}
 };
 
 use alloc::{string::String, vec::Vec};
@@ -31,7 +40,7 @@ unsafe impl Sync for PciDevice {}
 impl PciDevice {
     pub fn read(base: u64, devid: u16) -> Option<Self> {
         let ptr = base as usize + ((devid as usize) << 12);
-        GLACIER.write().map_range(ptr, ptr, page_size(), flags::D_RW).ok()?;
+        map_ecam(ptr)?;
         let dev = PciDevice { devid, ptr: ptr as *mut u32 };
         if dev.vendor_id() == 0xFFFF { return None; }
         return Some(dev);
@@ -45,7 +54,9 @@ impl PciDevice {
     pub fn enable_pci_device(&mut self) { self.set_command(self.command() | 0x0006); }
 
     pub fn is_nvme(&self) -> bool { self.class() == 0x01 && self.subclass() == 0x08 }
+    pub fn is_ahci(&self) -> bool { self.class() == 0x01 && self.subclass() == 0x06 && self.prog_if() == 0x01 }
     pub fn is_usb(&self) -> bool { self.class() == 0x0c && self.subclass() == 0x03 }
+    pub fn is_virtio_blk(&self) -> bool { self.vendor_id() == 0x1af4 && matches!(self.device_id(), 0x1001 | 0x1042) }
     pub fn is_display(&self) -> bool { self.class() == 0x03 }
     pub fn is_vga(&self) -> bool { self.class() == 0x03 && self.subclass() == 0x00 }
     pub fn is_bridge(&self) -> bool { self.is_type1() }
@@ -53,6 +64,16 @@ impl PciDevice {
     fn blob(&self) -> &[u32] { unsafe { core::slice::from_raw_parts(self.ptr, 16) } }
     fn blob_mut(&self) -> &mut [u32] { unsafe { core::slice::from_raw_parts_mut(self.ptr, 16) } }
 
+    // The whole 4 KiB ECAM window of the function, extended space included
+    pub fn cfg_read(&self, off: usize) -> u32 {
+        assert!(off < CFG_SIZE, "PCI config offset out of range");
+        unsafe { self.ptr.add(off >> 2).read_volatile() }
+    }
+    pub fn cfg_write(&mut self, off: usize, val: u32) {
+        assert!(off < CFG_SIZE, "PCI config offset out of range");
+        unsafe { self.ptr.add(off >> 2).write_volatile(val) }
+    }
+
     // Common methods
     pub fn device_id(&self) -> u16       { (self.blob()[0] >> 16) as u16 }
     pub fn vendor_id(&self) -> u16       {  self.blob()[0] as u16 }
@@ -85,12 +106,7 @@ impl PciDevice {
     }
 
     pub fn mmio_addr(&self) -> usize {
-        let base = self.bar(0).unwrap() as usize;
-        if (base & 0b110) == 0b100 {
-            return ((self.bar(1).unwrap() as usize) << 32) | (base & !0b111);
-        } else {
-            return base & !0b11;
-        }
+        return self.bar_addr(0).unwrap_or(0);
     }
 
     pub fn expansion_rom_base(&self) -> u32 {
@@ -101,6 +117,140 @@ impl PciDevice {
         }
     }
 
+    // Capabilities
+    // (id, offset) of every entry in the capability list
+    pub fn capabilities(&self) -> Vec<(u8, usize)> {
+        let mut caps = Vec::new();
+        if self.status() & (1 << 4) == 0 { return caps; }
+
+        let mut off = (self.capabilities_ptr() & !0b11) as usize;
+        for _ in 0..48 { // A looping list must not hang the scan
+            if off == 0 { break; }
+            let hdr = self.cfg_read(off);
+            caps.push((hdr as u8, off));
+            off = ((hdr >> 8) as u8 & !0b11) as usize;
+        }
+        return caps;
+    }
+
+    pub fn find_capability(&self, id: u8) -> Option<usize> {
+        return self.capabilities().into_iter()
+            .find(|&(cid, _)| cid == id)
+            .map(|(_, off)| off);
+    }
+
+    // (id, offset) of every PCIe extended capability, the list starts at 0x100
+    pub fn ext_capabilities(&self) -> Vec<(u16, usize)> {
+        let mut caps = Vec::new();
+        let mut off = EXT_CAP_START;
+        for _ in 0..(CFG_SIZE - EXT_CAP_START) / 4 {
+            let hdr = self.cfg_read(off);
+            if hdr == 0 || hdr == 0xffffffff { break; } // None, or not PCIe
+            caps.push((hdr as u16, off));
+
+            off = (hdr >> 20) as usize & !0b11;
+            if off < EXT_CAP_START { break; }
+        }
+        return caps;
+    }
+
+    pub fn find_ext_capability(&self, id: u16) -> Option<usize> {
+        return self.ext_capabilities().into_iter()
+            .find(|&(cid, _)| cid == id)
+            .map(|(_, off)| off);
+    }
+
+    pub fn bar_addr(&self, index: usize) -> Option<usize> {
+        let lo = self.bar(index)? as usize;
+        if lo & 1 != 0 { return None; } // I/O space
+        if lo & 0b110 == 0b100 {
+            return Some(((self.bar(index + 1)? as usize) << 32) | (lo & !0xf));
+        }
+        return Some(lo & !0xf);
+    }
+
+    // (base, size, is_mmio), None for unimplemented BARs and the upper half of a 64-bit pair.
+    // Sizes come from writing all ones and reading back the mask, with decoding off meanwhile.
+    pub fn bar_region(&mut self, index: usize) -> Option<(u64, u64, bool)> {
+        let lo = self.bar(index)?;
+        let is_upper = (0..index).try_fold(false, |upper, i| {
+            let bar = self.bar(i)?;
+            Some(!upper && bar & 0b111 == 0b100)
+        })?;
+        if is_upper { return None; }
+
+        let is_mmio = lo & 1 == 0;
+        let is_64 = is_mmio && lo & 0b110 == 0b100;
+        let hi = if is_64 { self.bar(index + 1)? } else { 0 };
+        let off = 0x10 + index * 4;
+
+        let cmd = self.command();
+        self.set_command(cmd & !0b11);
+        self.cfg_write(off, 0xffffffff);
+        let lo_mask = self.cfg_read(off);
+        self.cfg_write(off, lo);
+        let hi_mask = if is_64 {
+            self.cfg_write(off + 4, 0xffffffff);
+            let mask = self.cfg_read(off + 4);
+            self.cfg_write(off + 4, hi);
+            mask
+        } else { 0xffffffff };
+        self.set_command(cmd);
+
+        return decode_bar(lo, hi, lo_mask, hi_mask);
+    }
+
+    pub fn enable_msi(&mut self, vector: u8, apic_id: u32) -> Result<(), String> {
+        let cap = self.find_capability(CAP_MSI).ok_or("Device has no MSI capability")?;
+        let (addr, data) = intc::msi_msg(vector as u32, apic_id).ok_or("No MSI doorbell")?;
+
+        let ctrl = (self.cfg_read(cap) >> 16) as u16;
+        self.cfg_write(cap + 0x4, addr as u32);
+        if ctrl & (1 << 7) != 0 { // 64-bit address
+            self.cfg_write(cap + 0x8, (addr >> 32) as u32);
+            self.cfg_write(cap + 0xc, data);
+        } else {
+            if addr >> 32 != 0 { return Err("MSI doorbell above 4 GiB".into()); }
+            self.cfg_write(cap + 0x8, data);
+        }
+
+        // Single message, enabled
+        let ctrl = (ctrl & !(0b111 << 4)) | 1;
+        let hdr = self.cfg_read(cap) & 0xffff;
+        self.cfg_write(cap, hdr | (ctrl as u32) << 16);
+        self.set_command(self.command() | (1 << 10)); // INTx off
+        return Ok(());
+    }
+
+    pub fn enable_msix(&mut self, entry: u16, vector: u8, apic_id: u32) -> Result<(), String> {
+        let cap = self.find_capability(CAP_MSIX).ok_or("Device has no MSI-X capability")?;
+        let (addr, data) = intc::msi_msg(vector as u32, apic_id).ok_or("No MSI doorbell")?;
+
+        let ctrl = (self.cfg_read(cap) >> 16) as u16;
+        if entry > ctrl & 0x7ff { return Err("MSI-X entry out of range".into()); }
+
+        let table = self.cfg_read(cap + 0x4);
+        let base = self.bar_addr((table & 0b111) as usize).ok_or("Invalid MSI-X table BAR")?;
+        let table_addr = base + (table & !0b111) as usize;
+        let table_size = ((ctrl & 0x7ff) as usize + 1) * 16;
+        GLACIER.write().map_range(table_addr, table_addr, table_size, flags::D_RW)
+            .map_err(|_| "Failed to map MSI-X table")?;
+
+        let ent = (table_addr + entry as usize * 16) as *mut u32;
+        unsafe {
+            ent.add(0).write_volatile(addr as u32);
+            ent.add(1).write_volatile((addr >> 32) as u32);
+            ent.add(2).write_volatile(data);
+            ent.add(3).write_volatile(0); // Unmasked
+        }
+
+        let ctrl = (ctrl & !(1 << 14)) | (1 << 15);
+        let hdr = self.cfg_read(cap) & 0xffff;
+        self.cfg_write(cap, hdr | (ctrl as u32) << 16);
+        self.set_command(self.command() | (1 << 10)); // INTx off
+        return Ok(());
+    }
+
     // Type 0 specific methods
     pub fn is_type0(&self) -> bool { self.header_type() & 0x7f == 0 }
 
@@ -138,21 +288,100 @@ impl PciDevice {
     pub fn bridge_control(&self) -> u16        { (self.blob()[15] >> 16) as u16 }
 }
 
+// (base, size, is_mmio) from a BAR pair and the masks read back after writing all ones
+fn decode_bar(lo: u32, hi: u32, lo_mask: u32, hi_mask: u32) -> Option<(u64, u64, bool)> {
+    let is_mmio = lo & 1 == 0;
+    let is_64 = is_mmio && lo & 0b110 == 0b100;
+
+    let (base, mask) = if !is_mmio {
+        // I/O BARs may only implement the low 16 bits
+        let mask = lo_mask & !0b11;
+        let mask = if mask >> 16 == 0 { mask | 0xffff0000 } else { mask };
+        ((lo & !0b11) as u64, mask as u64 | 0xffffffff_00000000)
+    } else {
+        let base = (hi as u64) << 32 | (lo & !0xf) as u64;
+        (base, (hi_mask as u64) << 32 | (lo_mask & !0xf) as u64)
+    };
+
+    // No writable address bits means the BAR is not implemented
+    let lo_bits = lo_mask & if is_mmio { !0xf } else { !0b11 };
+    if lo_bits == 0 && (!is_64 || hi_mask == 0) { return None; }
+    return Some((base, (!mask).wrapping_add(1), is_mmio));
+}
+
+struct PciScan {
+    base: u64,
+    buses: core::ops::RangeInclusive<u8>, // From MCFG or the device tree
+    visited: [bool; 256],
+    devices: Vec<PciDevice>
+}
+
+impl PciScan {
+    fn scan_bus(&mut self, bus: u8) {
+        if !self.buses.contains(&bus) || self.visited[bus as usize] { return; }
+        self.visited[bus as usize] = true;
+
+        for slot in 0..32u16 {
+            let devid = (bus as u16) << 8 | slot << 3;
+            let Some(dev) = PciDevice::read(self.base, devid) else { continue; };
+            let funcs = if dev.header_type() & 0x80 != 0 { 8 } else { 1 };
+
+            for func in 0..funcs {
+                let Some(mut dev) = PciDevice::read(self.base, devid | func) else { continue; };
+                dev.enable_pci_device();
+                self.devices.push(dev);
+
+                // Devices behind a PCI-to-PCI bridge sit on its secondary bus
+                if dev.is_bridge() {
+                    self.scan_bus(dev.secondary_bus());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(test))]
+fn map_ecam(ptr: usize) -> Option<()> {
+    let size = crate::ram::glacier::page_size();
+    return GLACIER.write().map_range(ptr, ptr, size, flags::D_RW).ok();
+}
+
+// Host tests hand in plain buffers as the ECAM window
+#[cfg(test)]
+fn map_ecam(_ptr: usize) -> Option<()> { Some(()) }
+
+// Depth first from the root bus through bridges, each bus at most once
 fn scan_pcie_devices(base: u64, start_bus: u8, end_bus: u8) -> Vec<PciDevice> {
-    let mut devices = Vec::new();
-    let (start, end) = ((start_bus as u16) << 8, ((end_bus as u16 + 1) << 8).wrapping_sub(1));
+    let mut scan = PciScan {
+        base,
+        buses: start_bus..=end_bus,
+        visited: [false; 256],
+        devices: Vec::new()
+    };
 
-    for devid in start..=end {
-        if let Some(mut dev) = PciDevice::read(base, devid) {
-            dev.enable_pci_device();
-            devices.push(dev);
+    // A multi-function host bridge at function N is the root of bus start + N
+    let root = (start_bus as u16) << 8;
+    let multi_root = PciDevice::read(base, root).is_some_and(|host| host.header_type() & 0x80 != 0);
+    if !multi_root {
+        scan.scan_bus(start_bus);
+    } else {
+        for func in 0..8 {
+            if PciDevice::read(base, root | func).is_some() {
+                scan.scan_bus(start_bus.saturating_add(func as u8));
+            }
         }
     }
 
-    return devices;
+    return scan.devices;
 }
 
-pub static PCI_DEVICES: RwLock<Vec<PciDevice>> = RwLock::new(Vec::new());
+const CAP_MSI: u8 = 0x05;
+const CAP_MSIX: u8 = 0x11;
+
+const CFG_SIZE: usize = 0x1000;
+const EXT_CAP_START: usize = 0x100;
+
+pub static PCI_DEVICES: KRwLock<Vec<PciDevice>> = KRwLock::new(Vec::new());
 pub static ACPI: RwLock<Option<AcpiTables<KernelAcpiHandler>>> = RwLock::new(None);
 pub static DEVICETREE: RwLock<Option<Fdt>> = RwLock::new(None);
 
@@ -202,7 +431,7 @@ pub fn scan_pci() {
 
 pub fn init_acpi() {
     let ptr = SYSINFO.read().acpi_ptr;
-    *ACPI.write() = match unsafe { AcpiTables::from_rsdp(KernelAcpiHandler, ptr) } {
+    *ACPI.write() = match unsafe { AcpiTables::from_rsdp(KernelAcpiHandler::default(), ptr) } {
         Ok(tables) => Some(tables),
         Err(_) => None
     };
@@ -222,29 +451,210 @@ pub fn init_device() {
     scan_pci();
 
     for dev in PCI_DEVICES.write().iter_mut() {
-        printk!(
-            "/bus{}/dev{}/fn{} | {:04x}:{:04x} Class {:02x}.{:02x} IF {:02x}",
+        let role = if dev.is_nvme() {
+            nvme::add(dev);
+            " --> NVMe Controller"
+        } else if dev.is_ahci() {
+            ahci::add(dev);
+            " --> AHCI Controller"
+        } else if dev.is_virtio_blk() {
+            virtio_blk::add(dev);
+            " --> VirtIO Block Device"
+        } else if dev.is_usb() {
+            let _ = usb::add(dev);
+            " --> USB Controller"
+        } else if dev.is_display() {
+            " --> Display Controller"
+        } else if dev.is_bridge() {
+            " (PCI Bridge)"
+        } else {
+            ""
+        };
+
+        info!(
+            "/bus{}/dev{}/fn{} | {:04x}:{:04x} Class {:02x}.{:02x} IF {:02x}{}",
             dev.bus(), dev.device(), dev.function(),
             dev.vendor_id(), dev.device_id(),
-            dev.class(), dev.subclass(), dev.prog_if()
+            dev.class(), dev.subclass(), dev.prog_if(),
+            role
         );
+    }
 
-        if dev.is_nvme() {
-            printk!(" --> NVMe Controller");
-            nvme::add(dev);
-        }
+    cpu::init_cpu();
+    #[cfg(target_arch = "x86_64")]
+    ps2kbd::init();
+    vga::init_vga();
+}
 
-        if dev.is_usb()     {
-            printk!(" --> USB Controller");
-            let _ = usb::add(dev);
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
 
-        if dev.is_display() { printk!(" --> Display Controller"); }
-        if dev.is_bridge()  { printk!(" (PCI Bridge)"); }
+    // A function whose config space is a host buffer, with the capability list bit set
+    fn host_dev(cfg: &mut Vec<u32>, first: u8) -> PciDevice {
+        cfg[1] = 1 << 20;
+        cfg[13] = first as u32;
+        return PciDevice { devid: 0, ptr: cfg.as_mut_ptr() };
+    }
 
-        printlnk!();
+    fn put_cap(dev: &mut PciDevice, off: usize, id: u8, next: u8) {
+        dev.cfg_write(off, (next as u32) << 8 | id as u32);
     }
 
-    cpu::init_cpu();
-    vga::init_vga();
+    #[test]
+    fn capability_list_is_walked() {
+        let mut cfg = vec![0u32; CFG_SIZE / 4];
+        let mut dev = host_dev(&mut cfg, 0x40);
+        put_cap(&mut dev, 0x40, 0x01, 0x50);
+        put_cap(&mut dev, 0x50, CAP_MSI, 0x70);
+        put_cap(&mut dev, 0x70, CAP_MSIX, 0x00);
+
+        assert_eq!(dev.capabilities(), vec![(0x01, 0x40), (CAP_MSI, 0x50), (CAP_MSIX, 0x70)]);
+        assert_eq!(dev.find_capability(CAP_MSI), Some(0x50));
+        assert_eq!(dev.find_capability(CAP_MSIX), Some(0x70));
+        assert_eq!(dev.find_capability(0x10), None);
+    }
+
+    #[test]
+    fn capability_list_edge_cases() {
+        let mut cfg = vec![0u32; CFG_SIZE / 4];
+        let mut dev = host_dev(&mut cfg, 0x40);
+        put_cap(&mut dev, 0x40, CAP_MSI, 0x40);
+        // A list pointing at itself ends after a bounded number of steps
+        assert_eq!(dev.capabilities().len(), 48);
+        assert_eq!(dev.find_capability(CAP_MSI), Some(0x40));
+
+        // The low two bits of the pointers are reserved
+        put_cap(&mut dev, 0x40, 0x01, 0x53);
+        put_cap(&mut dev, 0x50, CAP_MSIX, 0x00);
+        assert_eq!(dev.find_capability(CAP_MSIX), Some(0x50));
+
+        // Without the status bit the pointer means nothing
+        dev.cfg_write(0x4, 0);
+        assert_eq!(dev.find_capability(0x01), None);
+        assert!(dev.enable_msi(0x40, 0).is_err());
+    }
+
+    fn put_ext_cap(dev: &mut PciDevice, off: usize, id: u16, next: usize) {
+        dev.cfg_write(off, (next as u32) << 20 | 1 << 16 | id as u32);
+    }
+
+    #[test]
+    fn extended_capabilities_are_walked() {
+        let mut cfg = vec![0u32; CFG_SIZE / 4];
+        let mut dev = host_dev(&mut cfg, 0);
+        put_ext_cap(&mut dev, 0x100, 0x0001, 0x148); // AER
+        put_ext_cap(&mut dev, 0x148, 0x0018, 0xffc); // LTR
+        put_ext_cap(&mut dev, 0xffc, 0x0003, 0x000); // Serial number, last dword
+
+        assert_eq!(dev.ext_capabilities(), vec![(0x0001, 0x100), (0x0018, 0x148), (0x0003, 0xffc)]);
+        assert_eq!(dev.find_ext_capability(0x0018), Some(0x148));
+        assert_eq!(dev.find_ext_capability(0x0003), Some(0xffc));
+        assert_eq!(dev.find_ext_capability(0x0010), None);
+    }
+
+    #[test]
+    fn extended_capability_edge_cases() {
+        let mut cfg = vec![0u32; CFG_SIZE / 4];
+        let mut dev = host_dev(&mut cfg, 0);
+        assert!(dev.ext_capabilities().is_empty());
+
+        // Conventional PCI reads all ones past 0x100
+        dev.cfg_write(0x100, 0xffffffff);
+        assert!(dev.ext_capabilities().is_empty());
+
+        // A list pointing at itself ends after a bounded number of steps
+        put_ext_cap(&mut dev, 0x100, 0x0001, 0x100);
+        assert_eq!(dev.ext_capabilities().len(), (CFG_SIZE - EXT_CAP_START) / 4);
+
+        // Pointers back into the legacy header end the list, reserved bits are masked
+        put_ext_cap(&mut dev, 0x100, 0x0001, 0x0fc);
+        assert_eq!(dev.ext_capabilities(), vec![(0x0001, 0x100)]);
+        put_ext_cap(&mut dev, 0x100, 0x0001, 0x203);
+        put_ext_cap(&mut dev, 0x200, 0x000b, 0x000);
+        assert_eq!(dev.find_ext_capability(0x000b), Some(0x200));
+    }
+
+    #[test]
+    #[should_panic]
+    fn extended_space_ends_at_4k() {
+        let mut cfg = vec![0u32; CFG_SIZE / 4];
+        host_dev(&mut cfg, 0).cfg_read(CFG_SIZE);
+    }
+
+    const ECAM_BUSES: usize = 4;
+
+    // An ECAM window for a few buses with every function absent
+    fn host_ecam() -> Vec<u32> { vec![0xffffffff; ECAM_BUSES << 20 >> 2] }
+
+    fn put_fn(ecam: &mut [u32], devid: u16, header_type: u8, secondary: u8) {
+        let cfg = &mut ecam[(devid as usize) << 12 >> 2..][..16];
+        cfg.fill(0);
+        cfg[0] = 0x1234_8086;
+        cfg[3] = (header_type as u32) << 16;
+        if header_type & 0x7f == 1 { cfg[6] = (secondary as u32) << 16 | (secondary as u32) << 8; }
+    }
+
+    #[test]
+    fn devices_behind_bridges_are_found_once() {
+        let mut ecam = host_ecam();
+        put_fn(&mut ecam, 0x0000, 0, 0);       // 00:00.0 host bridge
+        put_fn(&mut ecam, 0x0008, 1, 1);       // 00:01.0 bridge to bus 1
+        put_fn(&mut ecam, 0x0010, 1, 1);       // 00:02.0 another bridge claiming bus 1
+        put_fn(&mut ecam, 0x0018, 1, 9);       // 00:03.0 bridge outside the MCFG range
+        put_fn(&mut ecam, 0x0100, 0, 0);       // 01:00.0 endpoint
+        put_fn(&mut ecam, 0x0300, 0, 0);       // 03:00.0 on a bus nothing leads to
+
+        let base = ecam.as_mut_ptr() as u64;
+        let devs = scan_pcie_devices(base, 0, ECAM_BUSES as u8 - 1);
+        let ids: Vec<u16> = devs.iter().map(|d| d.devid).collect();
+        assert_eq!(ids, vec![0x0000, 0x0008, 0x0100, 0x0010, 0x0018]);
+        assert!(devs.iter().all(|d| d.command() & 0x6 == 0x6));
+    }
+
+    #[test]
+    fn multi_function_root_scans_each_bus() {
+        let mut ecam = host_ecam();
+        put_fn(&mut ecam, 0x0000, 0x80, 0);    // 00:00.0 multi-function host bridge
+        put_fn(&mut ecam, 0x0002, 0, 0);       // 00:00.2 roots bus 2
+        put_fn(&mut ecam, 0x0100, 0, 0);       // 01:00.0 skipped, no 00:00.1
+        put_fn(&mut ecam, 0x0200, 0, 0);       // 02:00.0
+
+        let base = ecam.as_mut_ptr() as u64;
+        let ids: Vec<u16> = scan_pcie_devices(base, 0, ECAM_BUSES as u8 - 1)
+            .iter().map(|d| d.devid).collect();
+        assert_eq!(ids, vec![0x0000, 0x0002, 0x0200]);
+    }
+
+    #[test]
+    fn bar_sizes_are_decoded() {
+        // 32-bit MMIO, 16 KiB
+        assert_eq!(decode_bar(0xfebf_0000, 0, 0xffff_c000, 0xffffffff), Some((0xfebf_0000, 0x4000, true)));
+        // 64-bit prefetchable MMIO above 4 GiB, 8 GiB
+        assert_eq!(decode_bar(0x0000_000c, 0x0000_0080, 0x0000_000c, 0xffff_fffe),
+            Some((0x80_0000_0000, 0x2_0000_0000, true)));
+        // I/O with only the low 16 bits wired, 32 ports
+        assert_eq!(decode_bar(0xc041, 0, 0xffe1, 0xffffffff), Some((0xc040, 0x20, false)));
+        // Nothing sticks, the BAR is not implemented
+        assert_eq!(decode_bar(0, 0, 0, 0xffffffff), None);
+        assert_eq!(decode_bar(0x4, 0, 0x4, 0), None);
+    }
+
+    #[test]
+    fn bar_probing_restores_the_device() {
+        let mut cfg = vec![0u32; CFG_SIZE / 4];
+        let mut dev = host_dev(&mut cfg, 0);
+        dev.set_command(0x0007);
+        dev.cfg_write(0x10, 0xfebf_000c); // BAR0 and BAR1 form a 64-bit pair
+        dev.cfg_write(0x14, 0x0000_0001);
+        dev.cfg_write(0x18, 0xfe00_0000);
+
+        // Plain memory reads back every bit written, so only the lowest address bit is sizeable
+        assert_eq!(dev.bar_region(0), Some((0x1_febf_0000, 0x10, true)));
+        assert_eq!(dev.bar_region(1), None);
+        assert_eq!(dev.bar_region(2), Some((0xfe00_0000, 0x10, true)));
+        assert_eq!((dev.cfg_read(0x10), dev.cfg_read(0x14)), (0xfebf_000c, 0x0000_0001));
+        assert_eq!(dev.command(), 0x0007);
+    }
 }
@@ -9,9 +9,11 @@ use crate::{
         glacier::{GLACIER, page_size},
         physalloc::{AllocParams, PHYS_ALLOC},
         size_align
-    }
+    },
+    warn
 };
 
+use core::ops::Range;
 use alloc::{collections::btree_map::BTreeMap, format, string::String, sync::Arc};
 use nvme_oxide::{Dma, NVMeDev, Ns};
 use spin::RwLock;
@@ -49,14 +51,36 @@ impl Dma for NVMeAlloc {
     fn page_size(&self) -> usize { return page_size(); }
 }
 
+// Bytes one command may move without a PRP list: PRP1 and PRP2 cover two pages
+// of a page aligned buffer. Two of the controller's smallest pages (CAP.MPSMIN)
+// is also the least MDTS can be, so this holds without the Identify data.
+fn xfer_limit(mmio: usize) -> usize {
+    let cap_hi = unsafe { ((mmio + 4) as *const u32).read_volatile() };
+    let mps_min = 0x1000usize << ((cap_hi >> 16) & 0xf);
+    return 2 * page_size().min(mps_min);
+}
+
+// Whole blocks per command, None when even one block is over the limit
+fn per_cmd(bs: usize, limit: usize) -> Option<usize> {
+    if bs == 0 || bs > limit { return None; }
+    return Some(limit / bs * bs);
+}
+
+// LBA and byte range of every command covering `len` bytes of whole blocks
+fn commands(lba: u64, len: usize, bs: usize, per_cmd: usize) -> impl Iterator<Item = (u64, Range<usize>)> {
+    return (0..len).step_by(per_cmd)
+        .map(move |off| (lba + (off / bs) as u64, off..(off + per_cmd).min(len)));
+}
+
 pub struct BlockDeviceNVMe {
     ns: Arc<Ns<NVMeAlloc>>,
-    devid: u16
+    devid: u16,
+    max_xfer: usize
 }
 
 impl BlockDeviceNVMe {
-    pub fn new(ns: Arc<Ns<NVMeAlloc>>, devid: u16) -> Self {
-        Self { ns, devid }
+    pub fn new(ns: Arc<Ns<NVMeAlloc>>, devid: u16, max_xfer: usize) -> Self {
+        Self { ns, devid, max_xfer }
     }
 }
 
@@ -73,14 +97,25 @@ impl BlockDevice for BlockDeviceNVMe {
         // PhysPageBuf ensures both address and size alignment to 4 kiB
         // via AllocParams settings.
         let bs = self.block_size() as usize;
-        let mut pabuf = PhysPageBuf::new(bs)
+        let full = buf.len() / bs * bs;
+        let per_cmd = per_cmd(bs, self.max_xfer).ok_or("NVMe block size over the transfer limit")?;
+        let mut pabuf = PhysPageBuf::new_zeroed(per_cmd.min(full).max(bs))
             .ok_or("Failed to allocate DMA buffer")?;
 
-        for (i, ck) in buf.chunks_mut(bs).enumerate() {
-            self.ns.read(lba + i as u64, &mut pabuf).map_err(|e|
+        for (cmd_lba, range) in commands(lba, full, bs, per_cmd) {
+            let len = range.len();
+            self.ns.read(cmd_lba, &mut pabuf[..len]).map_err(|e|
                 format!("NVMe read error: {:?}", e)
             )?;
-            ck.copy_from_slice(&pabuf[..ck.len()]);
+            buf[range].copy_from_slice(&pabuf[..len]);
+        }
+
+        let tail = &mut buf[full..];
+        if !tail.is_empty() {
+            self.ns.read(lba + (full / bs) as u64, &mut pabuf[..bs]).map_err(|e|
+                format!("NVMe read error: {:?}", e)
+            )?;
+            tail.copy_from_slice(&pabuf[..tail.len()]);
         }
 
         return Ok(());
@@ -90,17 +125,27 @@ impl BlockDevice for BlockDeviceNVMe {
         // PhysPageBuf ensures both address and size alignment to 4 kiB
         // via AllocParams settings.
         let bs = self.block_size() as usize;
-        let mut pabuf = PhysPageBuf::new(bs)
+        let full = buf.len() / bs * bs;
+        let per_cmd = per_cmd(bs, self.max_xfer).ok_or("NVMe block size over the transfer limit")?;
+        let mut pabuf = PhysPageBuf::new_zeroed(per_cmd.min(full).max(bs))
             .ok_or("Failed to allocate DMA buffer")?;
 
-        for (i, ck) in buf.chunks(bs).enumerate() {
-            if ck.len() < bs {
-                self.ns.read(lba + i as u64, &mut pabuf).map_err(|e|
-                    format!("NVMe read error: {:?}", e)
-                )?;
-            }
-            pabuf[..ck.len()].copy_from_slice(ck);
-            self.ns.write(lba + i as u64, &pabuf).map_err(|e|
+        for (cmd_lba, range) in commands(lba, full, bs, per_cmd) {
+            let len = range.len();
+            pabuf[..len].copy_from_slice(&buf[range]);
+            self.ns.write(cmd_lba, &pabuf[..len]).map_err(|e|
+                format!("NVMe write error: {:?}", e)
+            )?;
+        }
+
+        let tail = &buf[full..];
+        if !tail.is_empty() {
+            let lba = lba + (full / bs) as u64;
+            self.ns.read(lba, &mut pabuf[..bs]).map_err(|e|
+                format!("NVMe read error: {:?}", e)
+            )?;
+            pabuf[..tail.len()].copy_from_slice(tail);
+            self.ns.write(lba, &pabuf[..bs]).map_err(|e|
                 format!("NVMe write error: {:?}", e)
             )?;
         }
@@ -127,11 +172,43 @@ pub fn add(dev: &mut PciDevice) {
 
     let devid = dev.devid;
     if let Ok(nvme) = NVMeDev::new(dev.mmio_addr(), NVMeAlloc) {
+        let max_xfer = xfer_limit(dev.mmio_addr());
         let mut nvme_devices = NVME_DEV.write();
         let mut block_devices = BLOCK_DEVICES.write();
         for ns in nvme.ns_list() {
-            block_devices.push(Arc::new(BlockDeviceNVMe::new(ns.clone(), devid)));
+            if per_cmd(ns.blk_sz() as usize, max_xfer).is_none() {
+                warn!("NVMe {:#x} ns {}: {} byte blocks need a PRP list, skipped", devid, ns.id(), ns.blk_sz());
+                continue;
+            }
+            block_devices.push(Arc::new(BlockDeviceNVMe::new(ns.clone(), devid, max_xfer)));
         }
         nvme_devices.insert(devid, nvme);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{vec, vec::Vec};
+
+    #[test]
+    fn per_cmd_whole_blocks() {
+        assert_eq!(per_cmd(512, 0x2000), Some(0x2000));
+        assert_eq!(per_cmd(4096, 0x2000), Some(0x2000));
+        assert_eq!(per_cmd(3000, 0x2000), Some(6000));
+        assert_eq!(per_cmd(0x2000, 0x2000), Some(0x2000));
+        assert_eq!(per_cmd(0x4000, 0x2000), None);
+    }
+
+    #[test]
+    fn commands_split_at_per_cmd() {
+        let cmds: Vec<_> = commands(10, 5 * 512, 512, 1024).collect();
+        assert_eq!(cmds, vec![(10, 0..1024), (12, 1024..2048), (14, 2048..2560)]);
+
+        // Exactly on a boundary, no empty command at the end
+        let cmds: Vec<_> = commands(0, 4 * 512, 512, 1024).collect();
+        assert_eq!(cmds, vec![(0, 0..1024), (2, 1024..2048)]);
+
+        assert_eq!(commands(7, 0, 512, 1024).count(), 0);
+    }
+}
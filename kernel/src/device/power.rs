@@ -0,0 +1,191 @@
+use crate::{
+    arch,
+    device::{ACPI, acpi::KernelAcpiHandler},
+    warn
+};
+
+use acpi::{
+    Handler,
+    address::{AddressSpace, GenericAddress},
+    sdt::{SdtHeader, fadt::Fadt}
+};
+
+const SLP_TYP: u16 = 0b111 << 10;
+const SLP_EN: u16  = 1 << 13;
+
+const AML_NAME_OP: u8    = 0x08;
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_BYTE_PREFIX: u8 = 0x0a;
+
+// Keeps every bit of the current PM1_CNT but SLP_TYP and SLP_EN
+pub fn pm1_cnt_value(cur: u16, slp_typ: u8) -> u16 {
+    return (cur & !(SLP_TYP | SLP_EN)) | (((slp_typ as u16) << 10) & SLP_TYP) | SLP_EN;
+}
+
+// Reads one package element, either a ByteConst or a bare Zero/One
+fn aml_byte(aml: &[u8], pos: &mut usize) -> Option<u8> {
+    let op = *aml.get(*pos)?;
+    *pos += 1;
+    if op == AML_BYTE_PREFIX {
+        *pos += 1;
+        return aml.get(*pos - 1).copied();
+    }
+    return Some(op);
+}
+
+// SLP_TYPa and SLP_TYPb from `Name (_S5, Package () { a, b, ... })`
+pub fn parse_s5(aml: &[u8]) -> Option<(u8, u8)> {
+    let idx = aml.windows(4).position(|w| w == b"_S5_")?;
+    let named = idx >= 1 && aml[idx - 1] == AML_NAME_OP
+        || idx >= 2 && aml[idx - 2] == AML_NAME_OP && aml[idx - 1] == b'\\';
+    if !named || *aml.get(idx + 4)? != AML_PACKAGE_OP {
+        return None;
+    }
+
+    // PkgLength, then NumElements
+    let lead = *aml.get(idx + 5)?;
+    let mut pos = idx + 5 + 1 + (lead >> 6) as usize + 1;
+
+    let slp_typa = aml_byte(aml, &mut pos)?;
+    let slp_typb = aml_byte(aml, &mut pos)?;
+    return Some((slp_typa, slp_typb));
+}
+
+fn read_gas(gas: &GenericAddress) -> u64 {
+    let handler = KernelAcpiHandler::default();
+    let addr = gas.address as usize;
+
+    return match gas.address_space {
+        AddressSpace::SystemIo => match gas.bit_width {
+            8  => handler.read_io_u8(addr as u16) as u64,
+            32 => handler.read_io_u32(addr as u16) as u64,
+            _  => handler.read_io_u16(addr as u16) as u64
+        },
+        AddressSpace::SystemMemory => match gas.bit_width {
+            8  => handler.read_u8(addr) as u64,
+            32 => handler.read_u32(addr) as u64,
+            64 => handler.read_u64(addr),
+            _  => handler.read_u16(addr) as u64
+        },
+        _ => {
+            warn!("Unsupported ACPI register space: {:?}", gas.address_space);
+            0
+        }
+    };
+}
+
+fn write_gas(gas: &GenericAddress, val: u64) {
+    let handler = KernelAcpiHandler::default();
+    let addr = gas.address as usize;
+
+    match gas.address_space {
+        AddressSpace::SystemIo => match gas.bit_width {
+            8  => handler.write_io_u8(addr as u16, val as u8),
+            32 => handler.write_io_u32(addr as u16, val as u32),
+            _  => handler.write_io_u16(addr as u16, val as u16)
+        },
+        // The handler maps the register writable around each write
+        AddressSpace::SystemMemory => match gas.bit_width {
+            8  => handler.write_u8(addr, val as u8),
+            32 => handler.write_u32(addr, val as u32),
+            64 => handler.write_u64(addr, val),
+            _  => handler.write_u16(addr, val as u16)
+        },
+        _ => warn!("Unsupported ACPI register space: {:?}", gas.address_space)
+    }
+}
+
+fn dsdt_s5(dsdt: usize) -> Option<(u8, u8)> {
+    let handler = KernelAcpiHandler::default();
+    let len = {
+        let hdr = unsafe { handler.map_physical_region::<SdtHeader>(dsdt, size_of::<SdtHeader>()) };
+        hdr.length as usize
+    };
+
+    let mapping = unsafe { handler.map_physical_region::<u8>(dsdt, len) };
+    let aml = unsafe { core::slice::from_raw_parts(dsdt as *const u8, len) };
+    let s5 = parse_s5(&aml[size_of::<SdtHeader>()..]);
+    drop(mapping);
+    return s5;
+}
+
+fn acpi_shutdown() -> Option<()> {
+    let acpi_lock = ACPI.read();
+    let fadt = acpi_lock.as_ref()?.find_table::<Fadt>()?;
+    let fadt = fadt.get();
+
+    let (slp_typa, slp_typb) = dsdt_s5(fadt.dsdt_address().ok()?)?;
+    let pm1a = fadt.pm1a_control_block().ok()?;
+    let pm1b = fadt.pm1b_control_block().ok()?;
+
+    write_gas(&pm1a, pm1_cnt_value(read_gas(&pm1a) as u16, slp_typa) as u64);
+    if let Some(pm1b) = pm1b {
+        write_gas(&pm1b, pm1_cnt_value(read_gas(&pm1b) as u16, slp_typb) as u64);
+    }
+    return Some(());
+}
+
+fn acpi_reboot() -> Option<()> {
+    let acpi_lock = ACPI.read();
+    let fadt = acpi_lock.as_ref()?.find_table::<Fadt>()?;
+    let fadt = fadt.get();
+
+    let flags = fadt.flags;
+    if !flags.supports_system_reset() { return None; }
+
+    let reset = fadt.reset_register().ok()?;
+    write_gas(&reset, fadt.reset_value as u64);
+    return Some(());
+}
+
+pub fn shutdown() -> ! {
+    arch::exc::set(false);
+    acpi_shutdown();
+
+    #[cfg(target_arch = "aarch64")]
+    arch::psci(0x8400_0008, 0, 0, 0); // SYSTEM_OFF
+
+    warn!("Shutdown failed, halting");
+    loop { arch::halt(); }
+}
+
+pub fn reboot() -> ! {
+    arch::exc::set(false);
+    acpi_reboot();
+
+    #[cfg(target_arch = "x86_64")]
+    unsafe { core::arch::asm!("out 0x64, al", in("al") 0xfeu8); } // 8042 reset pulse
+    #[cfg(target_arch = "aarch64")]
+    arch::psci(0x8400_0009, 0, 0, 0); // SYSTEM_RESET
+
+    warn!("Reboot failed, halting");
+    loop { arch::halt(); }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pm1_cnt_keeps_other_bits() {
+        assert_eq!(pm1_cnt_value(0x0000, 5), 0x3400);
+        assert_eq!(pm1_cnt_value(0x1c01, 0), 0x2001); // Old SLP_TYP is cleared, SCI_EN kept
+        assert_eq!(pm1_cnt_value(0x0001, 0xff), 0x3c01); // Only three bits of SLP_TYP fit
+    }
+
+    #[test]
+    fn s5_package_from_dsdt() {
+        // Name (_S5, Package (0x04) { 0x05, 0x05, Zero, Zero })
+        let aml = [0x10, 0x08, b'_', b'S', b'5', b'_', 0x12, 0x0a, 0x04, 0x0a, 0x05, 0x0a, 0x05, 0x00, 0x00];
+        assert_eq!(parse_s5(&aml), Some((5, 5)));
+
+        // Name (\_S5, Package (0x02) { Zero, 0x07 })
+        let aml = [0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x06, 0x02, 0x00, 0x0a, 0x07];
+        assert_eq!(parse_s5(&aml), Some((0, 7)));
+
+        // A method referring to _S5 is not the object
+        let aml = [0x70, b'_', b'S', b'5', b'_', 0x12, 0x06, 0x02, 0x00, 0x00];
+        assert_eq!(parse_s5(&aml), None);
+        assert_eq!(parse_s5(&[0x08, b'_', b'S', b'5', b'_', 0x12]), None);
+    }
+}
@@ -0,0 +1,157 @@
+use crate::{
+    arch::{ioapic, phys_id},
+    device::ringbuf::ByteRing,
+    warn
+};
+
+use core::{
+    arch::asm,
+    hint::spin_loop,
+    sync::atomic::{AtomicBool, Ordering as AtomOrd}
+};
+
+pub const KBD_VECTOR: u8 = 34;
+
+const PORT_DATA: u16   = 0x60;
+const PORT_STATUS: u16 = 0x64; // Command port on write
+
+const STATUS_OUT_FULL: u8 = 1 << 0;
+const STATUS_IN_FULL: u8  = 1 << 1;
+
+const CMD_READ_CFG: u8     = 0x20;
+const CMD_WRITE_CFG: u8    = 0x60;
+const CMD_DISABLE_AUX: u8  = 0xa7;
+const CMD_DISABLE_PORT: u8 = 0xad;
+const CMD_ENABLE_PORT: u8  = 0xae;
+
+const CFG_PORT_IRQ: u8   = 1 << 0;
+const CFG_PORT_CLOCK: u8 = 1 << 4; // Set to disable
+const CFG_TRANSLATE: u8  = 1 << 6;
+
+fn inb(port: u16) -> u8 {
+    let val: u8;
+    unsafe { asm!("in al, dx", out("al") val, in("dx") port, options(nomem, nostack)); }
+    return val;
+}
+
+fn outb(port: u16, val: u8) {
+    unsafe { asm!("out dx, al", in("dx") port, in("al") val, options(nomem, nostack)); }
+}
+
+fn write_cmd(cmd: u8) {
+    while inb(PORT_STATUS) & STATUS_IN_FULL != 0 { spin_loop(); }
+    outb(PORT_STATUS, cmd);
+}
+
+fn write_data(val: u8) {
+    while inb(PORT_STATUS) & STATUS_IN_FULL != 0 { spin_loop(); }
+    outb(PORT_DATA, val);
+}
+
+fn read_data() -> u8 {
+    while inb(PORT_STATUS) & STATUS_OUT_FULL == 0 { spin_loop(); }
+    return inb(PORT_DATA);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Key {
+    Char(u8),
+    Shift,
+    Unknown
+}
+
+// Set 1 make codes up to 0x39, as (plain, shifted)
+const KEYMAP: [(u8, u8); 0x3a] = [
+    (0, 0),       (0x1b, 0x1b), (b'1', b'!'), (b'2', b'@'), (b'3', b'#'), (b'4', b'$'),
+    (b'5', b'%'), (b'6', b'^'), (b'7', b'&'), (b'8', b'*'), (b'9', b'('), (b'0', b')'),
+    (b'-', b'_'), (b'=', b'+'), (0x08, 0x08), (b'\t', b'\t'),
+    (b'q', b'Q'), (b'w', b'W'), (b'e', b'E'), (b'r', b'R'), (b't', b'T'), (b'y', b'Y'),
+    (b'u', b'U'), (b'i', b'I'), (b'o', b'O'), (b'p', b'P'), (b'[', b'{'), (b']', b'}'),
+    (b'\n', b'\n'), (0, 0),
+    (b'a', b'A'), (b's', b'S'), (b'd', b'D'), (b'f', b'F'), (b'g', b'G'), (b'h', b'H'),
+    (b'j', b'J'), (b'k', b'K'), (b'l', b'L'), (b';', b':'), (b'\'', b'"'), (b'`', b'~'),
+    (0, 0),       (b'\\', b'|'),
+    (b'z', b'Z'), (b'x', b'X'), (b'c', b'C'), (b'v', b'V'), (b'b', b'B'), (b'n', b'N'),
+    (b'm', b'M'), (b',', b'<'), (b'.', b'>'), (b'/', b'?'), (0, 0),       (b'*', b'*'),
+    (0, 0),       (b' ', b' ')
+];
+
+pub fn translate(scancode: u8, shift: bool) -> Key {
+    let code = scancode & 0x7f;
+    if code == 0x2a || code == 0x36 { return Key::Shift; }
+
+    return match KEYMAP.get(code as usize) {
+        Some(&(plain, shifted)) if plain != 0 => Key::Char(if shift { shifted } else { plain }),
+        _ => Key::Unknown
+    };
+}
+
+// Filled by the IRQ, keys typed while full are dropped
+static RING: ByteRing<256> = ByteRing::new(false);
+static SHIFT: AtomicBool = AtomicBool::new(false);
+
+pub fn pop() -> Option<u8> {
+    return RING.pop();
+}
+
+pub fn handle_irq() {
+    let scancode = inb(PORT_DATA);
+    let release = scancode & 0x80 != 0;
+
+    match translate(scancode, SHIFT.load(AtomOrd::Relaxed)) {
+        Key::Shift => SHIFT.store(!release, AtomOrd::Relaxed),
+        Key::Char(c) if !release => { RING.push(c); }
+        _ => {}
+    }
+}
+
+pub fn init() {
+    write_cmd(CMD_DISABLE_PORT);
+    write_cmd(CMD_DISABLE_AUX);
+    while inb(PORT_STATUS) & STATUS_OUT_FULL != 0 { inb(PORT_DATA); }
+
+    write_cmd(CMD_READ_CFG);
+    let cfg = (read_data() | CFG_PORT_IRQ | CFG_TRANSLATE) & !CFG_PORT_CLOCK;
+    write_cmd(CMD_WRITE_CFG);
+    write_data(cfg);
+    write_cmd(CMD_ENABLE_PORT);
+
+    if let Err(e) = ioapic::route_isa(1, KBD_VECTOR, phys_id() as u32) {
+        warn!("PS/2 keyboard: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn typed(codes: &[u8], shift: bool) -> [Key; 4] {
+        let mut keys = [Key::Unknown; 4];
+        for (key, &code) in keys.iter_mut().zip(codes) { *key = translate(code, shift); }
+        return keys;
+    }
+
+    #[test]
+    fn digits_and_shifted_symbols() {
+        use Key::Char;
+        assert_eq!(typed(&[0x02, 0x03, 0x0a, 0x0b], false), [Char(b'1'), Char(b'2'), Char(b'9'), Char(b'0')]);
+        assert_eq!(typed(&[0x02, 0x03, 0x0a, 0x0b], true), [Char(b'!'), Char(b'@'), Char(b'('), Char(b')')]);
+    }
+
+    #[test]
+    fn letters_follow_shift() {
+        use Key::Char;
+        assert_eq!(typed(&[0x10, 0x1e, 0x2c, 0x32], false), [Char(b'q'), Char(b'a'), Char(b'z'), Char(b'm')]);
+        assert_eq!(typed(&[0x10, 0x1e, 0x2c, 0x32], true), [Char(b'Q'), Char(b'A'), Char(b'Z'), Char(b'M')]);
+    }
+
+    #[test]
+    fn breaks_shift_and_unmapped() {
+        // A break code maps like its make code, handle_irq drops the release
+        assert_eq!(translate(0x9e, false), Key::Char(b'a'));
+        assert_eq!(translate(0x2a, false), Key::Shift);
+        assert_eq!(translate(0xb6, true), Key::Shift);
+        assert_eq!(translate(0x1d, false), Key::Unknown); // Left ctrl
+        assert_eq!(translate(0x3b, false), Key::Unknown); // F1, past the table
+    }
+}
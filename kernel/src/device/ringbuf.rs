@@ -0,0 +1,180 @@
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering as AtomOrd};
+
+// Fixed-size byte queue for input devices, filled from IRQ handlers.
+// Any number of producers and consumers: a slot is claimed by moving head (or tail)
+// with a compare-exchange, then published through its state once the byte is in.
+// A reader never sees a claimed slot before its byte lands.
+pub struct ByteRing<const N: usize> {
+    buf: [Slot; N],
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    overwrite: bool // When full, drop the oldest byte instead of the new one
+}
+
+// 2 * lap while free to write, 2 * lap + 1 once written, lap being how often
+// the ring has wrapped at that position. All zero is an empty ring.
+struct Slot {
+    state: AtomicUsize,
+    byte: AtomicU8
+}
+
+impl<const N: usize> ByteRing<N> {
+    pub const fn new(overwrite: bool) -> Self {
+        return Self {
+            buf: [const { Slot { state: AtomicUsize::new(0), byte: AtomicU8::new(0) } }; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            overwrite
+        };
+    }
+
+    pub const fn capacity(&self) -> usize { N }
+
+    fn lap(pos: usize) -> usize {
+        return (pos / N).wrapping_mul(2);
+    }
+
+    // False when the byte was dropped
+    pub fn push(&self, byte: u8) -> bool {
+        let mut pos = self.head.load(AtomOrd::Relaxed);
+        loop {
+            let slot = &self.buf[pos % N];
+            let diff = slot.state.load(AtomOrd::Acquire).wrapping_sub(Self::lap(pos)) as isize;
+
+            if diff == 0 {
+                match self.head.compare_exchange_weak(
+                    pos, pos.wrapping_add(1), AtomOrd::Relaxed, AtomOrd::Relaxed
+                ) {
+                    Ok(_) => {
+                        slot.byte.store(byte, AtomOrd::Relaxed);
+                        slot.state.store(Self::lap(pos) + 1, AtomOrd::Release);
+                        return true;
+                    }
+                    Err(cur) => pos = cur
+                }
+            } else if diff < 0 {
+                // Full, the slot still holds the byte from a lap ago. Never waits for a
+                // producer to publish, that one may be the code this IRQ interrupted.
+                if !self.overwrite || self.pop().is_none() { return false; }
+                pos = self.head.load(AtomOrd::Relaxed);
+            } else {
+                pos = self.head.load(AtomOrd::Relaxed); // Another producer got here first
+            }
+        }
+    }
+
+    // None also while the oldest byte is claimed but not yet published
+    pub fn pop(&self) -> Option<u8> {
+        let mut pos = self.tail.load(AtomOrd::Relaxed);
+        loop {
+            let slot = &self.buf[pos % N];
+            let diff = slot.state.load(AtomOrd::Acquire).wrapping_sub(Self::lap(pos) + 1) as isize;
+
+            if diff == 0 {
+                match self.tail.compare_exchange_weak(
+                    pos, pos.wrapping_add(1), AtomOrd::Relaxed, AtomOrd::Relaxed
+                ) {
+                    Ok(_) => {
+                        let byte = slot.byte.load(AtomOrd::Relaxed);
+                        slot.state.store(Self::lap(pos) + 2, AtomOrd::Release);
+                        return Some(byte);
+                    }
+                    Err(cur) => pos = cur
+                }
+            } else if diff < 0 {
+                return None;
+            } else {
+                pos = self.tail.load(AtomOrd::Relaxed);
+            }
+        }
+    }
+
+    // Pops into buf until either runs out, returns the count
+    pub fn drain(&self, buf: &mut [u8]) -> usize {
+        let mut n = 0;
+        while n < buf.len() {
+            let Some(byte) = self.pop() else { break; };
+            buf[n] = byte;
+            n += 1;
+        }
+        return n;
+    }
+
+    // Claimed slots count too, so this may run ahead of what pop returns
+    pub fn len(&self) -> usize {
+        let tail = self.tail.load(AtomOrd::Acquire);
+        return self.head.load(AtomOrd::Acquire).wrapping_sub(tail).min(N);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use alloc::vec::Vec;
+    use std::{sync::Arc, thread};
+
+    #[test]
+    fn wraps_around() {
+        let ring = ByteRing::<4>::new(false);
+        for round in 0..10u8 {
+            for i in 0..3 {
+                assert!(ring.push(round * 3 + i));
+            }
+            assert_eq!(ring.len(), 3);
+            let mut out = [0; 4];
+            assert_eq!(ring.drain(&mut out), 3);
+            assert_eq!(out[..3], [round * 3, round * 3 + 1, round * 3 + 2]);
+        }
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn full_policy() {
+        let drop_new = ByteRing::<3>::new(false);
+        let drop_old = ByteRing::<3>::new(true);
+        for b in 1..=5 {
+            let _ = drop_new.push(b);
+            assert!(drop_old.push(b));
+        }
+        assert!(!drop_new.push(6));
+
+        let mut out = [0; 3];
+        assert_eq!(drop_new.drain(&mut out), 3);
+        assert_eq!(out, [1, 2, 3]);
+        assert_eq!(drop_old.drain(&mut out), 3);
+        assert_eq!(out, [3, 4, 5]);
+    }
+
+    // Bytes from each producer come out in the order that producer pushed them
+    #[test]
+    fn producers_keep_their_order() {
+        const PER: usize = 5000;
+        let ring = Arc::new(ByteRing::<64>::new(false));
+        let producers: Vec<_> = (0..4u8).map(|id| {
+            let ring = ring.clone();
+            thread::spawn(move || {
+                for i in 0..PER {
+                    while !ring.push(id << 6 | (i % 64) as u8) {
+                        thread::yield_now();
+                    }
+                }
+            })
+        }).collect();
+
+        let mut next = [0usize; 4];
+        while next.iter().sum::<usize>() < 4 * PER {
+            let Some(byte) = ring.pop() else { continue; };
+            let id = (byte >> 6) as usize;
+            assert_eq!((byte & 63) as usize, next[id] % 64);
+            next[id] += 1;
+        }
+        producers.into_iter().for_each(|p| p.join().unwrap());
+        assert!(ring.is_empty());
+    }
+}
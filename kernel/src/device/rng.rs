@@ -0,0 +1,138 @@
+use crate::arch::intc;
+
+use core::sync::atomic::{AtomicU64, Ordering as AtomOrd};
+
+#[cfg(target_arch = "x86_64")]
+mod hw {
+    use crate::arch::cpuid;
+    use core::arch::asm;
+
+    const RETRIES: usize = 10;
+
+    pub fn available() -> bool {
+        return cpuid::has_rdseed() || cpuid::has_rdrand();
+    }
+
+    // RDSEED draws straight from the entropy source, RDRAND from a DRBG it reseeds
+    pub fn next() -> Option<u64> {
+        for _ in 0..RETRIES {
+            let (val, ok): (u64, u8);
+            if cpuid::has_rdseed() {
+                unsafe { asm!("rdseed {}", "setc {}", out(reg) val, out(reg_byte) ok, options(nomem, nostack)); }
+            } else if cpuid::has_rdrand() {
+                unsafe { asm!("rdrand {}", "setc {}", out(reg) val, out(reg_byte) ok, options(nomem, nostack)); }
+            } else {
+                return None;
+            }
+            if ok != 0 { return Some(val); }
+        }
+        return None;
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod hw {
+    use core::arch::asm;
+
+    const RETRIES: usize = 10;
+
+    // FEAT_RNG, ID_AA64ISAR0_EL1.RNDR
+    pub fn available() -> bool {
+        let isar0: u64;
+        unsafe { asm!("mrs {}, id_aa64isar0_el1", out(reg) isar0, options(nomem, nostack)); }
+        return (isar0 >> 60) & 0xf != 0;
+    }
+
+    // RNDR sets Z when no number could be returned in reasonable time
+    pub fn next() -> Option<u64> {
+        if !available() { return None; }
+        for _ in 0..RETRIES {
+            let (val, ok): (u64, u64);
+            unsafe { asm!("mrs {}, s3_3_c2_c4_0", "cset {}, ne", out(reg) val, out(reg) ok, options(nomem, nostack)); }
+            if ok != 0 { return Some(val); }
+        }
+        return None;
+    }
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod hw {
+    pub fn available() -> bool { false }
+    pub fn next() -> Option<u64> { None }
+}
+
+// Fallback only, NOT cryptographically secure.
+// xorshift64* seeded from the counter on first use, the timer is mixed in again per draw.
+static STATE: AtomicU64 = AtomicU64::new(0);
+
+fn xorshift(mut x: u64) -> u64 {
+    x ^= x >> 12;
+    x ^= x << 25;
+    x ^= x >> 27;
+    return x;
+}
+
+fn soft_next() -> u64 {
+    let mut old = STATE.load(AtomOrd::Relaxed);
+    loop {
+        let seed = if old == 0 { intc::counter() | 1 } else { old };
+        let new = xorshift(seed);
+        match STATE.compare_exchange_weak(old, new, AtomOrd::Relaxed, AtomOrd::Relaxed) {
+            Ok(_) => return new.wrapping_mul(0x2545_f491_4f6c_dd1d) ^ intc::counter(),
+            Err(cur) => old = cur
+        }
+    }
+}
+
+pub fn is_hardware() -> bool {
+    return hw::available();
+}
+
+pub fn next_u64() -> u64 {
+    return hw::next().unwrap_or_else(soft_next);
+}
+
+pub fn fill(buf: &mut [u8]) {
+    fill_with(buf, next_u64);
+}
+
+fn fill_with(buf: &mut [u8], mut next: impl FnMut() -> u64) {
+    for chunk in buf.chunks_mut(8) {
+        let bytes = next().to_ne_bytes();
+        chunk.copy_from_slice(&bytes[..chunk.len()]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{collections::BTreeSet, vec};
+
+    #[test]
+    fn xorshift_is_deterministic() {
+        let walk = |seed: u64| (0..4096).scan(seed, |x, _| { *x = xorshift(*x); Some(*x) });
+        assert!(walk(0x2545f4914f6cdd1d).eq(walk(0x2545f4914f6cdd1d)));
+        assert!(!walk(1).eq(walk(2)));
+
+        // A permutation of the non-zero words, so no short cycles and never stuck at zero
+        let seen: BTreeSet<u64> = walk(1).collect();
+        assert_eq!(seen.len(), 4096);
+        assert!(!seen.contains(&0) && !seen.contains(&1));
+        assert_eq!(xorshift(0), 0);
+    }
+
+    #[test]
+    fn fill_covers_exactly_the_buffer() {
+        for len in [0, 1, 7, 8, 9, 17, 64] {
+            let mut buf = vec![0xaau8; len + 8];
+            let mut calls = 0u64;
+            fill_with(&mut buf[..len], || { calls += 1; 0x0101_0101_0101_0101 * calls });
+
+            assert_eq!(calls as usize, len.div_ceil(8));
+            for (i, byte) in buf[..len].iter().enumerate() {
+                assert_eq!(*byte as usize, i / 8 + 1);
+            }
+            assert!(buf[len..].iter().all(|&b| b == 0xaa));
+        }
+    }
+}
@@ -1,10 +1,17 @@
 use crate::{
     arch::rvm::flags,
-    device::{PciDevice, PCI_DEVICES},
+    device::{
+        edid::{self, Mode},
+        font::{FONT_8X16, FONT_FIRST, FONT_HEIGHT, FONT_LAST, FONT_WIDTH},
+        PciDevice, PCI_DEVICES
+    },
+    kargs::{FrameBuffer, SYSINFO, SysInfo},
     printk, printlnk,
-    ram::{glacier::GLACIER, PAGE_4KIB}
+    ram::{glacier::GLACIER, PhysPageBuf, PAGE_4KIB}
 };
 
+use core::{fmt, sync::atomic::{AtomicBool, Ordering}};
+use alloc::vec::Vec;
 use spin::Mutex;
 
 #[repr(C, packed)]
@@ -29,20 +36,47 @@ impl Colour {
     pub const MAGENTA: Self = Self::new(0xff, 0x00, 0xff);
 }
 
+// Channel order of a 32-bit pixel, most significant byte first
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PixelFormat {
+    Argb, // GOP BGR, B G R X in memory
+    Abgr, // GOP RGB, R G B X in memory
+    Rgba,
+    Bgra
+}
+
+impl Colour {
+    pub fn to_pixel(self, format: PixelFormat) -> u32 {
+        let (a, r, g, b) = (self.alpha, self.red, self.green, self.blue);
+        let bytes = match format {
+            PixelFormat::Argb => [a, r, g, b],
+            PixelFormat::Abgr => [a, b, g, r],
+            PixelFormat::Rgba => [r, g, b, a],
+            PixelFormat::Bgra => [b, g, r, a]
+        };
+        return u32::from_be_bytes(bytes);
+    }
+
+    pub fn from_pixel(pixel: u32, format: PixelFormat) -> Self {
+        let [p0, p1, p2, p3] = pixel.to_be_bytes();
+        return match format {
+            PixelFormat::Argb => Self::rgba(p1, p2, p3, p0),
+            PixelFormat::Abgr => Self::rgba(p3, p2, p1, p0),
+            PixelFormat::Rgba => Self::rgba(p0, p1, p2, p3),
+            PixelFormat::Bgra => Self::rgba(p2, p1, p0, p3)
+        };
+    }
+}
+
 impl From<u32> for Colour {
     fn from(value: u32) -> Self {
-        Self {
-            alpha: ((value >> 24) & 0xff) as u8,
-            red: ((value >> 16) & 0xff) as u8,
-            green: ((value >> 8) & 0xff) as u8,
-            blue: (value & 0xff) as u8
-        }
+        return Self::from_pixel(value, PixelFormat::Argb);
     }
 }
 
 impl From<Colour> for u32 {
     fn from(colour: Colour) -> Self {
-        u32::from_be_bytes(unsafe { core::mem::transmute(colour) })
+        return colour.to_pixel(PixelFormat::Argb);
     }
 }
 
@@ -51,90 +85,227 @@ pub struct Vga {
     edid: *mut u8,
     width: u32,
     height: u32,
-    pitch: u32
+    pitch: u32,
+    format: PixelFormat,
+    modes: Vec<Mode>, // From EDID, the preferred one first
+    back: Option<PhysPageBuf> // Drawing goes here when present
 }
 
 impl Vga {
-    const EDID_HEADER: [u8; 8] = [0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00];
-
     pub fn new(dev: &PciDevice) -> Option<Self> {
         if !dev.is_vga() { return None; }
 
-        let mut fb_addr = dev.bar(0).unwrap() as usize;
-        if (fb_addr & 0x6) == 0x4 {
-            fb_addr |= (dev.bar(1).unwrap() as usize) << 32;
-        }
-        fb_addr &= !0xf; // 16 byte alignment
-
-        let mut edid_addr = dev.bar(2).unwrap() as usize;
-        if (edid_addr & 0x6) == 0x4 {
-            edid_addr |= (dev.bar(3).unwrap() as usize) << 32;
-        }
-        edid_addr &= !0xf; // 16 byte alignment
+        let fb_addr = dev.bar_addr(0)?;
+        let edid_addr = dev.bar_addr(2)?;
 
-        GLACIER.write().map_range(edid_addr, edid_addr, PAGE_4KIB, flags::D_RW);
+        GLACIER.write().map_range(edid_addr, edid_addr, PAGE_4KIB, flags::D_RW).ok()?;
         let edid_regs = unsafe {
             core::slice::from_raw_parts(edid_addr as *mut u8, PAGE_4KIB)
         };
 
-        if &edid_regs[0..8] != Self::EDID_HEADER { return None; }
-
-        let timing_desc = &edid_regs[54..72];
-        let width = timing_desc[2] as u32 | ((timing_desc[4] as u32 & 0xf0) << 4);
-        let height = timing_desc[5] as u32 | ((timing_desc[7] as u32 & 0xf0) << 4);
-        let width_blanking = timing_desc[3] as u32 | ((timing_desc[4] as u32 & 0x0f) << 8);
-        let height_blanking = timing_desc[6] as u32 | ((timing_desc[7] as u32 & 0x0f) << 8);
+        let modes = edid::modes(edid_regs).ok()?;
+        let preferred = modes.first()?;
+        let (width, height) = (preferred.width, preferred.height);
         let pitch = width * 4;
 
         let map_size = height as usize * pitch as usize;
-        GLACIER.write().map_range(fb_addr, fb_addr, map_size, flags::D_RW);
+        GLACIER.write().map_range(fb_addr, fb_addr, map_size, flags::D_RW).ok()?;
         return Some(Vga {
             framebuffer: fb_addr as *mut u32,
             edid: edid_addr as *mut u8,
-            width, height, pitch
+            width, height, pitch,
+            format: PixelFormat::Argb,
+            modes,
+            back: None
         });
     }
 
+    // Framebuffer left by the firmware, without EDID
+    pub fn from_gop(sys: &SysInfo) -> Option<Self> {
+        let fb = sys.fb;
+        let vga = Self::over_gop(fb)?;
+        let map_size = fb.height as usize * fb.pitch as usize;
+        GLACIER.write().map_range(fb.ptr, fb.ptr, map_size, flags::D_RW).ok()?;
+        return Some(vga);
+    }
+
+    fn over_gop(fb: FrameBuffer) -> Option<Self> {
+        if fb.ptr == 0 { return None; }
+        let format = match fb.format {
+            FrameBuffer::FORMAT_RGB => PixelFormat::Abgr,
+            FrameBuffer::FORMAT_BGR => PixelFormat::Argb,
+            _ => return None
+        };
+
+        return Some(Vga {
+            framebuffer: fb.ptr as *mut u32,
+            edid: core::ptr::null_mut(),
+            width: fb.width,
+            height: fb.height,
+            pitch: fb.pitch,
+            format,
+            modes: Vec::new(),
+            back: None
+        });
+    }
+
+    pub fn enable_back_buffer(&mut self) -> bool {
+        if self.back.is_some() { return true; }
+
+        let size = self.height as usize * self.pitch as usize;
+        let Some(back) = PhysPageBuf::new(size) else { return false; };
+        unsafe {
+            core::ptr::copy_nonoverlapping(self.framebuffer as *const u8, back.ptr::<u8>(), size);
+        }
+        self.back = Some(back);
+        return true;
+    }
+
+    // Back to drawing straight into the framebuffer
+    pub fn disable_back_buffer(&mut self) {
+        self.present();
+        self.back = None;
+    }
+
+    pub fn present(&self) {
+        self.present_rows(0, self.height);
+    }
+
+    // Only scanlines [y, y + height), clipped to the screen
+    pub fn present_rows(&self, y: u32, height: u32) {
+        let Some(back) = &self.back else { return; };
+        let y_end = y.saturating_add(height).min(self.height);
+        if y >= y_end { return; }
+
+        let start = y as usize * self.pitch as usize;
+        let size = (y_end - y) as usize * self.pitch as usize;
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                back.ptr::<u8>().add(start),
+                (self.framebuffer as *mut u8).add(start),
+                size
+            );
+        }
+    }
+
+    // Where drawing currently lands
+    pub fn buffer(&self) -> *mut u32 {
+        match &self.back {
+            Some(back) => back.ptr(),
+            None => self.framebuffer
+        }
+    }
+
     pub fn framebuffer(&self) -> *mut u32 { self.framebuffer }
     pub fn edid(&self) -> *mut u8 { self.edid }
     pub fn width(&self) -> u32 { self.width }
     pub fn height(&self) -> u32 { self.height }
     pub fn pitch(&self) -> u32 { self.pitch }
+    pub fn format(&self) -> PixelFormat { self.format }
+
+    // Empty for the GOP framebuffer, which comes without EDID
+    pub fn available_modes(&self) -> Vec<Mode> {
+        return self.modes.clone();
+    }
+
+    // Rows are pitch bytes apart, which may be more than width pixels
+    fn pixel_offset(&self, x: u32, y: u32) -> usize {
+        return y as usize * (self.pitch() / 4) as usize + x as usize;
+    }
 
     pub fn set_pixel(&self, x: u32, y: u32, colour: Colour) {
         if x >= self.width() || y >= self.height() { return; }
 
         unsafe {
-            let offset = (y * self.width() + x) as usize;
-            let addr = self.framebuffer().add(offset);
-            *addr = colour.into();
+            let offset = self.pixel_offset(x, y);
+            let addr = self.buffer().add(offset);
+            addr.write_volatile(colour.to_pixel(self.format));
         }
     }
 
     pub fn get_pixel(&self, x: u32, y: u32) -> Colour {
         if x >= self.width() || y >= self.height() { return Colour::BLACK; }
 
-        let offset = (y * self.width() + x) as usize;
-        let addr = unsafe { self.framebuffer.add(offset) };
-        return unsafe { (*addr).into() };
+        let offset = self.pixel_offset(x, y);
+        let addr = unsafe { self.buffer().add(offset) };
+        return Colour::from_pixel(unsafe { addr.read_volatile() }, self.format);
     }
 
     pub fn fill_screen(&self, colour: Colour) {
-        for y in 0..self.height() {
-            for x in 0..self.width() {
-                self.set_pixel(x, y, colour);
+        self.fill_rect_fast(0, 0, self.width(), self.height(), colour);
+    }
+
+    // Clipped to the screen, one scanline at a time
+    pub fn draw_rect(&self, x: u32, y: u32, width: u32, height: u32, colour: Colour) {
+        let x_end = x.saturating_add(width).min(self.width());
+        let y_end = y.saturating_add(height).min(self.height());
+        if x >= x_end { return; }
+
+        let pixel = colour.to_pixel(self.format);
+        for row in y..y_end {
+            let line = unsafe { self.buffer().add(self.pixel_offset(x, row)) };
+            for i in 0..(x_end - x) as usize {
+                unsafe { line.add(i).write_volatile(pixel); }
             }
         }
     }
 
-    pub fn draw_rect(&self, x: u32, y: u32, width: u32, height: u32, colour: Colour) {
-        for dy in 0..height {
-            for dx in 0..width {
-                self.set_pixel(x + dx, y + dy, colour);
+    // Same clipping as draw_rect, but whole rows at a time rather than volatile pixel writes
+    pub fn fill_rect_fast(&self, x: u32, y: u32, width: u32, height: u32, colour: Colour) {
+        let x_end = x.saturating_add(width).min(self.width());
+        let y_end = y.saturating_add(height).min(self.height());
+        if x >= x_end { return; }
+
+        let pixel = colour.to_pixel(self.format);
+        let bytes = pixel.to_ne_bytes();
+        let uniform = bytes.iter().all(|&b| b == bytes[0]); // Black and white, mostly
+        let len = (x_end - x) as usize;
+
+        for row in y..y_end {
+            unsafe {
+                let line = self.buffer().add(self.pixel_offset(x, row));
+                if uniform {
+                    core::ptr::write_bytes(line, bytes[0], len);
+                } else {
+                    core::slice::from_raw_parts_mut(line, len).fill(pixel);
+                }
             }
         }
     }
 
+    // Moves a block from (sx, sy) to (dx, dy), clipped to the screen. Overlap is fine:
+    // rows are copied bottom-up when moving down, and each row is a memmove.
+    pub fn copy_rect(&self, sx: u32, sy: u32, dx: u32, dy: u32, width: u32, height: u32) {
+        let width = width
+            .min(self.width().saturating_sub(sx))
+            .min(self.width().saturating_sub(dx));
+        let height = height
+            .min(self.height().saturating_sub(sy))
+            .min(self.height().saturating_sub(dy));
+        if width == 0 || height == 0 { return; }
+
+        // Full rows of a packed buffer are one contiguous span
+        if sx == 0 && dx == 0 && width == self.width() && self.pitch() == width * 4 {
+            unsafe {
+                let src = self.buffer().add(self.pixel_offset(0, sy));
+                let dst = self.buffer().add(self.pixel_offset(0, dy));
+                core::ptr::copy(src, dst, width as usize * height as usize);
+            }
+            return;
+        }
+
+        let copy_row = |i: u32| unsafe {
+            let src = self.buffer().add(self.pixel_offset(sx, sy + i));
+            let dst = self.buffer().add(self.pixel_offset(dx, dy + i));
+            core::ptr::copy(src, dst, width as usize);
+        };
+        if dy > sy {
+            (0..height).rev().for_each(copy_row);
+        } else {
+            (0..height).for_each(copy_row);
+        }
+    }
+
     pub fn draw_line(&self, x0: u32, y0: u32, x1: u32, y1: u32, colour: Colour) {
         // Bresenham's line algorithm
         let dx = (x1 as i32 - x0 as i32).abs();
@@ -161,11 +332,16 @@ impl Vga {
     }
 
     pub fn edid_regs(&self) -> &[u8] {
+        if self.edid.is_null() { return &[]; }
         unsafe { core::slice::from_raw_parts(self.edid(), 0x1000) }
     }
 
     pub fn print_edid_info(&self) {
         let edid = self.edid_regs();
+        if edid.is_empty() {
+            printlnk!("No EDID, resolution: {}x{}", self.width(), self.height());
+            return;
+        }
 
         printlnk!("=== EDID Info ===");
 
@@ -183,6 +359,10 @@ impl Vga {
 
         printlnk!("EDID Version: {}.{}", edid[18], edid[19]);
         printlnk!("Resolution: {}x{}", self.width(), self.height());
+        for (i, mode) in self.modes.iter().enumerate() {
+            let preferred = if i == 0 { " (preferred)" } else { "" };
+            printlnk!("Mode {}: {}{}", i, mode, preferred);
+        }
 
         printlnk!("RAW EDID:");
         for (i, line) in edid[0..0x80].chunks(16).enumerate() {
@@ -203,31 +383,157 @@ impl Vga {
         for (i, &color) in colors.iter().enumerate() {
             let x_start = i as u32 * bar_width;
             let x_end = if i == colors.len() - 1 { self.width } else { (i + 1) as u32 * bar_width };
+            self.draw_rect(x_start, 0, x_end - x_start, self.height(), color);
+        }
+    }
+}
+
+pub struct Cursor {
+    pub col: u32,
+    pub row: u32,
+    pub fg: Colour,
+    pub bg: Colour,
+    dirty: Option<(u32, u32)> // Text rows drawn since the last flush, end exclusive
+}
 
-            for x in x_start..x_end {
-                for y in 0..self.height() {
-                    self.set_pixel(x, y, color);
+impl Cursor {
+    pub const fn new() -> Self {
+        Self { col: 0, row: 0, fg: Colour::WHITE, bg: Colour::BLACK, dirty: None }
+    }
+
+    fn mark(&mut self, lo: u32, hi: u32) {
+        self.dirty = Some(match self.dirty {
+            Some((l, h)) => (l.min(lo), h.max(hi)),
+            None => (lo, hi)
+        });
+    }
+}
+
+// Text console drawn with the 8x16 font
+pub struct Console<'a> {
+    vga: &'a Vga,
+    cur: &'a mut Cursor
+}
+
+impl<'a> Console<'a> {
+    pub fn new(vga: &'a Vga, cur: &'a mut Cursor) -> Self {
+        Self { vga, cur }
+    }
+
+    pub fn cols(&self) -> u32 { self.vga.width() / FONT_WIDTH }
+    pub fn rows(&self) -> u32 { self.vga.height() / FONT_HEIGHT }
+
+    fn draw_glyph(&mut self, c: u8) {
+        let c = if (FONT_FIRST..=FONT_LAST).contains(&c) { c } else { b'?' };
+        let glyph = &FONT_8X16[(c - FONT_FIRST) as usize];
+        let (x0, y0) = (self.cur.col * FONT_WIDTH, self.cur.row * FONT_HEIGHT);
+
+        for (dy, bits) in glyph.iter().enumerate() {
+            for dx in 0..FONT_WIDTH {
+                let on = bits & (0x80 >> dx) != 0;
+                let colour = if on { self.cur.fg } else { self.cur.bg };
+                self.vga.set_pixel(x0 + dx, y0 + dy as u32, colour);
+            }
+        }
+        self.cur.mark(self.cur.row, self.cur.row + 1);
+    }
+
+    fn scroll(&mut self) {
+        let text_height = self.rows() * FONT_HEIGHT;
+        self.vga.copy_rect(0, FONT_HEIGHT, 0, 0, self.vga.width(), text_height - FONT_HEIGHT);
+
+        let y = (self.rows() - 1) * FONT_HEIGHT;
+        self.vga.fill_rect_fast(0, y, self.vga.width(), FONT_HEIGHT, self.cur.bg);
+        self.cur.mark(0, self.rows());
+    }
+
+    // Copies the rows drawn since the last flush to the screen
+    pub fn flush(&mut self) {
+        if let Some((lo, hi)) = self.cur.dirty.take() {
+            self.vga.present_rows(lo * FONT_HEIGHT, (hi - lo) * FONT_HEIGHT);
+        }
+    }
+
+    fn newline(&mut self) {
+        self.cur.col = 0;
+        self.cur.row += 1;
+        if self.cur.row >= self.rows() {
+            self.scroll();
+            self.cur.row = self.rows() - 1;
+        }
+    }
+
+    pub fn putc(&mut self, c: u8) {
+        if self.cols() == 0 || self.rows() == 0 { return; }
+
+        match c {
+            b'\n' => self.newline(),
+            b'\r' => self.cur.col = 0,
+            0x08 => self.cur.col = self.cur.col.saturating_sub(1),
+            _ => {
+                self.draw_glyph(c);
+                self.cur.col += 1;
+                if self.cur.col >= self.cols() {
+                    self.newline();
                 }
             }
         }
     }
 }
 
+impl fmt::Write for Console<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            self.putc(if c.is_ascii() { c as u8 } else { b'?' });
+        }
+        Ok(())
+    }
+}
+
 unsafe impl Send for Vga {}
 unsafe impl Sync for Vga {}
 
 pub static VGA_DEVICE: Mutex<Option<Vga>> = Mutex::new(None);
+static CURSOR: Mutex<Cursor> = Mutex::new(Cursor::new());
+static MIRROR: AtomicBool = AtomicBool::new(false);
+
+pub fn set_mirror(enable: bool) {
+    MIRROR.store(enable, Ordering::Relaxed);
+}
+
+// Called by printk, must never block
+pub fn mirror(s: &str) {
+    if !MIRROR.load(Ordering::Relaxed) { return; }
+
+    let Some(vga) = VGA_DEVICE.try_lock() else { return; };
+    let Some(mut cur) = CURSOR.try_lock() else { return; };
+    if let Some(ref vga) = *vga {
+        let mut console = Console::new(vga, &mut cur);
+        let _ = fmt::Write::write_str(&mut console, s);
+        console.flush();
+    }
+}
+
+fn install(mut vga: Vga) {
+    vga.enable_back_buffer();
+    vga.fill_screen(Colour::WHITE);
+    vga.test_pattern();
+    vga.present();
+    *VGA_DEVICE.lock() = Some(vga);
+}
 
+// The GOP framebuffer first, a PCI display with EDID otherwise
 pub fn init_vga() {
+    let gop = Vga::from_gop(&SYSINFO.read());
+    if let Some(vga) = gop {
+        install(vga);
+        return;
+    }
+
     for dev in PCI_DEVICES.read().iter() {
         if dev.is_vga() {
-            let vga = match Vga::new(dev) {
-                Some(vga) => vga,
-                None => { continue; }
-            };
-            vga.fill_screen(Colour::WHITE);
-            vga.test_pattern();
-            *VGA_DEVICE.lock() = Some(vga);
+            let Some(vga) = Vga::new(dev) else { continue; };
+            install(vga);
         }
     }
 }
@@ -256,3 +562,226 @@ pub fn draw_rect(x: u32, y: u32, width: u32, height: u32, colour: Colour) {
         vga.draw_rect(x, y, width, height, colour)
     }
 }
+
+pub fn present() {
+    if let Some(ref vga) = *VGA_DEVICE.lock() {
+        vga.present();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use core::fmt::Write;
+
+    // A Vga over host memory, with rows padded past the visible width
+    fn host_vga(fb: &mut Vec<u32>, width: u32, height: u32) -> Vga {
+        let pitch = (width + 4) * 4;
+        *fb = vec![0; (pitch / 4 * height) as usize];
+        return Vga {
+            framebuffer: fb.as_mut_ptr(),
+            edid: core::ptr::null_mut(),
+            width, height, pitch,
+            format: PixelFormat::Argb,
+            modes: Vec::new(),
+            back: None
+        };
+    }
+
+    // Every pixel of the text cell matches the glyph in white on black
+    fn cell_shows(vga: &Vga, col: u32, row: u32, c: u8) -> bool {
+        let glyph = &FONT_8X16[(c - FONT_FIRST) as usize];
+        return glyph.iter().enumerate().all(|(dy, bits)| (0..FONT_WIDTH).all(|dx| {
+            let on = bits & (0x80 >> dx) != 0;
+            let want = if on { Colour::WHITE } else { Colour::BLACK };
+            let got = vga.get_pixel(col * FONT_WIDTH + dx, row * FONT_HEIGHT + dy as u32);
+            u32::from(got) == u32::from(want)
+        }));
+    }
+
+    #[test]
+    fn cursor_advances_and_wraps() {
+        let mut fb = Vec::new();
+        let vga = host_vga(&mut fb, 8 * FONT_WIDTH + 5, 3 * FONT_HEIGHT);
+        let mut cur = Cursor::new();
+        let mut con = Console::new(&vga, &mut cur);
+        assert_eq!((con.cols(), con.rows()), (8, 3));
+
+        write!(con, "hello").unwrap();
+        assert_eq!((con.cur.col, con.cur.row), (5, 0));
+        write!(con, "abc").unwrap(); // Exactly fills the row
+        assert_eq!((con.cur.col, con.cur.row), (0, 1));
+        write!(con, "xy\rz\n").unwrap();
+        assert_eq!((con.cur.col, con.cur.row), (0, 2));
+        assert!(cell_shows(&vga, 0, 0, b'h') && cell_shows(&vga, 7, 0, b'c'));
+        assert!(cell_shows(&vga, 0, 1, b'z') && cell_shows(&vga, 1, 1, b'y'));
+    }
+
+    #[test]
+    fn last_row_scrolls() {
+        let mut fb = Vec::new();
+        let vga = host_vga(&mut fb, 8 * FONT_WIDTH, 3 * FONT_HEIGHT);
+        let mut cur = Cursor::new();
+        let mut con = Console::new(&vga, &mut cur);
+
+        write!(con, "a\nb\nc\nd").unwrap();
+        assert_eq!((con.cur.col, con.cur.row), (1, 2));
+        assert!(cell_shows(&vga, 0, 0, b'b'));
+        assert!(cell_shows(&vga, 0, 1, b'c'));
+        assert!(cell_shows(&vga, 0, 2, b'd'));
+        assert!(cell_shows(&vga, 1, 2, b' '));
+    }
+
+    fn front(vga: &Vga, x: u32, y: u32) -> u32 {
+        return unsafe { vga.framebuffer().add(vga.pixel_offset(x, y)).read_volatile() };
+    }
+
+    #[test]
+    fn back_buffer_shows_on_present() {
+        let mut fb = Vec::new();
+        let mut vga = host_vga(&mut fb, 16, 8);
+        let red = Colour::RED.to_pixel(vga.format());
+        assert!(vga.enable_back_buffer());
+
+        vga.draw_rect(2, 1, 3, 2, Colour::RED);
+        assert_eq!(u32::from(vga.get_pixel(2, 1)), red);
+        assert_eq!(front(&vga, 2, 1), 0);
+
+        vga.present();
+        for y in 0..8 {
+            for x in 0..16 {
+                let inside = (2..5).contains(&x) && (1..3).contains(&y);
+                assert_eq!(front(&vga, x, y), if inside { red } else { 0 });
+            }
+        }
+
+        // Only the rows asked for reach the screen
+        vga.draw_rect(0, 6, 16, 2, Colour::RED);
+        vga.present_rows(7, 1);
+        assert_eq!((front(&vga, 0, 6), front(&vga, 0, 7)), (0, red));
+
+        vga.disable_back_buffer();
+        assert_eq!(u32::from(vga.get_pixel(0, 6)), red);
+    }
+
+    #[test]
+    fn gop_pitch_wider_than_the_row() {
+        let (width, height, pitch) = (10, 4, 64); // 16 pixels per scanline
+        let mut fb = vec![0u32; (pitch / 4 * height) as usize];
+        let gop = FrameBuffer { ptr: fb.as_mut_ptr() as usize, width, height, pitch, format: FrameBuffer::FORMAT_BGR };
+
+        let vga = Vga::over_gop(gop).unwrap();
+        assert_eq!(vga.format(), PixelFormat::Argb);
+        vga.set_pixel(3, 2, Colour::RED);
+        vga.set_pixel(10, 0, Colour::RED); // Padding, not a pixel
+        drop(vga);
+        let red = Colour::RED.to_pixel(PixelFormat::Argb);
+        assert_eq!(fb[2 * 16 + 3], red);
+        assert_eq!(fb.iter().filter(|&&p| p == red).count(), 1);
+
+        let rgb = Vga::over_gop(FrameBuffer { format: FrameBuffer::FORMAT_RGB, ..gop }).unwrap();
+        assert_eq!(rgb.format(), PixelFormat::Abgr);
+        assert!(Vga::over_gop(FrameBuffer { format: 2, ..gop }).is_none());
+        assert!(Vga::over_gop(FrameBuffer { ptr: 0, ..gop }).is_none());
+    }
+
+    #[test]
+    fn pixels_land_on_their_scanline() {
+        let mut fb = Vec::new();
+        let vga = host_vga(&mut fb, 6, 3);
+        let stride = (vga.pitch() / 4) as usize; // 10 pixels, 4 of them padding
+        let white = Colour::WHITE.to_pixel(vga.format());
+
+        vga.set_pixel(5, 0, Colour::WHITE);
+        vga.set_pixel(0, 1, Colour::WHITE);
+        vga.set_pixel(6, 1, Colour::WHITE); // Past the width, dropped
+        assert_eq!(fb[5], white);
+        assert_eq!(fb[stride], white);
+        assert_eq!(fb[6..stride], [0; 4]);
+        assert_eq!(fb.iter().filter(|&&p| p == white).count(), 2);
+        assert_eq!(u32::from(vga.get_pixel(0, 1)), white);
+    }
+
+    #[test]
+    fn rects_stay_inside_their_rows() {
+        let mut fb = Vec::new();
+        let vga = host_vga(&mut fb, 6, 4);
+        let stride = (vga.pitch() / 4) as usize;
+        let red = Colour::RED.to_pixel(vga.format());
+
+        // Wider than the screen, so it is clipped at the last visible column
+        vga.draw_rect(2, 1, 10, 2, Colour::RED);
+        for (i, &p) in fb.iter().enumerate() {
+            let (x, y) = (i % stride, i / stride);
+            let inside = (2..6).contains(&x) && (1..3).contains(&y);
+            assert_eq!(p, if inside { red } else { 0 }, "({}, {})", x, y);
+        }
+
+        vga.fill_screen(Colour::RED);
+        assert!(fb.chunks(stride).all(|row| row[..6] == [red; 6] && row[6..] == [0; 4]));
+    }
+
+    #[test]
+    fn colours_round_trip_every_format() {
+        let colour = Colour::rgba(0x12, 0x34, 0x56, 0x78);
+        let cases = [
+            (PixelFormat::Argb, 0x7812_3456),
+            (PixelFormat::Abgr, 0x7856_3412),
+            (PixelFormat::Rgba, 0x1234_5678),
+            (PixelFormat::Bgra, 0x5634_1278)
+        ];
+        for (format, pixel) in cases {
+            assert_eq!(colour.to_pixel(format), pixel, "{:?}", format);
+            assert_eq!(Colour::from_pixel(pixel, format).to_pixel(format), pixel, "{:?}", format);
+            assert_eq!(Colour::from_pixel(pixel, format).to_pixel(PixelFormat::Argb), 0x7812_3456);
+        }
+        assert_eq!(u32::from(Colour::from(0x7812_3456)), 0x7812_3456);
+    }
+
+    // Pixel value y * 100 + x, which Argb stores unchanged
+    fn paint_pattern(vga: &Vga) {
+        for y in 0..vga.height() {
+            for x in 0..vga.width() {
+                vga.set_pixel(x, y, Colour::from(y * 100 + x));
+            }
+        }
+    }
+
+    fn pixel(vga: &Vga, x: u32, y: u32) -> u32 { u32::from(vga.get_pixel(x, y)) }
+
+    #[test]
+    fn copy_rect_moves_rows() {
+        let mut fb = Vec::new();
+        let mut vga = host_vga(&mut fb, 12, 10);
+        assert!(vga.enable_back_buffer());
+        paint_pattern(&vga);
+
+        // Down by three over itself, rows go bottom-up so none is read after it is overwritten
+        vga.copy_rect(2, 1, 2, 4, 5, 5);
+        for y in 0..10 {
+            for x in 0..12 {
+                let moved = (2..7).contains(&x) && (4..9).contains(&y);
+                let want = if moved { (y - 3) * 100 + x } else { y * 100 + x };
+                assert_eq!(pixel(&vga, x, y), want, "({}, {})", x, y);
+            }
+        }
+        assert!(fb.iter().all(|&p| p == 0)); // Not on screen before present
+
+        // Up and to the left, clipped to what fits
+        paint_pattern(&vga);
+        vga.copy_rect(4, 5, 1, 0, 100, 100);
+        assert_eq!(pixel(&vga, 1, 0), 504);
+        assert_eq!(pixel(&vga, 8, 4), 911);
+        assert_eq!(pixel(&vga, 9, 4), 409);
+        assert_eq!(pixel(&vga, 1, 5), 501);
+
+        // Whole packed rows take the single span path
+        let mut packed = Vec::new();
+        let vga = Vga { pitch: 8 * 4, ..host_vga(&mut packed, 8, 6) };
+        paint_pattern(&vga);
+        vga.copy_rect(0, 2, 0, 0, 8, 4);
+        assert!((0..8).all(|x| (0..4).all(|y| pixel(&vga, x, y) == (y + 2) * 100 + x)));
+        assert_eq!(pixel(&vga, 7, 5), 507);
+    }
+}
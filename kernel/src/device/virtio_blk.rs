@@ -0,0 +1,348 @@
+use crate::{
+    arch::rvm::flags,
+    device::{
+        PciDevice,
+        block::{BLOCK_DEVICES, BlockDevType, BlockDevice, DevId}
+    },
+    ram::{PhysPageBuf, glacier::GLACIER},
+    warn
+};
+
+use alloc::{string::String, sync::Arc};
+use core::{
+    hint::spin_loop,
+    sync::atomic::{Ordering as AtomOrd, fence}
+};
+use spin::Mutex;
+
+// virtio-pci capability types
+const CAP_VENDOR: u8 = 0x09;
+const CFG_COMMON: u8 = 1;
+const CFG_NOTIFY: u8 = 2;
+const CFG_DEVICE: u8 = 4;
+
+// Common config offsets
+const COMMON_DFSELECT: usize = 0x00;
+const COMMON_DF: usize       = 0x04;
+const COMMON_GFSELECT: usize = 0x08;
+const COMMON_GF: usize       = 0x0c;
+const COMMON_STATUS: usize   = 0x14;
+const COMMON_CFGGEN: usize   = 0x15;
+const COMMON_Q_SELECT: usize = 0x16;
+const COMMON_Q_SIZE: usize   = 0x18;
+const COMMON_Q_ENABLE: usize = 0x1c;
+const COMMON_Q_NOFF: usize   = 0x1e;
+const COMMON_Q_DESC: usize   = 0x20;
+const COMMON_Q_AVAIL: usize  = 0x28;
+const COMMON_Q_USED: usize   = 0x30;
+
+const STATUS_ACK: u8         = 1;
+const STATUS_DRIVER: u8      = 2;
+const STATUS_DRIVER_OK: u8   = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8      = 128;
+
+const F_VERSION_1: u32 = 1 << 0; // Bit 32, in the high feature word
+
+const DESC_NEXT: u16  = 1;
+const DESC_WRITE: u16 = 2;
+
+const REQ_IN: u32  = 0;
+const REQ_OUT: u32 = 1;
+
+const SECTOR: usize = 512;
+const QUEUE_SIZE: u16 = 16;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Desc {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16
+}
+
+#[repr(C)]
+struct ReqHeader {
+    ty: u32,
+    _reserved: u32,
+    sector: u64
+}
+
+struct Mmio(usize);
+
+impl Mmio {
+    fn read<T>(&self, off: usize) -> T {
+        unsafe { return ((self.0 + off) as *const T).read_volatile(); }
+    }
+
+    fn write<T>(&self, off: usize, val: T) {
+        unsafe { ((self.0 + off) as *mut T).write_volatile(val); }
+    }
+
+    // 64-bit fields as two 32-bit accesses, low half first. Not every transport
+    // takes a single 64-bit access.
+    fn read_u64(&self, off: usize) -> u64 {
+        let lo = self.read::<u32>(off) as u64;
+        let hi = self.read::<u32>(off + 4) as u64;
+        return hi << 32 | lo;
+    }
+
+    fn write_u64(&self, off: usize, val: u64) {
+        self.write::<u32>(off, val as u32);
+        self.write::<u32>(off + 4, (val >> 32) as u32);
+    }
+}
+
+// Slot of a free running ring index. Split queue sizes are powers of two,
+// so the slots stay in sequence across the u16 wrap.
+fn ring_slot(idx: u16, size: u16) -> usize {
+    return (idx % size) as usize;
+}
+
+// Completions posted by the device since `seen`, across the wrap too
+fn used_pending(dev_idx: u16, seen: u16) -> u16 {
+    return dev_idx.wrapping_sub(seen);
+}
+
+// Split virtqueue, requests are issued one at a time
+struct VirtQueue {
+    size: u16,
+    desc: PhysPageBuf,
+    avail: PhysPageBuf,
+    used: PhysPageBuf,
+    req: PhysPageBuf, // Header at 0, status byte at 16
+    avail_idx: u16,
+    used_idx: u16,
+    notify: usize
+}
+
+impl VirtQueue {
+    fn push(&mut self, chain: &[Desc]) {
+        let desc = self.desc.ptr::<Desc>();
+        for (i, d) in chain.iter().enumerate() {
+            unsafe { desc.add(i).write_volatile(*d); }
+        }
+
+        // avail: flags u16, idx u16, ring [u16; size]
+        let avail = self.avail.ptr::<u16>();
+        unsafe { avail.add(2 + ring_slot(self.avail_idx, self.size)).write_volatile(0); }
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        fence(AtomOrd::SeqCst);
+        unsafe { avail.add(1).write_volatile(self.avail_idx); }
+        fence(AtomOrd::SeqCst);
+
+        unsafe { (self.notify as *mut u16).write_volatile(0); }
+    }
+
+    fn wait(&mut self) {
+        // used: flags u16, idx u16, ring [(id u32, len u32); size]
+        let used = self.used.ptr::<u16>();
+        while used_pending(unsafe { used.add(1).read_volatile() }, self.used_idx) == 0 {
+            spin_loop();
+        }
+        fence(AtomOrd::SeqCst);
+        self.used_idx = self.used_idx.wrapping_add(1);
+    }
+}
+
+pub struct BlockDeviceVirtio {
+    queue: Mutex<VirtQueue>,
+    capacity: u64,
+    devid: u16
+}
+
+impl BlockDeviceVirtio {
+    fn transfer(&self, buf: &mut [u8], lba: u64, write: bool) -> Result<(), String> {
+        let len = buf.len().next_multiple_of(SECTOR);
+        let mut data = PhysPageBuf::new_zeroed(len).ok_or("Failed to allocate DMA buffer")?;
+        if write { data[..buf.len()].copy_from_slice(buf); }
+
+        let mut q = self.queue.lock();
+        unsafe {
+            q.req.ptr::<ReqHeader>().write_volatile(ReqHeader {
+                ty: if write { REQ_OUT } else { REQ_IN },
+                _reserved: 0,
+                sector: lba
+            });
+            q.req.ptr::<u8>().add(16).write_volatile(0xff);
+        }
+
+        let req = q.req.ptr::<u8>() as u64;
+        q.push(&[
+            Desc { addr: req, len: size_of::<ReqHeader>() as u32, flags: DESC_NEXT, next: 1 },
+            Desc {
+                addr: data.ptr::<u8>() as u64, len: len as u32,
+                flags: DESC_NEXT | if write { 0 } else { DESC_WRITE }, next: 2
+            },
+            Desc { addr: req + 16, len: 1, flags: DESC_WRITE, next: 0 }
+        ]);
+        q.wait();
+
+        let status = unsafe { q.req.ptr::<u8>().add(16).read_volatile() };
+        if status != 0 {
+            return Err("virtio-blk request failed".into());
+        }
+        if !write { buf.copy_from_slice(&data[..buf.len()]); }
+        return Ok(());
+    }
+}
+
+impl BlockDevice for BlockDeviceVirtio {
+    fn block_size(&self) -> u64 {
+        return SECTOR as u64;
+    }
+
+    fn block_count(&self) -> u64 {
+        return self.capacity;
+    }
+
+    fn read_block(&self, buf: &mut [u8], lba: u64) -> Result<(), String> {
+        return self.transfer(buf, lba, false);
+    }
+
+    fn write_block(&self, buf: &[u8], lba: u64) -> Result<(), String> {
+        // Partial sectors keep whatever the disk had
+        let mut tmp = alloc::vec![0u8; buf.len().next_multiple_of(SECTOR)];
+        if buf.len() % SECTOR != 0 {
+            let last = buf.len() / SECTOR * SECTOR;
+            self.transfer(&mut tmp[last..], lba + (last / SECTOR) as u64, false)?;
+        }
+        tmp[..buf.len()].copy_from_slice(buf);
+        return self.transfer(&mut tmp, lba, true);
+    }
+
+    fn devid(&self) -> u64 {
+        return DevId::new(0)
+            .ty(BlockDevType::PCIe)
+            .loc((self.devid as u32) << 16)
+            .build();
+    }
+}
+
+// Maps the BAR window a virtio capability points at
+fn cap_region(dev: &PciDevice, cap: usize) -> Option<usize> {
+    let bar = (dev.cfg_read(cap + 4) & 0xff) as usize;
+    let off = dev.cfg_read(cap + 8) as usize;
+    let len = dev.cfg_read(cap + 12) as usize;
+    let addr = dev.bar_addr(bar)? + off;
+    GLACIER.write().map_range(addr, addr, len, flags::D_RW).ok()?;
+    return Some(addr);
+}
+
+fn init(dev: &mut PciDevice) -> Result<BlockDeviceVirtio, String> {
+    let (mut common, mut notify, mut device) = (None, None, None);
+    let mut notify_mul = 0;
+
+    for (_, cap) in dev.capabilities().into_iter().filter(|&(id, _)| id == CAP_VENDOR) {
+        match (dev.cfg_read(cap) >> 24) as u8 {
+            CFG_COMMON if common.is_none() => common = cap_region(dev, cap),
+            CFG_NOTIFY if notify.is_none() => {
+                notify = cap_region(dev, cap);
+                notify_mul = dev.cfg_read(cap + 16) as usize;
+            }
+            CFG_DEVICE if device.is_none() => device = cap_region(dev, cap),
+            _ => {}
+        }
+    }
+
+    let common = Mmio(common.ok_or("No virtio common config")?);
+    let notify = notify.ok_or("No virtio notify config")?;
+    let device = Mmio(device.ok_or("No virtio device config")?);
+
+    common.write::<u8>(COMMON_STATUS, 0);
+    common.write::<u8>(COMMON_STATUS, STATUS_ACK | STATUS_DRIVER);
+
+    common.write::<u32>(COMMON_DFSELECT, 1);
+    if common.read::<u32>(COMMON_DF) & F_VERSION_1 == 0 {
+        common.write::<u8>(COMMON_STATUS, STATUS_FAILED);
+        return Err("Legacy-only virtio device".into());
+    }
+    common.write::<u32>(COMMON_GFSELECT, 0);
+    common.write::<u32>(COMMON_GF, 0);
+    common.write::<u32>(COMMON_GFSELECT, 1);
+    common.write::<u32>(COMMON_GF, F_VERSION_1);
+
+    common.write::<u8>(COMMON_STATUS, STATUS_ACK | STATUS_DRIVER | STATUS_FEATURES_OK);
+    if common.read::<u8>(COMMON_STATUS) & STATUS_FEATURES_OK == 0 {
+        common.write::<u8>(COMMON_STATUS, STATUS_FAILED);
+        return Err("virtio feature negotiation failed".into());
+    }
+
+    common.write::<u16>(COMMON_Q_SELECT, 0);
+    let size = common.read::<u16>(COMMON_Q_SIZE).min(QUEUE_SIZE);
+    if size == 0 { return Err("virtio request queue unavailable".into()); }
+    common.write::<u16>(COMMON_Q_SIZE, size);
+
+    let alloc_page = || PhysPageBuf::new_zeroed(size as usize * 16).ok_or("Failed to allocate virtqueue");
+    let mut queue = VirtQueue {
+        size,
+        desc: alloc_page()?,
+        avail: alloc_page()?,
+        used: alloc_page()?,
+        req: alloc_page()?,
+        avail_idx: 0,
+        used_idx: 0,
+        notify: notify + common.read::<u16>(COMMON_Q_NOFF) as usize * notify_mul
+    };
+
+    common.write_u64(COMMON_Q_DESC, queue.desc.ptr::<u8>() as u64);
+    common.write_u64(COMMON_Q_AVAIL, queue.avail.ptr::<u8>() as u64);
+    common.write_u64(COMMON_Q_USED, queue.used.ptr::<u8>() as u64);
+    common.write::<u16>(COMMON_Q_ENABLE, 1);
+
+    common.write::<u8>(COMMON_STATUS, STATUS_ACK | STATUS_DRIVER | STATUS_FEATURES_OK | STATUS_DRIVER_OK);
+
+    // The halves must come from the same config generation
+    let capacity = loop {
+        let generation = common.read::<u8>(COMMON_CFGGEN);
+        let capacity = device.read_u64(0);
+        if common.read::<u8>(COMMON_CFGGEN) == generation { break capacity; }
+    };
+
+    return Ok(BlockDeviceVirtio {
+        queue: Mutex::new(queue),
+        capacity,
+        devid: dev.devid
+    });
+}
+
+pub fn add(dev: &mut PciDevice) {
+    if !dev.is_virtio_blk() {
+        return;
+    }
+
+    dev.enable_pci_device();
+
+    match init(dev) {
+        Ok(blk) => BLOCK_DEVICES.write().push(Arc::new(blk)),
+        Err(e) => warn!("virtio-blk: {}", e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ring_wraps_in_sequence() {
+        for size in [1, 8, 16] {
+            let mut idx = u16::MAX - 20;
+            let mut slot = ring_slot(idx, size);
+            for _ in 0..40 {
+                idx = idx.wrapping_add(1);
+                let next = ring_slot(idx, size);
+                assert_eq!(next, (slot + 1) % size as usize);
+                slot = next;
+            }
+        }
+    }
+
+    #[test]
+    fn used_pending_across_wrap() {
+        assert_eq!(used_pending(5, 5), 0);
+        assert_eq!(used_pending(6, 5), 1);
+        assert_eq!(used_pending(0, u16::MAX), 1);
+        assert_eq!(used_pending(2, u16::MAX - 1), 4);
+    }
+}
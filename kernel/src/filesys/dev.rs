@@ -1,9 +1,58 @@
 use crate::{
-    device::block::{BlockDevice, DevId},
-    filesys::vfn::{vfid, FMeta, FType, VirtFNode}
+    arch::{self, serial_getchar, serial_putchar},
+    device::{block::{BlockDevice, DevId, check_lba}, rng},
+    filesys::{gpt::PartInfo, vfn::{read_len, vfid, FMeta, FType, VirtFNode}},
+    ram::physalloc::{PHYS_ALLOC, RAMBlock}
 };
 
-use alloc::{string::String, sync::Arc};
+use core::{fmt::Write, hint::spin_loop};
+use alloc::{string::String, sync::Arc, vec::Vec};
+
+// Block device ioctls, the value comes back as the return value
+pub const BLKGETSIZE: u32 = 0x1260; // Block count
+pub const BLKFLSBUF: u32  = 0x1261;
+pub const BLKSSZGET: u32  = 0x1268; // Block size in bytes
+
+fn blk_ioctl(dev: &dyn BlockDevice, cmd: u32) -> Result<usize, String> {
+    return match cmd {
+        BLKGETSIZE => Ok(dev.block_count() as usize),
+        BLKSSZGET => Ok(dev.block_size() as usize),
+        BLKFLSBUF => dev.flush().map(|_| 0),
+        _ => Err("Unsupported ioctl".into())
+    };
+}
+
+// Reads stop at the end of the device
+pub fn read_span(dev: &dyn BlockDevice, buf: &mut [u8], offset: u64) -> Result<usize, String> {
+    let bs = dev.block_size();
+    let len = read_len(bs * dev.block_count(), offset, buf.len())?;
+    if len == 0 { return Ok(0); }
+    let buf = &mut buf[..len];
+
+    let (start, end) = (offset / bs, (offset + buf.len() as u64).div_ceil(bs));
+    let mut vec = alloc::vec![0; ((end - start) * bs) as usize];
+
+    dev.read_block(&mut vec, start)?;
+
+    buf.copy_from_slice(&vec[(offset % bs) as usize..][..buf.len()]);
+    return Ok(buf.len());
+}
+
+// Whole blocks are written back, so the span is read first unless buf covers it exactly
+fn write_span(dev: &dyn BlockDevice, buf: &[u8], offset: u64) -> Result<usize, String> {
+    let bs = dev.block_size();
+    let (start, end) = (offset / bs, (offset + buf.len() as u64).div_ceil(bs));
+    if start == end { return Ok(0); }
+    let mut vec = alloc::vec![0; ((end - start) * bs) as usize];
+
+    if offset % bs != 0 || buf.len() as u64 % bs != 0 {
+        dev.read_block(&mut vec, start)?;
+    }
+
+    vec[(offset % bs) as usize..][..buf.len()].copy_from_slice(buf);
+    dev.write_block(&vec, start)?;
+    return Ok(buf.len());
+}
 
 #[derive(Clone)]
 pub struct DevFile {
@@ -34,16 +83,22 @@ impl BlockDevice for DevFile {
     }
 
     fn read_block(&self, buf: &mut [u8], lba: u64) -> Result<(), String> {
+        check_lba(self, lba, buf.len())?;
         self.dev.read_block(buf, lba)
     }
 
     fn write_block(&self, buf: &[u8], lba: u64) -> Result<(), String> {
+        check_lba(self, lba, buf.len())?;
         self.dev.write_block(buf, lba)
     }
 
     fn devid(&self) -> u64 {
         self.dev.devid()
     }
+
+    fn flush(&self) -> Result<(), String> {
+        self.dev.flush()
+    }
 }
 
 impl VirtFNode for DevFile {
@@ -51,28 +106,16 @@ impl VirtFNode for DevFile {
         return self.meta.clone();
     }
 
-    fn read(&self, buf: &mut [u8], offset: u64) -> Result<(), String> {
-        let bs = self.block_size();
-        let (start, end) = (offset / bs, (offset + buf.len() as u64).div_ceil(bs));
-        let mut vec = alloc::vec![0; ((end - start) * bs) as usize];
-
-        self.read_block(&mut vec, start)?;
-
-        buf.copy_from_slice(&vec[(offset % bs) as usize..][..buf.len()]);
-        return Ok(());
+    fn read(&self, buf: &mut [u8], offset: u64) -> Result<usize, String> {
+        return read_span(self, buf, offset);
     }
 
-    fn write(&self, buf: &[u8], offset: u64) -> Result<(), String> {
-        let bs = self.block_size();
-        let (start, end) = (offset / bs, (offset + buf.len() as u64).div_ceil(bs));
-        let mut vec = alloc::vec![0; ((end - start) * bs) as usize];
-        let len = vec.len();
-
-        self.read_block(&mut vec[..bs as usize], start)?;
-        self.read_block(&mut vec[(len - bs as usize)..], end - 1)?;
+    fn write(&self, buf: &[u8], offset: u64) -> Result<usize, String> {
+        return write_span(self, buf, offset);
+    }
 
-        vec[(offset % bs) as usize..][..buf.len()].copy_from_slice(buf);
-        return self.write_block(&vec, offset / bs);
+    fn ioctl(&self, cmd: u32, _arg: usize) -> Result<usize, String> {
+        return blk_ioctl(self, cmd);
     }
 
     fn truncate(&self, _: u64) -> Result<(), String> {
@@ -90,18 +133,28 @@ pub struct PartDev {
     meta: FMeta,
     devid: u64,
     start_lba: u64,
-    block_count: u64
+    block_count: u64,
+    info: Option<PartInfo>
 }
 
 impl PartDev {
     pub fn new(dev: Arc<dyn BlockDevice>, part_no: u32, start_lba: u64, block_count: u64) -> Self {
         let devid = DevId::new(dev.devid()).part(part_no).build();
         let meta = FMeta::default(vfid(), 1, FType::BlockDev);
-        let mut s = Self { dev, meta, devid, start_lba, block_count };
+        let mut s = Self { dev, meta, devid, start_lba, block_count, info: None };
         s.meta.size = s.total_size();
         return s;
     }
 
+    pub fn with_info(mut self, info: PartInfo) -> Self {
+        self.info = Some(info);
+        return self;
+    }
+
+    pub fn info(&self) -> Option<&PartInfo> {
+        return self.info.as_ref();
+    }
+
     pub fn total_size(&self) -> u64 {
         self.block_size() * self.block_count()
     }
@@ -116,17 +169,24 @@ impl BlockDevice for PartDev {
         self.block_count
     }
 
+    // Checked so a filesystem bug stays inside its own partition
     fn read_block(&self, buf: &mut [u8], lba: u64) -> Result<(), String> {
+        check_lba(self, lba, buf.len())?;
         self.dev.read_block(buf, lba + self.start_lba)
     }
 
     fn write_block(&self, buf: &[u8], lba: u64) -> Result<(), String> {
+        check_lba(self, lba, buf.len())?;
         self.dev.write_block(buf, lba + self.start_lba)
     }
 
     fn devid(&self) -> u64 {
         self.devid
     }
+
+    fn flush(&self) -> Result<(), String> {
+        self.dev.flush()
+    }
 }
 
 impl VirtFNode for PartDev {
@@ -134,28 +194,16 @@ impl VirtFNode for PartDev {
         return self.meta.clone();
     }
 
-    fn read(&self, buf: &mut [u8], offset: u64) -> Result<(), String> {
-        let bs = self.block_size();
-        let (start, end) = (offset / bs, (offset + buf.len() as u64).div_ceil(bs));
-        let mut vec = alloc::vec![0; ((end - start) * bs) as usize];
-
-        self.read_block(&mut vec, start)?;
-
-        buf.copy_from_slice(&vec[(offset % bs) as usize..][..buf.len()]);
-        return Ok(());
+    fn read(&self, buf: &mut [u8], offset: u64) -> Result<usize, String> {
+        return read_span(self, buf, offset);
     }
 
-    fn write(&self, buf: &[u8], offset: u64) -> Result<(), String> {
-        let bs = self.block_size();
-        let (start, end) = (offset / bs, (offset + buf.len() as u64).div_ceil(bs));
-        let mut vec = alloc::vec![0; ((end - start) * bs) as usize];
-        let len = vec.len();
-
-        self.read_block(&mut vec[..bs as usize], start)?;
-        self.read_block(&mut vec[(len - bs as usize)..], end - 1)?;
+    fn write(&self, buf: &[u8], offset: u64) -> Result<usize, String> {
+        return write_span(self, buf, offset);
+    }
 
-        vec[(offset % bs) as usize..][..buf.len()].copy_from_slice(buf);
-        return self.write_block(&vec, offset / bs);
+    fn ioctl(&self, cmd: u32, _arg: usize) -> Result<usize, String> {
+        return blk_ioctl(self, cmd);
     }
 
     fn truncate(&self, _: u64) -> Result<(), String> {
@@ -166,3 +214,298 @@ impl VirtFNode for PartDev {
         Some(Arc::new(self.clone()))
     }
 }
+
+// Fills buf with what `next` has ready right now, returns the count
+fn drain_ready(buf: &mut [u8], mut next: impl FnMut() -> Option<u8>) -> usize {
+    for (n, byte) in buf.iter_mut().enumerate() {
+        match next() {
+            Some(c) => *byte = c,
+            None => return n
+        }
+    }
+    return buf.len();
+}
+
+// Waits for the first byte only. Interrupts are on while waiting, requests run with
+// them masked and the IRQ feeding `next` may be routed to this CPU.
+fn read_ready(buf: &mut [u8], mut next: impl FnMut() -> Option<u8>) -> usize {
+    let Some((first, rest)) = buf.split_first_mut() else { return 0; };
+    *first = loop {
+        if let Some(c) = next() { break c; }
+        arch::exc::set(true);
+        spin_loop();
+        arch::exc::set(false);
+    };
+    return 1 + drain_ready(rest, next);
+}
+
+pub struct Console {
+    meta: FMeta
+}
+
+impl Console {
+    pub fn new() -> Self {
+        return Self { meta: FMeta::default(vfid(), 1, FType::CharDev) };
+    }
+}
+
+impl VirtFNode for Console {
+    fn meta(&self) -> FMeta {
+        return self.meta.clone();
+    }
+
+    fn read(&self, buf: &mut [u8], _offset: u64) -> Result<usize, String> {
+        return Ok(read_ready(buf, serial_getchar));
+    }
+
+    fn write(&self, buf: &[u8], _offset: u64) -> Result<usize, String> {
+        buf.iter().for_each(|&c| serial_putchar(c));
+        return Ok(buf.len());
+    }
+}
+
+// Hardware RNG when there is one, see rng for the fallback
+pub struct Random {
+    meta: FMeta
+}
+
+impl Random {
+    pub fn new() -> Self {
+        return Self { meta: FMeta::default(vfid(), 1, FType::CharDev) };
+    }
+}
+
+impl VirtFNode for Random {
+    fn meta(&self) -> FMeta {
+        return self.meta.clone();
+    }
+
+    fn read(&self, buf: &mut [u8], _offset: u64) -> Result<usize, String> {
+        rng::fill(buf);
+        return Ok(buf.len());
+    }
+}
+
+// Physical memory map as text, one `base size type used|free` line per block.
+// Rendered from a fresh snapshot on every call, so offsets only line up while
+// the map stays the same.
+pub struct MemMap {
+    meta: FMeta,
+    snapshot: fn() -> Vec<RAMBlock>
+}
+
+impl MemMap {
+    pub fn new() -> Self {
+        let mut meta = FMeta::default(vfid(), 1, FType::Regular);
+        meta.perm = 0o444;
+        return Self { meta, snapshot: || PHYS_ALLOC.snapshot() };
+    }
+
+    fn render(&self) -> String {
+        let mut text = String::new();
+        for block in (self.snapshot)() {
+            let state = if block.used() { "used" } else { "free" };
+            let _ = writeln!(text, "{:016x} {:016x} {:<21} {}", block.addr(), block.size(), block.ty(), state);
+        }
+        return text;
+    }
+}
+
+impl VirtFNode for MemMap {
+    fn meta(&self) -> FMeta {
+        let mut meta = self.meta.clone();
+        meta.size = self.render().len() as u64;
+        return meta;
+    }
+
+    fn read(&self, buf: &mut [u8], offset: u64) -> Result<usize, String> {
+        let text = self.render();
+        let len = read_len(text.len() as u64, offset, buf.len())?;
+        buf[..len].copy_from_slice(&text.as_bytes()[offset as usize..][..len]);
+        return Ok(len);
+    }
+
+    fn write(&self, _buf: &[u8], _offset: u64) -> Result<usize, String> {
+        return Err("Read-only file system".into());
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+pub struct Keyboard {
+    meta: FMeta
+}
+
+#[cfg(target_arch = "x86_64")]
+impl Keyboard {
+    pub fn new() -> Self {
+        return Self { meta: FMeta::default(vfid(), 1, FType::CharDev) };
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl VirtFNode for Keyboard {
+    fn meta(&self) -> FMeta {
+        return self.meta.clone();
+    }
+
+    fn read(&self, buf: &mut [u8], _offset: u64) -> Result<usize, String> {
+        return Ok(read_ready(buf, crate::device::ps2kbd::pop));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{vec, vec::Vec};
+    use core::sync::atomic::{AtomicUsize, Ordering as AtomOrd};
+    use spin::Mutex;
+
+    struct Disk {
+        data: Mutex<Vec<u8>>,
+        reads: AtomicUsize
+    }
+
+    impl Disk {
+        fn new(blocks: usize) -> Self {
+            let data = (0..blocks * 512).map(|i| i as u8).collect();
+            return Self { data: Mutex::new(data), reads: AtomicUsize::new(0) };
+        }
+    }
+
+    impl BlockDevice for Disk {
+        fn block_size(&self) -> u64 { 512 }
+        fn block_count(&self) -> u64 { self.data.lock().len() as u64 / 512 }
+        fn devid(&self) -> u64 { 0 }
+
+        fn read_block(&self, buf: &mut [u8], lba: u64) -> Result<(), String> {
+            check_lba(self, lba, buf.len())?;
+            self.reads.fetch_add(1, AtomOrd::Relaxed);
+            buf.copy_from_slice(&self.data.lock()[lba as usize * 512..][..buf.len()]);
+            return Ok(());
+        }
+
+        fn write_block(&self, buf: &[u8], lba: u64) -> Result<(), String> {
+            check_lba(self, lba, buf.len())?;
+            self.data.lock()[lba as usize * 512..][..buf.len()].copy_from_slice(buf);
+            return Ok(());
+        }
+    }
+
+    #[test]
+    fn read_span_unaligned_and_clamped() {
+        let disk = Disk::new(4);
+        let mut buf = [0; 600];
+        assert_eq!(read_span(&disk, &mut buf, 500), Ok(600));
+        assert!(buf.iter().enumerate().all(|(i, &b)| b == (500 + i) as u8));
+
+        assert_eq!(read_span(&disk, &mut buf, 2000), Ok(48));
+        assert_eq!(buf[..48], disk.data.lock()[2000..]);
+        assert_eq!(read_span(&disk, &mut buf, 2048), Ok(0));
+        assert!(read_span(&disk, &mut buf, 2049).is_err());
+    }
+
+    #[test]
+    fn drain_stops_when_dry() {
+        let mut src = [b'a', b'b'].into_iter();
+        let mut buf = [0; 8];
+        assert_eq!(drain_ready(&mut buf, || src.next()), 2);
+        assert_eq!(buf[..2], *b"ab");
+        assert_eq!(drain_ready(&mut [], || Some(0)), 0);
+
+        let mut src = b"0123456789".iter().copied();
+        assert_eq!(drain_ready(&mut buf, || src.next()), 8);
+        assert_eq!(src.next(), Some(b'8'));
+    }
+
+    // 16550 style registers, data ready (LSR bit 0) drops once the FIFO is empty
+    #[test]
+    fn read_returns_after_data_ready_drops() {
+        let mut fifo = b"ls\n".iter().copied();
+        let mut regs = [0u8; 6];
+        regs[5] = 1;
+        let mut uart = || {
+            if regs[5] & 1 == 0 { return None; }
+            regs[0] = fifo.next()?;
+            if fifo.len() == 0 { regs[5] &= !1; }
+            return Some(regs[0]);
+        };
+
+        let mut buf = [0; 16];
+        assert_eq!(read_ready(&mut buf, &mut uart), 3);
+        assert_eq!(buf[..3], *b"ls\n");
+        assert_eq!(read_ready(&mut [], &mut uart), 0);
+    }
+
+    #[test]
+    fn write_span_keeps_neighbours() {
+        let disk = Disk::new(4);
+        let before = disk.data.lock().clone();
+        assert_eq!(write_span(&disk, &[0xee; 100], 1000), Ok(100));
+
+        let after = disk.data.lock().clone();
+        assert_eq!(after[..1000], before[..1000]);
+        assert!(after[1000..1100].iter().all(|&b| b == 0xee));
+        assert_eq!(after[1100..], before[1100..]);
+    }
+
+    #[test]
+    fn write_span_whole_blocks_skip_the_read() {
+        let disk = Disk::new(4);
+        assert_eq!(write_span(&disk, &vec![7; 1024], 512), Ok(1024));
+        assert_eq!(disk.reads.load(AtomOrd::Relaxed), 0);
+        assert!(disk.data.lock()[512..1536].iter().all(|&b| b == 7));
+    }
+
+    #[test]
+    fn write_span_past_the_end() {
+        let disk = Disk::new(4);
+        let before = disk.data.lock().clone();
+        assert!(write_span(&disk, &[1; 600], 1800).is_err());
+        assert_eq!(write_span(&disk, &[], 100), Ok(0));
+        assert_eq!(*disk.data.lock(), before);
+    }
+
+    #[test]
+    fn ioctl_reports_the_geometry() {
+        let disk = Disk::new(4);
+        assert_eq!(blk_ioctl(&disk, BLKSSZGET), Ok(512));
+        assert_eq!(blk_ioctl(&disk, BLKGETSIZE), Ok(4));
+        assert_eq!(blk_ioctl(&disk, BLKFLSBUF), Ok(0));
+        assert!(blk_ioctl(&disk, 0x5401).is_err()); // TCGETS is not for disks
+    }
+
+    fn sample_map() -> Vec<RAMBlock> {
+        use crate::kargs::RAMType;
+        return vec![
+            RAMBlock::new(0x0000_0000, 0x1000, RAMType::Reserved, true),
+            RAMBlock::new(0x0000_1000, 0x9_f000, RAMType::Conv, false),
+            RAMBlock::new(0x0010_0000, 0x20_0000, RAMType::Kernel, true)
+        ];
+    }
+
+    #[test]
+    fn memmap_reads_a_line_per_block() {
+        let map = MemMap { meta: FMeta::default(vfid(), 1, FType::Regular), snapshot: sample_map };
+        let size = map.meta().size as usize;
+        let mut whole = vec![0; size + 16];
+        assert_eq!(map.read(&mut whole, 0), Ok(size));
+        let text = core::str::from_utf8(&whole[..size]).unwrap();
+        assert_eq!(text.lines().collect::<Vec<_>>(), [
+            "0000000000000000 0000000000001000 Reserved              used",
+            "0000000000001000 000000000009f000 Conventional          free",
+            "0000000000100000 0000000000200000 Kernel                used"
+        ]);
+
+        // Odd sized reads at increasing offsets give back the same bytes
+        let mut stitched = Vec::new();
+        let mut chunk = [0; 7];
+        loop {
+            let n = map.read(&mut chunk, stitched.len() as u64).unwrap();
+            if n == 0 { break; }
+            stitched.extend_from_slice(&chunk[..n]);
+        }
+        assert_eq!(stitched, &whole[..size]);
+        assert!(map.read(&mut chunk, size as u64 + 1).is_err());
+        assert!(map.write(b"x", 0).is_err());
+    }
+}
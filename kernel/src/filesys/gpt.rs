@@ -1,6 +1,6 @@
 #![allow(non_camel_case_types)]
 
-use crate::{device::block::BlockDevice, filesys::dev::PartDev};
+use crate::{device::block::BlockDevice, filesys::dev::PartDev, warn};
 
 use alloc::{format, string::String, sync::Arc, vec::Vec};
 use zerocopy::{FromBytes, LE, U16, U32, U64};
@@ -45,31 +45,125 @@ struct UUIDPartitionEntry {
     name: [U16<LE>; 36]
 }
 
+impl UUIDPartitionEntry {
+    fn name(&self) -> String {
+        let units = self.name.iter()
+            .map(|c| c.get())
+            .take_while(|&c| c != 0);
+        return char::decode_utf16(units)
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect();
+    }
+}
+
+#[derive(Clone)]
+pub struct PartInfo {
+    pub name: String,
+    pub type_guid: [u8; 16],
+    pub unique_guid: [u8; 16]
+}
+
+impl PartInfo {
+    pub fn is_esp(&self) -> bool {
+        return self.type_guid == PART_EFI;
+    }
+}
+
 const PART_EFI: [u8; 16] = [
     0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11,
     0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9, 0x3b
 ];
 
+const HEAD_MIN: usize = 92;
+const HEAD_CRC: core::ops::Range<usize> = 16..20;
+const ENTRIES_MAX: usize = 0x100000; // Far beyond the usual 128 entries of 128 bytes
+
+// CRC-32 as in zlib and the UEFI spec: reflected, polynomial 0x04c11db7
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+pub fn crc32(data: &[u8]) -> u32 {
+    return !data.iter().fold(!0u32, |crc, &b| CRC_TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8));
+}
+
+// Header at `lba` and its entry array, both checked against their CRCs
+fn read_table(dev: &Arc<dyn BlockDevice>, lba: u64) -> Result<(UUIDPartitionTable, Vec<u8>), String> {
+    let bsize = dev.block_size() as usize;
+    let mut buf = alloc::vec![0u8; bsize];
+    dev.read_block(&mut buf, lba)?;
+    let head: UUIDPartitionTable = FromBytes::read_from_bytes(&buf[..size_of::<UUIDPartitionTable>()])
+        .map_err(|_| "Failed to parse GPT header")?;
+
+    if &head.sign != b"EFI PART" {
+        return Err("Invalid GPT signature".into());
+    }
+    let head_len = head.headsize.get() as usize;
+    if head_len < HEAD_MIN || head_len > bsize {
+        return Err("Invalid GPT header size".into());
+    }
+    buf[HEAD_CRC].fill(0);
+    if crc32(&buf[..head_len]) != head.crc32.get() {
+        return Err("GPT header CRC mismatch".into());
+    }
+    if head.lba_here.get() != lba {
+        return Err("GPT header is not where it says it is".into());
+    }
+
+    let ent_size = head.partentry_len.get() as usize;
+    let ent_len = ent_size * head.partentry_num.get() as usize;
+    if ent_size < size_of::<UUIDPartitionEntry>() || ent_len > ENTRIES_MAX {
+        return Err("Invalid GPT entry array".into());
+    }
+    let mut ent_buf = alloc::vec![0u8; ent_len.div_ceil(bsize) * bsize];
+    dev.read_block(&mut ent_buf, head.partentry_lba.get())?;
+    ent_buf.truncate(ent_len);
+    if crc32(&ent_buf) != head.partentry_crc.get() {
+        return Err("GPT entry array CRC mismatch".into());
+    }
+
+    return Ok((head, ent_buf));
+}
+
+// The primary table at LBA 1, or the backup at the last LBA when the primary is corrupt.
+// Falling back also hands over why the primary was refused.
+fn find_table(dev: &Arc<dyn BlockDevice>) -> Result<(UUIDPartitionTable, Vec<u8>, Option<String>), String> {
+    let primary = match read_table(dev, 1) {
+        Ok((head, ent_buf)) => return Ok((head, ent_buf, None)),
+        Err(e) => e
+    };
+    let last = dev.block_count().saturating_sub(1);
+    let (head, ent_buf) = read_table(dev, last)
+        .map_err(|backup| format!("No valid GPT: primary: {}, backup: {}", primary, backup))?;
+    return Ok((head, ent_buf, Some(primary)));
+}
+
 impl UEFIPartition {
     pub fn new(dev: Arc<dyn BlockDevice>) -> Result<Self, String> {
-        let mut buf = alloc::vec![0u8; dev.block_size() as usize];
-        dev.read_block(&mut buf, 1)?;
-        let head: UUIDPartitionTable = FromBytes::read_from_bytes(&buf[..size_of::<UUIDPartitionTable>()])
-            .map_err(|_| "Failed to parse GPT header")?;
-
-        if &head.sign != b"EFI PART" {
-            return Err("Invalid GPT signature".into());
+        let (head, ent_buf, primary) = find_table(&dev)?;
+        if let Some(primary) = primary {
+            warn!("GPT: {}, using the backup table", primary);
         }
 
         let ent_size = head.partentry_len.get() as usize;
         let ent_num = head.partentry_num.get() as usize;
-        let mut ent_buf = alloc::vec![0u8; ent_size * ent_num];
-        dev.read_block(&mut ent_buf, head.partentry_lba.get())?;
         let mut entries = Vec::with_capacity(ent_num);
 
         for p in 0..ent_num {
             let start = p * ent_size;
-            let end = start + ent_size;
+            let end = start + size_of::<UUIDPartitionEntry>();
             let entry: UUIDPartitionEntry = FromBytes::read_from_bytes(&ent_buf[start..end])
                 .map_err(|_| format!("Failed to parse GPT entry {}", p))?;
             if entry.type_uuid == [0; 16] { continue; }
@@ -94,12 +188,168 @@ impl UEFIPartition {
         for (i, entry) in self.entries.iter().enumerate() {
             let start = entry.first_lba.get();
             let end = entry.last_lba.get();
+            let info = PartInfo {
+                name: entry.name(),
+                type_guid: entry.type_uuid,
+                unique_guid: entry.unique_uuid
+            };
             let part = PartDev::new(
                 self.dev.clone(), i as u32,
                 start, end - start + 1
-            );
+            ).with_info(info);
             parts.push(part);
         }
         return parts;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::block::check_lba;
+    use alloc::vec;
+    use spin::Mutex;
+
+    const ENT: usize = 128;
+    const PART_LINUX: [u8; 16] = [
+        0xaf, 0x3d, 0xc6, 0x0f, 0x83, 0x84, 0x72, 0x47,
+        0x8e, 0x79, 0x3d, 0x69, 0xd8, 0x47, 0x7d, 0xe4
+    ];
+
+    fn entry(table: &mut [u8], i: usize, ty: [u8; 16], name: &str) {
+        let ent = &mut table[i * ENT..][..ENT];
+        ent[..16].copy_from_slice(&ty);
+        ent[16] = i as u8 + 1;
+        for (dst, c) in ent[56..].chunks_exact_mut(2).zip(name.encode_utf16()) {
+            dst.copy_from_slice(&c.to_le_bytes());
+        }
+    }
+
+    fn parse(table: &[u8], i: usize) -> UUIDPartitionEntry {
+        return FromBytes::read_from_bytes(&table[i * ENT..][..size_of::<UUIDPartitionEntry>()]).unwrap();
+    }
+
+    #[test]
+    fn names_and_type_guids() {
+        let mut table = vec![0u8; 2 * ENT];
+        entry(&mut table, 0, PART_EFI, "EFI System Partition");
+        entry(&mut table, 1, PART_LINUX, "root");
+
+        let esp = parse(&table, 0);
+        assert_eq!(esp.name(), "EFI System Partition");
+        assert_eq!(esp.type_uuid, PART_EFI);
+        let root = parse(&table, 1);
+        assert_eq!(root.name(), "root");
+        assert_eq!(root.type_uuid, PART_LINUX);
+        assert_eq!(root.unique_uuid[0], 2);
+    }
+
+    #[test]
+    fn name_fills_all_36_units() {
+        let mut table = vec![0u8; ENT];
+        let name = "Ünïcode partition name, 36 units ok!";
+        assert_eq!(name.encode_utf16().count(), 36);
+        entry(&mut table, 0, PART_LINUX, name);
+        assert_eq!(parse(&table, 0).name(), name);
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    const BLOCKS: u64 = 64;
+
+    struct Disk(Mutex<Vec<u8>>);
+
+    impl BlockDevice for Disk {
+        fn block_size(&self) -> u64 { 512 }
+        fn block_count(&self) -> u64 { BLOCKS }
+        fn devid(&self) -> u64 { 0 }
+
+        fn read_block(&self, buf: &mut [u8], lba: u64) -> Result<(), String> {
+            check_lba(self, lba, buf.len())?;
+            buf.copy_from_slice(&self.0.lock()[lba as usize * 512..][..buf.len()]);
+            return Ok(());
+        }
+
+        fn write_block(&self, buf: &[u8], lba: u64) -> Result<(), String> {
+            check_lba(self, lba, buf.len())?;
+            self.0.lock()[lba as usize * 512..][..buf.len()].copy_from_slice(buf);
+            return Ok(());
+        }
+    }
+
+    // Header at `here` with its four entries in the block `ents`, both CRCs filled in
+    fn table(img: &mut [u8], here: u64, backup: u64, ents: u64) {
+        let mut array = vec![0u8; 4 * ENT];
+        entry(&mut array, 0, PART_EFI, "EFI System Partition");
+        entry(&mut array, 1, PART_LINUX, "root");
+        img[ents as usize * 512..][..4 * ENT].copy_from_slice(&array);
+
+        let head = &mut img[here as usize * 512..][..512];
+        let fields: [(usize, &[u8]); 11] = [
+            (0, b"EFI PART"),
+            (8, &0x10000u32.to_le_bytes()),
+            (12, &92u32.to_le_bytes()),
+            (24, &here.to_le_bytes()),
+            (32, &backup.to_le_bytes()),
+            (40, &34u64.to_le_bytes()),
+            (48, &(BLOCKS - 34).to_le_bytes()),
+            (56, &[0xd1; 16]),
+            (72, &ents.to_le_bytes()),
+            (80, &4u32.to_le_bytes()),
+            (84, &(ENT as u32).to_le_bytes())
+        ];
+        for (off, bytes) in fields {
+            head[off..off + bytes.len()].copy_from_slice(bytes);
+        }
+        head[88..92].copy_from_slice(&crc32(&array).to_le_bytes());
+        let crc = crc32(&head[..92]);
+        head[HEAD_CRC].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    fn disk() -> Arc<Disk> {
+        let mut img = vec![0u8; BLOCKS as usize * 512];
+        table(&mut img, 1, BLOCKS - 1, 2);
+        table(&mut img, BLOCKS - 1, 1, BLOCKS - 2);
+        return Arc::new(Disk(Mutex::new(img)));
+    }
+
+    fn find(disk: &Arc<Disk>) -> Result<(u64, Vec<u8>, Option<String>), String> {
+        let dev: Arc<dyn BlockDevice> = disk.clone();
+        return find_table(&dev).map(|(head, ents, primary)| (head.lba_here.get(), ents, primary));
+    }
+
+    #[test]
+    fn corrupt_tables_are_refused() {
+        let good = disk();
+        let (here, ents, primary) = find(&good).unwrap();
+        assert_eq!((here, primary), (1, None));
+        assert_eq!(parse(&ents, 1).name(), "root");
+
+        // One flipped bit in the header, then in the entry array
+        let bad_head = disk();
+        bad_head.0.lock()[512 + 56] ^= 1;
+        let (here, ents, primary) = find(&bad_head).unwrap();
+        assert_eq!((here, primary.as_deref()), (BLOCKS - 1, Some("GPT header CRC mismatch")));
+        assert_eq!(parse(&ents, 0).name(), "EFI System Partition");
+
+        let bad_ents = disk();
+        bad_ents.0.lock()[2 * 512 + 56] ^= 1;
+        let (here, _, primary) = find(&bad_ents).unwrap();
+        assert_eq!((here, primary.as_deref()), (BLOCKS - 1, Some("GPT entry array CRC mismatch")));
+
+        // The header CRC is taken with its own field zeroed, a stale one fails
+        let stale = disk();
+        stale.0.lock()[512 + 16] ^= 0x80;
+        assert!(find(&stale).unwrap().2.is_some());
+
+        let both = disk();
+        both.0.lock()[512 + 56] ^= 1;
+        both.0.lock()[(BLOCKS as usize - 2) * 512 + 56] ^= 1;
+        let err = find(&both).err().unwrap();
+        assert_eq!(err, "No valid GPT: primary: GPT header CRC mismatch, backup: GPT entry array CRC mismatch");
+    }
+}
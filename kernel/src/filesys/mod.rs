@@ -1,31 +1,116 @@
 mod dev; mod parts; mod gpt; pub mod vfn;
 
 use crate::{
-    device::block::BLOCK_DEVICES,
+    device::block::{BlockCache, BlockDevice, DevId, BLOCK_DEVICES},
+    kargs::{cmdline_get, initrd, tmpfs_size},
     filesys::{
-        dev::DevFile,
+        dev::{Console, DevFile, MemMap, Random},
         gpt::UEFIPartition,
-        parts::{Partition, fat::FileAllocTable, vpart::VirtPart},
-        vfn::{FMeta, FType, VirtFNode}
+        parts::{Partition, StatFs, probe_filesystem, tar::TarPart, vpart::VirtPart},
+        vfn::{Credentials, FMeta, FType, MAY_EXEC, MAY_READ, MAY_WRITE, OpenFile, VirtFNode, read_len}
     },
     printlnk,
     ram::dump_bytes
 };
 
-use core::ops::{Deref, DerefMut};
+use core::{
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicUsize, Ordering as AtomOrd}
+};
 use alloc::{
     collections::btree_map::BTreeMap,
     format, string::String, sync::Arc, vec::Vec
 };
 use spin::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
+// Bytes of VirtFile data across every in-memory partition, capped so a runaway
+// write fails instead of exhausting the kernel heap
+struct TmpfsCounter {
+    used: AtomicUsize,
+    limit: AtomicUsize
+}
+
+impl TmpfsCounter {
+    const fn new() -> Self {
+        return Self { used: AtomicUsize::new(0), limit: AtomicUsize::new(TMPFS_DEFAULT_LIMIT) };
+    }
+}
+
+const TMPFS_DEFAULT_LIMIT: usize = 64 << 20;
+
+#[cfg(not(test))]
+static TMPFS: TmpfsCounter = TmpfsCounter::new();
+
+#[cfg(not(test))]
+fn tmpfs() -> &'static TmpfsCounter { &TMPFS }
+
+// Each test thread counts on its own, as if it had booted alone
+#[cfg(test)]
+fn tmpfs() -> &'static TmpfsCounter {
+    use alloc::boxed::Box;
+    std::thread_local! {
+        static TMPFS: &'static TmpfsCounter = Box::leak(Box::new(TmpfsCounter::new()));
+    }
+    return TMPFS.with(|tmpfs| *tmpfs);
+}
+
+// Lowering it below the usage only stops further growth
+pub fn set_tmpfs_limit(bytes: usize) {
+    tmpfs().limit.store(bytes, AtomOrd::Relaxed);
+}
+
+// (used, limit) in bytes
+pub fn tmpfs_usage() -> (usize, usize) {
+    let tmpfs = tmpfs();
+    return (tmpfs.used.load(AtomOrd::Relaxed), tmpfs.limit.load(AtomOrd::Relaxed));
+}
+
+fn tmpfs_reserve(bytes: usize) -> Result<(), String> {
+    let tmpfs = tmpfs();
+    let limit = tmpfs.limit.load(AtomOrd::Relaxed);
+    return tmpfs.used.fetch_update(AtomOrd::Relaxed, AtomOrd::Relaxed, |used| {
+        used.checked_add(bytes).filter(|&new| new <= limit)
+    }).map(|_| ()).map_err(|_| "No space left".into());
+}
+
+fn tmpfs_release(bytes: usize) {
+    tmpfs().used.fetch_sub(bytes, AtomOrd::Relaxed);
+}
+
 struct VirtFile {
     vfd: Mutex<VFileData>
 }
 
 struct VFileData {
     meta: FMeta,
-    data: Vec<u8>
+    data: Vec<u8> // Its length is what counts against the limit
+}
+
+impl VFileData {
+    fn resize(&mut self, size: usize) -> Result<(), String> {
+        let cur = self.data.len();
+        if size > cur {
+            tmpfs_reserve(size - cur)?;
+            if self.data.try_reserve(size - cur).is_err() {
+                tmpfs_release(size - cur);
+                return Err("Out of memory".into());
+            }
+        }
+
+        self.data.resize(size, 0);
+        if size < cur {
+            self.data.shrink_to_fit();
+            tmpfs_release(cur - size);
+        }
+        self.meta.size = size as u64;
+        return Ok(());
+    }
+}
+
+impl Drop for VFileData {
+    fn drop(&mut self) {
+        tmpfs_release(self.data.len());
+    }
 }
 
 impl VirtFile {
@@ -44,37 +129,30 @@ impl VirtFNode for VirtFile {
         return self.vfd.lock().meta.clone();
     }
 
-    fn read(&self, buf: &mut [u8], offset: u64) -> Result<(), String> {
+    fn read(&self, buf: &mut [u8], offset: u64) -> Result<usize, String> {
         let data = &self.vfd.lock().data;
+        let read_len = read_len(data.len() as u64, offset, buf.len())?;
         let offset = offset as usize;
-        if offset >= data.len() {
-            return Err("Offset out of bounds".into());
-        }
-
-        let read_len = buf.len().min(data.len() - offset);
         buf[..read_len].clone_from_slice(&data[offset..offset + read_len]);
 
-        return Ok(());
+        return Ok(read_len);
     }
 
-    fn write(&self, buf: &[u8], offset: u64) -> Result<(), String> {
+    fn write(&self, buf: &[u8], offset: u64) -> Result<usize, String> {
         let mut vfd = self.vfd.lock();
 
         let offset = offset as usize;
-        let write_end = buf.len() + offset;
+        let write_end = offset.checked_add(buf.len()).ok_or("File too large")?;
         let new_size = write_end.max(vfd.data.len());
 
-        vfd.data.resize(new_size, 0);
+        vfd.resize(new_size)?;
         vfd.data[offset..write_end].clone_from_slice(buf);
-        vfd.meta.size = new_size as u64;
-        return Ok(());
+        return Ok(buf.len());
     }
 
     fn truncate(&self, size: u64) -> Result<(), String> {
-        let mut vfd = self.vfd.lock();
-        vfd.data.resize(size as usize, 0);
-        vfd.meta.size = size;
-        return Ok(());
+        let size = usize::try_from(size).map_err(|_| "File too large")?;
+        return self.vfd.lock().resize(size);
     }
 }
 
@@ -97,8 +175,11 @@ impl VirtFNode for VirtDir {
         return self.meta.clone();
     }
 
-    fn list(&self) -> Result<Vec<String>, String> {
-        return Ok(self.files.lock().keys().cloned().collect());
+    fn read_dir(&self, f: &mut dyn FnMut(&str, FType) -> bool) -> Result<(), String> {
+        for (name, node) in self.files.lock().iter() {
+            if !f(name, node.meta().ftype) { break; }
+        }
+        return Ok(());
     }
 
     fn walk(&self, name: &str) -> Result<Arc<dyn VirtFNode>, String> {
@@ -126,6 +207,29 @@ impl VirtFNode for VirtDir {
     }
 }
 
+struct VirtSymlink {
+    meta: FMeta,
+    target: String
+}
+
+impl VirtSymlink {
+    pub fn new(target: &str) -> Self {
+        let mut meta = FMeta::vfs_only(FType::SymLink);
+        meta.size = target.len() as u64;
+        return Self { meta, target: String::from(target) };
+    }
+}
+
+impl VirtFNode for VirtSymlink {
+    fn meta(&self) -> FMeta {
+        return self.meta.clone();
+    }
+
+    fn readlink(&self) -> Result<String, String> {
+        return Ok(self.target.clone());
+    }
+}
+
 enum VfsLockType<'a> {
     Read(RwLockReadGuard<'a, BTreeMap<String, Arc<dyn Partition>>>),
     Write(RwLockWriteGuard<'a, BTreeMap<String, Arc<dyn Partition>>>)
@@ -173,116 +277,385 @@ impl VirtualFileSystem { // Constructors
     }
 }
 
+fn check_perm(node: &dyn VirtFNode, creds: &Credentials, want: u16) -> Result<(), String> {
+    if node.meta().permits(creds, want) { return Ok(()); }
+    return Err("Permission denied".into());
+}
+
+// Every path below is resolved as `creds`, which need execute on each directory on the way
 impl VirtualFileSystem { // File operations
-    pub fn read(&self, path: &str, buf: &mut [u8], offset: u64) -> Result<(), String> {
+    // `access` is MAY_READ and/or MAY_WRITE
+    pub fn open(&self, creds: &Credentials, path: &str, access: u16) -> Result<Arc<OpenFile>, String> {
+        let lock = self.parts_read();
+        let node = self.walk_inner(creds, path, false, &lock)?;
+        check_perm(&*node, creds, access)?;
+        return Ok(Arc::new(OpenFile::new(node, access)));
+    }
+
+    pub fn read(&self, creds: &Credentials, path: &str, buf: &mut [u8], offset: u64) -> Result<usize, String> {
+        let lock = self.parts_read();
+        let file = self.walk_inner(creds, path, false, &lock)?;
+        check_perm(&*file, creds, MAY_READ)?;
+        return file.read(buf, offset);
+    }
+
+    pub fn write(&self, creds: &Credentials, path: &str, buf: &[u8], offset: u64) -> Result<usize, String> {
         let lock = self.parts_read();
-        return self.walk_inner(path, false, &lock).and_then(|file|
-            file.read(buf, offset)
-        );
+        let file = self.walk_inner(creds, path, false, &lock)?;
+        check_perm(&*file, creds, MAY_WRITE)?;
+        return file.write(buf, offset);
     }
 
-    pub fn write(&self, path: &str, buf: &[u8], offset: u64) -> Result<(), String> {
+    pub fn truncate(&self, creds: &Credentials, path: &str, size: u64) -> Result<(), String> {
         let lock = self.parts_read();
-        return self.walk_inner(path, false, &lock).and_then(|file|
-            file.write(buf, offset)
-        );
+        let file = self.walk_inner(creds, path, false, &lock)?;
+        check_perm(&*file, creds, MAY_WRITE)?;
+        return file.truncate(size);
     }
 
-    pub fn truncate(&self, path: &str, size: u64) -> Result<(), String> {
+    pub fn list(&self, creds: &Credentials, path: &str) -> Result<Vec<String>, String> {
         let lock = self.parts_read();
-        return self.walk_inner(path, false, &lock).and_then(|file|
-            file.truncate(size)
-        );
+        let dir = self.walk_inner(creds, path, false, &lock)?;
+        check_perm(&*dir, creds, MAY_READ)?;
+        return dir.list();
     }
 
-    pub fn list(&self, path: &str) -> Result<Vec<String>, String> {
+    pub fn read_dir(
+        &self, creds: &Credentials, path: &str, f: &mut dyn FnMut(&str, FType) -> bool
+    ) -> Result<(), String> {
         let lock = self.parts_read();
-        return self.walk_inner(path, false, &lock).and_then(|node| node.list());
+        let dir = self.walk_inner(creds, path, false, &lock)?;
+        check_perm(&*dir, creds, MAY_READ)?;
+        return dir.read_dir(f);
     }
 }
 
+// Paths may come straight from userland
+fn valid_component(part: &str) -> bool {
+    return part.len() <= MAX_NAME_LEN && !part.contains('\0');
+}
+
 impl VirtualFileSystem { // Directory operations
     fn walk_inner(
-        &self, path: &str, isparent: bool, parts: &VfsLockType<'_>
+        &self, creds: &Credentials, path: &str, isparent: bool, parts: &VfsLockType<'_>
     ) -> Result<Arc<dyn VirtFNode>, String> {
         let root = parts.get("/").ok_or("VFS not initialised")?.clone().root();
-        let partlen = path.split('/').count();
-        let mut stack = Vec::<Arc<dyn VirtFNode>>::new();
-        let mut path_now = String::new();
-
-        for (i, part) in path.split('/').enumerate() {
-            let last = stack.last().unwrap_or(&root);
-            if last.meta().ftype != FType::Directory {
-                return Err("Directory walk error".into());
-            }
+        let mut path = String::from(path);
+        let mut hops = 0;
 
-            if !["", ".", ".."].contains(&part) {
-                if isparent && i >= partlen - 1 { break; }
-                if !path_now.ends_with('/') { path_now.push('/') }
-                path_now.push_str(part);
+        loop {
+            let partlen = path.split('/').count();
+            let mut stack = Vec::<Arc<dyn VirtFNode>>::new();
+            let mut path_now = String::new();
+            let mut redirect = None;
 
-                if let Some(mounted) = parts.get(&path_now) {
-                    stack.push(mounted.clone().root());
-                } else {
-                    stack.push(last.walk(part)?);
+            if !path.split('/').all(valid_component) {
+                return Err("Invalid path component".into());
+            }
+
+            for (i, part) in path.split('/').enumerate() {
+                let last = stack.last().unwrap_or(&root);
+                let meta = last.meta();
+                if meta.ftype != FType::Directory {
+                    return Err("Directory walk error".into());
                 }
-            } else if part == ".." && !stack.is_empty() {
-                stack.pop();
-                if let Some(pos) = path_now.rfind('/') {
-                    path_now.truncate(pos.max(1));
+                if !meta.permits(creds, MAY_EXEC) {
+                    return Err("Permission denied".into());
+                }
+
+                if !["", ".", ".."].contains(&part) {
+                    if isparent && i >= partlen - 1 { break; }
+                    if !path_now.ends_with('/') { path_now.push('/') }
+                    path_now.push_str(part);
+
+                    let node = match parts.get(&path_now) {
+                        Some(mounted) => mounted.clone().root(),
+                        None => last.walk(part)?
+                    };
+
+                    if node.meta().ftype == FType::SymLink {
+                        hops += 1;
+                        if hops > MAX_SYMLINK_HOPS {
+                            return Err("Too many levels of symbolic links".into());
+                        }
+
+                        // Relative targets resolve against the directory holding the link
+                        let target = node.readlink()?;
+                        let mut new_path = if target.starts_with('/') {
+                            String::new()
+                        } else {
+                            String::from(&path_now[..path_now.rfind('/').unwrap_or(0)])
+                        };
+                        new_path.push('/');
+                        new_path.push_str(&target);
+                        for rest in path.split('/').skip(i + 1) {
+                            new_path.push('/');
+                            new_path.push_str(rest);
+                        }
+                        redirect = Some(new_path);
+                        break;
+                    }
+
+                    stack.push(node);
+                } else if part == ".." && !stack.is_empty() {
+                    stack.pop();
+                    if let Some(pos) = path_now.rfind('/') {
+                        path_now.truncate(pos.max(1));
+                    }
                 }
             }
+
+            match redirect {
+                Some(new_path) => path = new_path,
+                None => return Ok(stack.last().unwrap_or(&root).clone())
+            }
         }
-        return Ok(stack.last().unwrap_or(&root).clone());
     }
 
-    pub fn walk(&self, path: &str) -> Result<Arc<dyn VirtFNode>, String> {
+    pub fn walk(&self, creds: &Credentials, path: &str) -> Result<Arc<dyn VirtFNode>, String> {
         let lock = self.parts_read();
-        return self.walk_inner(path, false, &lock);
+        return self.walk_inner(creds, path, false, &lock);
     }
 
-    pub fn walk_parent(&self, path: &str) -> Result<Arc<dyn VirtFNode>, String> {
+    pub fn walk_parent(&self, creds: &Credentials, path: &str) -> Result<Arc<dyn VirtFNode>, String> {
         let lock = self.parts_read();
-        return self.walk_inner(path, true, &lock);
+        return self.walk_inner(creds, path, true, &lock);
     }
 
-    pub fn create(&self, path: &str, ftype: FType) -> Result<(), String> {
+    // Adding or removing a name needs write on the directory holding it
+    pub fn create(&self, creds: &Credentials, path: &str, ftype: FType) -> Result<(), String> {
         let lock = self.parts_read();
-        let dir = self.walk_inner(path, true, &lock)?;
+        let dir = self.walk_inner(creds, path, true, &lock)?;
         let filename = get_file_name(path).ok_or("Invalid path")?;
+        check_perm(&*dir, creds, MAY_WRITE)?;
         return dir.create(filename, ftype);
     }
 
-    pub fn link(&self, path: &str, node: Arc<dyn VirtFNode>) -> Result<(), String> {
+    // O_CREAT, the existing node is followed through symlinks and mounts like a walk
+    pub fn open_or_create(&self, creds: &Credentials, path: &str, ftype: FType) -> Result<Arc<dyn VirtFNode>, String> {
+        return self.create_inner(creds, path, ftype, false);
+    }
+
+    // O_CREAT | O_EXCL
+    pub fn create_excl(&self, creds: &Credentials, path: &str, ftype: FType) -> Result<Arc<dyn VirtFNode>, String> {
+        return self.create_inner(creds, path, ftype, true);
+    }
+
+    fn create_inner(
+        &self, creds: &Credentials, path: &str, ftype: FType, excl: bool
+    ) -> Result<Arc<dyn VirtFNode>, String> {
+        // Write lock so no other path operation runs between the lookup and the link
+        let lock = self.parts_write();
+        let dir = self.walk_inner(creds, path, true, &lock)?;
+        let filename = get_file_name(path).ok_or("Invalid path")?;
+
+        if dir.walk(filename).is_ok() {
+            if excl { return Err("File already exists".into()); }
+            return self.walk_inner(creds, path, false, &lock);
+        }
+
+        check_perm(&*dir, creds, MAY_WRITE)?;
+        dir.create(filename, ftype)?;
+        return dir.walk(filename);
+    }
+
+    pub fn link(&self, creds: &Credentials, path: &str, node: Arc<dyn VirtFNode>) -> Result<(), String> {
         let lock = self.parts_read();
-        let dir = self.walk_inner(path, true, &lock)?;
+        let dir = self.walk_inner(creds, path, true, &lock)?;
         let filename = get_file_name(path).ok_or("Invalid path")?;
+        check_perm(&*dir, creds, MAY_WRITE)?;
         return dir.link(filename, node);
     }
 
-    pub fn unlink(&self, path: &str) -> Result<(), String> {
+    pub fn unlink(&self, creds: &Credentials, path: &str) -> Result<(), String> {
         let lock = self.parts_read();
-        let dir = self.walk_inner(path, true, &lock)?;
+        let dir = self.walk_inner(creds, path, true, &lock)?;
         let filename = get_file_name(path).ok_or("Invalid path")?;
+        check_perm(&*dir, creds, MAY_WRITE)?;
         return dir.remove(filename);
     }
+
+    pub fn rename(&self, creds: &Credentials, from: &str, to: &str) -> Result<(), String> {
+        // Write lock keeps concurrent walks from seeing both or neither name
+        let lock = self.parts_write();
+        if lock.contains_key(from) { return Err("Device busy".into()); }
+
+        let src_dir = self.walk_inner(creds, from, true, &lock)?;
+        let src_name = get_file_name(from).ok_or("Invalid path")?;
+        check_perm(&*src_dir, creds, MAY_WRITE)?;
+        let node = src_dir.walk(src_name)?;
+
+        let dst_dir = self.walk_inner(creds, to, true, &lock)?;
+        check_perm(&*dst_dir, creds, MAY_WRITE)?;
+        let dst_name = get_file_name(to).ok_or("Invalid path")?;
+        if dst_dir.walk(dst_name).is_ok() { return Err("File already exists".into()); }
+        if src_dir.meta().hostdev != dst_dir.meta().hostdev {
+            return Err("Cross-device link".into());
+        }
+
+        if node.meta().ftype == FType::Directory {
+            let meta = node.meta();
+            let comps = to.split('/').filter(|p| !p.is_empty()).collect::<Vec<_>>();
+            let mut prefix = String::new();
+            for part in &comps[..comps.len().saturating_sub(1)] {
+                prefix.push('/');
+                prefix.push_str(part);
+                let anc = self.walk_inner(creds, &prefix, false, &lock)?.meta();
+                if anc.fid == meta.fid && anc.hostdev == meta.hostdev {
+                    return Err("Cannot move a directory into itself".into());
+                }
+            }
+        }
+
+        dst_dir.link(dst_name, node)?;
+        if let Err(e) = src_dir.remove(src_name) {
+            let _ = dst_dir.remove(dst_name);
+            return Err(e);
+        }
+        return Ok(());
+    }
+
+    pub fn symlink(&self, creds: &Credentials, path: &str, target: &str) -> Result<(), String> {
+        return self.link(creds, path, Arc::new(VirtSymlink::new(target)));
+    }
+
+    pub fn readlink(&self, creds: &Credentials, path: &str) -> Result<String, String> {
+        let lock = self.parts_read();
+        let dir = self.walk_inner(creds, path, true, &lock)?;
+        let filename = get_file_name(path).ok_or("Invalid path")?;
+        return dir.walk(filename)?.readlink();
+    }
+
+    // Depth-first, mount points included. Symlinks are reported but never followed.
+    // The mount table stays read-locked, so `f` must not mount or unmount.
+    // Runs as root, it is meant for the kernel's own diagnostics.
+    pub fn walk_tree(&self, path: &str, mut f: impl FnMut(&str, &FMeta)) -> Result<(), String> {
+        let lock = self.parts_read();
+        let node = self.walk_inner(&Credentials::ROOT, path, false, &lock)?;
+        let path = normalize(path);
+        return self.walk_tree_inner(&path, node, &lock, &mut Vec::new(), &mut f);
+    }
+
+    fn walk_tree_inner(
+        &self, path: &str, node: Arc<dyn VirtFNode>, parts: &VfsLockType<'_>,
+        ancestors: &mut Vec<(u64, u64)>, f: &mut dyn FnMut(&str, &FMeta)
+    ) -> Result<(), String> {
+        let meta = node.meta();
+        f(if path.is_empty() { "/" } else { path }, &meta);
+        if meta.ftype != FType::Directory { return Ok(()); }
+
+        // A directory linked somewhere below itself
+        let id = (meta.hostdev, meta.fid);
+        if ancestors.contains(&id) { return Ok(()); }
+        ancestors.push(id);
+
+        for name in node.list()? {
+            let child_path = format!("{}/{}", path, name);
+            let child = match parts.get(&child_path) {
+                Some(mounted) => mounted.clone().root(),
+                None => node.walk(&name)?
+            };
+            self.walk_tree_inner(&child_path, child, parts, ancestors, f)?;
+        }
+
+        ancestors.pop();
+        return Ok(());
+    }
 }
 
 impl VirtualFileSystem { // Mount operations
     pub fn mount(&self, path: &str, part: Arc<dyn Partition>) -> Result<(), String> {
         let mut lock = self.parts_write();
         if lock.contains_key(path) { return Err("Mount point already exists".into()); }
-        let dir = self.walk_inner(path, false, &lock).map_err(|_| "Mount point does not exist")?;
+        let dir = self.walk_inner(&Credentials::ROOT, path, false, &lock)
+            .map_err(|_| "Mount point does not exist")?;
         if dir.meta().ftype != FType::Directory { return Err("Mount point is not a directory".into()); }
         lock.insert(path.into(), part);
         return Ok(());
     }
 
-    pub fn unmount(&mut self, path: &str) -> Result<(), String> {
+    // Busy while anything but the mount table holds the partition, such as open FAT files.
+    // In-memory partitions own their nodes' data, so nodes outliving them stay valid.
+    pub fn unmount(&self, path: &str) -> Result<(), String> {
+        return self.unmount_inner(path, false);
+    }
+
+    // Usage of the partition holding `path`, the mount is picked by path prefix
+    // so a symlink into another partition reports the one holding the link
+    pub fn statfs(&self, creds: &Credentials, path: &str) -> Result<StatFs, String> {
+        let lock = self.parts_read();
+        self.walk_inner(creds, path, false, &lock)?;
+        let path = normalize(path);
+
+        let part = lock.iter()
+            .filter(|(mnt, _)| {
+                *mnt == "/" || path == **mnt
+                || (path.starts_with(mnt.as_str()) && path[mnt.len()..].starts_with('/'))
+            })
+            .max_by_key(|(mnt, _)| mnt.len())
+            .map(|(_, part)| part)
+            .ok_or("VFS not initialised")?;
+        return part.statfs();
+    }
+
+    pub fn sync(&self, path: &str) -> Result<(), String> {
+        let lock = self.parts_read();
+        return lock.get(path).ok_or("No such mount point")?.sync();
+    }
+
+    // Every mount is tried, the first error is reported
+    pub fn sync_all(&self) -> Result<(), String> {
+        let lock = self.parts_read();
+        let mut res = Ok(());
+        for part in lock.values() {
+            if let Err(e) = part.sync() {
+                res = res.and(Err(e));
+            }
+        }
+        return res;
+    }
+
+    // Detaches even when busy, remaining nodes keep the partition alive
+    pub fn force_unmount(&self, path: &str) -> Result<(), String> {
+        return self.unmount_inner(path, true);
+    }
+
+    fn unmount_inner(&self, path: &str, force: bool) -> Result<(), String> {
         let mut lock = self.parts_write();
         if path == "/" { return Err("Cannot unmount root".into()); }
-        lock.remove(path).map(|_| ()).ok_or("No such mount point".into())
+        let part = lock.get(path).ok_or("No such mount point")?;
+
+        if !force {
+            let nested = lock.keys().any(|p|
+                p.len() > path.len() && p.starts_with(path) && p[path.len()..].starts_with('/')
+            );
+            if nested || Arc::strong_count(part) > 1 {
+                return Err("Device busy".into());
+            }
+            part.sync()?;
+        } else {
+            let _ = part.sync();
+        }
+
+        lock.remove(path);
+        return Ok(());
+    }
+}
+
+const MAX_SYMLINK_HOPS: usize = 40;
+const MAX_NAME_LEN: usize = 255;
+
+// Lexical only, `..` above the root stays at the root and the root itself is empty
+fn normalize(path: &str) -> String {
+    let mut comps = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => { comps.pop(); }
+            _ => comps.push(part)
+        }
     }
+    return comps.iter().fold(String::new(), |acc, c| acc + "/" + c);
 }
 
 fn get_file_name(path: &str) -> Option<&str> {
@@ -293,28 +666,67 @@ fn get_file_name(path: &str) -> Option<&str> {
 
 pub static VFS: VirtualFileSystem = VirtualFileSystem::empty();
 
+// Mount point of the partition named by `root=`, block0p0 by default
+pub fn root_mount() -> String {
+    let root = cmdline_get("root").filter(|r| !r.is_empty()).unwrap_or("block0p0");
+    return format!("/mnt/{}", root);
+}
+
 pub fn init_filesys() -> Result<(), String> {
     VFS.init();
+    let creds = &Credentials::ROOT;
+    if let Some(size) = tmpfs_size() {
+        set_tmpfs_limit(size);
+    }
 
     // mkdir /dev
-    VFS.create("/dev", FType::Directory)?;
-    VFS.create("/mnt", FType::Directory)?;
+    VFS.create(creds, "/dev", FType::Directory)?;
+    VFS.create(creds, "/mnt", FType::Directory)?;
+
+    if let Some(archive) = initrd() {
+        VFS.create(creds, "/init", FType::Directory)?;
+        VFS.mount("/init", Arc::new(TarPart::new(archive)))?;
+    }
 
-    let devdir = VFS.walk("/dev")?;
+    let devdir = VFS.walk(creds, "/dev")?;
+    devdir.link("console", Arc::new(Console::new()))?;
+    devdir.link("random", Arc::new(Random::new()))?;
+    devdir.link("memmap", Arc::new(MemMap::new()))?;
+    #[cfg(target_arch = "x86_64")]
+    devdir.link("kbd", Arc::new(dev::Keyboard::new()))?;
 
     for (idx, dev) in BLOCK_DEVICES.read().iter().enumerate() {
         let devname = format!("block{}", idx);
+        let dev: Arc<dyn BlockDevice> = Arc::new(BlockCache::new(dev.clone()));
 
         let block = Arc::new(DevFile::new(dev.clone()));
         devdir.link(&devname, block)?;
-        let uefi_partable = UEFIPartition::new(dev.clone())?;
+        let uefi_partable = match UEFIPartition::new(dev.clone()) {
+            Ok(table) => table,
+            Err(e) => {
+                // No partition table, the disk may hold one filesystem as a whole (superfloppy)
+                match probe_filesystem(dev.clone()) {
+                    Some(fs) => {
+                        let name = format!("/mnt/{}", devname);
+                        VFS.create(creds, &name, FType::Directory)?;
+                        VFS.mount(&name, fs)?;
+                    }
+                    None => printlnk!("{}: {}", devname, e)
+                }
+                continue;
+            }
+        };
         for (i, part) in uefi_partable.get_parts().into_iter().enumerate() {
+            if let Some(info) = part.info() {
+                let kind = if info.is_esp() { " (ESP)" } else { "" };
+                printlnk!("{}p{}: {}{}", devname, i, info.name, kind);
+            }
             let partdev = Arc::new(part);
 
-            if let Some(fat) = FileAllocTable::new(partdev.clone()) {
+            if let Some(fs) = probe_filesystem(partdev.clone()) {
                 let name = format!("/mnt/{}p{}", devname, i);
-                VFS.create(&name, FType::Directory)?;
-                VFS.mount(&name, fat)?;
+                VFS.create(creds, &name, FType::Directory)?;
+                VFS.mount(&name, fs)?;
             }
             devdir.link(&format!("{}p{}", devname, i), partdev)?;
         }
@@ -322,30 +734,26 @@ pub fn init_filesys() -> Result<(), String> {
 
     // echo buf > /main.rs
     let mut buf = "fn main() {\n    println!(\"Hello, world!\");\n}".as_bytes().to_vec();
-    VFS.link("/main.rs", Arc::new(VirtFile::new()))?;
-    VFS.write("/main.rs", &buf, 0)?;
+    VFS.link(creds, "/main.rs", Arc::new(VirtFile::new()))?;
+    VFS.write(creds, "/main.rs", &buf, 0)?;
 
     // mv
-    VFS.walk("/main.rs").and_then(|file| {
-        VFS.link("/src", Arc::new(VirtDir::new()))?;
-        VFS.link("/src/main.rs", file)?;
-        VFS.unlink("/main.rs")?;
-        return Ok(());
-    })?;
+    VFS.link(creds, "/src", Arc::new(VirtDir::new()))?;
+    VFS.rename(creds, "/main.rs", "/src/main.rs")?;
 
     // xd /src/main.rs
     buf.iter_mut().for_each(|b| *b = 0);
     buf.resize(13, 0);
     // // walk in to read
-    // let file = VFS.walk("/src/main.rs")?;
+    // let file = VFS.walk(creds, "/src/main.rs")?;
     // file.read(&mut buf, 0);
     // or direct read from VFS
-    VFS.read("/src/main.rs", &mut buf, 26)?;
+    VFS.read(creds, "/src/main.rs", &mut buf, 26)?;
     dump_bytes(&buf);
 
     // ls /dev
     let dir = "/dev";
-    let vdirn = VFS.walk(dir)?;
+    let vdirn = VFS.walk(creds, dir)?;
     vdirn.list().iter().for_each(|entries| {
         printlnk!("in {}:", dir);
         for entry in entries {
@@ -364,13 +772,13 @@ pub fn init_filesys() -> Result<(), String> {
             printlnk!("    File ID     {}", meta.fid);
             printlnk!("    Host Device {}", meta.hostdev);
             if let Some(vdevn) = vfn.as_blkdev() {
-                printlnk!("    Device ID   {}", vdevn.devid());
+                printlnk!("    Device ID   {}", DevId::new(vdevn.devid()));
             }
         }
     });
 
     // xd -n 64 /mnt/block0p0/unix
-    match VFS.walk("/mnt/block0p0/unix") {
+    match VFS.walk(creds, "/mnt/block0p0/unix") {
         Ok(fnode) => {
             printlnk!("Found /mnt/block0p0/unix");
             printlnk!("    size: {} bytes", fnode.meta().size);
@@ -387,3 +795,337 @@ pub fn init_filesys() -> Result<(), String> {
 
     return Ok(());
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesys::vfn::SeekFrom;
+    use alloc::vec::Vec;
+
+    const ROOT: &Credentials = &Credentials::ROOT;
+
+    fn vfs() -> VirtualFileSystem {
+        let vfs = VirtualFileSystem::empty();
+        vfs.init();
+        return vfs;
+    }
+
+    fn file_with(vfs: &VirtualFileSystem, path: &str, data: &[u8]) {
+        vfs.create(ROOT, path, FType::Regular).unwrap();
+        vfs.write(ROOT, path, data, 0).unwrap();
+    }
+
+    fn read_all(vfs: &VirtualFileSystem, path: &str) -> Result<Vec<u8>, String> {
+        let mut buf = [0; 64];
+        let n = vfs.read(ROOT, path, &mut buf, 0)?;
+        return Ok(buf[..n].to_vec());
+    }
+
+    #[test]
+    fn symlink_relative() {
+        let vfs = vfs();
+        vfs.create(ROOT, "/bin", FType::Directory).unwrap();
+        vfs.create(ROOT, "/lib", FType::Directory).unwrap();
+        file_with(&vfs, "/lib/sh", b"shell");
+
+        vfs.symlink(ROOT, "/bin/sh", "../lib/sh").unwrap();
+        assert_eq!(vfs.readlink(ROOT, "/bin/sh").unwrap(), "../lib/sh");
+        assert_eq!(read_all(&vfs, "/bin/sh").unwrap(), b"shell");
+    }
+
+    #[test]
+    fn symlink_absolute() {
+        let vfs = vfs();
+        vfs.create(ROOT, "/lib", FType::Directory).unwrap();
+        file_with(&vfs, "/lib/sh", b"shell");
+
+        vfs.symlink(ROOT, "/usr", "/lib").unwrap();
+        assert_eq!(read_all(&vfs, "/usr/sh").unwrap(), b"shell");
+        assert_eq!(vfs.walk(ROOT, "/usr").unwrap().meta().ftype, FType::Directory);
+    }
+
+    #[test]
+    fn symlink_loop_fails() {
+        let vfs = vfs();
+        vfs.symlink(ROOT, "/loop", "/loop").unwrap();
+        assert_eq!(vfs.walk(ROOT, "/loop").err().unwrap(), "Too many levels of symbolic links");
+        assert_eq!(vfs.readlink(ROOT, "/loop").unwrap(), "/loop");
+    }
+
+    #[test]
+    fn rename_moves_the_node() {
+        let vfs = vfs();
+        vfs.create(ROOT, "/src", FType::Directory).unwrap();
+        file_with(&vfs, "/main.rs", b"fn main");
+
+        vfs.rename(ROOT, "/main.rs", "/src/main.rs").unwrap();
+        assert!(vfs.walk(ROOT, "/main.rs").is_err());
+        assert_eq!(read_all(&vfs, "/src/main.rs").unwrap(), b"fn main");
+    }
+
+    #[test]
+    fn rename_onto_existing_fails() {
+        let vfs = vfs();
+        file_with(&vfs, "/a", b"a");
+        file_with(&vfs, "/b", b"b");
+
+        assert!(vfs.rename(ROOT, "/a", "/b").is_err());
+        assert_eq!(read_all(&vfs, "/a").unwrap(), b"a");
+        assert_eq!(read_all(&vfs, "/b").unwrap(), b"b");
+    }
+
+    #[test]
+    fn rename_into_own_descendant_fails() {
+        let vfs = vfs();
+        vfs.create(ROOT, "/a", FType::Directory).unwrap();
+        vfs.create(ROOT, "/a/b", FType::Directory).unwrap();
+
+        assert!(vfs.rename(ROOT, "/a", "/a/b/c").is_err());
+        assert!(vfs.walk(ROOT, "/a/b").is_ok());
+        assert!(vfs.walk(ROOT, "/a/b/c").is_err());
+    }
+
+    #[test]
+    fn bad_components_are_rejected() {
+        let vfs = vfs();
+        let err = Some("Invalid path component".into());
+        assert_eq!(vfs.create(ROOT, "/a\0b", FType::Regular).err(), err);
+        assert_eq!(vfs.walk(ROOT, "/tmp\0/x").err(), err);
+
+        let longest = "n".repeat(MAX_NAME_LEN);
+        vfs.create(ROOT, &alloc::format!("/{}", longest), FType::Regular).unwrap();
+        assert!(vfs.walk(ROOT, &alloc::format!("/{}", longest)).is_ok());
+        assert_eq!(vfs.walk(ROOT, &alloc::format!("/{}n", longest)).err(), err);
+        assert_eq!(vfs.create(ROOT, &alloc::format!("/{}n/x", longest), FType::Regular).err(), err);
+    }
+
+    #[test]
+    fn walk_tree_visits_every_node_once() {
+        let vfs = vfs();
+        vfs.create(ROOT, "/a", FType::Directory).unwrap();
+        file_with(&vfs, "/a/b", b"b");
+        vfs.create(ROOT, "/mnt", FType::Directory).unwrap();
+        vfs.mount("/mnt", Arc::new(VirtPart::new())).unwrap();
+        file_with(&vfs, "/mnt/x", b"x");
+        vfs.symlink(ROOT, "/a/up", "/a").unwrap(); // Reported, not followed
+
+        let mut seen = Vec::new();
+        vfs.walk_tree("/", |path, _| seen.push(String::from(path))).unwrap();
+        seen.sort();
+        assert_eq!(seen, ["/", "/a", "/a/b", "/a/up", "/mnt", "/mnt/x"]);
+
+        seen.clear();
+        vfs.walk_tree("/mnt", |path, meta| seen.push(alloc::format!("{} {:?}", path, meta.ftype))).unwrap();
+        assert_eq!(seen, ["/mnt Directory", "/mnt/x Regular"]);
+    }
+
+    #[test]
+    fn busy_mounts_stay_mounted() {
+        let vfs = vfs();
+        vfs.create(ROOT, "/mnt", FType::Directory).unwrap();
+        let part = Arc::new(VirtPart::new());
+        vfs.mount("/mnt", part.clone()).unwrap();
+        file_with(&vfs, "/mnt/x", b"x");
+
+        assert_eq!(vfs.unmount("/mnt").err(), Some("Device busy".into()));
+        assert_eq!(read_all(&vfs, "/mnt/x").unwrap(), b"x");
+        drop(part);
+        vfs.unmount("/mnt").unwrap();
+        assert!(vfs.walk(ROOT, "/mnt/x").is_err());
+
+        let part = Arc::new(VirtPart::new());
+        vfs.mount("/mnt", part.clone()).unwrap();
+        vfs.force_unmount("/mnt").unwrap();
+        assert_eq!(vfs.unmount("/mnt").err(), Some("No such mount point".into()));
+        assert_eq!(vfs.unmount("/").err(), Some("Cannot unmount root".into()));
+    }
+
+    #[test]
+    fn nested_mount_keeps_the_parent_busy() {
+        let vfs = vfs();
+        vfs.create(ROOT, "/mnt", FType::Directory).unwrap();
+        vfs.mount("/mnt", Arc::new(VirtPart::new())).unwrap();
+        vfs.create(ROOT, "/mnt/usb", FType::Directory).unwrap();
+        vfs.mount("/mnt/usb", Arc::new(VirtPart::new())).unwrap();
+
+        assert_eq!(vfs.unmount("/mnt").err(), Some("Device busy".into()));
+        vfs.unmount("/mnt/usb").unwrap();
+        vfs.unmount("/mnt").unwrap();
+    }
+
+    // Counts its syncs, failing them on request
+    struct Syncing {
+        root: Arc<dyn VirtFNode>,
+        syncs: AtomicUsize,
+        fail: bool
+    }
+
+    impl Syncing {
+        fn new(fail: bool) -> Arc<Self> {
+            return Arc::new(Self { root: Arc::new(VirtDir::new()), syncs: AtomicUsize::new(0), fail });
+        }
+
+        fn syncs(&self) -> usize {
+            return self.syncs.load(AtomOrd::Relaxed);
+        }
+    }
+
+    impl Partition for Syncing {
+        fn root(self: Arc<Self>) -> Arc<dyn VirtFNode> {
+            return self.root.clone();
+        }
+
+        fn sync(&self) -> Result<(), String> {
+            self.syncs.fetch_add(1, AtomOrd::Relaxed);
+            return if self.fail { Err("Write error".into()) } else { Ok(()) };
+        }
+    }
+
+    #[test]
+    fn sync_all_reaches_every_mount_once() {
+        let vfs = vfs();
+        let parts = [Syncing::new(false), Syncing::new(true), Syncing::new(false)];
+        for (part, path) in parts.iter().zip(["/a", "/b", "/c"]) {
+            vfs.create(ROOT, path, FType::Directory).unwrap();
+            vfs.mount(path, part.clone()).unwrap();
+        }
+
+        // One failure does not stop the rest
+        assert_eq!(vfs.sync_all().err(), Some("Write error".into()));
+        assert!(parts.iter().all(|part| part.syncs() == 1));
+
+        vfs.sync("/c").unwrap();
+        assert_eq!(parts.iter().map(|part| part.syncs()).collect::<Vec<_>>(), [1, 1, 2]);
+        assert_eq!(vfs.sync("/d").err(), Some("No such mount point".into()));
+    }
+
+    #[test]
+    fn tmpfs_cap_is_enforced() {
+        let vfs = vfs();
+        let (base, _) = tmpfs_usage();
+        set_tmpfs_limit(base + 100);
+
+        file_with(&vfs, "/a", &[1; 60]);
+        vfs.create(ROOT, "/b", FType::Regular).unwrap();
+        assert_eq!(vfs.write(ROOT, "/b", &[2; 50], 0), Err("No space left".into()));
+        assert_eq!(read_all(&vfs, "/b").unwrap(), []);
+        assert_eq!(tmpfs_usage(), (base + 60, base + 100));
+
+        // Growth through truncate counts the same
+        vfs.write(ROOT, "/b", &[2; 40], 0).unwrap();
+        assert_eq!(vfs.truncate(ROOT, "/b", 41), Err("No space left".into()));
+        assert_eq!(vfs.statfs(ROOT, "/").unwrap().free, 0);
+
+        // Shrinking and deleting hand the bytes back
+        vfs.truncate(ROOT, "/a", 10).unwrap();
+        vfs.unlink(ROOT, "/b").unwrap();
+        assert_eq!(tmpfs_usage().0, base + 10);
+        file_with(&vfs, "/c", &[3; 90]);
+        assert_eq!(tmpfs_usage().0, base + 100);
+    }
+
+    #[test]
+    fn read_dir_stops_when_asked() {
+        let vfs = vfs();
+        vfs.create(ROOT, "/d", FType::Directory).unwrap();
+        for name in ["a", "b", "c", "d", "e"] {
+            vfs.create(ROOT, &format!("/d/{}", name), FType::Regular).unwrap();
+        }
+        vfs.create(ROOT, "/d/sub", FType::Directory).unwrap();
+
+        let mut seen = Vec::new();
+        vfs.read_dir(ROOT, "/d", &mut |name, ftype| { seen.push((String::from(name), ftype)); true }).unwrap();
+        assert_eq!(seen.len(), 6);
+        assert_eq!(seen[5], (String::from("sub"), FType::Directory));
+
+        let mut calls = 0;
+        vfs.read_dir(ROOT, "/d", &mut |_, _| { calls += 1; calls < 3 }).unwrap();
+        assert_eq!(calls, 3);
+        assert_eq!(vfs.list(ROOT, "/d").unwrap(), ["a", "b", "c", "d", "e", "sub"]);
+    }
+
+    #[test]
+    fn open_files_keep_their_own_cursor() {
+        let vfs = vfs();
+        file_with(&vfs, "/f", b"0123456789");
+        let a = vfs.open(ROOT, "/f", MAY_READ).unwrap();
+        let b = vfs.open(ROOT, "/f", MAY_READ | MAY_WRITE).unwrap();
+
+        let mut buf = [0; 4];
+        assert_eq!(a.read(&mut buf), Ok(4));
+        assert_eq!(&buf, b"0123");
+        assert_eq!(a.read(&mut buf), Ok(4));
+        assert_eq!(&buf, b"4567");
+        assert_eq!(a.offset(), 8);
+
+        // b starts from the top and its write lands under a's cursor
+        assert_eq!(b.read(&mut buf[..2]), Ok(2));
+        assert_eq!(b.seek(SeekFrom::Current(6)), Ok(8));
+        assert_eq!(b.write(b"xy"), Ok(2));
+        assert_eq!((a.offset(), b.offset()), (8, 10));
+        assert_eq!(a.read(&mut buf), Ok(2));
+        assert_eq!(&buf[..2], b"xy");
+        assert_eq!(a.read(&mut buf), Ok(0));
+
+        assert_eq!(a.write(b"no"), Err("Bad file descriptor".into()));
+        assert_eq!(b.seek(SeekFrom::End(-3)), Ok(7));
+        assert!(b.seek(SeekFrom::Current(-8)).is_err());
+    }
+
+    // A 0o644 file owned by uid 1000, gid 100
+    struct Owned(FMeta);
+
+    impl VirtFNode for Owned {
+        fn meta(&self) -> FMeta { self.0.clone() }
+        fn read(&self, _buf: &mut [u8], _offset: u64) -> Result<usize, String> { Ok(0) }
+        fn write(&self, buf: &[u8], _offset: u64) -> Result<usize, String> { Ok(buf.len()) }
+    }
+
+    #[test]
+    fn permission_matrix() {
+        let vfs = vfs();
+        let mut meta = FMeta::vfs_only(FType::Regular);
+        (meta.uid, meta.gid, meta.perm) = (1000, 100, 0o644);
+        vfs.link(ROOT, "/f", Arc::new(Owned(meta))).unwrap();
+
+        let owner = &Credentials { uid: 1000, gid: 100 };
+        let group = &Credentials { uid: 1001, gid: 100 };
+        let other = &Credentials { uid: 1002, gid: 200 };
+        let denied = String::from("Permission denied");
+        let mut buf = [0; 4];
+
+        for creds in [ROOT, owner, group, other] {
+            assert_eq!(vfs.read(creds, "/f", &mut buf, 0), Ok(0));
+        }
+        assert_eq!(vfs.write(owner, "/f", b"ok", 0), Ok(2));
+        assert_eq!(vfs.write(ROOT, "/f", b"ok", 0), Ok(2));
+        assert_eq!(vfs.write(group, "/f", b"no", 0), Err(denied.clone()));
+        assert_eq!(vfs.write(other, "/f", b"no", 0), Err(denied.clone()));
+        assert!(vfs.open(other, "/f", MAY_READ | MAY_WRITE).is_err());
+
+        // The directory is root's 0o755: everyone may look, only root may change it
+        assert_eq!(vfs.unlink(owner, "/f"), Err(denied.clone()));
+        assert_eq!(vfs.create(other, "/g", FType::Regular), Err(denied));
+        assert!(vfs.list(other, "/").is_ok());
+        vfs.unlink(ROOT, "/f").unwrap();
+    }
+
+    #[test]
+    fn create_then_open_is_one_node() {
+        let vfs = vfs();
+        let first = vfs.open_or_create(ROOT, "/f", FType::Regular).unwrap();
+        first.write(b"data", 0).unwrap();
+
+        // The second call opens what the first made instead of replacing it
+        let second = vfs.open_or_create(ROOT, "/f", FType::Regular).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(read_all(&vfs, "/f").unwrap(), b"data");
+        assert_eq!(vfs.list(ROOT, "/").unwrap().iter().filter(|name| *name == "f").count(), 1);
+
+        assert_eq!(vfs.create_excl(ROOT, "/f", FType::Regular).err(), Some("File already exists".into()));
+        let excl = vfs.create_excl(ROOT, "/g", FType::Regular).unwrap();
+        assert_eq!(excl.meta().size, 0);
+        assert!(vfs.create_excl(ROOT, "/g", FType::Regular).is_err());
+        assert!(vfs.open_or_create(ROOT, "/missing/f", FType::Regular).is_err());
+    }
+}
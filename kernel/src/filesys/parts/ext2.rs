@@ -0,0 +1,564 @@
+#![allow(non_camel_case_types)]
+
+use crate::{
+    device::block::BlockDevice,
+    filesys::{
+        dev::read_span,
+        parts::{Partition, StatFs},
+        vfn::{FMeta, FType, VirtFNode, read_len}
+    },
+    warn
+};
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+use zerocopy::{FromBytes, FromZeros, LE, U16, U32};
+
+type u16le = U16<LE>;
+type u32le = U32<LE>;
+
+const SUPER_OFFSET: u64 = 1024;
+const MAGIC: u16 = 0xef53;
+const ROOT_INO: u32 = 2;
+const GOOD_OLD_INODE_SIZE: usize = 128;
+
+// Block pointers in the inode: 12 direct, then single, double and triple indirect
+const NDIR_BLOCKS: u64 = 12;
+const IND_BLOCK: usize = 12;
+const DIND_BLOCK: usize = 13;
+const TIND_BLOCK: usize = 14;
+
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+const INCOMPAT_SUPPORTED: u32 = INCOMPAT_FILETYPE;
+const EXTENTS_FL: u32 = 0x0008_0000;
+
+// Up to s_feature_ro_compat, the rest of the 1024 bytes is not needed
+#[repr(C)]
+#[derive(Clone, Copy, FromBytes)]
+struct SuperBlock {
+    inodes_count: u32le,
+    blocks_count: u32le,
+    r_blocks_count: u32le,
+    free_blocks_count: u32le,
+    free_inodes_count: u32le,
+    first_data_block: u32le,
+    log_block_size: u32le,
+    log_frag_size: u32le,
+    blocks_per_group: u32le,
+    frags_per_group: u32le,
+    inodes_per_group: u32le,
+    mtime: u32le,
+    wtime: u32le,
+    mnt_count: u16le,
+    max_mnt_count: u16le,
+    magic: u16le,
+    state: u16le,
+    errors: u16le,
+    minor_rev_level: u16le,
+    lastcheck: u32le,
+    checkinterval: u32le,
+    creator_os: u32le,
+    rev_level: u32le,
+    def_resuid: u16le,
+    def_resgid: u16le,
+    // Dynamic revision (1) only
+    first_ino: u32le,
+    inode_size: u16le,
+    block_group_nr: u16le,
+    feature_compat: u32le,
+    feature_incompat: u32le,
+    feature_ro_compat: u32le
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, FromBytes)]
+struct GroupDesc {
+    block_bitmap: u32le,
+    inode_bitmap: u32le,
+    inode_table: u32le,
+    free_blocks_count: u16le,
+    free_inodes_count: u16le,
+    used_dirs_count: u16le,
+    pad: u16le,
+    reserved: [u8; 12]
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, FromBytes)]
+struct Inode {
+    mode: u16le,
+    uid: u16le,
+    size: u32le,
+    atime: u32le,
+    ctime: u32le,
+    mtime: u32le,
+    dtime: u32le,
+    gid: u16le,
+    links_count: u16le,
+    blocks: u32le, // In 512-byte units
+    flags: u32le,
+    osd1: u32le,
+    block: [u32le; 15],
+    generation: u32le,
+    file_acl: u32le,
+    size_high: u32le, // i_dir_acl on revision 0
+    faddr: u32le,
+    osd2: [u8; 12]
+}
+
+impl Inode {
+    // The S_IFMT nibble lines up with FType
+    fn ftype(&self) -> Option<FType> {
+        return match self.mode.get() >> 12 {
+            0x1 => Some(FType::Fifo),
+            0x2 => Some(FType::CharDev),
+            0x4 => Some(FType::Directory),
+            0x6 => Some(FType::BlockDev),
+            0x8 => Some(FType::Regular),
+            0xa => Some(FType::SymLink),
+            0xc => Some(FType::Socket),
+            _ => None
+        };
+    }
+}
+
+// file_type of a directory entry, 0 when unknown
+fn dirent_ftype(ty: u8) -> Option<FType> {
+    return match ty {
+        1 => Some(FType::Regular),
+        2 => Some(FType::Directory),
+        3 => Some(FType::CharDev),
+        4 => Some(FType::BlockDev),
+        5 => Some(FType::Fifo),
+        6 => Some(FType::Socket),
+        7 => Some(FType::SymLink),
+        _ => None
+    };
+}
+
+// Read only for now: no block or inode allocation, nothing is written back
+pub struct Ext2 {
+    dev: Arc<dyn BlockDevice>,
+    sb: SuperBlock,
+    block_size: u64,
+    inode_size: u64,
+    groups: Vec<GroupDesc>,
+    root: Inode
+}
+
+impl Ext2 {
+    pub fn new(dev: Arc<dyn BlockDevice>) -> Result<Arc<Self>, String> {
+        let mut buf = [0u8; size_of::<SuperBlock>()];
+        read_exact(&*dev, &mut buf, SUPER_OFFSET)?;
+        let sb = SuperBlock::read_from_bytes(&buf).map_err(|_| "Failed to parse ext2 superblock")?;
+
+        if sb.magic.get() != MAGIC {
+            return Err("Invalid ext2 magic".into());
+        }
+        let incompat = if sb.rev_level.get() >= 1 { sb.feature_incompat.get() } else { 0 };
+        if incompat & !INCOMPAT_SUPPORTED != 0 {
+            return Err(alloc::format!("Unsupported ext2 features {:#x}", incompat & !INCOMPAT_SUPPORTED));
+        }
+        if sb.log_block_size.get() > 6 || sb.blocks_per_group.get() == 0 || sb.inodes_per_group.get() == 0 {
+            return Err("Invalid ext2 geometry".into());
+        }
+
+        let block_size = 1024u64 << sb.log_block_size.get();
+        let inode_size = match sb.rev_level.get() {
+            0 => GOOD_OLD_INODE_SIZE as u64,
+            _ => sb.inode_size.get() as u64
+        };
+        if inode_size < GOOD_OLD_INODE_SIZE as u64 || !inode_size.is_power_of_two() || inode_size > block_size {
+            return Err("Invalid ext2 inode size".into());
+        }
+
+        // The descriptor table takes the block after the superblock's
+        let data_blocks = sb.blocks_count.get().saturating_sub(sb.first_data_block.get());
+        let group_cnt = data_blocks.div_ceil(sb.blocks_per_group.get()) as usize;
+        let mut table = alloc::vec![0u8; group_cnt * size_of::<GroupDesc>()];
+        read_exact(&*dev, &mut table, (sb.first_data_block.get() as u64 + 1) * block_size)?;
+        let groups = table.chunks_exact(size_of::<GroupDesc>())
+            .filter_map(|desc| GroupDesc::read_from_bytes(desc).ok())
+            .collect::<Vec<_>>();
+
+        let mut fs = Self {
+            dev, sb, block_size, inode_size, groups,
+            root: Inode::new_zeroed()
+        };
+        fs.root = fs.inode(ROOT_INO)?;
+        if fs.root.ftype() != Some(FType::Directory) {
+            return Err("ext2 root is not a directory".into());
+        }
+        return Ok(Arc::new(fs));
+    }
+
+    fn inode(&self, ino: u32) -> Result<Inode, String> {
+        if ino == 0 || ino > self.sb.inodes_count.get() {
+            return Err("Invalid inode number".into());
+        }
+        let ipg = self.sb.inodes_per_group.get();
+        let group = self.groups.get(((ino - 1) / ipg) as usize).ok_or("Inode outside every group")?;
+        let offset = group.inode_table.get() as u64 * self.block_size
+            + ((ino - 1) % ipg) as u64 * self.inode_size;
+
+        let mut buf = [0u8; size_of::<Inode>()];
+        read_exact(&*self.dev, &mut buf, offset)?;
+        return Inode::read_from_bytes(&buf).map_err(|_| "Failed to parse ext2 inode".into());
+    }
+
+    // Entry `idx` of the pointer block `blk`, 0 for a hole
+    fn indirect(&self, blk: u32, idx: u64) -> Result<u32, String> {
+        if blk == 0 { return Ok(0); }
+        let mut buf = [0u8; 4];
+        read_exact(&*self.dev, &mut buf, blk as u64 * self.block_size + idx * 4)?;
+        return Ok(u32::from_le_bytes(buf));
+    }
+
+    // Physical block holding block `idx` of the file, 0 for a hole
+    fn map_block(&self, inode: &Inode, idx: u64) -> Result<u32, String> {
+        if inode.flags.get() & EXTENTS_FL != 0 {
+            return Err("ext4 extents are not supported".into());
+        }
+
+        let per = self.block_size / 4;
+        let mut idx = idx;
+        if idx < NDIR_BLOCKS {
+            return Ok(inode.block[idx as usize].get());
+        }
+        idx -= NDIR_BLOCKS;
+        if idx < per {
+            return self.indirect(inode.block[IND_BLOCK].get(), idx);
+        }
+        idx -= per;
+        if idx < per * per {
+            let ind = self.indirect(inode.block[DIND_BLOCK].get(), idx / per)?;
+            return self.indirect(ind, idx % per);
+        }
+        idx -= per * per;
+        if idx < per * per * per {
+            let dind = self.indirect(inode.block[TIND_BLOCK].get(), idx / (per * per))?;
+            let ind = self.indirect(dind, idx / per % per)?;
+            return self.indirect(ind, idx % per);
+        }
+        return Err("File block out of range".into());
+    }
+
+    // i_size_high only means something for regular files
+    fn size(&self, inode: &Inode) -> u64 {
+        let high = match (self.sb.rev_level.get(), inode.ftype()) {
+            (1.., Some(FType::Regular)) => inode.size_high.get() as u64,
+            _ => 0
+        };
+        return high << 32 | inode.size.get() as u64;
+    }
+
+    // Holes read as zeros, reads stop at the file size
+    fn read_data(&self, inode: &Inode, buf: &mut [u8], offset: u64) -> Result<usize, String> {
+        let len = read_len(self.size(inode), offset, buf.len())?;
+
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let within = pos % self.block_size;
+            let chunk = (len - done).min((self.block_size - within) as usize);
+            let dst = &mut buf[done..done + chunk];
+
+            match self.map_block(inode, pos / self.block_size)? {
+                0 => dst.fill(0),
+                blk => read_exact(&*self.dev, dst, blk as u64 * self.block_size + within)?
+            }
+            done += chunk;
+        }
+        return Ok(len);
+    }
+
+    // Calls `f` with each live entry but `.` and `..` until it returns Some
+    fn for_each_dirent<T, F>(&self, dir: &Inode, mut f: F) -> Result<Option<T>, String>
+    where F: FnMut(u32, &str, Option<FType>) -> Option<T> {
+        let has_ftype = self.sb.rev_level.get() >= 1
+            && self.sb.feature_incompat.get() & INCOMPAT_FILETYPE != 0;
+        let mut block = alloc::vec![0u8; self.block_size as usize];
+        let mut offset = 0;
+
+        while offset < self.size(dir) {
+            let len = self.read_data(dir, &mut block, offset)?;
+            let mut pos = 0;
+            while pos + 8 <= len {
+                let ent = &block[pos..len];
+                let ino = u32::from_le_bytes([ent[0], ent[1], ent[2], ent[3]]);
+                let rec_len = u16::from_le_bytes([ent[4], ent[5]]) as usize;
+                let name_len = if has_ftype { ent[6] as usize } else { u16::from_le_bytes([ent[6], ent[7]]) as usize };
+                if rec_len < 8 || rec_len > ent.len() || 8 + name_len > rec_len {
+                    return Err("Corrupt ext2 directory entry".into());
+                }
+
+                let name = core::str::from_utf8(&ent[8..8 + name_len]).unwrap_or("");
+                if ino != 0 && !name.is_empty() && name != "." && name != ".." {
+                    let ftype = if has_ftype { dirent_ftype(ent[7]) } else { None };
+                    if let Some(res) = f(ino, name, ftype) {
+                        return Ok(Some(res));
+                    }
+                }
+                pos += rec_len;
+            }
+            offset += self.block_size;
+        }
+        return Ok(None);
+    }
+}
+
+// read_span stops short at the end of the device, which here means a corrupt filesystem
+fn read_exact(dev: &dyn BlockDevice, buf: &mut [u8], offset: u64) -> Result<(), String> {
+    if read_span(dev, buf, offset)? != buf.len() {
+        return Err("ext2 structure past the end of the device".into());
+    }
+    return Ok(());
+}
+
+struct Ext2Node {
+    fs: Arc<Ext2>,
+    ino: u32,
+    inode: Inode
+}
+
+impl VirtFNode for Ext2Node {
+    fn meta(&self) -> FMeta {
+        let inode = &self.inode;
+        return FMeta {
+            fid: self.ino as u64,
+            hostdev: self.fs.dev.devid(),
+            size: self.fs.size(inode),
+            ftype: inode.ftype().unwrap_or(FType::Regular),
+            perm: inode.mode.get() & 0o7777,
+            uid: inode.uid.get(),
+            gid: inode.gid.get(),
+            atime: inode.atime.get() as u64,
+            mtime: inode.mtime.get() as u64,
+            ctime: inode.ctime.get() as u64
+        };
+    }
+
+    fn read(&self, buf: &mut [u8], offset: u64) -> Result<usize, String> {
+        if self.inode.ftype() != Some(FType::Regular) {
+            return Err("This file is not IOable".into());
+        }
+        return self.fs.read_data(&self.inode, buf, offset);
+    }
+
+    fn write(&self, _buf: &[u8], _offset: u64) -> Result<usize, String> {
+        return Err("Read-only file system".into());
+    }
+
+    fn truncate(&self, _size: u64) -> Result<(), String> {
+        return Err("Read-only file system".into());
+    }
+
+    fn read_dir(&self, f: &mut dyn FnMut(&str, FType) -> bool) -> Result<(), String> {
+        if self.inode.ftype() != Some(FType::Directory) {
+            return Err("This is not a directory".into());
+        }
+        self.fs.for_each_dirent(&self.inode, |ino, name, ftype| {
+            // Without the filetype feature the inode has to be looked at
+            let ftype = match ftype {
+                Some(ftype) => ftype,
+                None => match self.fs.inode(ino) {
+                    Ok(inode) => inode.ftype().unwrap_or(FType::Regular),
+                    Err(e) => { warn!("ext2: inode {}: {}", ino, e); return None; }
+                }
+            };
+            return (!f(name, ftype)).then_some(());
+        })?;
+        return Ok(());
+    }
+
+    fn walk(&self, name: &str) -> Result<Arc<dyn VirtFNode>, String> {
+        if self.inode.ftype() != Some(FType::Directory) {
+            return Err("This is not a directory".into());
+        }
+        let ino = self.fs.for_each_dirent(&self.inode, |ino, fname, _| (fname == name).then_some(ino))?
+            .ok_or("File not found")?;
+        let inode = self.fs.inode(ino)?;
+        return Ok(Arc::new(Ext2Node { fs: self.fs.clone(), ino, inode }));
+    }
+
+    fn create(&self, _name: &str, _ftype: FType) -> Result<(), String> {
+        return Err("Read-only file system".into());
+    }
+
+    fn link(&self, _name: &str, _node: Arc<dyn VirtFNode>) -> Result<(), String> {
+        return Err("Read-only file system".into());
+    }
+
+    fn remove(&self, _name: &str) -> Result<(), String> {
+        return Err("Read-only file system".into());
+    }
+
+    // Targets under 60 bytes live in the block pointers themselves
+    fn readlink(&self) -> Result<String, String> {
+        if self.inode.ftype() != Some(FType::SymLink) {
+            return Err("This is not a symbolic link".into());
+        }
+        let size = self.fs.size(&self.inode) as usize;
+        let acl_sectors = if self.inode.file_acl.get() != 0 { self.fs.block_size / 512 } else { 0 };
+        let mut target = alloc::vec![0u8; size];
+
+        if size < 60 && self.inode.blocks.get() as u64 == acl_sectors {
+            let bytes = self.inode.block.iter().flat_map(|b| b.get().to_le_bytes()).collect::<Vec<_>>();
+            target.copy_from_slice(&bytes[..size]);
+        } else {
+            self.fs.read_data(&self.inode, &mut target, 0)?;
+        }
+        return String::from_utf8(target).map_err(|_| "Invalid symbolic link target".into());
+    }
+}
+
+// The superblock is past LBA 0 on 512-byte devices, so it is read here
+pub fn probe(_boot: &[u8], dev: Arc<dyn BlockDevice>) -> Option<Arc<dyn Partition>> {
+    let mut magic = [0u8; 2];
+    read_exact(&*dev, &mut magic, SUPER_OFFSET + 56).ok()?;
+    if u16::from_le_bytes(magic) != MAGIC { return None; }
+
+    return match Ext2::new(dev) {
+        Ok(fs) => Some(fs as Arc<dyn Partition>),
+        Err(e) => { warn!("ext2: {}", e); None }
+    };
+}
+
+impl Partition for Ext2 {
+    fn root(self: Arc<Self>) -> Arc<dyn VirtFNode> {
+        let root = self.root;
+        return Arc::new(Ext2Node { fs: self, ino: ROOT_INO, inode: root });
+    }
+
+    fn statfs(&self) -> Result<StatFs, String> {
+        return Ok(StatFs {
+            fstype: "ext2",
+            block_size: self.block_size,
+            total: self.sb.blocks_count.get() as u64 * self.block_size,
+            free: self.sb.free_blocks_count.get() as u64 * self.block_size
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::block::check_lba;
+    use alloc::vec;
+
+    const BS: usize = 1024;
+
+    struct Image(Vec<u8>);
+
+    impl BlockDevice for Image {
+        fn block_size(&self) -> u64 { 512 }
+        fn block_count(&self) -> u64 { self.0.len() as u64 / 512 }
+        fn devid(&self) -> u64 { 0 }
+
+        fn read_block(&self, buf: &mut [u8], lba: u64) -> Result<(), String> {
+            check_lba(self, lba, buf.len())?;
+            buf.copy_from_slice(&self.0[lba as usize * 512..][..buf.len()]);
+            return Ok(());
+        }
+
+        fn write_block(&self, _buf: &[u8], _lba: u64) -> Result<(), String> {
+            return Err("Read-only image".into());
+        }
+    }
+
+    fn put(img: &mut [u8], off: usize, bytes: &[u8]) {
+        img[off..off + bytes.len()].copy_from_slice(bytes);
+    }
+
+    fn put_inode(img: &mut [u8], ino: usize, mode: u16, size: u32, blocks: &[(usize, u32)]) {
+        let off = 3 * BS + (ino - 1) * GOOD_OLD_INODE_SIZE;
+        put(img, off, &mode.to_le_bytes());
+        put(img, off + 4, &size.to_le_bytes());
+        for &(idx, blk) in blocks {
+            put(img, off + 40 + idx * 4, &blk.to_le_bytes());
+        }
+    }
+
+    fn put_dirent(img: &mut [u8], off: usize, ino: u32, rec_len: u16, ty: u8, name: &str) {
+        put(img, off, &ino.to_le_bytes());
+        put(img, off + 4, &rec_len.to_le_bytes());
+        put(img, off + 6, &[name.len() as u8, ty]);
+        put(img, off + 8, name.as_bytes());
+    }
+
+    // 1 KiB blocks: superblock in 1, descriptors in 2, inode table in 3 and 4,
+    // the root directory in 5. `sparse` only has its 13th block, behind the
+    // single indirect block 7.
+    fn image() -> Vec<u8> {
+        let mut img = vec![0u8; 10 * BS];
+        let sb = SUPER_OFFSET as usize;
+        put(&mut img, sb, &16u32.to_le_bytes());         // inodes_count
+        put(&mut img, sb + 4, &10u32.to_le_bytes());     // blocks_count
+        put(&mut img, sb + 20, &1u32.to_le_bytes());     // first_data_block
+        put(&mut img, sb + 32, &8192u32.to_le_bytes());  // blocks_per_group
+        put(&mut img, sb + 40, &16u32.to_le_bytes());    // inodes_per_group
+        put(&mut img, sb + 56, &MAGIC.to_le_bytes());
+        put(&mut img, sb + 76, &1u32.to_le_bytes());     // rev_level
+        put(&mut img, sb + 88, &(GOOD_OLD_INODE_SIZE as u16).to_le_bytes());
+        put(&mut img, sb + 96, &INCOMPAT_FILETYPE.to_le_bytes());
+        put(&mut img, 2 * BS + 8, &3u32.to_le_bytes()); // inode_table
+
+        put_inode(&mut img, 2, 0o040755, BS as u32, &[(0, 5)]);
+        put_inode(&mut img, 12, 0o100644, 5, &[(0, 6)]);
+        put_inode(&mut img, 13, 0o120777, 5, &[]);
+        put(&mut img, 3 * BS + 12 * GOOD_OLD_INODE_SIZE + 40, b"hello");
+        put_inode(&mut img, 14, 0o100644, 13 * BS as u32, &[(IND_BLOCK, 7)]);
+
+        put_dirent(&mut img, 5 * BS, 2, 12, 2, ".");
+        put_dirent(&mut img, 5 * BS + 12, 2, 12, 2, "..");
+        put_dirent(&mut img, 5 * BS + 24, 12, 16, 1, "hello");
+        put_dirent(&mut img, 5 * BS + 40, 13, 12, 7, "link");
+        put_dirent(&mut img, 5 * BS + 52, 14, (BS - 52) as u16, 1, "sparse");
+
+        put(&mut img, 6 * BS, b"hello");
+        put(&mut img, 7 * BS, &8u32.to_le_bytes());
+        img[8 * BS..9 * BS].fill(0x5a);
+        return img;
+    }
+
+    fn mount(img: Vec<u8>) -> Result<Arc<dyn VirtFNode>, String> {
+        return Ext2::new(Arc::new(Image(img))).map(|fs| fs.root());
+    }
+
+    #[test]
+    fn walks_and_reads() {
+        let root = mount(image()).unwrap();
+        assert_eq!(root.list().unwrap(), ["hello", "link", "sparse"]);
+        assert!(root.walk("missing").is_err());
+
+        let hello = root.walk("hello").unwrap();
+        let mut buf = [0u8; 16];
+        assert_eq!(hello.read(&mut buf, 0), Ok(5));
+        assert_eq!(&buf[..5], b"hello");
+        assert_eq!(hello.read(&mut buf, 5), Ok(0));
+        assert_eq!(root.walk("link").unwrap().readlink().unwrap(), "hello");
+    }
+
+    #[test]
+    fn holes_and_indirect_blocks() {
+        let sparse = mount(image()).unwrap().walk("sparse").unwrap();
+        let mut buf = vec![0xffu8; 14 * BS];
+        assert_eq!(sparse.read(&mut buf, 0), Ok(13 * BS));
+        assert!(buf[..12 * BS].iter().all(|&b| b == 0));
+        assert!(buf[12 * BS..13 * BS].iter().all(|&b| b == 0x5a));
+    }
+
+    #[test]
+    fn rejects_bad_images() {
+        let mut img = image();
+        put(&mut img, 5 * BS + 4, &4u16.to_le_bytes()); // rec_len under 8
+        assert!(mount(img).unwrap().list().is_err());
+
+        let mut img = image();
+        put(&mut img, SUPER_OFFSET as usize + 96, &0x42u32.to_le_bytes()); // extents, 64bit
+        assert!(mount(img).is_err());
+
+        let mut img = image();
+        img[SUPER_OFFSET as usize + 56] = 0;
+        assert!(mount(img).is_err());
+    }
+}
@@ -3,13 +3,15 @@
 use crate::{
     device::block::BlockDevice,
     filesys::{
-        parts::Partition,
-        vfn::{FMeta, FType, VirtFNode}
-    }
+        parts::{Partition, StatFs},
+        vfn::{FMeta, FType, VirtFNode, read_len}
+    },
+    time
 };
 
 use core::str::Utf8Error;
 use alloc::{string::String, sync::Arc, vec::Vec};
+use spin::Mutex;
 use zerocopy::{LE, U16, U32};
 
 type u16le = U16<LE>;
@@ -34,7 +36,7 @@ struct FatDirEnt {
 }
 
 impl FatDirEnt {
-    pub fn filename(&self) -> Result<String, Utf8Error> {
+    pub fn short_name(&self) -> Result<String, Utf8Error> {
         let name = core::str::from_utf8(&self.name)?.trim_end();
         let ext = core::str::from_utf8(&self.ext)?.trim_end();
 
@@ -52,31 +54,229 @@ impl FatDirEnt {
             return FType::Regular;
         }
     }
+
+    fn fst_clus(&self) -> u32 {
+        return (self.fst_clus_hi.get() as u32) << 16
+            | (self.fst_clus_lo.get() as u32);
+    }
+
+    fn set_fst_clus(&mut self, clust: u32) {
+        self.fst_clus_hi = u16le::new((clust >> 16) as u16);
+        self.fst_clus_lo = u16le::new((clust & 0xffff) as u16);
+    }
+
+    fn chksum(&self) -> u8 {
+        return self.name.iter().chain(self.ext.iter())
+            .fold(0u8, |sum, &b| sum.rotate_right(1).wrapping_add(b));
+    }
+
+    // Fresh entry stamped with the current time
+    fn new(short: [u8; 11], attr: u8, clust: u32) -> Self {
+        let (date, tod) = fat_stamp(time::now());
+        let mut ent = Self {
+            name: short[..8].try_into().unwrap(),
+            ext: short[8..].try_into().unwrap(),
+            attr,
+            ntres: 0,
+            crt_time_tenth: 0,
+            crt_time: u16le::new(tod),
+            crt_date: u16le::new(date),
+            lst_acc_date: u16le::new(date),
+            fst_clus_hi: u16le::new(0),
+            wrt_time: u16le::new(tod),
+            wrt_date: u16le::new(date),
+            fst_clus_lo: u16le::new(0),
+            file_size: u32le::new(0)
+        };
+        ent.set_fst_clus(clust);
+        return ent;
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct FatLfnEnt {
+    ord: u8,
+    name1: [u8; 10],
+    attr: u8,
+    ty: u8,
+    chksum: u8,
+    name2: [u8; 12],
+    fst_clus_lo: u16le,
+    name3: [u8; 4]
+}
+
+impl FatLfnEnt {
+    const CHARS: usize = 13;
+    const LAST: u8 = 0x40;
+
+    fn chars(&self) -> impl Iterator<Item = u16> + '_ {
+        return self.name1.chunks_exact(2)
+            .chain(self.name2.chunks_exact(2))
+            .chain(self.name3.chunks_exact(2))
+            .map(|c| u16::from_le_bytes([c[0], c[1]]));
+    }
+
+    // Entries for a long name in on-disk order, the last part first
+    fn for_name(name: &str, chksum: u8) -> Vec<Self> {
+        let mut chars: Vec<u16> = name.encode_utf16().collect();
+        let count = chars.len().div_ceil(Self::CHARS);
+        if chars.len() % Self::CHARS != 0 {
+            chars.push(0x0000);
+        }
+        chars.resize(count * Self::CHARS, 0xffff);
+
+        return (1..=count).rev().map(|ord| {
+            let mut ent = Self {
+                ord: ord as u8 | if ord == count { Self::LAST } else { 0 },
+                name1: [0; 10],
+                attr: 0x0f,
+                ty: 0,
+                chksum,
+                name2: [0; 12],
+                fst_clus_lo: u16le::new(0),
+                name3: [0; 4]
+            };
+            let part = chars[(ord - 1) * Self::CHARS..ord * Self::CHARS].iter()
+                .flat_map(|c| c.to_le_bytes());
+            ent.name1.iter_mut()
+                .chain(ent.name2.iter_mut())
+                .chain(ent.name3.iter_mut())
+                .zip(part)
+                .for_each(|(dst, src)| *dst = src);
+            return ent;
+        }).collect();
+    }
+}
+
+fn valid_name(name: &str) -> bool {
+    return !name.is_empty() && name != "." && name != ".."
+        && name.encode_utf16().count() <= 255
+        && !name.ends_with(['.', ' '])
+        && !name.chars().any(|c| (c as u32) < 0x20 || "\"*/:<>?\\|".contains(c));
+}
+
+fn short_char(c: char) -> bool {
+    return c.is_ascii_uppercase() || c.is_ascii_digit() || "!#$%&'()-@^_`{}~".contains(c);
+}
+
+// The name as an 8.3 entry if it already is one, otherwise it needs an LFN
+fn exact_short(name: &str) -> Option<[u8; 11]> {
+    let (base, ext) = name.split_once('.').unwrap_or((name, ""));
+    if base.is_empty() || base.len() > 8 || ext.len() > 3
+    || !base.chars().chain(ext.chars()).all(short_char) {
+        return None;
+    }
+
+    let mut short = [b' '; 11];
+    short[..base.len()].copy_from_slice(base.as_bytes());
+    short[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+    return Some(short);
+}
+
+// BASE~N.EXT alias for a long name
+fn mangled_short(name: &str, n: u32) -> [u8; 11] {
+    let (base, ext) = match name.rsplit_once('.') {
+        Some((base, ext)) if !base.trim_start_matches('.').is_empty() => (base, ext),
+        _ => (name, "")
+    };
+    let conv = |part: &str| part.chars()
+        .filter(|&c| c != ' ' && c != '.')
+        .map(|c| c.to_ascii_uppercase())
+        .map(|c| if short_char(c) { c as u8 } else { b'_' })
+        .collect::<Vec<u8>>();
+    let (base, ext) = (conv(base), conv(ext));
+
+    let tail = alloc::format!("~{}", n);
+    let keep = base.len().min(8 - tail.len());
+    let mut short = [b' '; 11];
+    short[..keep].copy_from_slice(&base[..keep]);
+    short[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+    short[8..].iter_mut().zip(&ext).for_each(|(dst, src)| *dst = *src);
+    return short;
+}
+
+// Long name fragments collected ahead of their short entry
+struct LfnChain {
+    name: Vec<u16>,
+    next_ord: u8,
+    chksum: u8
+}
+
+impl LfnChain {
+    fn new() -> Self {
+        return Self { name: Vec::new(), next_ord: 0, chksum: 0 };
+    }
+
+    fn reset(&mut self) {
+        self.name.clear();
+        self.next_ord = 0;
+    }
+
+    fn push(&mut self, ent: &FatLfnEnt) {
+        let ord = ent.ord & !FatLfnEnt::LAST;
+        if ord == 0 {
+            self.reset();
+            return;
+        }
+
+        if ent.ord & FatLfnEnt::LAST != 0 {
+            self.name = alloc::vec![0xffff; ord as usize * FatLfnEnt::CHARS];
+            self.chksum = ent.chksum;
+        } else if self.name.is_empty() || ord != self.next_ord || ent.chksum != self.chksum {
+            self.reset();
+            return;
+        }
+
+        let base = (ord as usize - 1) * FatLfnEnt::CHARS;
+        for (i, c) in ent.chars().enumerate() {
+            self.name[base + i] = c;
+        }
+        self.next_ord = ord - 1;
+    }
+
+    fn take(&mut self, short: &FatDirEnt) -> Option<String> {
+        let complete = !self.name.is_empty() && self.next_ord == 0 && self.chksum == short.chksum();
+        let name = core::mem::take(&mut self.name);
+        self.reset();
+        if !complete { return None; }
+
+        let end = name.iter().position(|&c| c == 0x0000 || c == 0xffff).unwrap_or(name.len());
+        return String::from_utf16(&name[..end]).ok().filter(|n| !n.is_empty());
+    }
+}
+
+// On-disk location of a directory entry
+#[derive(Clone, Copy)]
+struct EntPos {
+    sct: u64,
+    off: usize
 }
 
 struct FatFile {
-    dirent: FatDirEnt,
+    dirent: Mutex<FatDirEnt>,
+    pos: Option<EntPos>, // None for the root directory
     fs: Arc<FileAllocTable>,
     hostdev: u64,
     fid: u64
 }
 
 impl FatFile {
-    pub fn new(fs: Arc<FileAllocTable>, dirent: FatDirEnt, fid: u64) -> Self {
+    pub fn new(fs: Arc<FileAllocTable>, dirent: FatDirEnt, pos: Option<EntPos>, fid: u64) -> Self {
         let hostdev = fs.part.devid();
-        return Self { dirent, fs, hostdev, fid };
+        return Self { dirent: Mutex::new(dirent), pos, fs, hostdev, fid };
     }
 
-    pub fn for_each_ent<T, F>(&self, mut f: F) -> Result<Option<T>, String>
-    where F: FnMut(&FatDirEnt, u64) -> Option<T> {
-        if self.dirent.ftype() != FType::Directory {
+    // Every 32-byte slot in order, free and LFN ones included, with its position
+    // and fid. Runs to the end of the chain rather than stopping at a 0x00 entry.
+    fn for_each_slot<T, F>(&self, mut f: F) -> Result<Option<T>, String>
+    where F: FnMut(&FatDirEnt, EntPos, u64) -> Option<T> {
+        let dirent = *self.dirent.lock();
+        if dirent.ftype() != FType::Directory {
             return Err("This is not a directory".into());
         }
 
-        let mut clust =
-            (self.dirent.fst_clus_hi.get() as u32) << 16
-            | (self.dirent.fst_clus_lo.get() as u32);
-
+        let mut clust = dirent.fst_clus();
         let is_chained = clust != 0;
 
         loop {
@@ -88,7 +288,7 @@ impl FatFile {
             };
 
             let buf_size = if is_chained {
-                self.fs.bpb.byts_per_sec.get() as usize * self.fs.bpb.sec_per_clus as usize
+                self.fs.clust_size()
             } else {
                 self.fs.bpb.root_ent_cnt.get() as usize * size_of::<FatDirEnt>()
             };
@@ -99,25 +299,16 @@ impl FatFile {
 
             let ent_cnt = buf.len() / size_of::<FatDirEnt>();
             let ent_ptr = buf.as_ptr() as *const FatDirEnt;
+            let bps = self.fs.bpb.byts_per_sec.get() as usize;
 
             for i in 0..ent_cnt {
                 let ent = unsafe { ent_ptr.add(i).read() };
-
-                if ent.name[0] == 0x00 {
-                    return Ok(None);
-                }
-                if ent.name[0] == 0xe5 {
-                    continue;
-                }
-                if ent.attr == 0x0f {
-                    continue;
-                }
-                if ent.attr & 0x08 != 0 {
-                    continue;
-                }
-
+                let pos = EntPos {
+                    sct: sct + (i * size_of::<FatDirEnt>() / bps) as u64,
+                    off: (i * size_of::<FatDirEnt>()) % bps
+                };
                 let fid = ((clust as u64) << 32) | i as u64;
-                if let Some(res) = f(&ent, fid) {
+                if let Some(res) = f(&ent, pos, fid) {
                     return Ok(Some(res));
                 }
             }
@@ -134,43 +325,233 @@ impl FatFile {
 
         return Ok(None);
     }
+
+    // Named entries with the positions of the LFN slots in front of them
+    fn for_each_named<T, F>(&self, mut f: F) -> Result<Option<T>, String>
+    where F: FnMut(&FatDirEnt, &str, EntPos, &[EntPos], u64) -> Option<T> {
+        let mut lfn = LfnChain::new();
+        let mut lfn_pos = Vec::new();
+
+        let res = self.for_each_slot(|ent, pos, fid| {
+            if ent.name[0] == 0x00 {
+                return Some(None);
+            }
+            if ent.name[0] == 0xe5 {
+                lfn.reset();
+                lfn_pos.clear();
+                return None;
+            }
+            if ent.attr == 0x0f {
+                lfn.push(unsafe { &*(ent as *const FatDirEnt as *const FatLfnEnt) });
+                lfn_pos.push(pos);
+                return None;
+            }
+            if ent.attr & 0x08 != 0 {
+                lfn.reset();
+                lfn_pos.clear();
+                return None;
+            }
+
+            let name = match lfn.take(ent) {
+                Some(name) => name,
+                None => match ent.short_name() {
+                    Ok(name) => name,
+                    Err(_) => { lfn_pos.clear(); return None; }
+                }
+            };
+            let res = f(ent, &name, pos, &lfn_pos, fid);
+            lfn_pos.clear();
+            return res.map(Some);
+        })?;
+        return Ok(res.flatten());
+    }
+
+    pub fn for_each_ent<T, F>(&self, mut f: F) -> Result<Option<T>, String>
+    where F: FnMut(&FatDirEnt, &str, EntPos, u64) -> Option<T> {
+        return self.for_each_named(|ent, name, pos, _lfn, fid| f(ent, name, pos, fid));
+    }
+}
+
+impl FatFile { // Write helpers
+    fn sync_dirent(&self, dirent: &FatDirEnt) -> Result<(), String> {
+        let Some(pos) = self.pos else { return Ok(()); };
+        return self.fs.write_slot(pos, dirent);
+    }
+
+    // `count` consecutive free slots, growing a chained directory if they run out
+    fn find_free_slots(&self, count: usize) -> Result<Vec<EntPos>, String> {
+        let mut run = Vec::new();
+        let found = self.for_each_slot(|ent, pos, _fid| {
+            if ent.name[0] == 0x00 || ent.name[0] == 0xe5 {
+                run.push(pos);
+            } else {
+                run.clear();
+            }
+            return (run.len() == count).then_some(());
+        })?;
+        if found.is_some() { return Ok(run); }
+
+        let first = self.dirent.lock().fst_clus();
+        if first == 0 { return Err("Directory full".into()); }
+
+        let mut last = first;
+        while let Some(nc) = self.fs.next_clust(last) {
+            last = nc;
+        }
+
+        // Fresh clusters come zeroed, so every slot in them is free
+        let bps = self.fs.bpb.byts_per_sec.get() as usize;
+        let per_clust = self.fs.clust_size() / size_of::<FatDirEnt>();
+        while run.len() < count {
+            last = self.fs.alloc_clust(Some(last))?;
+            let sct = self.fs.clust2sct(last);
+            for i in 0..per_clust.min(count - run.len()) {
+                run.push(EntPos {
+                    sct: sct + (i * size_of::<FatDirEnt>() / bps) as u64,
+                    off: (i * size_of::<FatDirEnt>()) % bps
+                });
+            }
+        }
+        return Ok(run);
+    }
+
+    fn short_names(&self) -> Result<Vec<[u8; 11]>, String> {
+        let mut names = Vec::new();
+        self.for_each_ent(|ent, _name, _pos, _fid| {
+            let mut short = [0u8; 11];
+            short[..8].copy_from_slice(&ent.name);
+            short[8..].copy_from_slice(&ent.ext);
+            names.push(short);
+            return None::<()>;
+        })?;
+        return Ok(names);
+    }
+
+    // Only `.` and `..` left
+    fn is_empty_dir(&self) -> Result<bool, String> {
+        let other = self.for_each_ent(|_ent, name, _pos, _fid| {
+            return (name != "." && name != "..").then_some(());
+        })?;
+        return Ok(other.is_none());
+    }
+
+    fn write_locked(&self, dirent: &mut FatDirEnt, buf: &[u8], offset: u64) -> Result<(), String> {
+        let size = dirent.file_size.get() as u64;
+        if offset > size {
+            // Fill the hole so stale cluster bytes never become file data
+            let gap = alloc::vec![0u8; (offset - size) as usize];
+            self.write_locked(dirent, &gap, size)?;
+        }
+        if buf.is_empty() { return Ok(()); }
+
+        let end = offset.checked_add(buf.len() as u64)
+            .filter(|&e| e <= u32::MAX as u64)
+            .ok_or("File too large")?;
+
+        let clust_size = self.fs.clust_size();
+        let mut clust = dirent.fst_clus();
+        if clust == 0 {
+            clust = self.fs.alloc_clust(None)?;
+            dirent.set_fst_clus(clust);
+        }
+
+        for _ in 0..(offset as usize / clust_size) {
+            clust = match self.fs.next_clust(clust) {
+                Some(nc) => nc,
+                None => self.fs.alloc_clust(Some(clust))?
+            };
+        }
+
+        let mut skip = offset as usize % clust_size;
+        let mut done = 0;
+        let mut clust_buf = alloc::vec![0u8; clust_size];
+
+        loop {
+            let sct = self.fs.clust2sct(clust);
+            let write_size = (buf.len() - done).min(clust_size - skip);
+            if write_size < clust_size {
+                self.fs.part.read_block(&mut clust_buf, sct)
+                    .map_err(|e| alloc::format!("FAT read error: {}", e))?;
+            }
+
+            clust_buf[skip..skip + write_size].copy_from_slice(&buf[done..done + write_size]);
+            self.fs.part.write_block(&clust_buf, sct)
+                .map_err(|e| alloc::format!("FAT write error: {}", e))?;
+
+            done += write_size;
+            skip = 0;
+            if done >= buf.len() { break; }
+
+            clust = match self.fs.next_clust(clust) {
+                Some(nc) => nc,
+                None => self.fs.alloc_clust(Some(clust))?
+            };
+        }
+
+        if end > size {
+            dirent.file_size = u32le::new(end as u32);
+        }
+        return Ok(());
+    }
+}
+
+// Local time as stored, the year counts from 1980
+fn fat_time(date: u16, tod: u16) -> u64 {
+    if date == 0 { return 0; }
+    return time::unix_time(
+        1980 + (date >> 9) as i64, ((date >> 5) & 0xf) as u8, (date & 0x1f) as u8,
+        (tod >> 11) as u8, ((tod >> 5) & 0x3f) as u8, ((tod & 0x1f) * 2) as u8
+    );
+}
+
+// Inverse of fat_time as (date, time), zero when out of range or without a clock
+fn fat_stamp(unix: u64) -> (u16, u16) {
+    let (year, month, day) = time::civil_from_days((unix / 86400) as i64);
+    if !(1980..=2107).contains(&year) { return (0, 0); }
+
+    let secs = unix % 86400;
+    let date = ((year - 1980) as u16) << 9 | (month as u16) << 5 | day as u16;
+    let tod = ((secs / 3600) as u16) << 11 | ((secs / 60 % 60) as u16) << 5 | (secs % 60 / 2) as u16;
+    return (date, tod);
 }
 
 impl VirtFNode for FatFile {
     fn meta(&self) -> FMeta {
+        let dirent = self.dirent.lock();
         return FMeta {
             fid: self.fid,
-            size: self.dirent.file_size.get() as u64,
+            size: dirent.file_size.get() as u64,
             hostdev: self.hostdev,
-            ftype: self.dirent.ftype(),
+            ftype: dirent.ftype(),
             perm: 0o777,
             uid: 0xffff,
-            gid: 0xffff
+            gid: 0xffff,
+            atime: fat_time(dirent.lst_acc_date.get(), 0),
+            mtime: fat_time(dirent.wrt_date.get(), dirent.wrt_time.get()),
+            ctime: fat_time(dirent.crt_date.get(), dirent.crt_time.get())
         };
     }
 
-    fn read(&self, buf: &mut [u8], offset: u64) -> Result<(), String> {
-        if self.dirent.ftype() != FType::Regular {
+    fn read(&self, buf: &mut [u8], offset: u64) -> Result<usize, String> {
+        let dirent = *self.dirent.lock();
+        if dirent.ftype() != FType::Regular {
             return Err("This file is not IOable".into());
         }
 
+        // The last cluster is mostly slack, so stop at the file size
+        let len = read_len(dirent.file_size.get() as u64, offset, buf.len())?;
+        if len == 0 { return Ok(0); }
+        let buf = &mut buf[..len];
+
         let mut skip_rem = offset as usize;
         let mut bytes_rem = buf.len();
 
-        let mut clust =
-            (self.dirent.fst_clus_hi.get() as u32) << 16
-            | (self.dirent.fst_clus_lo.get() as u32);
-
-        let clust_size =
-            self.fs.bpb.byts_per_sec.get() as usize
-            * self.fs.bpb.sec_per_clus as usize;
+        let mut clust = dirent.fst_clus();
+        let clust_size = self.fs.clust_size();
 
         while skip_rem >= clust_size {
             skip_rem -= clust_size;
-            clust = match self.fs.next_clust(clust) {
-                Some(nc) => nc,
-                None => return Ok(())
-            };
+            clust = self.fs.next_clust(clust).ok_or("FAT32 chain shorter than the file")?;
         }
 
         while bytes_rem > 0 {
@@ -194,32 +575,82 @@ impl VirtFNode for FatFile {
             };
         }
 
-        return Ok(());
+        return Ok(buf.len() - bytes_rem);
     }
 
-    fn list(&self) -> Result<Vec<String>, String> {
-        let mut entries = Vec::new();
-        self.for_each_ent(|ent, _fid| {
-            match ent.filename() {
-                Ok(name) => {
-                    entries.push(name);
-                }
-                Err(_) => {}
+    fn write(&self, buf: &[u8], offset: u64) -> Result<usize, String> {
+        let mut dirent = self.dirent.lock();
+        if dirent.ftype() != FType::Regular {
+            return Err("This file is not IOable".into());
+        }
+
+        let mut new_dirent = *dirent;
+        let res = self.write_locked(&mut new_dirent, buf, offset);
+        // Clusters may have been linked even on failure, so always persist the entry
+        self.sync_dirent(&new_dirent)?;
+        *dirent = new_dirent;
+        return res.map(|_| buf.len());
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), String> {
+        let mut dirent = self.dirent.lock();
+        if dirent.ftype() != FType::Regular {
+            return Err("This file is not IOable".into());
+        }
+        if size > u32::MAX as u64 { return Err("File too large".into()); }
+
+        let cur = dirent.file_size.get() as u64;
+        let mut new_dirent = *dirent;
+
+        if size > cur {
+            let gap = alloc::vec![0u8; (size - cur) as usize];
+            let res = self.write_locked(&mut new_dirent, &gap, cur);
+            self.sync_dirent(&new_dirent)?;
+            *dirent = new_dirent;
+            return res;
+        }
+
+        let keep = (size as usize).div_ceil(self.fs.clust_size());
+        let first = new_dirent.fst_clus();
+
+        if first != 0 {
+            if keep == 0 {
+                new_dirent.set_fst_clus(0);
+                new_dirent.file_size = u32le::new(0);
+                self.sync_dirent(&new_dirent)?;
+                *dirent = new_dirent;
+                return self.fs.free_chain(first);
             }
-            return None::<()>;
-        })?;
 
-        return Ok(entries);
+            let mut last = first;
+            for _ in 1..keep {
+                last = match self.fs.next_clust(last) {
+                    Some(nc) => nc,
+                    None => break
+                };
+            }
+            if let Some(tail) = self.fs.next_clust(last) {
+                self.fs.set_fat_ent(last, self.fs.eoc())?;
+                self.fs.free_chain(tail)?;
+            }
+        }
+
+        new_dirent.file_size = u32le::new(size as u32);
+        self.sync_dirent(&new_dirent)?;
+        *dirent = new_dirent;
+        return Ok(());
+    }
+
+    fn read_dir(&self, f: &mut dyn FnMut(&str, FType) -> bool) -> Result<(), String> {
+        self.for_each_ent(|ent, name, _pos, _fid| (!f(name, ent.ftype())).then_some(()))?;
+        return Ok(());
     }
 
     fn walk(&self, name: &str) -> Result<Arc<dyn VirtFNode>, String> {
-        let file = self.for_each_ent(|&ent, fid| {
-            match ent.filename() {
-                Ok(fname) if fname.eq_ignore_ascii_case(name) => {
-                    let file = FatFile::new(self.fs.clone(), ent, fid);
-                    return Some(file);
-                }
-                _ => {}
+        let file = self.for_each_ent(|&ent, fname, pos, fid| {
+            if fname.eq_ignore_ascii_case(name) {
+                let file = FatFile::new(self.fs.clone(), ent, Some(pos), fid);
+                return Some(file);
             }
             return None;
         })?;
@@ -230,6 +661,83 @@ impl VirtFNode for FatFile {
             return Err("File not found".into());
         }
     }
+
+    fn create(&self, name: &str, ftype: FType) -> Result<(), String> {
+        let attr = match ftype {
+            FType::Regular => 0x20, // Archive
+            FType::Directory => 0x10,
+            _ => return Err("Unsupported file type for creation".into())
+        };
+        if !valid_name(name) {
+            return Err("Invalid file name".into());
+        }
+
+        let _guard = self.fs.dir_lock.lock();
+        let dup = self.for_each_ent(|_ent, fname, _pos, _fid| fname.eq_ignore_ascii_case(name).then_some(()))?;
+        if dup.is_some() {
+            return Err("File already exists".into());
+        }
+
+        // An 8.3 name may still clash with the alias of a long one
+        let taken = self.short_names()?;
+        let (short, lfn_cnt) = match exact_short(name) {
+            Some(short) if taken.contains(&short) => return Err("File already exists".into()),
+            Some(short) => (short, 0),
+            None => {
+                let short = (1..=999_999).map(|n| mangled_short(name, n))
+                    .find(|short| !taken.contains(short))
+                    .ok_or("Directory full")?;
+                (short, name.encode_utf16().count().div_ceil(FatLfnEnt::CHARS))
+            }
+        };
+        let slots = self.find_free_slots(lfn_cnt + 1)?;
+
+        let clust = if ftype == FType::Directory {
+            let clust = self.fs.alloc_clust(None)?;
+            // `..` of a directory in the root points at cluster 0, even on FAT32
+            let parent = if self.pos.is_some() { self.dirent.lock().fst_clus() } else { 0 };
+            let sct = self.fs.clust2sct(clust);
+            self.fs.write_slot(EntPos { sct, off: 0 }, &FatDirEnt::new(*b".          ", 0x10, clust))?;
+            self.fs.write_slot(EntPos { sct, off: size_of::<FatDirEnt>() }, &FatDirEnt::new(*b"..         ", 0x10, parent))?;
+            clust
+        } else {
+            0
+        };
+
+        // LFN slots go first so a torn create leaves only orphans behind
+        let ent = FatDirEnt::new(short, attr, clust);
+        let lfn = if lfn_cnt > 0 { FatLfnEnt::for_name(name, ent.chksum()) } else { Vec::new() };
+        for (lfn_ent, &pos) in lfn.iter().zip(&slots) {
+            self.fs.write_slot(pos, lfn_ent)?;
+        }
+        return self.fs.write_slot(slots[lfn_cnt], &ent);
+    }
+
+    fn remove(&self, name: &str) -> Result<(), String> {
+        let _guard = self.fs.dir_lock.lock();
+        let found = self.for_each_named(|&ent, fname, pos, lfn, fid| {
+            if fname == "." || fname == ".." { return None; }
+            return fname.eq_ignore_ascii_case(name).then(|| (ent, pos, lfn.to_vec(), fid));
+        })?;
+        let Some((ent, pos, lfn, fid)) = found else {
+            return Err("File not found".into());
+        };
+
+        if ent.ftype() == FType::Directory {
+            let dir = FatFile::new(self.fs.clone(), ent, Some(pos), fid);
+            if !dir.is_empty_dir()? {
+                return Err("Directory not empty".into());
+            }
+        }
+
+        for &slot in lfn.iter().chain([&pos]) {
+            self.fs.free_slot(slot)?;
+        }
+        if ent.fst_clus() != 0 {
+            self.fs.free_chain(ent.fst_clus())?;
+        }
+        return Ok(());
+    }
 }
 
 #[repr(C)]
@@ -276,11 +784,44 @@ pub struct Fat32BpbExt {
     _0: [u8; 12]
 }
 
+// FSInfo fields, u32::MAX means unknown
+#[derive(Clone, Copy)]
+struct FreeHint {
+    free_cnt: u32,
+    nxt_free: u32,
+    dirty: bool // Only a hint, so it is written back on sync
+}
+
+impl FreeHint {
+    const UNKNOWN: u32 = 0xffffffff;
+    const LEAD_SIG: u32 = 0x41615252;
+    const STRUC_SIG: u32 = 0x61417272;
+    const TRAIL_SIG: u32 = 0xaa550000;
+
+    fn unknown() -> Self {
+        return Self { free_cnt: Self::UNKNOWN, nxt_free: Self::UNKNOWN, dirty: false };
+    }
+
+    fn parse(buf: &[u8]) -> Option<Self> {
+        let get = |off: usize| u32::from_le_bytes(buf[off..off + 4].try_into().unwrap());
+        if buf.len() < 512
+        || get(0) != Self::LEAD_SIG
+        || get(484) != Self::STRUC_SIG
+        || get(508) != Self::TRAIL_SIG {
+            return None;
+        }
+
+        return Some(Self { free_cnt: get(488), nxt_free: get(492), dirty: false });
+    }
+}
+
 pub struct FileAllocTable {
     part: Arc<dyn BlockDevice>,
     bpb: BootParamBlock,
     ext32: Option<Fat32BpbExt>,
-    ext12: Fat12BpbExt
+    ext12: Fat12BpbExt,
+    free: Mutex<FreeHint>, // Also serialises allocation
+    dir_lock: Mutex<()> // Serialises directory entry changes
 }
 
 pub enum FatType {
@@ -307,9 +848,63 @@ impl FileAllocTable {
 
         let ext12 = unsafe { (bptr.add(offset) as *const Fat12BpbExt).read() };
 
-        return Some(Arc::new(Self {
-            part, bpb, ext32, ext12
-        }));
+        let fat = Arc::new(Self {
+            part, bpb, ext32, ext12,
+            free: Mutex::new(FreeHint::unknown()),
+            dir_lock: Mutex::new(())
+        });
+
+        if let Some(sct) = fat.fsinfo_sct() {
+            let mut buf = alloc::vec![0u8; (fat.part.block_size() as usize).max(512)];
+            if fat.part.read_block(&mut buf, sct).is_ok() {
+                if let Some(hint) = FreeHint::parse(&buf) {
+                    *fat.free.lock() = hint;
+                }
+            }
+        }
+
+        return Some(fat);
+    }
+
+    fn fsinfo_sct(&self) -> Option<u64> {
+        let sct = self.ext32.as_ref()?.fs_info.get();
+        return (sct != 0 && sct != 0xffff).then_some(sct as u64);
+    }
+
+    fn sync_fsinfo(&self, hint: &FreeHint) -> Result<(), String> {
+        let Some(sct) = self.fsinfo_sct() else { return Ok(()); };
+
+        let mut buf = alloc::vec![0u8; (self.part.block_size() as usize).max(512)];
+        self.part.read_block(&mut buf, sct)
+            .map_err(|e| alloc::format!("FAT read error: {}", e))?;
+        if FreeHint::parse(&buf).is_none() {
+            return Ok(());
+        }
+
+        buf[488..492].copy_from_slice(&hint.free_cnt.to_le_bytes());
+        buf[492..496].copy_from_slice(&hint.nxt_free.to_le_bytes());
+        return self.part.write_block(&buf, sct)
+            .map_err(|e| alloc::format!("FAT write error: {}", e));
+    }
+
+    // Read-modify-write of the sector holding one directory slot
+    fn update_slot(&self, pos: EntPos, f: impl FnOnce(&mut [u8])) -> Result<(), String> {
+        let mut buf = alloc::vec![0u8; self.part.block_size() as usize];
+        self.part.read_block(&mut buf, pos.sct)
+            .map_err(|e| alloc::format!("FAT read error: {}", e))?;
+        f(&mut buf[pos.off..pos.off + size_of::<FatDirEnt>()]);
+        return self.part.write_block(&buf, pos.sct)
+            .map_err(|e| alloc::format!("FAT write error: {}", e));
+    }
+
+    fn write_slot<E: Copy>(&self, pos: EntPos, ent: &E) -> Result<(), String> {
+        return self.update_slot(pos, |slot| unsafe {
+            (slot.as_mut_ptr() as *mut E).write_unaligned(*ent);
+        });
+    }
+
+    fn free_slot(&self, pos: EntPos) -> Result<(), String> {
+        return self.update_slot(pos, |slot| slot[0] = 0xe5);
     }
 
     fn fat_sz(&self) -> u32 {
@@ -361,20 +956,33 @@ impl FileAllocTable {
         return sct;
     }
 
-    fn next_clust(&self, clust: u32) -> Option<u32> {
+    fn clust_size(&self) -> usize {
+        return self.bpb.byts_per_sec.get() as usize * self.bpb.sec_per_clus as usize;
+    }
+
+    // (sector, byte offset) of a cluster's entry in the first FAT
+    fn fat_ent_pos(&self, clust: u32) -> (u64, usize) {
         let fat_off = match self.fat_type() {
             FatType::Fat12 => clust as u64 + (clust as u64 >> 1),
             FatType::Fat16 => clust as u64 * size_of::<u16>() as u64,
             FatType::Fat32(_) => clust as u64 * size_of::<u32>() as u64
         };
 
-        let fat_sct = self.bpb.rsvd_sec_cnt.get() as u64 + (fat_off / self.bpb.byts_per_sec.get() as u64);
-        let ent_off = (fat_off % self.bpb.byts_per_sec.get() as u64) as usize;
+        let bps = self.bpb.byts_per_sec.get() as u64;
+        let fat_sct = self.bpb.rsvd_sec_cnt.get() as u64 + (fat_off / bps);
+        return (fat_sct, (fat_off % bps) as usize);
+    }
 
-        let mut buf = alloc::vec![0u8; self.part.block_size() as usize];
+    fn fat_ent(&self, clust: u32) -> Option<u32> {
+        let (fat_sct, ent_off) = self.fat_ent_pos(clust);
+
+        // A FAT12 entry may straddle two sectors
+        let bs = self.part.block_size() as usize;
+        let span = if ent_off + size_of::<u32>() > bs { bs * 2 } else { bs };
+        let mut buf = alloc::vec![0u8; span];
         self.part.read_block(&mut buf, fat_sct).ok()?;
 
-        let entry = match self.fat_type() {
+        return Some(match self.fat_type() {
             FatType::Fat12 | FatType::Fat16 => {
                 let raw = &buf[ent_off..ent_off + size_of::<u16>()];
                 let raw = u16le::from_bytes(raw.try_into().unwrap()).get();
@@ -396,15 +1004,149 @@ impl FileAllocTable {
                 let raw = u32le::from_bytes(raw.try_into().unwrap()).get();
                 raw & 0x0fffffff
             }
+        });
+    }
+
+    fn set_fat_ent(&self, clust: u32, val: u32) -> Result<(), String> {
+        let (fat_sct, ent_off) = self.fat_ent_pos(clust);
+        let bs = self.part.block_size() as usize;
+        let span = if ent_off + size_of::<u32>() > bs { bs * 2 } else { bs };
+        let mut buf = alloc::vec![0u8; span];
+
+        for fat in 0..self.bpb.num_fats as u64 {
+            let sct = fat_sct + fat * self.fat_sz() as u64;
+            self.part.read_block(&mut buf, sct)
+                .map_err(|e| alloc::format!("FAT read error: {}", e))?;
+
+            match self.fat_type() {
+                FatType::Fat12 => {
+                    let raw = u16::from_le_bytes([buf[ent_off], buf[ent_off + 1]]);
+                    let raw = if clust & 1 == 0 {
+                        (raw & 0xf000) | (val as u16 & 0x0fff)
+                    } else {
+                        (raw & 0x000f) | ((val as u16 & 0x0fff) << 4)
+                    };
+                    buf[ent_off..ent_off + 2].copy_from_slice(&raw.to_le_bytes());
+                }
+                FatType::Fat16 => {
+                    buf[ent_off..ent_off + 2].copy_from_slice(&(val as u16).to_le_bytes());
+                }
+                FatType::Fat32(_) => {
+                    let raw = &buf[ent_off..ent_off + size_of::<u32>()];
+                    let raw = u32le::from_bytes(raw.try_into().unwrap()).get();
+                    let raw = (raw & 0xf0000000) | (val & 0x0fffffff);
+                    buf[ent_off..ent_off + 4].copy_from_slice(&raw.to_le_bytes());
+                }
+            }
+
+            self.part.write_block(&buf, sct)
+                .map_err(|e| alloc::format!("FAT write error: {}", e))?;
+        }
+
+        return Ok(());
+    }
+
+    fn eoc(&self) -> u32 {
+        return match self.fat_type() {
+            FatType::Fat12 => 0x0fff,
+            FatType::Fat16 => 0xffff,
+            FatType::Fat32(_) => 0x0fffffff
         };
+    }
+
+    fn next_clust(&self, clust: u32) -> Option<u32> {
+        let entry = self.fat_ent(clust)?;
 
         return match self.fat_type() {
             FatType::Fat12 if entry >= 0x0ff8 => None,
             FatType::Fat16 if entry >= 0xfff8 => None,
             FatType::Fat32(_) if entry >= 0x0ffffff8 => None,
+            _ if entry < 2 => None,
             _ => Some(entry)
         };
     }
+
+    // Searches from `hint` and wraps around, a bogus hint means a full scan
+    fn find_free_clust(&self, hint: u32) -> Option<u32> {
+        let (first, end) = (2, self.clust_cnt() + 2);
+        let start = if (first..end).contains(&hint) { hint } else { first };
+        return (start..end).chain(first..start).find(|&c| self.fat_ent(c) == Some(0));
+    }
+
+    pub fn next_free_clust(&self) -> Option<u32> {
+        let hint = self.free.lock().nxt_free;
+        return self.find_free_clust(hint);
+    }
+
+    // The FSInfo count is only trusted while it is plausible
+    pub fn free_clusters(&self) -> u32 {
+        let mut free = self.free.lock();
+        if free.free_cnt > self.clust_cnt() {
+            free.free_cnt = (2..self.clust_cnt() + 2)
+                .filter(|&c| self.fat_ent(c) == Some(0))
+                .count() as u32;
+        }
+        return free.free_cnt;
+    }
+
+    // Allocates a zeroed cluster, terminates it and links it after `prev`
+    fn alloc_clust(&self, prev: Option<u32>) -> Result<u32, String> {
+        let mut free = self.free.lock();
+        let clust = self.find_free_clust(free.nxt_free).ok_or("No space left on device")?;
+
+        self.set_fat_ent(clust, self.eoc())?;
+        let zero = alloc::vec![0u8; self.clust_size()];
+        self.part.write_block(&zero, self.clust2sct(clust))
+            .map_err(|e| alloc::format!("FAT write error: {}", e))?;
+
+        if let Some(prev) = prev {
+            self.set_fat_ent(prev, clust)?;
+        }
+
+        free.nxt_free = clust + 1;
+        if free.free_cnt != FreeHint::UNKNOWN {
+            free.free_cnt = free.free_cnt.saturating_sub(1);
+        }
+        free.dirty = true;
+        return Ok(clust);
+    }
+
+    fn free_chain(&self, first: u32) -> Result<(), String> {
+        let mut free = self.free.lock();
+        let mut clust = Some(first);
+        let mut freed = 0;
+        while let Some(c) = clust {
+            clust = self.next_clust(c);
+            self.set_fat_ent(c, 0)?;
+            freed += 1;
+        }
+
+        if free.free_cnt != FreeHint::UNKNOWN {
+            free.free_cnt += freed;
+        }
+        free.dirty = true;
+        return Ok(());
+    }
+}
+
+pub fn probe(boot: &[u8], dev: Arc<dyn BlockDevice>) -> Option<Arc<dyn Partition>> {
+    if boot.len() < 512 || boot[510..512] != [0x55, 0xaa] {
+        return None;
+    }
+
+    let bpb = unsafe { (boot.as_ptr() as *const BootParamBlock).read() };
+    if !bpb.byts_per_sec.get().is_power_of_two() || !bpb.sec_per_clus.is_power_of_two() {
+        return None;
+    }
+
+    // fil_sys_type of the FAT32 and FAT12/16 extended BPBs
+    let fat32 = bpb.fat_sz16.get() == 0 && boot[82..87] == *b"FAT32";
+    let fat16 = bpb.fat_sz16.get() != 0 && boot[54..57] == *b"FAT";
+    if !fat32 && !fat16 {
+        return None;
+    }
+
+    return FileAllocTable::new(dev).map(|fat| fat as Arc<dyn Partition>);
 }
 
 impl Partition for FileAllocTable {
@@ -430,6 +1172,335 @@ impl Partition for FileAllocTable {
             file_size: u32le::new(0)
         };
 
-        return Arc::new(FatFile::new(self, ent, 0)) as Arc<dyn VirtFNode>;
+        return Arc::new(FatFile::new(self, ent, None, 0)) as Arc<dyn VirtFNode>;
+    }
+
+    fn statfs(&self) -> Result<StatFs, String> {
+        let clust_size = self.clust_size() as u64;
+        return Ok(StatFs {
+            fstype: match self.fat_type() {
+                FatType::Fat12 => "fat12",
+                FatType::Fat16 => "fat16",
+                FatType::Fat32(_) => "fat32"
+            },
+            block_size: clust_size,
+            total: self.clust_cnt() as u64 * clust_size,
+            free: self.free_clusters() as u64 * clust_size
+        });
+    }
+
+    fn sync(&self) -> Result<(), String> {
+        let mut free = self.free.lock();
+        if free.dirty {
+            self.sync_fsinfo(&free)?;
+            free.dirty = false;
+        }
+        return self.part.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{device::block::check_lba, filesys::parts::probe_filesystem};
+    use alloc::vec;
+
+    const BS: usize = 512;
+    const RSVD: usize = 32;
+    const CLUSTS: usize = 64;
+
+    struct RamDisk(Mutex<Vec<u8>>);
+
+    impl BlockDevice for RamDisk {
+        fn block_size(&self) -> u64 { BS as u64 }
+        fn block_count(&self) -> u64 { (self.0.lock().len() / BS) as u64 }
+        fn devid(&self) -> u64 { 0 }
+
+        fn read_block(&self, buf: &mut [u8], lba: u64) -> Result<(), String> {
+            check_lba(self, lba, buf.len())?;
+            buf.copy_from_slice(&self.0.lock()[lba as usize * BS..][..buf.len()]);
+            return Ok(());
+        }
+
+        fn write_block(&self, buf: &[u8], lba: u64) -> Result<(), String> {
+            check_lba(self, lba, buf.len())?;
+            self.0.lock()[lba as usize * BS..][..buf.len()].copy_from_slice(buf);
+            return Ok(());
+        }
+    }
+
+    fn put(img: &mut [u8], off: usize, bytes: &[u8]) {
+        img[off..off + bytes.len()].copy_from_slice(bytes);
+    }
+
+    // FAT32 of one-sector clusters: FSInfo in sector 1, two one-sector FATs, root at cluster 2
+    fn image() -> Vec<u8> {
+        let total = RSVD + 2 + CLUSTS;
+        let mut img = vec![0u8; total * BS];
+        put(&mut img, 0, &[0xeb, 0x58, 0x90]);
+        put(&mut img, 11, &(BS as u16).to_le_bytes());
+        img[13] = 1;
+        put(&mut img, 14, &(RSVD as u16).to_le_bytes());
+        img[16] = 2;
+        img[21] = 0xf8;
+        put(&mut img, 32, &(total as u32).to_le_bytes());
+        put(&mut img, 36, &1u32.to_le_bytes());
+        put(&mut img, 44, &2u32.to_le_bytes());
+        put(&mut img, 48, &1u16.to_le_bytes());
+        img[66] = 0x29;
+        put(&mut img, 82, b"FAT32   ");
+        put(&mut img, 510, &[0x55, 0xaa]);
+
+        put(&mut img, BS, &FreeHint::LEAD_SIG.to_le_bytes());
+        put(&mut img, BS + 484, &FreeHint::STRUC_SIG.to_le_bytes());
+        put(&mut img, BS + 488, &(CLUSTS as u32 - 1).to_le_bytes());
+        put(&mut img, BS + 492, &3u32.to_le_bytes());
+        put(&mut img, BS + 508, &FreeHint::TRAIL_SIG.to_le_bytes());
+
+        for fat in [RSVD, RSVD + 1] {
+            put(&mut img, fat * BS, &0x0ffffff8u32.to_le_bytes());
+            put(&mut img, fat * BS + 4, &0x0fffffffu32.to_le_bytes());
+            put(&mut img, fat * BS + 8, &0x0fffffffu32.to_le_bytes());
+        }
+        return img;
+    }
+
+    fn fat32() -> Arc<FileAllocTable> {
+        return FileAllocTable::new(Arc::new(RamDisk(Mutex::new(image())))).unwrap();
+    }
+
+    fn chain(fs: &FileAllocTable, first: u32) -> Vec<u32> {
+        let mut clusts = Vec::new();
+        let mut clust = (first != 0).then_some(first);
+        while let Some(c) = clust {
+            clusts.push(c);
+            clust = fs.next_clust(c);
+        }
+        return clusts;
+    }
+
+    // Partition::root hands out a dyn node, these tests need the FatFile
+    fn root(fs: &Arc<FileAllocTable>) -> FatFile {
+        return FatFile::new(fs.clone(), FatDirEnt::new(*b"/          ", 0x10, 2), None, 0);
+    }
+
+    fn lookup(dir: &FatFile, name: &str) -> FatFile {
+        return dir.for_each_ent(|&ent, fname, pos, fid| {
+            (fname == name).then(|| FatFile::new(dir.fs.clone(), ent, Some(pos), fid))
+        }).unwrap().unwrap();
+    }
+
+    fn new_file(dir: &FatFile, name: &str) -> FatFile {
+        dir.create(name, FType::Regular).unwrap();
+        return lookup(dir, name);
+    }
+
+    #[test]
+    fn write_appends_within_a_cluster() {
+        let fs = fat32();
+        let file = new_file(&root(&fs), "A.TXT");
+        assert_eq!(file.write(b"hello", 0), Ok(5));
+        assert_eq!(file.write(b" world", 5), Ok(6));
+
+        let mut buf = [0; 16];
+        assert_eq!(file.read(&mut buf, 0), Ok(11));
+        assert_eq!(buf[..11], *b"hello world");
+        assert_eq!(chain(&fs, file.dirent.lock().fst_clus()).len(), 1);
+        assert_eq!(lookup(&root(&fs), "A.TXT").meta().size, 11);
+    }
+
+    #[test]
+    fn write_crosses_a_cluster_boundary() {
+        let fs = fat32();
+        let file = new_file(&root(&fs), "A.TXT");
+        assert_eq!(file.write(&[1; 500], 0), Ok(500));
+        assert_eq!(file.write(&[2; 100], 500), Ok(100));
+        assert_eq!(chain(&fs, file.dirent.lock().fst_clus()).len(), 2);
+
+        let mut buf = [0; 40];
+        assert_eq!(file.read(&mut buf, 490), Ok(40));
+        assert_eq!(buf[..10], [1; 10]);
+        assert_eq!(buf[10..], [2; 30]);
+        assert_eq!(lookup(&root(&fs), "A.TXT").meta().size, 600);
+    }
+
+    #[test]
+    fn truncate_frees_the_tail() {
+        let fs = fat32();
+        let file = new_file(&root(&fs), "A.TXT");
+        file.write(&[7; 3 * BS + 10], 0).unwrap();
+        let first = file.dirent.lock().fst_clus();
+        assert_eq!(chain(&fs, first).len(), 4);
+        let free = fs.free_clusters();
+
+        file.truncate(BS as u64 + 1).unwrap();
+        assert_eq!(chain(&fs, first).len(), 2);
+        assert_eq!(fs.free_clusters(), free + 2);
+        assert_eq!(file.meta().size, BS as u64 + 1);
+        let mut buf = [0; 8];
+        assert_eq!(file.read(&mut buf, BS as u64 - 4), Ok(5));
+
+        file.truncate(0).unwrap();
+        assert_eq!(file.dirent.lock().fst_clus(), 0);
+        assert_eq!(fs.free_clusters(), free + 4);
+    }
+
+    // Slots written by hand at the start of the root directory
+    fn put_slots(fs: &FileAllocTable, lfn: &[FatLfnEnt], short: &FatDirEnt) {
+        let sct = fs.clust2sct(2);
+        for (i, ent) in lfn.iter().enumerate() {
+            fs.write_slot(EntPos { sct, off: i * size_of::<FatDirEnt>() }, ent).unwrap();
+        }
+        fs.write_slot(EntPos { sct, off: lfn.len() * size_of::<FatDirEnt>() }, short).unwrap();
+    }
+
+    fn names(dir: &FatFile) -> Vec<String> {
+        let mut names = Vec::new();
+        dir.for_each_ent(|_ent, name, _pos, _fid| { names.push(name.into()); None::<()> }).unwrap();
+        return names;
+    }
+
+    #[test]
+    fn long_name_over_several_entries() {
+        let fs = fat32();
+        let name = "A rather long file name.txt";
+        let short = FatDirEnt::new(*b"ARATHE~1TXT", 0x20, 0);
+        let lfn = FatLfnEnt::for_name(name, short.chksum());
+        assert_eq!(lfn.len(), 3);
+
+        put_slots(&fs, &lfn, &short);
+        assert_eq!(names(&root(&fs)), vec![name]);
+    }
+
+    #[test]
+    fn bad_checksum_falls_back_to_short_name() {
+        let fs = fat32();
+        let short = FatDirEnt::new(*b"ARATHE~1TXT", 0x20, 0);
+        let lfn = FatLfnEnt::for_name("A rather long file name.txt", short.chksum().wrapping_add(1));
+
+        put_slots(&fs, &lfn, &short);
+        assert_eq!(names(&root(&fs)), vec!["ARATHE~1.TXT"]);
+    }
+
+    #[test]
+    fn probe_finds_fat_and_skips_garbage() {
+        let fat = Arc::new(RamDisk(Mutex::new(image())));
+        assert!(probe_filesystem(fat).is_some());
+
+        let mut seed = 0x2545f4914f6cdd1du64;
+        let junk = (0..8 * BS).map(|_| {
+            seed ^= seed << 13; seed ^= seed >> 7; seed ^= seed << 17;
+            seed as u8
+        }).collect();
+        assert!(probe_filesystem(Arc::new(RamDisk(Mutex::new(junk)))).is_none());
+        assert!(probe_filesystem(Arc::new(RamDisk(Mutex::new(vec![0; 8 * BS])))).is_none());
+    }
+
+    #[test]
+    fn allocation_moves_the_free_hint() {
+        let fs = fat32();
+        assert_eq!(fs.next_free_clust(), Some(3));
+        assert_eq!(fs.free_clusters(), CLUSTS as u32 - 1);
+
+        assert_eq!(fs.alloc_clust(None), Ok(3));
+        assert_eq!(fs.alloc_clust(Some(3)), Ok(4));
+        assert_eq!(fs.next_free_clust(), Some(5));
+        assert_eq!(fs.free_clusters(), CLUSTS as u32 - 3);
+
+        // Written back on sync and picked up by the next mount
+        fs.sync().unwrap();
+        let again = FileAllocTable::new(fs.part.clone()).unwrap();
+        assert_eq!(again.next_free_clust(), Some(5));
+        assert_eq!(again.free_clusters(), CLUSTS as u32 - 3);
+    }
+
+    #[test]
+    fn unknown_fsinfo_means_a_scan() {
+        let mut img = image();
+        put(&mut img, BS + 488, &FreeHint::UNKNOWN.to_le_bytes());
+        put(&mut img, BS + 492, &FreeHint::UNKNOWN.to_le_bytes());
+        let fs = FileAllocTable::new(Arc::new(RamDisk(Mutex::new(img)))).unwrap();
+
+        assert_eq!(fs.next_free_clust(), Some(3));
+        assert_eq!(fs.free_clusters(), CLUSTS as u32 - 1);
+    }
+
+    #[test]
+    fn create_list_remove() {
+        let fs = fat32();
+        let dir = root(&fs);
+        let free = fs.free_clusters();
+
+        dir.create("README.TXT", FType::Regular).unwrap();
+        dir.create("A long file name.txt", FType::Regular).unwrap();
+        dir.create("SUB", FType::Directory).unwrap();
+        assert_eq!(dir.list().unwrap(), vec!["README.TXT", "A long file name.txt", "SUB"]);
+        assert_eq!(fs.free_clusters(), free - 1);
+
+        assert!(dir.create("readme.txt", FType::Regular).is_err());
+        assert!(dir.create("a:b", FType::Regular).is_err());
+        assert!(dir.create("trailing.", FType::Regular).is_err());
+
+        let sub = lookup(&dir, "SUB");
+        assert_eq!(sub.list().unwrap(), vec![".", ".."]);
+        sub.create("INNER", FType::Regular).unwrap();
+        assert_eq!(dir.remove("SUB"), Err("Directory not empty".into()));
+
+        let long = lookup(&dir, "A long file name.txt");
+        long.write(&[0x5a; 3 * BS], 0).unwrap();
+        assert_eq!(fs.free_clusters(), free - 4);
+
+        dir.remove("a LONG file name.TXT").unwrap();
+        sub.remove("INNER").unwrap();
+        dir.remove("SUB").unwrap();
+        assert_eq!(dir.list().unwrap(), vec!["README.TXT"]);
+        assert_eq!(fs.free_clusters(), free);
+        assert_eq!(dir.remove("SUB"), Err("File not found".into()));
+
+        // The slots freed behind README.TXT are taken again
+        dir.create("NEW", FType::Regular).unwrap();
+        assert_eq!(names(&dir), vec!["README.TXT", "NEW"]);
+    }
+
+    #[test]
+    fn statfs_counts_used_clusters() {
+        let mut img = image();
+        put(&mut img, BS + 488, &FreeHint::UNKNOWN.to_le_bytes());
+        // A five cluster chain, a lone cluster and a bad one besides the root
+        for fat in [RSVD, RSVD + 1] {
+            for clust in 3..7u32 {
+                put(&mut img, fat * BS + clust as usize * 4, &(clust + 1).to_le_bytes());
+            }
+            put(&mut img, fat * BS + 7 * 4, &0x0fffffffu32.to_le_bytes());
+            put(&mut img, fat * BS + 10 * 4, &0x0fffffffu32.to_le_bytes());
+            put(&mut img, fat * BS + 12 * 4, &0x0ffffff7u32.to_le_bytes());
+        }
+        let fs = FileAllocTable::new(Arc::new(RamDisk(Mutex::new(img)))).unwrap();
+
+        let st = fs.statfs().unwrap();
+        assert_eq!(st.fstype, "fat32");
+        assert_eq!(st.block_size, BS as u64);
+        assert_eq!(st.total, (CLUSTS * BS) as u64);
+        assert_eq!(st.free, (CLUSTS - 8) as u64 * BS as u64);
+
+        fs.alloc_clust(None).unwrap();
+        assert_eq!(fs.statfs().unwrap().free, (CLUSTS - 9) as u64 * BS as u64);
+    }
+
+    #[test]
+    fn read_dir_stops_when_asked() {
+        let fs = fat32();
+        let dir = root(&fs);
+        for name in ["ONE", "TWO", "A long file name.txt", "FOUR"] {
+            dir.create(name, FType::Regular).unwrap();
+        }
+
+        let mut calls = 0;
+        dir.read_dir(&mut |_, _| { calls += 1; calls < 2 }).unwrap();
+        assert_eq!(calls, 2);
+
+        let mut seen = Vec::new();
+        dir.read_dir(&mut |name, _| { seen.push(String::from(name)); true }).unwrap();
+        assert_eq!(seen, ["ONE", "TWO", "A long file name.txt", "FOUR"]);
     }
 }
@@ -1,10 +1,39 @@
+pub mod ext2;
 pub mod fat;
+pub mod tar;
 pub mod vpart;
 
-use crate::filesys::vfn::VirtFNode;
+use crate::{device::block::BlockDevice, filesys::vfn::VirtFNode};
 
-use alloc::sync::Arc;
+use alloc::{string::String, sync::Arc};
+
+// Sizes in bytes
+#[derive(Clone, Copy, Debug)]
+pub struct StatFs {
+    pub fstype: &'static str,
+    pub block_size: u64,
+    pub total: u64,
+    pub free: u64
+}
 
 pub trait Partition: Send + Sync {
     fn root(self: Arc<Self>) -> Arc<dyn VirtFNode>;
+    fn sync(&self) -> Result<(), String> { Ok(()) }
+    fn statfs(&self) -> Result<StatFs, String> { Err("Unsupported operation".into()) }
+}
+
+// Receives LBA 0 of the device
+type ProbeFn = fn(&[u8], Arc<dyn BlockDevice>) -> Option<Arc<dyn Partition>>;
+
+// Tried in order, first match wins
+const PROBES: &[ProbeFn] = &[
+    fat::probe,
+    ext2::probe
+];
+
+pub fn probe_filesystem(dev: Arc<dyn BlockDevice>) -> Option<Arc<dyn Partition>> {
+    let mut buf = alloc::vec![0u8; (dev.block_size() as usize).max(512)];
+    dev.read_block(&mut buf, 0).ok()?;
+
+    return PROBES.iter().find_map(|probe| probe(&buf, dev.clone()));
 }
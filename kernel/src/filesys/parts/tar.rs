@@ -0,0 +1,271 @@
+use crate::filesys::{
+    VirtSymlink,
+    parts::Partition,
+    vfn::{FMeta, FType, VirtFNode, read_len}
+};
+
+use alloc::{collections::btree_map::BTreeMap, format, string::String, sync::Arc, vec::Vec};
+use spin::Mutex;
+
+const BLOCK: usize = 512;
+
+pub struct TarEntry<'a> {
+    pub path: String,
+    pub ftype: FType,
+    pub perm: u16,
+    pub data: &'a [u8],
+    pub link: &'a str
+}
+
+fn field_str(field: &[u8]) -> &str {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    return core::str::from_utf8(&field[..len]).unwrap_or("");
+}
+
+fn octal(field: &[u8]) -> Option<usize> {
+    let digits = field_str(field).trim_matches(' ');
+    if digits.is_empty() { return Some(0); }
+    return usize::from_str_radix(digits, 8).ok();
+}
+
+// ustar headers, stops at the first zero block or anything malformed
+pub fn parse(archive: &[u8]) -> Vec<TarEntry<'_>> {
+    let mut entries = Vec::new();
+    let mut pos = 0;
+
+    while pos + BLOCK <= archive.len() {
+        let hdr = &archive[pos..pos + BLOCK];
+        if hdr.iter().all(|&b| b == 0) { break; }
+        if &hdr[257..262] != b"ustar" { break; }
+
+        let Some(size) = octal(&hdr[124..136]) else { break; };
+        let data_start = pos + BLOCK;
+        let Some(data) = archive.get(data_start..data_start + size) else { break; };
+
+        // Names longer than 100 bytes are split into prefix and name
+        let (prefix, name) = (field_str(&hdr[345..500]), field_str(&hdr[0..100]));
+        let path = if prefix.is_empty() { String::from(name) } else { format!("{}/{}", prefix, name) };
+
+        let ftype = match hdr[156] {
+            b'0' | b'\0' => Some(FType::Regular),
+            b'2' => Some(FType::SymLink),
+            b'5' => Some(FType::Directory),
+            _ => None // Hard links, devices and the like are skipped
+        };
+
+        if let Some(ftype) = ftype {
+            entries.push(TarEntry {
+                path, ftype,
+                perm: octal(&hdr[100..108]).unwrap_or(0) as u16,
+                data,
+                link: field_str(&hdr[157..257])
+            });
+        }
+        pos = data_start + size.div_ceil(BLOCK) * BLOCK;
+    }
+    return entries;
+}
+
+struct TarFile {
+    meta: FMeta,
+    data: &'static [u8]
+}
+
+impl VirtFNode for TarFile {
+    fn meta(&self) -> FMeta {
+        return self.meta.clone();
+    }
+
+    fn read(&self, buf: &mut [u8], offset: u64) -> Result<usize, String> {
+        let read_len = read_len(self.data.len() as u64, offset, buf.len())?;
+        let offset = offset as usize;
+        buf[..read_len].copy_from_slice(&self.data[offset..offset + read_len]);
+        return Ok(read_len);
+    }
+
+    fn write(&self, _buf: &[u8], _offset: u64) -> Result<usize, String> {
+        return Err("Read-only file system".into());
+    }
+}
+
+// Filled while the archive is parsed, read only afterwards
+struct TarDir {
+    meta: FMeta,
+    dirs: Mutex<BTreeMap<String, Arc<TarDir>>>,
+    files: Mutex<BTreeMap<String, Arc<dyn VirtFNode>>>
+}
+
+impl TarDir {
+    fn new() -> Self {
+        return Self {
+            meta: FMeta::vfs_only(FType::Directory),
+            dirs: Mutex::new(BTreeMap::new()),
+            files: Mutex::new(BTreeMap::new())
+        };
+    }
+
+    fn subdir(&self, name: &str) -> Arc<TarDir> {
+        return self.dirs.lock()
+            .entry(String::from(name))
+            .or_insert_with(|| Arc::new(TarDir::new()))
+            .clone();
+    }
+
+    // Missing parent directories are made up on the way
+    fn insert(self: &Arc<Self>, entry: &TarEntry<'static>) {
+        let comps = entry.path.split('/')
+            .filter(|c| !c.is_empty() && *c != ".")
+            .collect::<Vec<_>>();
+        let Some((&name, parents)) = comps.split_last() else { return; };
+
+        let mut dir = self.clone();
+        for comp in parents {
+            dir = dir.subdir(comp);
+        }
+
+        let node: Arc<dyn VirtFNode> = match entry.ftype {
+            FType::Directory => { dir.subdir(name); return; }
+            FType::SymLink => Arc::new(VirtSymlink::new(entry.link)),
+            _ => {
+                let mut meta = FMeta::vfs_only(FType::Regular);
+                meta.size = entry.data.len() as u64;
+                meta.perm = entry.perm;
+                Arc::new(TarFile { meta, data: entry.data })
+            }
+        };
+        dir.files.lock().insert(String::from(name), node);
+    }
+}
+
+impl VirtFNode for TarDir {
+    fn meta(&self) -> FMeta {
+        return self.meta.clone();
+    }
+
+    fn read_dir(&self, f: &mut dyn FnMut(&str, FType) -> bool) -> Result<(), String> {
+        for name in self.dirs.lock().keys() {
+            if !f(name, FType::Directory) { return Ok(()); }
+        }
+        for (name, node) in self.files.lock().iter() {
+            if !f(name, node.meta().ftype) { break; }
+        }
+        return Ok(());
+    }
+
+    fn walk(&self, name: &str) -> Result<Arc<dyn VirtFNode>, String> {
+        if let Some(dir) = self.dirs.lock().get(name) {
+            return Ok(dir.clone());
+        }
+        return self.files.lock().get(name).cloned().ok_or("No such file".into());
+    }
+
+    fn create(&self, _name: &str, _ftype: FType) -> Result<(), String> {
+        return Err("Read-only file system".into());
+    }
+
+    fn link(&self, _name: &str, _node: Arc<dyn VirtFNode>) -> Result<(), String> {
+        return Err("Read-only file system".into());
+    }
+
+    fn remove(&self, _name: &str) -> Result<(), String> {
+        return Err("Read-only file system".into());
+    }
+}
+
+// In-memory tar archive, such as the initrd
+pub struct TarPart {
+    root: Arc<TarDir>
+}
+
+impl TarPart {
+    pub fn new(archive: &'static [u8]) -> Self {
+        let root = Arc::new(TarDir::new());
+        for entry in parse(archive) {
+            root.insert(&entry);
+        }
+        return Self { root };
+    }
+}
+
+impl Partition for TarPart {
+    fn root(self: Arc<Self>) -> Arc<dyn VirtFNode> {
+        return self.root.clone();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn header(name: &str, ty: u8, data: &[u8], link: &str) -> Vec<u8> {
+        let mut hdr = vec![0u8; BLOCK];
+        let (prefix, name) = if name.len() > 100 { name.rsplit_once('/').unwrap() } else { ("", name) };
+        hdr[..name.len()].copy_from_slice(name.as_bytes());
+        hdr[100..107].copy_from_slice(b"0000755");
+        hdr[124..135].copy_from_slice(format!("{:011o}", data.len()).as_bytes());
+        hdr[156] = ty;
+        hdr[157..157 + link.len()].copy_from_slice(link.as_bytes());
+        hdr[257..263].copy_from_slice(b"ustar\0");
+        hdr[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+        hdr.extend_from_slice(data);
+        hdr.resize(hdr.len().next_multiple_of(BLOCK), 0);
+        return hdr;
+    }
+
+    fn archive() -> Vec<u8> {
+        let long = format!("{}/deep.txt", "d".repeat(120));
+        return [
+            header("sbin/", b'5', b"", ""),
+            header("sbin/aleph", b'0', &[0x7f; 600], ""),
+            header("etc/motd", b'0', b"hello\n", ""),
+            header("bin", b'2', b"", "sbin"),
+            header("etc/hard", b'1', b"", "etc/motd"),
+            header(&long, b'0', b"x", ""),
+            vec![0; 2 * BLOCK],
+            header("after/the/end", b'0', b"", "")
+        ].concat();
+    }
+
+    #[test]
+    fn parse_small_archive() {
+        let blob = archive();
+        let entries = parse(&blob);
+        let paths = entries.iter().map(|e| e.path.as_str()).collect::<Vec<_>>();
+        assert_eq!(paths[..4], ["sbin/", "sbin/aleph", "etc/motd", "bin"]);
+        assert_eq!(paths[4], format!("{}/deep.txt", "d".repeat(120)));
+        assert_eq!(paths.len(), 5); // The hard link is skipped, nothing past the zero blocks is read
+
+        assert_eq!(entries[0].ftype, FType::Directory);
+        assert_eq!(entries[1].data, &[0x7f; 600]);
+        assert_eq!(entries[1].perm, 0o755);
+        assert_eq!(entries[2].data, b"hello\n");
+        assert_eq!((entries[3].ftype, entries[3].link), (FType::SymLink, "sbin"));
+    }
+
+    #[test]
+    fn truncated_archive_stops() {
+        let blob = archive();
+        assert_eq!(parse(&blob[..BLOCK + 100]).len(), 1); // The file's data is cut off
+        assert!(parse(&blob[..BLOCK - 1]).is_empty());
+        assert!(parse(&[0xff; BLOCK]).is_empty());
+    }
+
+    #[test]
+    fn tree_from_the_archive() {
+        let root = Arc::new(TarPart::new(archive().leak())).root();
+        let sbin = root.walk("sbin").unwrap();
+        assert_eq!(sbin.list().unwrap(), ["aleph"]);
+
+        let aleph = sbin.walk("aleph").unwrap();
+        assert_eq!(aleph.meta().size, 600);
+        let mut buf = [0u8; 8];
+        assert_eq!(aleph.read(&mut buf, 596), Ok(4));
+        assert!(aleph.write(b"x", 0).is_err());
+
+        assert_eq!(root.walk("etc").unwrap().list().unwrap(), ["motd"]);
+        assert_eq!(root.walk("bin").unwrap().readlink(), Ok("sbin".into()));
+        assert!(root.create("new", FType::Regular).is_err());
+    }
+}
@@ -1,4 +1,8 @@
-use crate::filesys::{parts::Partition, vfn::VirtFNode, VirtDir};
+use crate::filesys::{
+    VirtDir, tmpfs_usage,
+    parts::{Partition, StatFs},
+    vfn::VirtFNode
+};
 
 use alloc::sync::Arc;
 
@@ -18,4 +22,15 @@ impl Partition for VirtPart {
     fn root(self: Arc<Self>) -> Arc<dyn VirtFNode> {
         return self.root.clone();
     }
+
+    // The limit is shared by every in-memory partition
+    fn statfs(&self) -> Result<StatFs, String> {
+        let (used, limit) = tmpfs_usage();
+        return Ok(StatFs {
+            fstype: "tmpfs",
+            block_size: 1,
+            total: limit as u64,
+            free: limit.saturating_sub(used) as u64
+        });
+    }
 }
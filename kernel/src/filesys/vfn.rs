@@ -1,7 +1,8 @@
-use crate::device::block::BlockDevice;
+use crate::{device::block::BlockDevice, time};
 
 use core::sync::atomic::{AtomicU64, Ordering as SyncOrd};
 use alloc::{string::String, sync::Arc, vec::Vec};
+use spin::Mutex;
 
 #[repr(u16)]
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -23,16 +24,47 @@ pub struct FMeta {
     pub size: u64,
     pub ftype: FType,
     pub perm: u16,
+    pub uid: u16,
+    pub gid: u16,
+    pub atime: u64,
+    pub mtime: u64,
+    pub ctime: u64
+}
+
+// Permission bits an operation needs, shifted into place by FMeta::permits
+pub const MAY_READ: u16  = 0o4;
+pub const MAY_WRITE: u16 = 0o2;
+pub const MAY_EXEC: u16  = 0o1;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Credentials {
     pub uid: u16,
     pub gid: u16
 }
 
+impl Credentials {
+    pub const ROOT: Self = Self { uid: 0, gid: 0 };
+
+    pub fn is_root(&self) -> bool {
+        return self.uid == 0;
+    }
+}
+
 static FID: AtomicU64 = AtomicU64::new(2);
 
 pub fn vfid() -> u64 {
     return FID.fetch_add(1, SyncOrd::SeqCst);
 }
 
+// Bytes a read at `offset` takes from a file of `size`. Reading at EOF is
+// an empty read, only past it is an error. Every VirtFNode::read follows this.
+pub fn read_len(size: u64, offset: u64, buf_len: usize) -> Result<usize, String> {
+    if offset > size {
+        return Err("Offset out of bounds".into());
+    }
+    return Ok((size - offset).min(buf_len as u64) as usize);
+}
+
 impl FMeta {
     pub fn vfs_only(ftype: FType) -> Self {
         return Self::default(vfid(), 0, ftype);
@@ -40,32 +72,173 @@ impl FMeta {
 
     pub fn default(fid: u64, hostdev: u64, ftype: FType) -> Self {
         let perm = match ftype {
-            FType::Regular => 0x644,
-            FType::Directory => 0x755,
-            FType::BlockDev => 0x640,
-            FType::CharDev => 0x640,
-            FType::Fifo => 0x644,
+            FType::Regular => 0o644,
+            FType::Directory => 0o755,
+            FType::BlockDev => 0o640,
+            FType::CharDev => 0o640,
+            FType::Fifo => 0o644,
             FType::SymLink => 0o777,
-            FType::Socket => 0x644
+            FType::Socket => 0o644
         };
+        let now = time::now();
         return Self {
             fid, hostdev,
             size: 0, ftype, perm,
-            uid: 0, gid: 0
+            uid: 0, gid: 0,
+            atime: now, mtime: now, ctime: now
         };
     }
+
+    // Only the owner, group or other bits apply, whichever matches first. Root passes.
+    pub fn permits(&self, creds: &Credentials, want: u16) -> bool {
+        if creds.is_root() { return true; }
+        let shift = if creds.uid == self.uid {
+            6
+        } else if creds.gid == self.gid {
+            3
+        } else {
+            0
+        };
+        return (self.perm >> shift) & want == want;
+    }
 }
 
 // INTENTIONALLY FORCING INTERIOR MUTABILITY
 pub trait VirtFNode: Send + Sync {
     fn meta(&self) -> FMeta;
-    fn read(&self, _buf: &mut [u8], _offset: u64) -> Result<(), String> { Err("This file is not IOable".into()) }
-    fn write(&self, _buf: &[u8], _offset: u64) -> Result<(), String> { Err("This file is not IOable".into()) }
+    fn read(&self, _buf: &mut [u8], _offset: u64) -> Result<usize, String> { Err("This file is not IOable".into()) }
+    fn write(&self, _buf: &[u8], _offset: u64) -> Result<usize, String> { Err("This file is not IOable".into()) }
     fn truncate(&self, _size: u64) -> Result<(), String> { Err("This file is not IOable".into()) }
-    fn list(&self) -> Result<Vec<String>, String> { Err("This is not a directory".into()) }
+    fn ioctl(&self, _cmd: u32, _arg: usize) -> Result<usize, String> { Err("Unsupported ioctl".into()) }
+    // Calls `f` per entry until it returns false. `f` must not touch this directory.
+    fn read_dir(&self, _f: &mut dyn FnMut(&str, FType) -> bool) -> Result<(), String> { Err("This is not a directory".into()) }
     fn walk(&self, _name: &str) -> Result<Arc<dyn VirtFNode>, String> { Err("This is not a directory".into()) }
     fn create(&self, _name: &str, _ftype: FType) -> Result<(), String> { Err("This is not a directory".into()) }
     fn link(&self, _name: &str, _node: Arc<dyn VirtFNode>) -> Result<(), String> { Err("This is not a directory".into()) }
     fn remove(&self, _name: &str) -> Result<(), String> { Err("This is not a directory".into()) }
+    fn readlink(&self) -> Result<String, String> { Err("This is not a symbolic link".into()) }
     fn as_blkdev(&self) -> Option<Arc<dyn BlockDevice>> { None }
+
+    fn list(&self) -> Result<Vec<String>, String> {
+        let mut names = Vec::new();
+        self.read_dir(&mut |name, _ftype| {
+            names.push(String::from(name));
+            return true;
+        })?;
+        return Ok(names);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64)
+}
+
+// An open file description. Forked and duplicated fds share it, cursor included.
+pub struct OpenFile {
+    pub node: Arc<dyn VirtFNode>,
+    pub access: u16, // MAY_READ and MAY_WRITE, checked once at open
+    offset: Mutex<u64>
+}
+
+impl OpenFile {
+    pub fn new(node: Arc<dyn VirtFNode>, access: u16) -> Self {
+        return Self { node, access, offset: Mutex::new(0) };
+    }
+
+    pub fn offset(&self) -> u64 {
+        return *self.offset.lock();
+    }
+
+    // The cursor is not held across the I/O, which may block.
+    // Reading at or past the end of a regular file is an empty read.
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, String> {
+        if self.access & MAY_READ == 0 { return Err("Bad file descriptor".into()); }
+        let offset = self.offset();
+        let meta = self.node.meta();
+        let buf = match meta.ftype {
+            FType::Regular => {
+                let len = buf.len().min(meta.size.saturating_sub(offset) as usize);
+                if len == 0 { return Ok(0); }
+                &mut buf[..len]
+            }
+            _ => buf
+        };
+
+        let n = self.node.read(buf, offset)?;
+        *self.offset.lock() += n as u64;
+        return Ok(n);
+    }
+
+    pub fn write(&self, buf: &[u8]) -> Result<usize, String> {
+        if self.access & MAY_WRITE == 0 { return Err("Bad file descriptor".into()); }
+        let offset = self.offset();
+        let n = self.node.write(buf, offset)?;
+        *self.offset.lock() += n as u64;
+        return Ok(n);
+    }
+
+    // Past the end is fine, a later write fills the gap
+    pub fn seek(&self, pos: SeekFrom) -> Result<u64, String> {
+        let mut offset = self.offset.lock();
+        let new = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::Current(d) => offset.checked_add_signed(d),
+            SeekFrom::End(d) => self.node.meta().size.checked_add_signed(d)
+        }.ok_or("Invalid offset")?;
+        *offset = new;
+        return Ok(new);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_at_eof_is_empty() {
+        assert_eq!(read_len(100, 100, 64), Ok(0));
+        assert_eq!(read_len(0, 0, 64), Ok(0));
+    }
+
+    #[test]
+    fn zero_length_read() {
+        assert_eq!(read_len(100, 10, 0), Ok(0));
+        assert_eq!(read_len(100, 100, 0), Ok(0));
+    }
+
+    #[test]
+    fn read_past_eof_fails() {
+        assert!(read_len(100, 101, 64).is_err());
+        assert!(read_len(0, 1, 0).is_err());
+    }
+
+    #[test]
+    fn short_read_gets_what_is_left() {
+        assert_eq!(read_len(100, 90, 64), Ok(10));
+        assert_eq!(read_len(100, 0, 64), Ok(64));
+        assert_eq!(read_len(100, 36, 64), Ok(64));
+    }
+
+    #[test]
+    fn owner_group_other_bits() {
+        let mut meta = FMeta::default(1, 0, FType::Directory);
+        (meta.uid, meta.gid, meta.perm) = (1000, 100, 0o750);
+        let owner = Credentials { uid: 1000, gid: 100 };
+        let group = Credentials { uid: 1001, gid: 100 };
+        let other = Credentials { uid: 1002, gid: 200 };
+
+        assert!(meta.permits(&owner, MAY_READ | MAY_WRITE | MAY_EXEC));
+        assert!(meta.permits(&group, MAY_READ | MAY_EXEC));
+        assert!(!meta.permits(&group, MAY_WRITE));
+        assert!(!meta.permits(&other, MAY_EXEC));
+        assert!(meta.permits(&Credentials::ROOT, MAY_READ | MAY_WRITE | MAY_EXEC));
+
+        // Owner bits win even when they grant less than the group's
+        meta.perm = 0o070;
+        assert!(!meta.permits(&owner, MAY_READ));
+        assert!(meta.permits(&group, MAY_READ | MAY_WRITE | MAY_EXEC));
+    }
 }
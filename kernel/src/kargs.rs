@@ -1,6 +1,6 @@
 use crate::{arch::phys_id, ram::mutex::IntRwLock};
 
-use core::sync::atomic::{AtomicUsize, Ordering as AtomOrd};
+use core::{fmt, sync::atomic::{AtomicUsize, Ordering as AtomOrd}};
 use alloc::{collections::btree_map::BTreeMap, vec::Vec};
 use spin::RwLock;
 
@@ -39,7 +39,23 @@ pub struct SysInfo {
     pub layout_len: usize,
     pub acpi_ptr: usize,
     pub dtb_ptr: usize,
-    pub disk_uuid: [u8; 16]
+    pub disk_uuid: [u8; 16],
+    pub fb: FrameBuffer,
+    pub initrd_ptr: usize,
+    pub initrd_len: usize,
+    pub cmdline_ptr: usize,
+    pub cmdline_len: usize
+}
+
+// GOP mode at boot, `ptr` is 0 when there is none
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct FrameBuffer {
+    pub ptr: usize,
+    pub width: u32,
+    pub height: u32,
+    pub pitch: u32, // Bytes per scanline
+    pub format: u32
 }
 
 #[repr(C)]
@@ -68,7 +84,7 @@ pub struct RelaEntry {
 #[repr(C)]
 #[derive(Clone, Copy, Debug)]
 pub struct RAMDescriptor {
-    pub ty: RAMType,
+    pub ty: u32, // Raw, firmware can report types RAMType doesn't know
     pub reserved: u32,
     pub phys_start: u64,
     pub virt_start: u64,
@@ -77,6 +93,12 @@ pub struct RAMDescriptor {
     pub padding: u64
 }
 
+impl RAMDescriptor {
+    pub fn ty(&self) -> RAMType {
+        return RAMType::from_u32(self.ty);
+    }
+}
+
 pub struct ApList {
     bitmap: IntRwLock<RwLock<()>, Vec<usize>>,
     phys2virt: IntRwLock<RwLock<()>, BTreeMap<usize, usize>>
@@ -97,20 +119,26 @@ impl ApList {
     }
 
     pub fn assign(&self) -> usize {
-        let physid = phys_id();
-        let mut virtid = physid;
+        return self.assign_for(phys_id());
+    }
+
+    // Also used by the BSP to reserve an id before the AP is started
+    pub fn assign_for(&self, physid: usize) -> usize {
         let mut bm = self.bitmap.write();
 
-        for (i, word) in bm.iter_mut().enumerate() {
-            if *word != usize::MAX {
+        let free = bm.iter_mut().enumerate().find(|(_, word)| **word != usize::MAX);
+        let virtid = match free {
+            Some((i, word)) => {
                 let bit = (!*word).trailing_zeros() as usize;
                 *word |= 1 << bit;
-                virtid = i * usize::BITS as usize + bit;
-                break;
+                i * usize::BITS as usize + bit
             }
-        }
+            None => {
+                bm.push(1);
+                (bm.len() - 1) * usize::BITS as usize
+            }
+        };
 
-        bm.push(1);
         self.phys2virt.write().insert(physid, virtid);
         return virtid;
     }
@@ -153,6 +181,7 @@ pub enum RAMType {
     // ...
 
     KernelData      = 0x44415441,
+    LowMemory       = 0x4c4f574d,
     EfiRamLayout    = 0x524c594f,
     ElfSegments     = 0x7f454c46,
     KernelPTable    = 0x929b4000,
@@ -161,6 +190,76 @@ pub enum RAMType {
     Kernel          = 0xffffffff
 }
 
+impl RAMType {
+    // Unknown values, OEM and OS-defined ranges included, fall back to Reserved
+    pub fn from_u32(val: u32) -> Self {
+        return match val {
+            0x00 => Self::Reserved,
+            0x01 => Self::LoaderCode,
+            0x02 => Self::LoaderData,
+            0x03 => Self::BootSvcCode,
+            0x04 => Self::BootSvcData,
+            0x05 => Self::RtSvcCode,
+            0x06 => Self::RtSvcData,
+            0x07 => Self::Conv,
+            0x08 => Self::Unusable,
+            0x09 => Self::ACPIReclaim,
+            0x0a => Self::ACPINonVolatile,
+            0x0b => Self::MMIO,
+            0x0c => Self::MMIOPortSpace,
+            0x0d => Self::PALCode,
+            0x0e => Self::PersistentRAM,
+            0x0f => Self::Unaccepted,
+            0x44415441 => Self::KernelData,
+            0x4c4f574d => Self::LowMemory,
+            0x524c594f => Self::EfiRamLayout,
+            0x7f454c46 => Self::ElfSegments,
+            0x929b4000 => Self::KernelPTable,
+            0xb6876800 => Self::Reclaimable,
+            0xba9b4000 => Self::UserPTable,
+            0xffffffff => Self::Kernel,
+            _ => Self::Reserved
+        };
+    }
+
+    pub fn name(&self) -> &'static str {
+        return match self {
+            Self::Reserved        => "Reserved",
+            Self::LoaderCode      => "Loader Code",
+            Self::LoaderData      => "Loader Data",
+            Self::BootSvcCode     => "Boot Services Code",
+            Self::BootSvcData     => "Boot Services Data",
+            Self::RtSvcCode       => "Runtime Services Code",
+            Self::RtSvcData       => "Runtime Services Data",
+            Self::Conv            => "Conventional",
+            Self::Unusable        => "Unusable",
+            Self::ACPIReclaim     => "ACPI Reclaim",
+            Self::ACPINonVolatile => "ACPI NVS",
+            Self::MMIO            => "MMIO",
+            Self::MMIOPortSpace   => "MMIO Port Space",
+            Self::PALCode         => "PAL Code",
+            Self::PersistentRAM   => "Persistent",
+            Self::Unaccepted      => "Unaccepted",
+            Self::Max             => "Max",
+            Self::KernelData      => "Kernel Data",
+            Self::LowMemory       => "Low Memory",
+            Self::EfiRamLayout    => "EFI RAM Layout",
+            Self::ElfSegments     => "ELF Segments",
+            Self::KernelPTable    => "Kernel Page Table",
+            Self::Reclaimable     => "Reclaimable",
+            Self::UserPTable      => "User Page Table",
+            Self::Kernel          => "Kernel"
+        };
+    }
+}
+
+// Padded, so it lines up in tables with {:<N}
+impl fmt::Display for RAMType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return f.pad(self.name());
+    }
+}
+
 pub const DT_NULL: usize   = 0;
 // pub const DT_STRTAB: usize = 5;
 // pub const DT_SYMTAB: usize = 6;
@@ -202,11 +301,25 @@ impl SysInfo {
             layout_len: 0,
             acpi_ptr: 0,
             dtb_ptr: 0,
-            disk_uuid: [0; 16]
+            disk_uuid: [0; 16],
+            fb: FrameBuffer::empty(),
+            initrd_ptr: 0,
+            initrd_len: 0,
+            cmdline_ptr: 0,
+            cmdline_len: 0
         }
     }
 }
 
+impl FrameBuffer {
+    pub const FORMAT_RGB: u32 = 0;
+    pub const FORMAT_BGR: u32 = 1;
+
+    pub const fn empty() -> Self {
+        Self { ptr: 0, width: 0, height: 0, pitch: 0, format: 0 }
+    }
+}
+
 pub fn efi_ram_layout<'a>() -> &'a [RAMDescriptor] {
     let sys = SYSINFO.read();
     return unsafe { core::slice::from_raw_parts(sys.layout_ptr as *const RAMDescriptor, sys.layout_len) };
@@ -217,6 +330,78 @@ pub fn efi_ram_layout_mut<'a>() -> &'a mut [RAMDescriptor] {
     return unsafe { core::slice::from_raw_parts_mut(sys.layout_ptr as *mut RAMDescriptor, sys.layout_len) };
 }
 
+// Copied out of LOADER_DATA by PhysAlloc::init, so it outlives reclaim
+pub fn initrd() -> Option<&'static [u8]> {
+    let sys = SYSINFO.read();
+    if sys.initrd_ptr == 0 { return None; }
+    return Some(unsafe { core::slice::from_raw_parts(sys.initrd_ptr as *const u8, sys.initrd_len) });
+}
+
+// Also copied by PhysAlloc::init, empty if there was no \cmdline
+pub fn cmdline() -> &'static str {
+    let sys = SYSINFO.read();
+    if sys.cmdline_ptr == 0 { return ""; }
+    let bytes = unsafe { core::slice::from_raw_parts(sys.cmdline_ptr as *const u8, sys.cmdline_len) };
+    return core::str::from_utf8(bytes).unwrap_or("").trim();
+}
+
+// Whitespace separated `key=value` pairs, values may be double quoted.
+// Bare flags come out with an empty value.
+pub fn parse_kv(line: &str) -> impl Iterator<Item = (&str, &str)> {
+    let mut rest = line;
+    return core::iter::from_fn(move || {
+        rest = rest.trim_start();
+        if rest.is_empty() { return None; }
+
+        let key_end = rest.find(|c: char| c == '=' || c.is_whitespace()).unwrap_or(rest.len());
+        let key = &rest[..key_end];
+        rest = &rest[key_end..];
+
+        let Some(val) = rest.strip_prefix('=') else { return Some((key, "")); };
+        let (value, next) = match val.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"').unwrap_or(quoted.len());
+                (&quoted[..end], quoted.get(end + 1..).unwrap_or(""))
+            }
+            None => {
+                let end = val.find(char::is_whitespace).unwrap_or(val.len());
+                (&val[..end], &val[end..])
+            }
+        };
+        rest = next;
+        return Some((key, value));
+    });
+}
+
+// The last occurrence wins
+pub fn cmdline_get(key: &str) -> Option<&'static str> {
+    return parse_kv(cmdline()).filter(|(k, _)| *k == key).last().map(|(_, v)| v);
+}
+
+// `console=ttyS0,38400n8` style, only the speed is taken
+pub fn console_baud() -> Option<u32> {
+    let (_, opts) = cmdline_get("console")?.split_once(',')?;
+    let digits = opts.find(|c: char| !c.is_ascii_digit()).unwrap_or(opts.len());
+    return opts[..digits].parse().ok().filter(|&baud| baud != 0);
+}
+
+// `console=tty0` mirrors kernel output to the screen, as on Linux
+pub fn console_vga() -> bool {
+    return parse_kv(cmdline()).any(|(k, v)| k == "console" && v.split(',').next() == Some("tty0"));
+}
+
+// `tmpfs_size=64M` style, K, M and G suffixes are powers of 1024
+pub fn tmpfs_size() -> Option<usize> {
+    let val = cmdline_get("tmpfs_size")?;
+    let (digits, shift) = match val.as_bytes().last()? {
+        b'K' | b'k' => (&val[..val.len() - 1], 10),
+        b'M' | b'm' => (&val[..val.len() - 1], 20),
+        b'G' | b'g' => (&val[..val.len() - 1], 30),
+        _ => (val, 0)
+    };
+    return digits.parse::<usize>().ok()?.checked_mul(1 << shift);
+}
+
 pub fn elf_segments<'a>() -> &'a [Segment] {
     let kinfo = KINFO.read();
     return unsafe { core::slice::from_raw_parts(kinfo.seg_ptr as *const Segment, kinfo.seg_len) };
@@ -227,3 +412,57 @@ pub fn set_kargs(kargs: Kargs) {
     SYSINFO.write().clone_from(&kargs.sys);
     KBASE.store(kargs.kbase, AtomOrd::Relaxed);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_kv_pairs() {
+        let kv: Vec<_> = parse_kv("  root=/dev/nvme0p2 quiet  console=ttyS0,115200n8\tinit=\"/bin/sh -l\" x=").collect();
+        assert_eq!(kv, [
+            ("root", "/dev/nvme0p2"),
+            ("quiet", ""),
+            ("console", "ttyS0,115200n8"),
+            ("init", "/bin/sh -l"),
+            ("x", "")
+        ]);
+    }
+
+    #[test]
+    fn parse_kv_unterminated_quote() {
+        let kv: Vec<_> = parse_kv("a=\"b c").collect();
+        assert_eq!(kv, [("a", "b c")]);
+        assert_eq!(parse_kv("   ").count(), 0);
+    }
+
+    #[test]
+    fn ram_type_names_and_fallback() {
+        use alloc::format;
+        let all = [
+            (RAMType::Reserved, "Reserved"), (RAMType::LoaderCode, "Loader Code"),
+            (RAMType::LoaderData, "Loader Data"), (RAMType::BootSvcCode, "Boot Services Code"),
+            (RAMType::BootSvcData, "Boot Services Data"), (RAMType::RtSvcCode, "Runtime Services Code"),
+            (RAMType::RtSvcData, "Runtime Services Data"), (RAMType::Conv, "Conventional"),
+            (RAMType::Unusable, "Unusable"), (RAMType::ACPIReclaim, "ACPI Reclaim"),
+            (RAMType::ACPINonVolatile, "ACPI NVS"), (RAMType::MMIO, "MMIO"),
+            (RAMType::MMIOPortSpace, "MMIO Port Space"), (RAMType::PALCode, "PAL Code"),
+            (RAMType::PersistentRAM, "Persistent"), (RAMType::Unaccepted, "Unaccepted"),
+            (RAMType::KernelData, "Kernel Data"), (RAMType::LowMemory, "Low Memory"),
+            (RAMType::EfiRamLayout, "EFI RAM Layout"), (RAMType::ElfSegments, "ELF Segments"),
+            (RAMType::KernelPTable, "Kernel Page Table"), (RAMType::Reclaimable, "Reclaimable"),
+            (RAMType::UserPTable, "User Page Table"), (RAMType::Kernel, "Kernel")
+        ];
+        for (ty, name) in all {
+            assert_eq!(format!("{}", ty), name);
+            assert_eq!(RAMType::from_u32(ty as u32), ty);
+        }
+        assert_eq!(format!("[{:<8}]", RAMType::MMIO), "[MMIO    ]");
+
+        // Max only bounds the UEFI range, it never comes back from firmware
+        assert_eq!(format!("{}", RAMType::Max), "Max");
+        for val in [0x10, 0x7000_0000, 0x8000_0001, 0x4441_5442] {
+            assert_eq!(RAMType::from_u32(val), RAMType::Reserved);
+        }
+    }
+}
@@ -1,18 +1,430 @@
-use crate::{arch, proc::exit_proc, ram::glacier::hihalf};
+use crate::{
+    arch::{self, rvm::flags},
+    device::power,
+    filesys::{VFS, vfn::{Credentials, FType, MAY_READ, MAY_WRITE, OpenFile, SeekFrom}},
+    proc::{
+        PROCS, block_proc, current_pid, exit_proc, yield_proc,
+        ctrlblk::{FileDesc, ProcCtrlBlk}
+    },
+    ram::glacier::{hihalf, page_size}
+};
 
-use core::slice::from_raw_parts;
+use core::slice::{from_raw_parts, from_raw_parts_mut};
+use alloc::{collections::btree_map::BTreeMap, sync::Arc};
 
+// Returns EFAULT from the request unless `$ctr` items at `$ptr` are the caller's
+// own memory, writable too with `mut`
 macro_rules! check_fault {
-    ($ptr:tt, $ctr:tt, $sz:ty) => { {
-        const INVALID_VA: usize = 1 << (usize::BITS - 1);
-        let ctr_end = $ptr.saturating_add(
-            $ctr.saturating_mul(size_of::<$sz>())
-        );
+    (mut $ptr:expr, $ctr:expr, $sz:ty) => {
+        if !user_range($ptr, ($ctr as usize).saturating_mul(size_of::<$sz>()), true) {
+            return Errno::EFAULT.ret();
+        }
+    };
+    ($ptr:expr, $ctr:expr, $sz:ty) => {
+        if !user_range($ptr, ($ctr as usize).saturating_mul(size_of::<$sz>()), false) {
+            return Errno::EFAULT.ret();
+        }
+    };
+}
+
+#[repr(isize)]
+#[derive(Clone, Copy, Debug)]
+pub enum Errno {
+    ENOENT = 2,
+    EIO    = 5,
+    EBADF  = 9,
+    ECHILD = 10,
+    EAGAIN = 11,
+    ENOMEM = 12,
+    EACCES = 13,
+    EFAULT = 14,
+    EEXIST = 17,
+    ENOTDIR = 20,
+    EISDIR = 21,
+    EINVAL = 22,
+    EMFILE = 24,
+    ENOTTY = 25,
+    ENOSPC = 28,
+    ENAMETOOLONG = 36,
+    ENOSYS = 38
+}
+
+impl Errno {
+    pub fn ret(self) -> isize {
+        return -(self as isize);
+    }
+
+    // Best effort mapping of VFS error strings
+    pub fn from_vfs(err: &str) -> Self {
+        return match err {
+            "File not found" | "No such file" | "Invalid path" => Self::ENOENT,
+            "File already exists" => Self::EEXIST,
+            "This is not a directory" => Self::ENOTDIR,
+            "This file is not IOable" => Self::EISDIR,
+            "Unsupported ioctl" => Self::ENOTTY,
+            "Unsupported operation" => Self::ENOSYS,
+            "Invalid offset" => Self::EINVAL,
+            "Permission denied" => Self::EACCES,
+            "Bad file descriptor" => Self::EBADF,
+            "No space left" | "No space left on device" => Self::ENOSPC,
+            _ => Self::EIO
+        };
+    }
+}
+
+const MAX_FDS: usize = 256;
+const PATH_MAX: usize = 4096; // With the NUL
+
+const O_ACCMODE: usize = 3;
+const O_RDONLY: usize  = 0;
+const O_WRONLY: usize  = 1;
+const O_RDWR: usize    = 2;
+const O_CREAT: usize   = 0o100;
+const O_EXCL: usize    = 0o200;
+
+const SEEK_SET: usize = 0;
+const SEEK_CUR: usize = 1;
+const SEEK_END: usize = 2;
+
+const PROT_READ: usize  = 1;
+const PROT_WRITE: usize = 2;
+const PROT_EXEC: usize  = 4;
 
-        if ctr_end >= hihalf() {
-            let _ = unsafe { (INVALID_VA as *const u8).read_volatile() };
+type Args = [usize; 6];
+type ReqFn = fn(&Args) -> isize;
+
+const REQUESTS: &[(&[u8], ReqFn)] = &[
+    (b"exit",   req_exit),
+    (b"yield",  req_yield),
+    (b"fork",   req_fork),
+    (b"waitpid", req_waitpid),
+    (b"sbrk",   req_sbrk),
+    (b"mmap",   req_mmap),
+    (b"read",   req_read),
+    (b"write",  req_write),
+    (b"open",   req_open),
+    (b"close",  req_close),
+    (b"lseek",  req_lseek),
+    (b"sync",   req_sync),
+    (b"statfs", req_statfs),
+    (b"ioctl",  req_ioctl),
+    (b"poweroff", req_poweroff),
+    (b"reboot", req_reboot),
+    (b"_print", req_print) // This syscall is for debugging purposes only
+];
+
+fn lookup(req: &[u8]) -> Option<ReqFn> {
+    return REQUESTS.iter().find(|(name, _)| *name == req).map(|&(_, handler)| handler);
+}
+
+fn with_proc<R>(f: impl FnOnce(&mut ProcCtrlBlk) -> R) -> Option<R> {
+    let pid = current_pid()?;
+    return PROCS.write().procs.get_mut(&pid).map(f);
+}
+
+// Kernel threads have no process and act as root
+fn creds() -> Credentials {
+    return with_proc(|proc| proc.creds).unwrap_or(Credentials::ROOT);
+}
+
+fn get_fd(fd: usize) -> Option<Arc<OpenFile>> {
+    return with_proc(|proc| proc.fds.get(&fd).cloned()).flatten();
+}
+
+// End of the run of back to back regions from `addr`, stopping at `limit`.
+// `region_end(a)` is the end of the region holding `a`.
+fn run_end(addr: usize, limit: usize, region_end: impl Fn(usize) -> Option<usize>) -> Option<usize> {
+    let mut pos = region_end(addr).filter(|&end| end > addr)?;
+    while pos < limit {
+        match region_end(pos) {
+            Some(end) if end > pos => pos = end,
+            _ => break
         }
-    } };
+    }
+    return Some(pos.min(limit));
+}
+
+// How far from `addr` the caller's mappings reach, up to `limit`. Lazy regions
+// count, a kernel access backs them through the fault path like a user one.
+fn user_reach(addr: usize, limit: usize, write: bool) -> usize {
+    let limit = limit.min(hihalf());
+    if addr >= limit { return addr; }
+
+    let writable = |f: usize| f == flags::U_RWO || f == flags::U_RWX;
+    return with_proc(|proc| run_end(addr, limit, |a| {
+        proc.vram_map.iter()
+            .find(|m| (m.va..m.va + m.size).contains(&a) && (!write || writable(m.flags)))
+            .map(|m| m.va + m.size)
+    })).flatten().unwrap_or(addr);
+}
+
+fn user_range(ptr: usize, len: usize, write: bool) -> bool {
+    let Some(end) = ptr.checked_add(len) else { return false; };
+    return len == 0 || user_reach(ptr, end, write) == end;
+}
+
+// NUL terminated within PATH_MAX bytes. The scan checks each region before it steps into it.
+fn user_str<'a>(ptr: usize) -> Result<&'a str, Errno> {
+    let limit = ptr.saturating_add(PATH_MAX);
+    let (mut len, mut valid) = (0, ptr);
+    loop {
+        let addr = ptr + len;
+        if addr == limit { return Err(Errno::ENAMETOOLONG); }
+        if addr == valid {
+            valid = user_reach(addr, limit, false);
+            if valid == addr { return Err(Errno::EFAULT); }
+        }
+        if unsafe { (addr as *const u8).read() } == 0 { break; }
+        len += 1;
+    }
+
+    let bytes = unsafe { from_raw_parts(ptr as *const u8, len) };
+    return core::str::from_utf8(bytes).map_err(|_| Errno::EINVAL);
+}
+
+fn req_exit(args: &Args) -> isize {
+    exit_proc(args[0] as i32);
+}
+
+fn req_yield(_args: &Args) -> isize {
+    yield_proc(0);
+}
+
+fn req_fork(_args: &Args) -> isize {
+    let Some(pid) = current_pid() else { return Errno::EINVAL.ret(); };
+    return match PROCS.write().fork(pid) {
+        Ok(child) => child as isize,
+        Err(e) if e == "Out of PIDs" => Errno::EAGAIN.ret(),
+        Err(_) => Errno::ENOMEM.ret()
+    };
+}
+
+// Returns the child's exit code, blocking until it has exited
+fn req_waitpid(args: &Args) -> isize {
+    let Some(pid) = current_pid() else { return Errno::EINVAL.ret(); };
+    let res = PROCS.write().waitpid(pid, args[0]);
+
+    return match res {
+        Ok(Some(code)) => code as isize,
+        Ok(None) => block_proc(),
+        Err(_) => Errno::ECHILD.ret()
+    };
+}
+
+// Returns the previous program break
+fn req_sbrk(args: &Args) -> isize {
+    return match with_proc(|proc| proc.sbrk(args[0] as isize)) {
+        Some(Ok(old)) => old as isize,
+        Some(Err(_)) => Errno::ENOMEM.ret(),
+        None => Errno::EINVAL.ret()
+    };
+}
+
+// Write implies read, there are no write-only pages
+fn prot_flags(prot: usize) -> Option<usize> {
+    if prot & (PROT_READ | PROT_WRITE | PROT_EXEC) == 0 { return None; }
+    return Some(match (prot & PROT_WRITE != 0, prot & PROT_EXEC != 0) {
+        (false, false) => flags::U_ROO,
+        (false, true)  => flags::U_ROX,
+        (true, false)  => flags::U_RWO,
+        (true, true)   => flags::U_RWX
+    });
+}
+
+// File offsets must fall on a page boundary, like the pages they back
+fn mmap_flags(len: usize, prot: usize, offset: usize) -> Option<usize> {
+    if len == 0 || offset % page_size() != 0 { return None; }
+    return prot_flags(prot);
+}
+
+// The address hint is ignored, returns the base of the new mapping
+fn req_mmap(args: &Args) -> isize {
+    let (len, prot, fd, offset) = (args[1], args[2], args[3] as isize, args[4]);
+    let Some(flags) = mmap_flags(len, prot, offset) else { return Errno::EINVAL.ret(); };
+
+    // fd -1 is an anonymous demand-zero mapping
+    let file = if fd == -1 { None } else {
+        let Some(file) = get_fd(fd as usize) else { return Errno::EBADF.ret(); };
+        if file.node.meta().ftype != FType::Regular { return Errno::EINVAL.ret(); }
+        if file.access & MAY_READ == 0 { return Errno::EACCES.ret(); }
+        Some(FileDesc { node: file.node.clone(), offset: offset as u64 })
+    };
+
+    return match with_proc(|proc| proc.mmap(len, flags, file)) {
+        Some(Ok(base)) => base as isize,
+        Some(Err(_)) => Errno::ENOMEM.ret(),
+        None => Errno::EINVAL.ret()
+    };
+}
+
+fn req_read(args: &Args) -> isize {
+    let (fd, ptr, len) = (args[0], args[1], args[2]);
+    check_fault!(mut ptr, len, u8);
+    let Some(file) = get_fd(fd) else { return Errno::EBADF.ret(); };
+    if file.node.meta().ftype == FType::Directory { return Errno::EISDIR.ret(); }
+
+    let buf = unsafe { from_raw_parts_mut(ptr as *mut u8, len) };
+    return match file.read(buf) {
+        Ok(n) => n as isize,
+        Err(e) => Errno::from_vfs(&e).ret()
+    };
+}
+
+fn req_write(args: &Args) -> isize {
+    let (fd, ptr, len) = (args[0], args[1], args[2]);
+    check_fault!(ptr, len, u8);
+    let Some(file) = get_fd(fd) else { return Errno::EBADF.ret(); };
+
+    let buf = unsafe { from_raw_parts(ptr as *const u8, len) };
+    return match file.write(buf) {
+        Ok(n) => n as isize,
+        Err(e) => Errno::from_vfs(&e).ret()
+    };
+}
+
+fn open_access(flags: usize) -> Option<u16> {
+    return match flags & O_ACCMODE {
+        O_RDONLY => Some(MAY_READ),
+        O_WRONLY => Some(MAY_WRITE),
+        O_RDWR => Some(MAY_READ | MAY_WRITE),
+        _ => None
+    };
+}
+
+// The lowest free descriptor, as POSIX has it
+fn alloc_fd(fds: &mut BTreeMap<usize, Arc<OpenFile>>, file: Arc<OpenFile>) -> Result<usize, Errno> {
+    let fd = (0..MAX_FDS).find(|fd| !fds.contains_key(fd)).ok_or(Errno::EMFILE)?;
+    fds.insert(fd, file);
+    return Ok(fd);
+}
+
+fn req_open(args: &Args) -> isize {
+    let path = match user_str(args[0]) {
+        Ok(path) => path,
+        Err(e) => return e.ret()
+    };
+    let Some(access) = open_access(args[1]) else { return Errno::EINVAL.ret(); };
+
+    let creds = creds();
+    let file = if args[1] & O_CREAT == 0 {
+        VFS.open(&creds, path, access)
+    } else {
+        let node = if args[1] & O_EXCL != 0 {
+            VFS.create_excl(&creds, path, FType::Regular)
+        } else {
+            VFS.open_or_create(&creds, path, FType::Regular)
+        };
+        node.and_then(|node| match node.meta().permits(&creds, access) {
+            true => Ok(Arc::new(OpenFile::new(node, access))),
+            false => Err("Permission denied".into())
+        })
+    };
+    let file = match file {
+        Ok(file) => file,
+        Err(e) => return Errno::from_vfs(&e).ret()
+    };
+
+    return match with_proc(|proc| alloc_fd(&mut proc.fds, file)) {
+        Some(Ok(fd)) => fd as isize,
+        Some(Err(e)) => e.ret(),
+        None => Errno::EBADF.ret()
+    };
+}
+
+fn req_close(args: &Args) -> isize {
+    return match with_proc(|proc| proc.fds.remove(&args[0])).flatten() {
+        Some(_) => 0,
+        None => Errno::EBADF.ret()
+    };
+}
+
+// The offset register holds a signed value
+fn seek_from(off: usize, whence: usize) -> Option<SeekFrom> {
+    let off = off as i64;
+    return match whence {
+        SEEK_SET if off >= 0 => Some(SeekFrom::Start(off as u64)),
+        SEEK_CUR => Some(SeekFrom::Current(off)),
+        SEEK_END => Some(SeekFrom::End(off)),
+        _ => None
+    };
+}
+
+fn req_lseek(args: &Args) -> isize {
+    let Some(file) = get_fd(args[0]) else { return Errno::EBADF.ret(); };
+    let Some(pos) = seek_from(args[1], args[2]) else { return Errno::EINVAL.ret(); };
+    return match file.seek(pos) {
+        Ok(new) if new <= isize::MAX as u64 => new as isize,
+        Ok(_) => Errno::EINVAL.ret(),
+        Err(e) => Errno::from_vfs(&e).ret()
+    };
+}
+
+fn req_ioctl(args: &Args) -> isize {
+    let (fd, cmd, arg) = (args[0], args[1] as u32, args[2]);
+    let Some(file) = get_fd(fd) else { return Errno::EBADF.ret(); };
+
+    return match file.node.ioctl(cmd, arg) {
+        Ok(val) => val as isize,
+        Err(e) => Errno::from_vfs(&e).ret()
+    };
+}
+
+fn req_sync(_args: &Args) -> isize {
+    return match VFS.sync_all() {
+        Ok(()) => 0,
+        Err(_) => Errno::EIO.ret()
+    };
+}
+
+// Filled by statfs, fstype is NUL padded
+#[repr(C)]
+struct StatFsBuf {
+    block_size: u64,
+    total: u64,
+    free: u64,
+    fstype: [u8; 16]
+}
+
+fn req_statfs(args: &Args) -> isize {
+    let (path, ptr) = (args[0], args[1]);
+    let path = match user_str(path) {
+        Ok(path) => path,
+        Err(e) => return e.ret()
+    };
+    check_fault!(mut ptr, 1, StatFsBuf);
+
+    let stat = match VFS.statfs(&creds(), path) {
+        Ok(stat) => stat,
+        Err(e) => return Errno::from_vfs(&e).ret()
+    };
+
+    let mut fstype = [0u8; 16];
+    let len = stat.fstype.len().min(fstype.len() - 1);
+    fstype[..len].copy_from_slice(&stat.fstype.as_bytes()[..len]);
+    let buf = StatFsBuf { block_size: stat.block_size, total: stat.total, free: stat.free, fstype };
+    unsafe { (ptr as *mut StatFsBuf).write_unaligned(buf); }
+    return 0;
+}
+
+fn req_poweroff(_args: &Args) -> isize {
+    let _ = VFS.sync_all();
+    power::shutdown();
+}
+
+fn req_reboot(_args: &Args) -> isize {
+    let _ = VFS.sync_all();
+    power::reboot();
+}
+
+fn req_print(args: &Args) -> isize {
+    let (ptr, len) = (args[0], args[1]);
+    check_fault!(ptr, len, u8);
+    for i in 0..len {
+        arch::serial_putchar(
+            unsafe { *(ptr as *const u8).add(i) }
+        );
+    }
+    return 0;
 }
 
 #[unsafe(no_mangle)]
@@ -21,38 +433,106 @@ pub extern "C" fn kernel_requestee(
     arg1: usize, arg2: usize, arg3: usize,
     arg4: usize, arg5: usize, arg6: usize
 ) -> usize {
-    let len = (0..16)
+    let reach = user_reach(req as usize, (req as usize).saturating_add(16), false) - req as usize;
+    let len = (0..reach)
         .find(|&i| unsafe { *req.add(i) } == 0)
-        .unwrap_or(16);
+        .unwrap_or(reach);
 
     let req = unsafe { from_raw_parts(req, len) };
+    let args = [arg1, arg2, arg3, arg4, arg5, arg6];
 
-    if req == b"exit" {
-        exit_proc(arg1 as i32);
+    let ret = match lookup(req) {
+        Some(handler) => handler(&args),
+        None => Errno::ENOSYS.ret()
+    };
+
+    return ret as usize;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        filesys::vfn::{FMeta, VirtFNode},
+        ram::glacier::{BPage, G_CFG, RvmCfg}
+    };
+
+    // [0x1000, 0x3000) and [0x3000, 0x4000) back to back, then a gap up to 0x8000
+    fn regions(a: usize) -> Option<usize> {
+        return [(0x1000, 0x3000), (0x3000, 0x4000), (0x8000, 0x9000)].iter()
+            .find(|(start, end)| (*start..*end).contains(&a))
+            .map(|&(_, end)| end);
     }
 
-    match req {
-        b"open" => {
-            let path = unsafe {
-                let mut len = 0usize;
-                while *(arg1 as *const u8).add(len) != 0 {
-                    len += 1;
-                }
-                from_raw_parts(arg1 as *const u8, len)
-            };
-            check_fault!(arg1, (path.len() + 1), u8);
-        }
-        b"_print" => { // This syscall is for debugging purposes only
-            check_fault!(arg1, arg2, u8);
-            for i in 0..arg2 {
-                arch::serial_putchar(
-                    unsafe { *(arg1 as *const u8).add(i) }
-                );
-            }
+    #[test]
+    fn run_end_follows_adjacent_regions() {
+        assert_eq!(run_end(0x1800, 0x3800, regions), Some(0x3800));
+        assert_eq!(run_end(0x1800, 0x9000, regions), Some(0x4000));
+        assert_eq!(run_end(0x8000, 0x8001, regions), Some(0x8001));
+        assert_eq!(run_end(0x4000, 0x4001, regions), None);
+        assert_eq!(run_end(0, 0x2000, regions), None);
+    }
+
+    #[test]
+    fn requests_by_name() {
+        for name in [&b"read"[..], b"write", b"open", b"close", b"lseek", b"exit"] {
+            assert!(lookup(name).is_some());
         }
-        // ... kernel request impls goes here ...
-        _ => {}
+        assert!(lookup(b"").is_none());
+        assert!(lookup(b"rea").is_none());
+        assert!(lookup(b"read\0").is_none());
     }
 
-    return 0;
+    #[test]
+    fn open_and_seek_args() {
+        assert_eq!(open_access(O_RDONLY), Some(MAY_READ));
+        assert_eq!(open_access(O_WRONLY | O_CREAT), Some(MAY_WRITE));
+        assert_eq!(open_access(O_RDWR | O_CREAT | O_EXCL), Some(MAY_READ | MAY_WRITE));
+        assert_eq!(open_access(3), None);
+
+        assert_eq!(seek_from(10, SEEK_SET), Some(SeekFrom::Start(10)));
+        assert_eq!(seek_from(-4isize as usize, SEEK_CUR), Some(SeekFrom::Current(-4)));
+        assert_eq!(seek_from(-4isize as usize, SEEK_END), Some(SeekFrom::End(-4)));
+        assert_eq!(seek_from(-4isize as usize, SEEK_SET), None);
+        assert_eq!(seek_from(0, 3), None);
+    }
+
+    #[test]
+    fn mmap_prot_and_offset() {
+        G_CFG.call_once(|| RvmCfg { psz: BPage::Size4kiB, va_bits: 48, pa_bits: 52 });
+        assert_eq!(prot_flags(PROT_READ), Some(flags::U_ROO));
+        assert_eq!(prot_flags(PROT_READ | PROT_EXEC), Some(flags::U_ROX));
+        assert_eq!(prot_flags(PROT_WRITE), Some(flags::U_RWO));
+        assert_eq!(prot_flags(PROT_READ | PROT_WRITE | PROT_EXEC), Some(flags::U_RWX));
+        assert_eq!(prot_flags(0), None);
+        assert_eq!(prot_flags(8), None);
+
+        assert_eq!(mmap_flags(1, PROT_READ, 0x3000), Some(flags::U_ROO));
+        assert_eq!(mmap_flags(1, PROT_READ, 0x3001), None);
+        assert_eq!(mmap_flags(1, PROT_READ, 0x800), None);
+        assert_eq!(mmap_flags(0, PROT_READ, 0), None);
+    }
+
+    struct Node;
+
+    impl VirtFNode for Node {
+        fn meta(&self) -> FMeta { FMeta::vfs_only(FType::Regular) }
+    }
+
+    #[test]
+    fn fds_take_the_lowest_free_slot() {
+        let file = || Arc::new(OpenFile::new(Arc::new(Node), MAY_READ));
+        let mut fds = BTreeMap::new();
+        for fd in 0..4 {
+            assert_eq!(alloc_fd(&mut fds, file()).ok(), Some(fd));
+        }
+        fds.remove(&1);
+        assert_eq!(alloc_fd(&mut fds, file()).ok(), Some(1));
+        assert_eq!(alloc_fd(&mut fds, file()).ok(), Some(4));
+
+        while fds.len() < MAX_FDS {
+            alloc_fd(&mut fds, file()).unwrap();
+        }
+        assert!(matches!(alloc_fd(&mut fds, file()), Err(Errno::EMFILE)));
+    }
 }
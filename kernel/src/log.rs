@@ -0,0 +1,154 @@
+use core::{fmt, sync::atomic::{AtomicU8, Ordering}};
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error = 0,
+    Warn  = 1,
+    Info  = 2,
+    Debug = 3,
+    Trace = 4
+}
+
+impl LogLevel {
+    pub fn from_u8(level: u8) -> Option<Self> {
+        return match level {
+            0 => Some(Self::Error),
+            1 => Some(Self::Warn),
+            2 => Some(Self::Info),
+            3 => Some(Self::Debug),
+            4 => Some(Self::Trace),
+            _ => None
+        };
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        return match name {
+            "error" => Some(Self::Error),
+            "warn"  => Some(Self::Warn),
+            "info"  => Some(Self::Info),
+            "debug" => Some(Self::Debug),
+            "trace" => Some(Self::Trace),
+            _ => None
+        };
+    }
+
+    pub fn tag(self) -> &'static str {
+        return match self {
+            Self::Error => "[ERROR]",
+            Self::Warn  => "[WARN]",
+            Self::Info  => "[INFO]",
+            Self::Debug => "[DEBUG]",
+            Self::Trace => "[TRACE]"
+        };
+    }
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.tag())
+    }
+}
+
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+pub fn set_log_level(level: LogLevel) {
+    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn log_level() -> LogLevel {
+    return LogLevel::from_u8(LOG_LEVEL.load(Ordering::Relaxed)).unwrap_or(LogLevel::Trace);
+}
+
+#[inline(always)]
+pub fn enabled(level: LogLevel) -> bool {
+    return level as u8 <= LOG_LEVEL.load(Ordering::Relaxed);
+}
+
+// Nothing is formatted below the threshold
+pub fn write_log(w: &mut impl fmt::Write, level: LogLevel, args: fmt::Arguments) -> fmt::Result {
+    if !enabled(level) { return Ok(()); }
+    return writeln!(w, "{} {}", level, args);
+}
+
+#[macro_export]
+macro_rules! log {
+    ($lvl:expr, $($arg:tt)*) => {{
+        let _ = $crate::log::write_log(&mut $crate::PrintkWriter, $lvl, format_args!($($arg)*));
+    }};
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => { $crate::log!($crate::log::LogLevel::Error, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => { $crate::log!($crate::log::LogLevel::Warn, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => { $crate::log!($crate::log::LogLevel::Info, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => { $crate::log!($crate::log::LogLevel::Debug, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => { $crate::log!($crate::log::LogLevel::Trace, $($arg)*) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::String;
+
+    // Counts writes, so a suppressed line is seen to never reach the writer
+    struct Capture {
+        out: String,
+        writes: usize
+    }
+
+    impl fmt::Write for Capture {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            self.out.push_str(s);
+            self.writes += 1;
+            return Ok(());
+        }
+    }
+
+    #[test]
+    fn threshold_filters_levels() {
+        let mut cap = Capture { out: String::new(), writes: 0 };
+        set_log_level(LogLevel::Info);
+
+        write_log(&mut cap, LogLevel::Debug, format_args!("hidden {}", 1)).unwrap();
+        write_log(&mut cap, LogLevel::Trace, format_args!("hidden")).unwrap();
+        assert_eq!(cap.writes, 0);
+
+        write_log(&mut cap, LogLevel::Warn, format_args!("disk {}", 2)).unwrap();
+        write_log(&mut cap, LogLevel::Info, format_args!("up")).unwrap();
+        assert_eq!(cap.out, "[WARN] disk 2\n[INFO] up\n");
+
+        set_log_level(LogLevel::Error);
+        let writes = cap.writes;
+        write_log(&mut cap, LogLevel::Warn, format_args!("hidden")).unwrap();
+        assert_eq!(cap.writes, writes);
+        set_log_level(LogLevel::Info);
+    }
+
+    #[test]
+    fn names_round_trip() {
+        for level in [LogLevel::Error, LogLevel::Warn, LogLevel::Info, LogLevel::Debug, LogLevel::Trace] {
+            let name = level.tag().trim_matches(['[', ']']).to_ascii_lowercase();
+            assert_eq!(LogLevel::from_name(&name), Some(level));
+            assert_eq!(LogLevel::from_u8(level as u8), Some(level));
+        }
+        assert_eq!(LogLevel::from_name("loud"), None);
+    }
+}
@@ -5,13 +5,14 @@
 //! Description: Kernel of UNIX Version 11
 //! Licence: Non-assertion pledge
 
-#![no_std]
-#![no_main]
+// Host `cargo test` builds get std, its entry point, panic handler and allocator
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 
 extern crate alloc;
 
-mod arch; mod device; mod filesys; mod kargs;
-mod kreq; mod proc; mod ram; mod sort;
+mod arch; mod backtrace; mod device; mod filesys; mod kargs;
+mod kreq; mod log; mod proc; mod ram; mod sort; mod time;
 
 use crate::{
     kargs::{Kargs, RAMType},
@@ -22,13 +23,24 @@ use crate::{
     }
 };
 
-use core::panic::PanicInfo;
+use core::fmt::{self, Write};
+
+// Tees every formatted piece to serial and the screen, so arguments are formatted once
+pub struct PrintkWriter;
+
+impl Write for PrintkWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let _ = arch::SerialWriter.write_str(s);
+        device::vga::mirror(s);
+        return Ok(());
+    }
+}
 
 #[macro_export]
 macro_rules! printk {
     ($($arg:tt)*) => {{
         use core::fmt::Write;
-        let _ = core::write!($crate::arch::SerialWriter, $($arg)*);
+        let _ = core::write!($crate::PrintkWriter, $($arg)*);
     }};
 }
 
@@ -44,6 +56,7 @@ const _: () = {
 
 #[unsafe(no_mangle)]
 pub extern "efiapi" fn ignite(kargs: Kargs) -> ! {
+    arch::percpu::clear();
     G_CFG.call_once(|| RvmCfg::detect());
     kargs::set_kargs(kargs);
 
@@ -51,21 +64,32 @@ pub extern "efiapi" fn ignite(kargs: Kargs) -> ! {
     ram::glacier::init();
     ram::init_heap();
 
-    arch::init_serial();
+    match kargs::console_baud() {
+        Some(baud) => arch::init_serial_with(baud),
+        None => arch::init_serial()
+    }
     ram::reloc::reloc();
 }
 
 #[unsafe(no_mangle)]
 pub extern "C" fn spark() -> ! {
     ram::glacier::remap();
+    ram::paint_stack();
     arch::exc::init();
+    if let Some(level) = kargs::cmdline_get("loglevel").and_then(log::LogLevel::from_name) {
+        log::set_log_level(level);
+    }
     printlnk!("The UNIX Time-Sharing System: Eleventh Edition");
+    #[cfg(target_arch = "x86_64")]
+    printlnk!("CPU features: {}", arch::cpuid::features());
     PHYS_ALLOC.reclaim();
     device::init_device();
+    device::vga::set_mirror(kargs::console_vga());
     let _ = filesys::init_filesys();
 
     let stack_usage = stack_top() - crate::arch::stack_ptr() as usize;
     printlnk!("Kernel stack usage: {} / {} bytes", stack_usage, stack_size());
+    printlnk!("Kernel stack high water: {} bytes", ram::stack_high_water());
 
     printlnk!("ID of this AP: {}", arch::phys_id());
 
@@ -78,13 +102,32 @@ pub extern "C" fn spark() -> ! {
     let ksize = PHYS_ALLOC.filtsize(|b| b.ty() == RAMType::Kernel);
     printlnk!("Loaded kimg size: {:.3} kB", ksize as f64 / 1000.0);
 
+    for ty in [RAMType::KernelData, RAMType::KernelPTable, RAMType::Reclaimable, RAMType::Reserved] {
+        let size = PHYS_ALLOC.filtsize_raw(|b| b.ty() == ty);
+        printlnk!("  {}: {:.3} kB", ty, size as f64 / 1000.0);
+    }
+    if let Some(block) = PHYS_ALLOC.largest_block(RAMType::Conv) {
+        printlnk!("Largest conventional block: {:.6} MB at {:#x}", block.size() as f64 / 1000000.0, block.addr());
+    }
+
+    device::cpu::start_aps();
     proc::exec_aleph();
 
     loop { arch::halt(); }
 }
 
+#[unsafe(no_mangle)]
+pub extern "C" fn ap_main() -> ! {
+    ram::paint_stack();
+    arch::exc::init();
+    device::cpu::init_ap();
+    proc::schedule();
+}
+
+#[cfg(not(test))]
 #[panic_handler]
-fn panic(info: &PanicInfo) -> ! {
+fn panic(info: &core::panic::PanicInfo) -> ! {
     printlnk!("{}", info);
+    backtrace::print();
     loop { arch::halt(); }
 }
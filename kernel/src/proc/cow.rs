@@ -0,0 +1,88 @@
+use crate::ram::{
+    glacier::page_size,
+    physalloc::{OwnedPtr, PHYS_ALLOC}
+};
+
+use alloc::collections::btree_map::BTreeMap;
+use spin::Mutex;
+
+// Number of processes holding each shared frame, exclusive frames have no entry
+static COW_REFS: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+
+pub fn share(pa: usize) {
+    *COW_REFS.lock().entry(pa).or_insert(1) += 1;
+}
+
+pub fn is_shared(pa: usize) -> bool {
+    return COW_REFS.lock().contains_key(&pa);
+}
+
+// Returns true if some other process still holds the frame
+fn unshare_locked(refs: &mut BTreeMap<usize, usize>, pa: usize) -> bool {
+    let Some(cnt) = refs.get_mut(&pa) else { return false; };
+    *cnt -= 1;
+    if *cnt == 1 { refs.remove(&pa); }
+    return true;
+}
+
+pub fn unshare(pa: usize) -> bool {
+    return unshare_locked(&mut COW_REFS.lock(), pa);
+}
+
+// Frees the pages of `block` nobody else holds
+pub fn release(block: OwnedPtr) {
+    let mut refs = COW_REFS.lock();
+    if refs.range(block.addr()..block.end()).next().is_none() {
+        PHYS_ALLOC.free(block);
+        return;
+    }
+
+    for pa in (block.addr()..block.end()).step_by(page_size()) {
+        if !unshare_locked(&mut refs, pa) {
+            unsafe { PHYS_ALLOC.free_raw(pa as *mut u8, page_size()); }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn refcounts_follow_the_holders() {
+        let mut refs = BTreeMap::new();
+        let pa = 0x4000;
+        assert!(!unshare_locked(&mut refs, pa));
+
+        *refs.entry(pa).or_insert(1) += 1;
+        *refs.entry(pa).or_insert(1) += 1;
+        assert_eq!(refs[&pa], 3);
+
+        assert!(unshare_locked(&mut refs, pa));
+        assert_eq!(refs[&pa], 2);
+        // The last holder owns the frame outright again
+        assert!(unshare_locked(&mut refs, pa));
+        assert!(!refs.contains_key(&pa));
+        assert!(!unshare_locked(&mut refs, pa));
+    }
+
+    #[test]
+    fn child_write_leaves_the_parent_page() {
+        let parent = vec![0x11u8; 0x1000];
+        let pa = parent.as_ptr() as usize;
+        share(pa);
+        assert!(is_shared(pa));
+
+        // The child faults on a shared frame, so it writes to a copy
+        let mut child = parent.clone();
+        child[0x10] = 0x22;
+        assert!(unshare(pa));
+        assert_eq!(parent, vec![0x11; 0x1000]);
+
+        // The parent is the last holder and writes in place
+        assert!(!is_shared(pa));
+        assert!(!unshare(pa));
+        assert_eq!(child[0x10], 0x22);
+    }
+}
@@ -1,10 +1,10 @@
 use crate::{
     arch::{exc::ExcFrame, rvm::flags},
-    filesys::vfn::VirtFNode,
-    proc::kstack::KernelStack,
+    filesys::vfn::{Credentials, OpenFile, VirtFNode},
+    proc::{cow, kstack::KernelStack},
     ram::{
-        PhysPageBuf,
-        glacier::{Glacier, hihalf},
+        PhysPageBuf, align_down, align_up,
+        glacier::{Glacier, hihalf, page_size},
         physalloc::{AllocParams, OwnedPtr, PHYS_ALLOC}
     }
 };
@@ -18,18 +18,30 @@ use alloc::{
 };
 use xmas_elf::{ElfFile, program::Type};
 
+#[derive(Clone)]
 pub struct VRamMap {
     pub va: usize,
     pub pa: usize,
     pub size: usize,
-    pub flags: usize
+    pub flags: usize,
+    pub lazy: bool, // Pages are mapped on first touch
+    pub file: Option<FileDesc> // Lazy pages are read from here, `offset` matching `va`
 }
 
-#[derive(PartialEq, Eq)]
+// File backing a mapping, independent of any fd cursor
+#[derive(Clone)]
+pub struct FileDesc {
+    pub node: Arc<dyn VirtFNode>,
+    pub offset: u64
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum ProcState {
     Ready,
+    Running(usize), // phys_id of the CPU
     Blocked,
-    Sleeping
+    Sleeping,
+    Zombie(i32) // Exited, waiting to be reaped by the parent
 }
 
 pub struct ProcCtrlBlk {
@@ -40,9 +52,20 @@ pub struct ProcCtrlBlk {
     pub phys_alloc: Vec<OwnedPtr>,
     pub vram_map: Vec<VRamMap>,
     pub ctxt: Box<ExcFrame>,
+    pub heap_base: usize,
+    pub brk: usize,
+    pub mmap_base: usize, // Mappings grow down from the user stack
 
     pub state: ProcState,
-    pub fds: BTreeMap<usize, Arc<dyn VirtFNode>>
+    pub waiting: Option<usize>, // Child pid blocked on in waitpid
+    pub creds: Credentials,
+    pub fds: BTreeMap<usize, Arc<OpenFile>>
+}
+
+const USER_STACK_SIZE: usize = 0x100000;
+
+fn user_stack_base() -> usize {
+    return 0usize.wrapping_sub(hihalf()) - USER_STACK_SIZE;
 }
 
 fn get_proc_vaset(elf: &ElfFile) -> (usize, usize) {
@@ -58,18 +81,82 @@ fn get_proc_vaset(elf: &ElfFile) -> (usize, usize) {
     return (va_base, va_top);
 }
 
+// End of the part of a segment that shares pages with its file contents
+fn eager_end(va: usize, file_size: usize, mem_size: usize) -> usize {
+    return align_up(va + file_size, page_size()).min(va + mem_size);
+}
+
+// Frames may come straight from another process, so the whole image is cleared
+// before each segment's file bytes land at their offset into it
+fn fill_image(image: &mut [u8], segments: &[(usize, &[u8])]) {
+    image.fill(0);
+    for &(at, data) in segments {
+        image[at..at + data.len()].copy_from_slice(data);
+    }
+}
+
+// A parent page the child maps too, writable ones copy-on-write for both sides
+#[derive(Debug, PartialEq, Eq)]
+struct ForkPage {
+    va: usize,
+    pa: usize,
+    flags: usize,
+    cow: bool
+}
+
+// The backed pages of every region, in order. A page two regions share shows up twice.
+fn fork_pages(vram_map: &[VRamMap], get_pa: impl Fn(usize) -> Option<usize>) -> Vec<ForkPage> {
+    let psz = page_size();
+    let mut pages = Vec::new();
+    for region in vram_map {
+        let cow = region.flags == flags::U_RWO || region.flags == flags::U_RWX;
+        let va_start = align_down(region.va, psz);
+        let va_end = align_up(region.va + region.size, psz);
+
+        for va in (va_start..va_end).step_by(psz) {
+            let Some(pa) = get_pa(va) else { continue; };
+            pages.push(ForkPage { va, pa, flags: region.flags, cow });
+        }
+    }
+    return pages;
+}
+
+// The break `delta` bytes on, never below the heap base nor into the mappings above
+fn move_break(heap_base: usize, brk: usize, mmap_base: usize, delta: isize) -> Result<usize, String> {
+    let new = brk.checked_add_signed(delta)
+        .filter(|&brk| brk >= heap_base)
+        .ok_or("Invalid program break")?;
+    if align_up(new, page_size()) > mmap_base {
+        return Err("Program break collides with the mappings".into());
+    }
+    return Ok(new);
+}
+
 impl ProcCtrlBlk {
     pub fn new(node: &dyn VirtFNode, _args: &[&str]) -> Result<Self, String> {
         let read_len = node.meta().size as usize;
         let mut file_bin = PhysPageBuf::new(read_len).ok_or("Failed to allocate buffer")?;
-        node.read(&mut file_bin, 0)?;
+        if node.read(&mut file_bin, 0)? != read_len {
+            return Err("Short read of executable".into());
+        }
 
         let elf = ElfFile::new(&file_bin)?;
         let ep = elf.header.pt2.entry_point() as usize;
-        let mut glacier = Glacier::new();
+        let mut glacier = Glacier::new().map_err(|_| "Failed to allocate page table")?;
 
         let (va_base, va_top) = get_proc_vaset(&elf);
-        let proc_size = va_top - va_base;
+        let psz = page_size();
+
+        // Only the file backed part of each segment gets physical memory up front
+        let eager_top = elf.program_iter()
+            .filter(|ph| ph.get_type() == Ok(Type::Load))
+            .map(|ph| eager_end(
+                ph.virtual_addr() as usize,
+                ph.file_size() as usize,
+                ph.mem_size() as usize
+            ))
+            .max().unwrap();
+        let proc_size = align_up(eager_top, psz) - va_base;
 
         let mut phys_alloc = Vec::new();
 
@@ -77,9 +164,11 @@ impl ProcCtrlBlk {
             AllocParams::new(proc_size)
         ).ok_or("Failed to allocate process memory")?;
         let proc_addr = proc_ptr.addr();
+        let image = unsafe { core::slice::from_raw_parts_mut(proc_ptr.ptr::<u8>(), proc_size) };
         phys_alloc.push(proc_ptr);
 
         let mut vram_map = Vec::new();
+        let mut segments = Vec::new();
 
         for ph in elf.program_iter() {
             if let Ok(Type::Load) = ph.get_type() {
@@ -88,7 +177,6 @@ impl ProcCtrlBlk {
                 let mem_size = ph.mem_size() as usize;
                 let virt_addr = ph.virtual_addr() as usize;
                 let phys_addr = proc_addr + (virt_addr - va_base);
-                let phys_ptr = phys_addr as *mut u8;
 
                 let flags = match ph.flags().0 {
                     0b100 => flags::U_ROO, // read only
@@ -98,43 +186,60 @@ impl ProcCtrlBlk {
                     _     => flags::U_RWO  // fallback to read & write
                 };
 
+                let eager_top = eager_end(virt_addr, file_size, mem_size);
+                let eager_size = eager_top - virt_addr;
                 glacier.map_range(
                     virt_addr, phys_addr,
-                    mem_size, flags
+                    eager_size, flags
                 ).map_err(|_| "Failed to map process")?;
 
                 vram_map.push(VRamMap {
                     va: virt_addr,
                     pa: phys_addr,
-                    size: mem_size,
-                    flags
+                    size: eager_size,
+                    flags,
+                    lazy: false,
+                    file: None
                 });
 
-                unsafe {
-                    phys_ptr.write_bytes(0, mem_size);
-                    file_bin[offset..offset + file_size].as_ptr().copy_to(phys_ptr, file_size);
+                // BSS beyond the last file page is zero filled on first touch
+                if eager_top < virt_addr + mem_size {
+                    vram_map.push(VRamMap {
+                        va: eager_top,
+                        pa: 0,
+                        size: virt_addr + mem_size - eager_top,
+                        flags,
+                        lazy: true,
+                        file: None
+                    });
                 }
+
+                segments.push((virt_addr - va_base, &file_bin[offset..offset + file_size]));
             }
         }
+        fill_image(image, &segments);
 
-        let stack_size = 0x100000;
-        let stack_ptr = PHYS_ALLOC.alloc(
-            AllocParams::new(stack_size)
-        ).ok_or("Failed to allocate user stack")?;
-
+        // Stack pages are backed as it grows
         let lohalf_top = 0usize.wrapping_sub(hihalf());
-        glacier.map_range(
-            lohalf_top - stack_size, stack_ptr.addr(),
-            stack_size, flags::U_RWO
-        ).map_err(|_| "Failed to map user stack")?;
+        vram_map.push(VRamMap {
+            va: user_stack_base(),
+            pa: 0,
+            size: USER_STACK_SIZE,
+            flags: flags::U_RWO,
+            lazy: true,
+            file: None
+        });
 
+        // Heap pages are backed one by one through sbrk
+        let heap_base = align_up(va_top, psz);
         vram_map.push(VRamMap {
-            va: lohalf_top - stack_size,
-            pa: stack_ptr.addr(),
-            size: stack_size,
-            flags: flags::U_RWO
+            va: heap_base,
+            pa: 0,
+            size: 0,
+            flags: flags::U_RWO,
+            lazy: true,
+            file: None
         });
-        phys_alloc.push(stack_ptr);
 
         let mut ctxt = ExcFrame::new();
         ctxt.set_pc(ep);
@@ -147,16 +252,242 @@ impl ProcCtrlBlk {
             phys_alloc,
             vram_map,
             ctxt: Box::new(ctxt),
+            heap_base,
+            brk: heap_base,
+            mmap_base: user_stack_base(),
             state: ProcState::Ready,
+            waiting: None,
+            creds: Credentials::ROOT,
             fds: BTreeMap::new()
         });
     }
+
+    // Pages are shared with the child, writable ones copy-on-write for both sides
+    pub fn fork(&mut self, ppid: usize) -> Result<Self, String> {
+        let mut child = Self {
+            ppid,
+            glacier: Glacier::new().map_err(|_| "Failed to allocate page table")?,
+            kstack: KernelStack::new().ok_or("Failed to create kernel stack")?,
+            phys_alloc: Vec::new(),
+            vram_map: self.vram_map.clone(),
+            ctxt: self.ctxt.clone(),
+            heap_base: self.heap_base,
+            brk: self.brk,
+            mmap_base: self.mmap_base,
+            state: ProcState::Ready,
+            waiting: None,
+            creds: self.creds,
+            fds: self.fds.clone()
+        };
+        child.ctxt.set_ret(0);
+
+        let psz = page_size();
+        for ForkPage { va, pa, flags, cow } in fork_pages(&self.vram_map, |va| self.glacier.get_pa(va)) {
+            if child.glacier.get_pa(va).is_some() { continue; } // Page shared by two regions
+
+            let mapped = if cow {
+                self.glacier.map_cow(va, pa, psz, flags)
+                    .and_then(|_| child.glacier.map_cow(va, pa, psz, flags))
+            } else {
+                child.glacier.map_page(va, pa, flags)
+            };
+            mapped.map_err(|_| "Failed to map process")?;
+
+            cow::share(pa);
+            child.phys_alloc.push(unsafe { OwnedPtr::from_raw(pa as *mut u8, psz) });
+        }
+
+        return Ok(child);
+    }
+
+    // Splits the page at `pa` out of whichever block holds it
+    pub fn take_page(&mut self, pa: usize) -> Option<OwnedPtr> {
+        let idx = self.phys_alloc.iter().position(|p| (p.addr()..p.end()).contains(&pa))?;
+        let mut page = self.phys_alloc.swap_remove(idx);
+
+        if page.addr() < pa {
+            let rest = page.split(pa - page.addr()).ok()?;
+            self.phys_alloc.push(page);
+            page = rest;
+        }
+        if let Ok(rest) = page.split(page_size()) {
+            self.phys_alloc.push(rest);
+        }
+        return Some(page);
+    }
+
+    // Pages are zeroed through their user address, so this Glacier must be active
+    pub fn sbrk(&mut self, delta: isize) -> Result<usize, String> {
+        let old = self.brk;
+        let new = move_break(self.heap_base, old, self.mmap_base, delta)?;
+
+        let psz = page_size();
+        let (old_top, new_top) = (align_up(old, psz), align_up(new, psz));
+
+        for va in (old_top..new_top).step_by(psz) {
+            let Some(page) = PHYS_ALLOC.alloc(
+                AllocParams::new(psz).align(psz)
+            ) else {
+                self.unmap_heap(old_top, va);
+                return Err("Failed to allocate heap memory".into());
+            };
+
+            let mapped = self.glacier.map_page(va, page.addr(), flags::U_RWO);
+            self.phys_alloc.push(page);
+            if mapped.is_err() {
+                self.unmap_heap(old_top, va + psz);
+                return Err("Failed to map heap memory".into());
+            }
+            unsafe { (va as *mut u8).write_bytes(0, psz); }
+        }
+        self.unmap_heap(new_top, old_top);
+
+        if let Some(heap) = self.vram_map.iter_mut().find(|m| m.va == self.heap_base) {
+            heap.size = new_top - self.heap_base;
+        }
+        self.brk = new;
+        return Ok(old);
+    }
+
+    // Reserves `size` bytes below the previous mappings, backed on first touch
+    pub fn mmap(&mut self, size: usize, flags: usize, file: Option<FileDesc>) -> Result<usize, String> {
+        let size = align_up(size, page_size());
+        let base = self.mmap_base.checked_sub(size)
+            .filter(|&base| base >= align_up(self.brk, page_size()))
+            .ok_or("Out of address space")?;
+
+        self.vram_map.push(VRamMap {
+            va: base,
+            pa: 0,
+            size,
+            flags,
+            lazy: true,
+            file
+        });
+        self.mmap_base = base;
+        return Ok(base);
+    }
+
+    fn unmap_heap(&mut self, start: usize, end: usize) {
+        for va in (start..end).step_by(page_size()) {
+            let Some(pa) = self.glacier.get_pa(va) else { continue; };
+            self.glacier.unmap_page(va);
+            if let Some(page) = self.take_page(pa) {
+                cow::release(page);
+            }
+        }
+    }
+
+    // Gives back user memory and files, the kernel stack stays until reaped
+    pub fn release(&mut self) {
+        self.fds.clear();
+        for pptr in self.phys_alloc.drain(..) {
+            cow::release(pptr);
+        }
+    }
 }
 
 impl Drop for ProcCtrlBlk {
     fn drop(&mut self) {
-        for pptr in self.phys_alloc.drain(..) {
-            PHYS_ALLOC.free(pptr);
-        }
+        self.release();
+        self.glacier.take().destroy();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        filesys::vfn::{FMeta, FType, MAY_READ, SeekFrom},
+        ram::glacier::{BPage, G_CFG, RvmCfg}
+    };
+    use alloc::vec;
+
+    const PSZ: usize = 0x1000;
+
+    fn region(va: usize, size: usize, flags: usize, lazy: bool) -> VRamMap {
+        return VRamMap { va, pa: 0, size, flags, lazy, file: None };
+    }
+
+    struct Node;
+
+    impl VirtFNode for Node {
+        fn meta(&self) -> FMeta { FMeta::vfs_only(FType::Regular) }
+    }
+
+    #[test]
+    fn fork_shares_backed_pages() {
+        G_CFG.call_once(|| RvmCfg { psz: BPage::Size4kiB, va_bits: 48, pa_bits: 52 });
+        let map = [
+            region(0x40_0000, 2 * PSZ, flags::U_ROX, false), // Text
+            region(0x40_2000, 0x1800, flags::U_RWO, false),  // Data, its last page half used
+            region(0x40_4000, 4 * PSZ, flags::U_RWO, true)   // Heap, one page touched so far
+        ];
+        let backed = [0x40_0000, 0x40_1000, 0x40_2000, 0x40_3000, 0x40_6000];
+        let get_pa = |va: usize| backed.contains(&va).then_some(va + 0x100_0000);
+
+        let pages = fork_pages(&map, get_pa);
+        let page = |va: usize, flags, cow| ForkPage { va, pa: va + 0x100_0000, flags, cow };
+        assert_eq!(pages, [
+            page(0x40_0000, flags::U_ROX, false),
+            page(0x40_1000, flags::U_ROX, false),
+            page(0x40_2000, flags::U_RWO, true),
+            page(0x40_3000, flags::U_RWO, true),
+            page(0x40_6000, flags::U_RWO, true)
+        ]);
+    }
+
+    #[test]
+    fn fork_shares_open_files() {
+        let mut fds = BTreeMap::new();
+        fds.insert(0, Arc::new(OpenFile::new(Arc::new(Node), MAY_READ)));
+        let child = fds.clone();
+
+        assert_eq!(Arc::strong_count(&fds[&0]), 2);
+        fds[&0].seek(SeekFrom::Start(3)).unwrap();
+        assert_eq!(child[&0].seek(SeekFrom::Current(0)), Ok(3));
+
+        drop(fds);
+        assert_eq!(Arc::strong_count(&child[&0]), 1);
+    }
+
+    #[test]
+    fn break_moves_within_the_heap() {
+        G_CFG.call_once(|| RvmCfg { psz: BPage::Size4kiB, va_bits: 48, pa_bits: 52 });
+        let (base, mmap) = (0x40_8000, 0x80_0000);
+
+        assert_eq!(move_break(base, base, mmap, 0x10), Ok(base + 0x10));
+        assert_eq!(move_break(base, base + 0x10, mmap, -0x10), Ok(base));
+        assert_eq!(move_break(base, base + 0x3000, mmap, -0x1001), Ok(base + 0x1fff));
+        assert!(move_break(base, base, mmap, -1).is_err());
+        assert!(move_break(base, usize::MAX - 1, usize::MAX, 2).is_err());
+    }
+
+    #[test]
+    fn break_stops_at_the_mappings() {
+        G_CFG.call_once(|| RvmCfg { psz: BPage::Size4kiB, va_bits: 48, pa_bits: 52 });
+        let (base, mmap) = (0x40_8000, 0x40_c000);
+
+        assert_eq!(move_break(base, base, mmap, 0x4000), Ok(mmap));
+        // One byte into the page below the mappings still takes the whole page
+        assert_eq!(
+            move_break(base, mmap - 1, mmap, 2),
+            Err("Program break collides with the mappings".into())
+        );
+        assert!(move_break(base, base, mmap, 0x4001).is_err());
+    }
+
+    #[test]
+    fn reused_frames_read_as_zero() {
+        // Whatever the last process left behind
+        let mut image = vec![0xaa; 3 * PSZ];
+        let (text, data) = ([0x11; 0x180], [0x22; 0x40]);
+        fill_image(&mut image, &[(0x100, &text), (2 * PSZ + 0x10, &data)]);
+
+        assert!(image[..0x100].iter().all(|&b| b == 0));
+        assert_eq!(image[0x100..0x280], text);
+        assert!(image[0x280..2 * PSZ + 0x10].iter().all(|&b| b == 0));
+        assert_eq!(image[2 * PSZ + 0x10..2 * PSZ + 0x50], data);
+        assert!(image[2 * PSZ + 0x50..].iter().all(|&b| b == 0));
     }
 }
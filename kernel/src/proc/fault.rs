@@ -0,0 +1,229 @@
+use crate::{
+    arch::rvm::flags,
+    error,
+    proc::{PROCS, cow, current_pid, exit_proc, ctrlblk::VRamMap},
+    ram::{
+        glacier::{GLACIER, hihalf, page_size},
+        physalloc::{AllocParams, PHYS_ALLOC}
+    },
+    trace
+};
+
+use core::fmt;
+
+pub struct PageFault {
+    pub addr: usize,
+    pub present: bool, // Protection violation rather than a missing page
+    pub write: bool,
+    pub exec: bool,
+    pub user: bool
+}
+
+impl fmt::Display for PageFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let access = if self.exec { "exec" } else if self.write { "write" } else { "read" };
+        let mode = if self.user { "user" } else { "kernel" };
+        let cause = if self.present { "protection" } else { "not present" };
+        write!(f, "{} {} at {:#x} ({})", mode, access, self.addr, cause)
+    }
+}
+
+fn lazy_region(vram_map: &[VRamMap], addr: usize) -> Option<&VRamMap> {
+    return vram_map.iter().find(|m| m.lazy && (m.va..m.va + m.size).contains(&addr));
+}
+
+// Contents of the page at `va` of a lazy region. Bytes past the end of the file stay zero.
+fn fill_page(region: &VRamMap, va: usize, page: &mut [u8]) -> bool {
+    page.fill(0);
+    let Some(file) = &region.file else { return true; };
+
+    let offset = file.offset + (va - region.va) as u64;
+    let len = file.node.meta().size.saturating_sub(offset).min(page.len() as u64) as usize;
+    return len == 0 || file.node.read(&mut page[..len], offset).is_ok();
+}
+
+// Backs a lazily allocated page of the running process
+fn fault_in(fault: &PageFault) -> bool {
+    if fault.present { return false; }
+
+    let Some(pid) = current_pid() else { return false; };
+    let va = fault.addr & !(page_size() - 1);
+
+    // Copied out, the file read below may take long and must not hold PROCS
+    let region = {
+        let procs = PROCS.read();
+        let Some(proc) = procs.procs.get(&pid) else { return false; };
+        let Some(region) = lazy_region(&proc.vram_map, fault.addr) else { return false; };
+        region.clone()
+    };
+
+    let Some(page) = PHYS_ALLOC.alloc(
+        AllocParams::new(page_size()).align(page_size())
+    ) else { return false; };
+
+    // The frame may hold another process's data. It is only reachable through the identity map.
+    GLACIER.read().activate();
+    let buf = unsafe { core::slice::from_raw_parts_mut(page.ptr::<u8>(), page_size()) };
+    let filled = fill_page(&region, va, buf);
+
+    let mut procs = PROCS.write();
+    let Some(proc) = procs.procs.get_mut(&pid) else {
+        PHYS_ALLOC.free(page);
+        return false;
+    };
+    proc.glacier.activate();
+
+    if !filled {
+        PHYS_ALLOC.free(page);
+        return false;
+    }
+
+    // The mapping may have changed or been backed while unlocked
+    let unchanged = lazy_region(&proc.vram_map, fault.addr)
+        .is_some_and(|m| m.va == region.va && m.flags == region.flags);
+    if !unchanged || proc.glacier.get_pa(va).is_some() {
+        PHYS_ALLOC.free(page);
+        return unchanged;
+    }
+
+    if proc.glacier.map_page(va, page.addr(), region.flags).is_err() {
+        PHYS_ALLOC.free(page);
+        return false;
+    }
+    proc.glacier.flush(va);
+    proc.phys_alloc.push(page);
+    return true;
+}
+
+// Gives the writer its own copy of a shared page, the last holder keeps the frame
+fn cow_fault(fault: &PageFault) -> bool {
+    if !fault.present || !fault.write { return false; }
+
+    let Some(pid) = current_pid() else { return false; };
+    let mut procs = PROCS.write();
+    let Some(proc) = procs.procs.get_mut(&pid) else { return false; };
+
+    let va = fault.addr & !(page_size() - 1);
+    let (Some(pa), Some(pte_flags)) = (proc.glacier.get_pa(va), proc.glacier.get_flags(va)) else {
+        return false;
+    };
+    if pte_flags & flags::COW == 0 { return false; }
+    let flags = flags::uncow(pte_flags);
+
+    if !cow::is_shared(pa) {
+        let _ = proc.glacier.map_page(va, pa, flags);
+        return true;
+    }
+
+    let Some(page) = PHYS_ALLOC.alloc(
+        AllocParams::new(page_size()).align(page_size())
+    ) else { return false; };
+
+    // Both frames are reached through the identity map
+    GLACIER.read().activate();
+    unsafe { page.ptr::<u8>().copy_from(pa as *const u8, page_size()); }
+    proc.glacier.activate();
+
+    if proc.glacier.map_page(va, page.addr(), flags).is_err() {
+        PHYS_ALLOC.free(page);
+        return false;
+    }
+    proc.phys_alloc.push(page);
+    if let Some(old) = proc.take_page(pa) {
+        cow::release(old);
+    }
+    return true;
+}
+
+// Returns false only for faults the kernel cannot pin on a process
+pub fn handle_page_fault(fault: &PageFault) -> bool {
+    trace!("Page fault: {}", fault);
+    if fault_in(fault) || cow_fault(fault) { return true; }
+
+    // Requests check user buffers first, so a kernel mode fault below the kernel
+    // half is a lazy page that could not be backed. Either way the process dies.
+    if current_pid().is_some() && (fault.user || fault.addr < hihalf()) {
+        error!("Segmentation fault: {}", fault);
+        exit_proc(-11); // SIGSEGV
+    }
+
+    error!("Kernel page fault: {}", fault);
+    return false;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        filesys::vfn::{FMeta, FType, VirtFNode},
+        proc::ctrlblk::FileDesc
+    };
+    use alloc::{collections::btree_map::BTreeMap, string::String, sync::Arc, vec, vec::Vec};
+
+    const PSZ: usize = 0x1000;
+
+    fn region(va: usize, size: usize, lazy: bool) -> VRamMap {
+        return VRamMap { va, pa: 0, size, flags: flags::U_RWO, lazy, file: None };
+    }
+
+    #[test]
+    fn lazy_pages_appear_on_first_touch() {
+        let map = [
+            region(0x40_0000, PSZ, false),     // Data
+            region(0x40_1000, 3 * PSZ, true),  // Its BSS tail
+            region(0x7f_0000, 4 * PSZ, true)   // Stack
+        ];
+        let mut table: BTreeMap<usize, Vec<u8>> = BTreeMap::new();
+        table.insert(0x40_0000, vec![0x11; PSZ]);
+        let translate = |table: &BTreeMap<usize, Vec<u8>>, addr: usize| table.get(&(addr & !(PSZ - 1))).is_some();
+
+        assert!(lazy_region(&map, 0x40_0010).is_none());
+        assert!(!translate(&table, 0x40_2010));
+        assert!(!translate(&table, 0x7f_3ff8));
+
+        // A fault backs the one page touched with zeros, whatever the frame held
+        let addr = 0x40_2010;
+        let va = addr & !(PSZ - 1);
+        let lazy = lazy_region(&map, addr).unwrap();
+        let mut page = vec![0xaa; PSZ];
+        assert!(fill_page(lazy, va, &mut page));
+        assert_eq!(page, vec![0; PSZ]);
+        table.insert(va, page);
+
+        assert!(translate(&table, addr));
+        assert!(!translate(&table, 0x40_1000) && !translate(&table, 0x40_3000));
+        assert!(lazy_region(&map, 0x40_4000).is_none());
+    }
+
+    // Byte i of the file reads as i, and the file ends at 0x2800
+    struct File;
+
+    impl VirtFNode for File {
+        fn meta(&self) -> FMeta { FMeta { size: 0x2800, ..FMeta::vfs_only(FType::Regular) } }
+
+        fn read(&self, buf: &mut [u8], offset: u64) -> Result<usize, String> {
+            assert!(offset + buf.len() as u64 <= 0x2800);
+            buf.iter_mut().zip(offset..).for_each(|(b, i)| *b = i as u8);
+            return Ok(buf.len());
+        }
+    }
+
+    #[test]
+    fn file_pages_read_from_the_offset() {
+        let file = FileDesc { node: Arc::new(File), offset: 0x1000 };
+        let map = VRamMap { file: Some(file), ..region(0x50_0000, 3 * PSZ, true) };
+        let mut page = vec![0xaa; PSZ];
+
+        assert!(fill_page(&map, 0x50_0000, &mut page));
+        assert!(page.iter().zip(0x1000..).all(|(&b, i)| b == i as u8));
+
+        // The file ends halfway through the second page, the rest is zero
+        assert!(fill_page(&map, 0x50_1000, &mut page));
+        assert!(page[..0x800].iter().zip(0x2000..).all(|(&b, i)| b == i as u8));
+        assert!(page[0x800..].iter().all(|&b| b == 0));
+
+        page.fill(0xaa);
+        assert!(fill_page(&map, 0x50_2000, &mut page));
+        assert_eq!(page, vec![0; PSZ]);
+    }
+}
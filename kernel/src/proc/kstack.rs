@@ -1,31 +1,85 @@
-use crate::{
-    arch::rvm::flags,
-    ram::{VirtPageBuf, glacier::{GLACIER, page_size}, stack_size}
-};
+use crate::ram::{STACK_PAINT, VirtPageBuf, glacier::page_size, stack_size, untouched_below};
+
+use core::sync::atomic::{AtomicUsize, Ordering as AtomOrd};
 
 pub struct KernelStack {
     buf: VirtPageBuf,
-    pa: usize
+    pa: usize,
+    mark: AtomicUsize // Bytes from the base still painted at the last scan
 }
 
 impl KernelStack {
     pub fn new() -> Option<Self> {
-        let mut glacier = GLACIER.write();
-        let buf = VirtPageBuf::new(stack_size() + page_size())?;
-        let va = buf.as_ptr() as usize;
-        let pa = glacier.get_pa(va)?;
-        glacier.unmap_page(va);
-        return Some(Self { buf, pa });
+        let mut buf = VirtPageBuf::new(stack_size() + page_size())?;
+        buf[page_size()..].fill(STACK_PAINT);
+        let pa = unmap_guard(buf.as_ptr() as usize)?;
+        return Some(Self { buf, pa, mark: AtomicUsize::new(stack_size()) });
     }
 
     pub fn top(&self) -> usize {
         return self.buf.as_ptr() as usize + page_size() + stack_size();
     }
+
+    // Peak usage since new
+    pub fn high_water(&self) -> usize {
+        let base = self.top() - stack_size();
+        let stack = unsafe { core::slice::from_raw_parts(base as *const u8, stack_size()) };
+        let left = untouched_below(stack, self.mark.load(AtomOrd::Relaxed));
+        self.mark.fetch_min(left, AtomOrd::Relaxed);
+        return stack_size() - left;
+    }
 }
 
 impl Drop for KernelStack {
     fn drop(&mut self) {
-        let va = self.buf.as_ptr() as usize;
-        let _ = GLACIER.write().map_page(va, self.pa, flags::K_RWO);
+        remap_guard(self.buf.as_ptr() as usize, self.pa);
+    }
+}
+
+// The lowest page is left unmapped so an overflow faults instead of running into the heap
+#[cfg(not(test))]
+fn unmap_guard(va: usize) -> Option<usize> {
+    let mut glacier = crate::ram::glacier::GLACIER.write();
+    let pa = glacier.get_pa(va)?;
+    glacier.unmap_page(va);
+    return Some(pa);
+}
+
+#[cfg(not(test))]
+fn remap_guard(va: usize, pa: usize) {
+    let _ = crate::ram::glacier::GLACIER.write().map_page(va, pa, crate::arch::rvm::flags::K_RWO);
+}
+
+// Host tests have no page tables to punch the guard into
+#[cfg(test)]
+fn unmap_guard(va: usize) -> Option<usize> { Some(va) }
+
+#[cfg(test)]
+fn remap_guard(_va: usize, _pa: usize) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ram::glacier::{BPage, G_CFG, RvmCfg};
+
+    #[test]
+    fn stacks_do_not_overlap() {
+        G_CFG.call_once(|| RvmCfg { psz: BPage::Size4kiB, va_bits: 48, pa_bits: 52 });
+        let a = KernelStack::new().unwrap();
+        let b = KernelStack::new().unwrap();
+
+        // Guard page included
+        let span = |s: &KernelStack| s.top() - stack_size() - page_size()..s.top();
+        let (ra, rb) = (span(&a), span(&b));
+        assert!(ra.end <= rb.start || rb.end <= ra.start);
+        assert_eq!(a.top() % page_size(), 0);
+        assert_eq!(b.top() % page_size(), 0);
+
+        // Usage on one stack does not show up on the other
+        assert_eq!((a.high_water(), b.high_water()), (0, 0));
+        let used = unsafe { core::slice::from_raw_parts_mut((b.top() - 0x300) as *mut u8, 0x300) };
+        used.fill(0);
+        assert!(b.high_water() >= 0x300);
+        assert_eq!(a.high_water(), 0);
     }
 }
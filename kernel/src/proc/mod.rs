@@ -1,65 +1,356 @@
+pub mod cow;
 pub mod ctrlblk;
+pub mod fault;
 pub mod kstack;
+pub mod wait;
 
 use crate::{
-    arch,
-    filesys::{VFS, vfn::VirtFNode},
+    arch::{self, exc::ExcFrame, percpu::this_cpu},
+    filesys::{VFS, root_mount, vfn::{Credentials, MAY_READ, MAY_WRITE, VirtFNode}},
     printlnk,
     proc::ctrlblk::{ProcCtrlBlk, ProcState},
-    ram::{glacier::GLACIER, stack_top}
+    ram::{glacier::GLACIER, mutex::SpinRwLock, stack_top},
+    time, warn
 };
 
 use alloc::{
-    collections::btree_map::BTreeMap,
-    string::String
+    collections::{btree_map::BTreeMap, vec_deque::VecDeque},
+    format,
+    string::String,
+    vec::Vec
 };
-use spin::{Mutex, RwLock};
 
-pub struct ProcTables(pub BTreeMap<usize, ProcCtrlBlk>);
+const PID_MAX: usize = 0x8000;
+const WORD_BITS: usize = usize::BITS as usize;
 
-impl ProcTables {
+// PIDs count up from the last one handed out and only wrap around at PID_MAX,
+// so a freed PID isn't reused while a stale waitpid or kill may still name it
+struct PidMap {
+    bitmap: Vec<usize>,
+    next: usize
+}
+
+impl PidMap {
+    const fn new() -> Self {
+        return Self { bitmap: Vec::new(), next: 1 };
+    }
+
+    fn find_from(&self, start: usize) -> Option<usize> {
+        let mut mask = !0usize << (start % WORD_BITS);
+        for idx in start / WORD_BITS..self.bitmap.len() {
+            let free = !self.bitmap[idx] & mask;
+            if free != 0 {
+                return Some(idx * WORD_BITS + free.trailing_zeros() as usize);
+            }
+            mask = !0;
+        }
+        return None;
+    }
+
+    fn alloc(&mut self) -> Option<usize> {
+        if self.bitmap.is_empty() {
+            self.bitmap.push(1); // PID 0 is never handed out
+        }
+
+        let pid = match self.find_from(self.next) {
+            Some(pid) => pid,
+            None if self.bitmap.len() * WORD_BITS < PID_MAX => {
+                self.bitmap.push(0);
+                (self.bitmap.len() - 1) * WORD_BITS
+            }
+            None => self.find_from(1)?
+        };
+        self.bitmap[pid / WORD_BITS] |= 1 << (pid % WORD_BITS);
+        self.next = pid + 1;
+        return Some(pid);
+    }
+
+    fn free(&mut self, pid: usize) {
+        if pid == 0 { return; }
+        if let Some(word) = self.bitmap.get_mut(pid / WORD_BITS) {
+            *word &= !(1 << (pid % WORD_BITS));
+        }
+    }
+}
+
+// Round robin order, each PID queued at most once
+pub struct RunQueue(VecDeque<usize>);
+
+impl RunQueue {
+    const fn new() -> Self {
+        return Self(VecDeque::new());
+    }
+
+    pub fn push(&mut self, pid: usize) {
+        if !self.0.contains(&pid) {
+            self.0.push_back(pid);
+        }
+    }
+
+    // Entries failing `ready` are stale and dropped on the way
+    pub fn pop(&mut self, ready: impl Fn(usize) -> bool) -> Option<usize> {
+        while let Some(pid) = self.0.pop_front() {
+            if ready(pid) {
+                return Some(pid);
+            }
+        }
+        return None;
+    }
+}
+
+// What becomes of a process once its CPU has switched off its kernel stack
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Leave {
+    Ready,
+    Blocked,
+    Exit(i32)
+}
+
+// What the table's bookkeeping touches of a PCB, tests drive it with mock entries
+pub trait ProcEntry {
+    fn ppid(&self) -> usize;
+    fn set_ppid(&mut self, ppid: usize);
+    fn state(&self) -> ProcState;
+    fn set_state(&mut self, state: ProcState);
+    fn waiting(&self) -> Option<usize>;
+    fn set_waiting(&mut self, pid: Option<usize>);
+    fn set_ret(&mut self, ret: usize);
+    fn release(&mut self);
+}
+
+impl ProcEntry for ProcCtrlBlk {
+    fn ppid(&self) -> usize { self.ppid }
+    fn set_ppid(&mut self, ppid: usize) { self.ppid = ppid; }
+    fn state(&self) -> ProcState { self.state }
+    fn set_state(&mut self, state: ProcState) { self.state = state; }
+    fn waiting(&self) -> Option<usize> { self.waiting }
+    fn set_waiting(&mut self, pid: Option<usize>) { self.waiting = pid; }
+    fn set_ret(&mut self, ret: usize) { self.ctxt.set_ret(ret); }
+    fn release(&mut self) { ProcCtrlBlk::release(self); }
+}
+
+pub struct ProcTables<P = ProcCtrlBlk> {
+    pub procs: BTreeMap<usize, P>,
+    pub ready: RunQueue,
+    prev: BTreeMap<usize, (usize, Leave)>, // By phys_id, see leave
+    pids: PidMap
+}
+
+impl<P: ProcEntry> ProcTables<P> {
     const fn new() -> Self {
-        return Self(BTreeMap::new());
+        return Self {
+            procs: BTreeMap::new(), ready: RunQueue::new(),
+            prev: BTreeMap::new(), pids: PidMap::new()
+        };
+    }
+
+    // Drops the process from the table and gives its PID back
+    fn remove(&mut self, pid: usize) -> Option<P> {
+        let proc = self.procs.remove(&pid)?;
+        self.pids.free(pid);
+        return Some(proc);
+    }
+
+    // The current process still runs on its own kernel stack and page table,
+    // so it stays Running until this CPU is back in schedule() on its own stack.
+    // Nothing another CPU does can free it or run it in the meantime.
+    fn leave(&mut self, pid: usize, how: Leave) {
+        self.prev.insert(arch::phys_id(), (pid, how));
+    }
+
+    // Called from schedule() once this CPU is off the previous kernel stack
+    pub fn finish_switch(&mut self) {
+        let Some((pid, how)) = self.prev.remove(&arch::phys_id()) else { return; };
+        match how {
+            Leave::Ready => {
+                let Some(proc) = self.procs.get_mut(&pid) else { return; };
+                proc.set_state(ProcState::Ready);
+                self.enqueue(pid);
+            }
+            Leave::Blocked => {
+                let Some(proc) = self.procs.get_mut(&pid) else { return; };
+                proc.set_state(ProcState::Blocked);
+            }
+            Leave::Exit(code) => self.exit(pid, code)
+        }
+    }
+
+    // The current process goes to sleep once block_proc switches away.
+    // Must be recorded under the same lock hold that makes it wakeable.
+    pub fn block(&mut self, pid: usize) {
+        self.leave(pid, Leave::Blocked);
+    }
+
+    // A process that hasn't left its CPU yet is made ready by finish_switch instead
+    pub fn wake(&mut self, pid: usize) -> bool {
+        let Some(proc) = self.procs.get_mut(&pid) else { return false; };
+        match proc.state() {
+            ProcState::Blocked => {
+                proc.set_state(ProcState::Ready);
+                self.enqueue(pid);
+                return true;
+            }
+            ProcState::Running(cpu) => {
+                let Some(prev) = self.prev.get_mut(&cpu) else { return false; };
+                if *prev != (pid, Leave::Blocked) { return false; }
+                prev.1 = Leave::Ready;
+                return true;
+            }
+            _ => return false
+        }
+    }
+
+    // Orphans go to PID 1, exited orphans are dropped right away.
+    // Runs from finish_switch, so pid no longer owns any CPU.
+    fn exit(&mut self, pid: usize, code: i32) {
+        let children: Vec<usize> = self.procs.iter()
+            .filter(|(_, p)| p.ppid() == pid)
+            .map(|(&cpid, _)| cpid)
+            .collect();
+        for cpid in children {
+            let child = self.procs.get_mut(&cpid).unwrap();
+            if let ProcState::Zombie(_) = child.state() {
+                self.remove(cpid);
+            } else {
+                child.set_ppid(1);
+            }
+        }
+
+        let Some(proc) = self.procs.get_mut(&pid) else { return; };
+        let ppid = proc.ppid();
+
+        let Some(parent) = self.procs.get_mut(&ppid).filter(|_| ppid != pid) else {
+            self.remove(pid);
+            return;
+        };
+
+        if parent.waiting() == Some(pid) {
+            parent.set_waiting(None);
+            parent.set_ret(code as isize as usize);
+            self.wake(ppid);
+            self.remove(pid);
+            return;
+        }
+
+        let proc = self.procs.get_mut(&pid).unwrap();
+        proc.release();
+        proc.set_state(ProcState::Zombie(code));
+    }
+
+    // Ok(None) means the parent must block_proc() and stays blocked until the child exits
+    pub fn waitpid(&mut self, parent_pid: usize, child_pid: usize) -> Result<Option<i32>, String> {
+        let child = self.procs.get(&child_pid)
+            .filter(|c| c.ppid() == parent_pid)
+            .ok_or("No such child process")?;
+
+        if let ProcState::Zombie(code) = child.state() {
+            self.remove(child_pid);
+            return Ok(Some(code));
+        }
+
+        let parent = self.procs.get_mut(&parent_pid).ok_or("No such process")?;
+        parent.set_waiting(Some(child_pid));
+        self.block(parent_pid);
+        return Ok(None);
+    }
+
+    pub fn enqueue(&mut self, pid: usize) {
+        self.ready.push(pid);
+    }
+
+    // Exited processes and ones no longer ready are skipped
+    pub fn next_ready(&mut self) -> Option<usize> {
+        let procs = &self.procs;
+        return self.ready.pop(|pid| procs.get(&pid).is_some_and(|p| p.state() == ProcState::Ready));
     }
+}
 
+impl ProcTables {
     pub fn exec(&mut self, node: &dyn VirtFNode, args: &[&str]) -> Result<usize, String> {
         let proc = ProcCtrlBlk::new(node, args)?;
-        let mut pid_rr = PID_RR.lock();
-        let pid = loop {
-            let pid = *pid_rr;
-            if !self.0.contains_key(&pid) && pid != 0 {
-                break pid;
+        let pid = self.pids.alloc().ok_or("Out of PIDs")?;
+        self.procs.insert(pid, proc);
+        self.enqueue(pid);
+
+        // stdin, stdout, stderr, one open file like a login terminal
+        if let Ok(cons) = VFS.open(&Credentials::ROOT, "/dev/console", MAY_READ | MAY_WRITE) {
+            let fds = &mut self.procs.get_mut(&pid).unwrap().fds;
+            for fd in 0..3 {
+                fds.insert(fd, cons.clone());
             }
-            *pid_rr = pid_rr.wrapping_add(1);
-        };
-        self.0.insert(pid, proc);
+        }
+        return Ok(pid);
+    }
+
+    // The child resumes from the parent's saved context with 0 as the syscall result
+    pub fn fork(&mut self, parent_pid: usize) -> Result<usize, String> {
+        let parent = self.procs.get_mut(&parent_pid).ok_or("No such process")?;
+
+        let child = parent.fork(parent_pid);
+        if parent.glacier.is_active() {
+            parent.glacier.activate(); // Drop the stale writable TLB entries
+        }
+
+        let child = child?;
+        let pid = self.pids.alloc().ok_or("Out of PIDs")?;
+        self.procs.insert(pid, child);
+        self.enqueue(pid);
         return Ok(pid);
     }
 }
 
-pub static PID_RR: Mutex<usize> = Mutex::new(1);
-pub static PROCS: RwLock<ProcTables> = RwLock::new(ProcTables::new());
-pub static RQ: RwLock<BTreeMap<usize, usize>> = RwLock::new(BTreeMap::new());
+pub const TIME_SLICE_MS: u64 = 10;
+
+pub static PROCS: SpinRwLock<ProcTables> = SpinRwLock::new(ProcTables::new());
 
 pub fn exec_aleph() {
-    let path = "/mnt/block0p0/sbin/aleph";
+    // The initrd copy wins over the one on the root partition
+    let paths = [String::from("/init/sbin/aleph"), format!("{}/sbin/aleph", root_mount())];
+    let Some((path, node)) = paths.iter()
+        .find_map(|path| VFS.walk(&Credentials::ROOT, path).ok().map(|node| (path, node)))
+    else {
+        printlnk!("Failed to exec aleph: not found");
+        return;
+    };
+
+    let res = PROCS.write().exec(&*node, &[path.as_str()]);
+    match res {
+        Ok(_) => schedule(),
+        Err(err) => printlnk!("Failed to exec {}: {:?}", path, err)
+    }
+}
+
+pub fn current_pid() -> Option<usize> {
+    return this_cpu().pid();
+}
+
+// Of the process running here. None without one, or when the tick landed on a PROCS writer.
+pub fn kstack_high_water() -> Option<usize> {
+    let pid = current_pid()?;
+    return PROCS.try_read()?.procs.get(&pid).map(|proc| proc.kstack.high_water());
+}
 
-    VFS.walk(path).and_then(|node| {
-        let pid = PROCS.write().exec(&*node, &[path])?;
-        return Err(exec_proc(pid));
-    }).unwrap_or_else(|err| {
-        printlnk!("Failed to exec {}: {:?}", path, err);
-    });
+// Returns the pid that was running here before
+pub fn set_current_pid(pid: Option<usize>) -> Option<usize> {
+    return this_cpu().swap_pid(pid);
 }
 
-fn exec_proc(pid: usize) -> String {
+// Keeps the trapped user context so the process can be resumed from it
+pub fn save_ctxt(frame: &ExcFrame) {
+    let Some(pid) = current_pid() else { return; };
+    if let Some(proc) = PROCS.write().procs.get_mut(&pid) {
+        *proc.ctxt = *frame;
+    }
+}
+
+fn switch_to(pid: usize) -> String {
     let ctxt;
     let kstk_top;
 
     {
         let mut procs = PROCS.write();
 
-        let Some(proc) = procs.0.get_mut(&pid) else {
+        let Some(proc) = procs.procs.get_mut(&pid) else {
             return "No such process".into();
         };
 
@@ -67,37 +358,253 @@ fn exec_proc(pid: usize) -> String {
             return "Process not in ready state".into();
         }
 
-        RQ.write().insert(arch::phys_id(), pid);
+        proc.state = ProcState::Running(arch::phys_id());
+        set_current_pid(Some(pid));
         proc.glacier.activate();
         ctxt = *proc.ctxt;
         kstk_top = proc.kstack.top();
     }
 
+    arch::exc::set_kstk(kstk_top);
+    start_slice();
     unsafe { arch::proc::rstr_ctxt(&ctxt, kstk_top); }
 }
 
+// Queued by finish_switch, another CPU must not pick it up while still on its stack
+fn requeue_current(ret: Option<usize>) {
+    let mut procs = PROCS.write();
+    if let Some(pid) = set_current_pid(None) {
+        if let Some(proc) = procs.procs.get_mut(&pid) {
+            if let Some(ret) = ret {
+                proc.ctxt.set_ret(ret);
+            }
+            procs.leave(pid, Leave::Ready);
+        }
+    }
+}
+
+// Gives up the CPU, resuming later with `ret` as the syscall result
+pub fn yield_proc(ret: usize) -> ! {
+    arch::exc::set(false);
+    requeue_current(Some(ret));
+    enter_scheduler();
+}
+
+// Time slice expired, `frame` is the interrupted user context
+pub fn preempt(frame: &ExcFrame) -> ! {
+    arch::exc::set(false);
+    save_ctxt(frame);
+    requeue_current(None);
+    enter_scheduler();
+}
+
+// Leaves the CPU without requeueing, ProcTables::block has already been recorded
+// and someone else has to wake it
+pub fn block_proc() -> ! {
+    arch::exc::set(false);
+    set_current_pid(None);
+    enter_scheduler();
+}
+
 pub fn exit_proc(code: i32) -> ! {
     arch::exc::set(false);
-    GLACIER.read().activate();
 
     {
-        let pid = RQ.write().remove(&arch::phys_id()).unwrap_or(0);
-        PROCS.write().0.remove(&pid);
+        let pid = set_current_pid(None).unwrap_or(0);
+        PROCS.write().leave(pid, Leave::Exit(code));
 
         printlnk!("proc {} exited: {}", pid, code);
     }
 
+    enter_scheduler();
+}
+
+// The current kernel stack may belong to a process that is gone
+fn enter_scheduler() -> ! {
+    GLACIER.read().activate();
     arch::exc::set_kstk(stack_top());
     unsafe { arch::move_stack(stack_top()); }
     schedule();
 }
 
-fn schedule() -> ! {
-    printlnk!("scheduling...");
-    arch::intc::timer_set_ms(1000);
+// Replaces whatever slice the previous process left pending on this CPU
+fn start_slice() {
+    let cpu = this_cpu();
+    cpu.set_resched(false);
+    let id = time::after_ms(TIME_SLICE_MS, || this_cpu().set_resched(true));
+    time::cancel(cpu.swap_slice(id));
+}
+
+pub fn schedule() -> ! {
+    time::rearm();
     arch::intc::timer_enable();
 
     loop {
-        arch::wfi();
+        arch::exc::set(false);
+        let next = {
+            let mut procs = PROCS.write();
+            procs.finish_switch();
+            procs.next_ready()
+        };
+
+        match next {
+            Some(pid) => {
+                let err = switch_to(pid);
+                warn!("Failed to switch to proc {}: {}", pid, err);
+            }
+            None => arch::wfi()
+        }
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+
+    #[test]
+    fn pids_count_up_past_freed_ones() {
+        let mut pids = PidMap::new();
+        for pid in 1..=200 {
+            assert_eq!(pids.alloc(), Some(pid));
+        }
+        pids.free(2);
+        pids.free(0);
+        pids.free(PID_MAX * 2);
+        assert_eq!(pids.alloc(), Some(201));
+    }
+
+    #[test]
+    fn pids_wrap_at_pid_max() {
+        let mut pids = PidMap::new();
+        for pid in 1..PID_MAX {
+            assert_eq!(pids.alloc(), Some(pid));
+        }
+        assert_eq!(pids.alloc(), None);
+
+        pids.free(100);
+        pids.free(5);
+        assert_eq!(pids.alloc(), Some(5));
+        assert_eq!(pids.alloc(), Some(100));
+        assert_eq!(pids.alloc(), None);
+    }
+
+    pub struct Mock {
+        ppid: usize,
+        state: ProcState,
+        waiting: Option<usize>,
+        ret: Option<usize>,
+        released: bool
+    }
+
+    impl ProcEntry for Mock {
+        fn ppid(&self) -> usize { self.ppid }
+        fn set_ppid(&mut self, ppid: usize) { self.ppid = ppid; }
+        fn state(&self) -> ProcState { self.state }
+        fn set_state(&mut self, state: ProcState) { self.state = state; }
+        fn waiting(&self) -> Option<usize> { self.waiting }
+        fn set_waiting(&mut self, pid: Option<usize>) { self.waiting = pid; }
+        fn set_ret(&mut self, ret: usize) { self.ret = Some(ret); }
+        fn release(&mut self) { self.released = true; }
+    }
+
+    // PIDs 1, 2, ... with the given parents, all ready
+    pub fn table(ppids: &[usize]) -> ProcTables<Mock> {
+        let mut procs = ProcTables::new();
+        for &ppid in ppids {
+            let pid = procs.pids.alloc().unwrap();
+            let mock = Mock { ppid, state: ProcState::Ready, waiting: None, ret: None, released: false };
+            procs.procs.insert(pid, mock);
+        }
+        return procs;
+    }
+
+    #[test]
+    fn zombie_holds_code_until_reaped() {
+        let mut procs = table(&[0, 1]);
+        procs.exit(2, 7);
+        assert_eq!(procs.procs[&2].state, ProcState::Zombie(7));
+        assert!(procs.procs[&2].released);
+
+        assert_eq!(procs.waitpid(3, 2), Err("No such child process".into()));
+        assert_eq!(procs.waitpid(1, 2), Ok(Some(7)));
+        assert!(!procs.procs.contains_key(&2));
+        assert!(procs.waitpid(1, 2).is_err());
+    }
+
+    #[test]
+    fn blocked_parent_gets_the_code() {
+        let mut procs = table(&[0, 1]);
+        procs.procs.get_mut(&1).unwrap().state = ProcState::Running(arch::phys_id());
+        assert_eq!(procs.waitpid(1, 2), Ok(None));
+        assert_eq!(procs.procs[&1].waiting, Some(2));
+        procs.finish_switch();
+        assert_eq!(procs.procs[&1].state, ProcState::Blocked);
+
+        procs.exit(2, -1);
+        assert!(!procs.procs.contains_key(&2));
+        let parent = &procs.procs[&1];
+        assert_eq!((parent.state, parent.waiting, parent.ret), (ProcState::Ready, None, Some(usize::MAX)));
+        assert_eq!(procs.next_ready(), Some(1));
+    }
+
+    #[test]
+    fn child_exits_before_parent_leaves() {
+        let mut procs = table(&[0, 1]);
+        procs.procs.get_mut(&1).unwrap().state = ProcState::Running(arch::phys_id());
+        assert_eq!(procs.waitpid(1, 2), Ok(None));
+
+        // The parent is still on its CPU, finish_switch makes it ready instead
+        procs.exit(2, 5);
+        assert_eq!(procs.procs[&1].ret, Some(5));
+        procs.finish_switch();
+        assert_eq!(procs.procs[&1].state, ProcState::Ready);
+        assert_eq!(procs.next_ready(), Some(1));
+    }
+
+    #[test]
+    fn orphans_go_to_init() {
+        let mut procs = table(&[0, 1, 2, 2]);
+        procs.exit(4, 0);
+        procs.exit(2, 0);
+
+        assert_eq!(procs.procs[&3].ppid, 1);
+        assert!(!procs.procs.contains_key(&4));
+        assert_eq!(procs.procs[&2].state, ProcState::Zombie(0));
+
+        // Nobody left to reap it
+        procs.exit(1, 0);
+        assert!(!procs.procs.contains_key(&1) && !procs.procs.contains_key(&2));
+        procs.exit(3, 0);
+        assert!(procs.procs.is_empty());
+    }
+
+    #[test]
+    fn run_queue_rotates() {
+        let mut queue = RunQueue::new();
+        for pid in [1, 2, 3] {
+            queue.push(pid);
+        }
+        queue.push(2);
+
+        let mut order = Vec::new();
+        for _ in 0..7 {
+            let pid = queue.pop(|_| true).unwrap();
+            order.push(pid);
+            queue.push(pid); // Its slice is over
+        }
+        assert_eq!(order, [1, 2, 3, 1, 2, 3, 1]);
+    }
+
+    #[test]
+    fn run_queue_drops_stale_entries() {
+        let mut queue = RunQueue::new();
+        for pid in [1, 2, 3, 4] {
+            queue.push(pid);
+        }
+
+        // 2 blocked and 3 exited while queued
+        assert_eq!(queue.pop(|pid| pid != 2 && pid != 3), Some(1));
+        assert_eq!(queue.pop(|pid| pid != 2 && pid != 3), Some(4));
+        assert_eq!(queue.pop(|_| true), None);
     }
 }
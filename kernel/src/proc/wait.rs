@@ -0,0 +1,121 @@
+use crate::{
+    arch,
+    proc::{PROCS, ProcEntry, ProcTables, block_proc, current_pid},
+    ram::mutex::KMutex
+};
+
+use alloc::collections::vec_deque::VecDeque;
+
+// Processes blocked until some event, such as a device interrupt.
+// Waking is safe from IRQ handlers: PROCS is only ever taken with interrupts off.
+pub struct WaitQueue {
+    pids: KMutex<VecDeque<usize>>
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        return Self { pids: KMutex::new(VecDeque::new()) };
+    }
+
+    // Blocks the current process, which resumes from its saved context once woken.
+    // Queued under PROCS together with the block, so a wakeup can't slip in between.
+    // The process only turns Blocked once this CPU has switched off its stack.
+    pub fn sleep_on(&self) -> ! {
+        arch::exc::set(false);
+        if let Some(pid) = current_pid() {
+            self.enqueue(&mut *PROCS.write(), pid);
+        }
+        block_proc();
+    }
+
+    fn enqueue<P: ProcEntry>(&self, procs: &mut ProcTables<P>, pid: usize) {
+        if procs.procs.contains_key(&pid) {
+            procs.block(pid);
+            self.pids.lock().push_back(pid);
+        }
+    }
+
+    // Pids that exited or were woken some other way are skipped.
+    // A sleeper still switching away is woken too, see ProcTables::wake.
+    pub fn wake_one(&self) -> Option<usize> {
+        return self.wake_from(&mut *PROCS.write());
+    }
+
+    fn wake_from<P: ProcEntry>(&self, procs: &mut ProcTables<P>) -> Option<usize> {
+        while let Some(pid) = self.pids.lock().pop_front() {
+            if procs.wake(pid) {
+                return Some(pid);
+            }
+        }
+        return None;
+    }
+
+    pub fn wake_all(&self) -> usize {
+        let mut woken = 0;
+        while self.wake_one().is_some() {
+            woken += 1;
+        }
+        return woken;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.pids.lock().is_empty();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proc::{ProcState, tests::table};
+
+    #[test]
+    fn sleepers_wake_in_order() {
+        let mut procs = table(&[0, 1, 1]);
+        let queue = WaitQueue::new();
+        for pid in [2, 3] {
+            procs.procs.get_mut(&pid).unwrap().set_state(ProcState::Running(arch::phys_id()));
+            queue.enqueue(&mut procs, pid);
+            procs.finish_switch(); // The sleeper is off its CPU
+            assert_eq!(procs.procs[&pid].state(), ProcState::Blocked);
+        }
+        queue.enqueue(&mut procs, 9); // No such process
+        assert!(!queue.is_empty());
+
+        assert_eq!(queue.wake_from(&mut procs), Some(2));
+        assert_eq!(procs.procs[&2].state(), ProcState::Ready);
+        assert_eq!(procs.procs[&3].state(), ProcState::Blocked);
+        assert_eq!(queue.wake_from(&mut procs), Some(3));
+        assert_eq!(queue.wake_from(&mut procs), None);
+        assert!(queue.is_empty());
+        assert_eq!((procs.next_ready(), procs.next_ready()), (Some(2), Some(3)));
+    }
+
+    #[test]
+    fn wakeup_before_the_switch_is_kept() {
+        let mut procs = table(&[0, 1]);
+        let queue = WaitQueue::new();
+        procs.procs.get_mut(&2).unwrap().set_state(ProcState::Running(arch::phys_id()));
+        queue.enqueue(&mut procs, 2);
+
+        // Woken by an IRQ while still on its own stack
+        assert_eq!(queue.wake_from(&mut procs), Some(2));
+        procs.finish_switch();
+        assert_eq!(procs.procs[&2].state(), ProcState::Ready);
+        assert_eq!(procs.next_ready(), Some(2));
+    }
+
+    #[test]
+    fn exited_sleepers_are_skipped() {
+        let mut procs = table(&[0, 1, 1]);
+        let queue = WaitQueue::new();
+        for pid in [2, 3] {
+            queue.enqueue(&mut procs, pid);
+            procs.finish_switch();
+        }
+        procs.procs.remove(&2);
+
+        assert_eq!(queue.wake_from(&mut procs), Some(3));
+        assert_eq!(queue.wake_from(&mut procs), None);
+        assert_eq!(procs.procs[&3].state(), ProcState::Ready);
+    }
+}
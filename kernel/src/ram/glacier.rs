@@ -1,10 +1,10 @@
 use crate::{
     arch::rvm::flags,
     kargs::{NON_RAM, RAMType, efi_ram_layout},
-    ram::{mutex::IntRwLock, physalloc::{AllocParams, PHYS_ALLOC}}
+    ram::{mutex::KRwLock, physalloc::{AllocParams, OwnedPtr, PHYS_ALLOC}, tlb::tlb_shootdown}
 };
 
-use spin::{Once, RwLock};
+use spin::Once;
 
 #[repr(u8)]
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -71,6 +71,7 @@ impl RvmCfg {
 
 pub struct Glacier /* ˈɡlɑːˌsɪˑəh */ {
     root_table: usize,
+    table_ty: RAMType, // Type of the table frames this Glacier allocates itself
     is_init: bool
 }
 
@@ -86,31 +87,50 @@ impl Glacier {
     const fn empty() -> Self {
         return Self {
             root_table: 0,
+            table_ty: RAMType::KernelPTable,
             is_init: false
         };
     }
 
-    unsafe fn init(&mut self) {
+    unsafe fn init(&mut self) -> Result<(), GlacierErr> {
         // SAFETY: As this function is private, the is_init flag may be omitted.
         // if self.is_init { return; }
 
+        let root_table = self.alloc_table()?;
+        self.root_table = root_table.addr();
+        self.is_init = true;
+        return Ok(());
+    }
+
+    // Zeroed, for any level
+    #[cfg(not(test))]
+    fn alloc_table(&self) -> Result<OwnedPtr, GlacierErr> {
         let table_size = self.cfg().psz.size();
-        let root_table = PHYS_ALLOC.alloc(
+        let table = PHYS_ALLOC.alloc(
             AllocParams::new(table_size)
                 .align(table_size)
-                .as_type(RAMType::KernelPTable)
-        ).expect("Failed to allocate root page table");
+                .as_type(self.table_ty)
+        ).ok_or(GlacierErr::Failed2Alloc)?;
 
-        unsafe { root_table.ptr::<u8>().write_bytes(0, table_size); }
-        self.root_table = root_table.addr();
-        self.is_init = true;
+        unsafe { table.ptr::<u8>().write_bytes(0, table_size); }
+        return Ok(table);
+    }
+
+    // Host tests have no PHYS_ALLOC, tables come from the global allocator while the budget lasts
+    #[cfg(test)]
+    fn alloc_table(&self) -> Result<OwnedPtr, GlacierErr> {
+        let left = tests::TABLE_BUDGET.get().checked_sub(1).ok_or(GlacierErr::Failed2Alloc)?;
+        tests::TABLE_BUDGET.set(left);
+        let table = tests::host_table();
+        return Ok(unsafe { OwnedPtr::from_raw(table as *mut u8, self.cfg().psz.size()) });
     }
 
-    pub fn new() -> Self {
+    pub fn new() -> Result<Self, GlacierErr> {
         let mut new = Self::empty();
+        new.table_ty = RAMType::UserPTable;
 
         unsafe {
-            new.init();
+            new.init()?;
 
             let page_size = new.cfg().psz.size();
             let krvm_root = GLACIER.read().root_table;
@@ -124,7 +144,7 @@ impl Glacier {
                 .write_bytes(0, hihalf_idx * size_of::<usize>());
         }
 
-        return new;
+        return Ok(new);
     }
 
     pub fn map_page(&mut self, va: usize, pa: usize, flags: usize) -> Result<(), GlacierErr> {
@@ -143,25 +163,16 @@ impl Glacier {
             let entry = unsafe { (table as *mut usize).add(index) };
 
             if level == levels - 1 {
-                unsafe { *entry = pa | flags; }
+                unsafe { *entry = self.to_pte(pa) | flags; }
                 break;
             }
 
             if unsafe { *entry & flags::VALID == 0 } {
-                let table_size = self.cfg().psz.size();
-                let next_table = PHYS_ALLOC.alloc(
-                    AllocParams::new(table_size)
-                        .align(table_size)
-                        .as_type(RAMType::KernelPTable)
-                ).ok_or(GlacierErr::Failed2Alloc)?;
-
-                unsafe {
-                    next_table.ptr::<u8>().write_bytes(0, table_size);
-                    *entry = next_table.addr() | flags::NEXT;
-                }
+                let next_table = self.alloc_table()?;
+                unsafe { *entry = self.to_pte(next_table.addr()) | flags::NEXT; }
                 table = next_table.ptr::<()>() as usize;
             } else {
-                table = unsafe { *entry & self.cfg().psz.addr_mask() };
+                table = unsafe { self.pte_pa(*entry) };
             }
         }
 
@@ -175,6 +186,7 @@ impl Glacier {
 
         let va = va & !(self.cfg().psz.size() - 1);
         let _ = self.unmap_rec(self.root_table, va, 0);
+        tlb_shootdown(va, self.cfg().psz.size());
     }
 
     fn unmap_rec(&self, table: usize, va: usize, level: u8) -> bool {
@@ -196,7 +208,7 @@ impl Glacier {
             return false;
         }
 
-        let child = unsafe { *entry & self.cfg().psz.addr_mask() };
+        let child = unsafe { self.pte_pa(*entry) };
 
         if self.unmap_rec(child, va, level + 1) {
             unsafe {
@@ -239,11 +251,16 @@ impl Glacier {
         let va_end = (va + size + page_size - 1) & page_mask;
 
         for va in (va_start..va_end).step_by(page_size) {
-            self.unmap_page(va);
+            let _ = self.unmap_rec(self.root_table, va, 0);
         }
+        tlb_shootdown(va_start, va_end - va_start);
     }
 
-    pub fn get_pa(&self, va: usize) -> Option<usize> {
+    pub fn map_cow(&mut self, va: usize, pa: usize, size: usize, flags: usize) -> Result<(), GlacierErr> {
+        return self.map_range(va, pa, size, flags::cow(flags));
+    }
+
+    fn get_pte(&self, va: usize) -> Option<usize> {
         // SAFETY: As the `empty` and `init` functions are private, the is_init flag may be omitted.
         // if !self.is_init { return None; }
 
@@ -262,15 +279,37 @@ impl Glacier {
             }
 
             if level == levels - 1 {
-                return Some(entry & self.cfg().psz.addr_mask());
+                return Some(entry);
             } else {
-                table = entry & self.cfg().psz.addr_mask();
+                table = self.pte_pa(entry);
             }
         }
 
         return None;
     }
 
+    // Attribute bits live above the physical address as well
+    fn pte_addr_mask(&self) -> usize {
+        return (self.cfg().psz.addr_mask() & ((1usize << self.cfg().pa_bits) - 1)) >> flags::PPN_SHIFT;
+    }
+
+    // RISC-V keeps the page number at bit 10 instead of the address in place
+    fn to_pte(&self, pa: usize) -> usize {
+        return pa >> flags::PPN_SHIFT;
+    }
+
+    fn pte_pa(&self, pte: usize) -> usize {
+        return (pte & self.pte_addr_mask()) << flags::PPN_SHIFT;
+    }
+
+    pub fn get_pa(&self, va: usize) -> Option<usize> {
+        return self.get_pte(va).map(|pte| self.pte_pa(pte));
+    }
+
+    pub fn get_flags(&self, va: usize) -> Option<usize> {
+        return self.get_pte(va).map(|pte| pte & !self.pte_addr_mask());
+    }
+
     pub fn root_table(&self) -> *mut usize {
         return self.root_table as *mut usize;
     }
@@ -282,35 +321,53 @@ impl Glacier {
 
 impl Drop for Glacier {
     fn drop(&mut self) {
-        if !self.is_init { return; }
-        self._drop(self.root_table, 0);
+        self.free_tables();
     }
 }
 
 impl Glacier {
-    fn _drop(&self, table: usize, level: u8) {
+    // Frees the lower half tables, the frames they map belong to the caller
+    pub fn destroy(mut self) {
+        self.free_tables();
+    }
+
+    // Leaves an uninitialised Glacier behind, only for teardown
+    pub fn take(&mut self) -> Self {
+        return core::mem::replace(self, Self::empty());
+    }
+
+    fn free_tables(&mut self) {
+        if !self.is_init { return; }
+        if self.is_active() {
+            GLACIER.read().activate();
+        }
+        let table_size = self.cfg().psz.size();
+        self.for_tables(self.root_table, 0, &mut |table| unsafe {
+            PHYS_ALLOC.free_raw(table as *mut u8, table_size);
+        });
+        self.is_init = false;
+    }
+
+    // Calls `f` on `table` and every table below it, children first.
+    // The higher half entries of the root point to the shared kernel tables.
+    fn for_tables(&self, table: usize, level: u8, f: &mut dyn FnMut(usize)) {
         let mut entries = self.cfg().ent_cnt(level);
         if level == 0 { entries >>= 1; }
 
-        for i in 0..entries {
-            let entry = unsafe { *((table as *const usize).add(i)) };
-
-            if entry & flags::VALID != 0 {
-                if level < self.cfg().levels() - 1 {
-                    let child = entry & self.cfg().psz.addr_mask();
-                    self._drop(child, level + 1);
+        if level < self.cfg().levels() - 1 {
+            for i in 0..entries {
+                let entry = unsafe { *((table as *const usize).add(i)) };
+                if entry & flags::VALID != 0 {
+                    self.for_tables(self.pte_pa(entry), level + 1, f);
                 }
             }
         }
-
-        unsafe {
-            PHYS_ALLOC.free_raw(table as *mut u8, self.cfg().psz.size());
-        }
+        f(table);
     }
 }
 
 pub static G_CFG: Once<RvmCfg> = Once::new();
-pub static GLACIER: IntRwLock<RwLock<()>, Glacier> = IntRwLock::new(Glacier::empty());
+pub static GLACIER: KRwLock<Glacier> = KRwLock::new(Glacier::empty());
 
 #[inline(always)]
 pub fn hihalf() -> usize {
@@ -324,10 +381,10 @@ pub fn page_size() -> usize {
 
 pub fn init() {
     let mut glacier = GLACIER.write();
-    unsafe { glacier.init(); }
+    unsafe { glacier.init().expect("Failed to allocate root page table"); }
 
     for desc in efi_ram_layout() {
-        let block_ty = desc.ty;
+        let block_ty = desc.ty();
         let addr = desc.phys_start as usize;
         let size = desc.page_count as usize * 0x1000;
 
@@ -350,7 +407,7 @@ pub fn remap() {
     let mut glacier = GLACIER.write();
 
     for desc in efi_ram_layout() {
-        let block_ty = desc.ty;
+        let block_ty = desc.ty();
         let addr = desc.phys_start as usize;
         let size = desc.page_count as usize * 0x1000;
         if NON_RAM.contains(&block_ty) {
@@ -360,3 +417,94 @@ pub fn remap() {
         glacier.map_range(addr, addr, size, flags::K_RWO).unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{alloc::{Layout, alloc_zeroed}, vec::Vec};
+    use core::cell::Cell;
+
+    const PSZ: usize = 0x1000;
+
+    std::thread_local! {
+        pub static TABLE_BUDGET: Cell<usize> = const { Cell::new(0) };
+    }
+
+    pub fn host_table() -> usize {
+        return unsafe { alloc_zeroed(Layout::from_size_align(PSZ, PSZ).unwrap()) } as usize;
+    }
+
+    // map_page over host memory, without the TLB flush
+    fn map(glacier: &Glacier, va: usize) {
+        let levels = glacier.cfg().levels();
+        let mut table = glacier.root_table;
+        for level in 0..levels {
+            let entry = unsafe { &mut *(table as *mut usize).add(glacier.cfg().get_index(level, va)) };
+            if level == levels - 1 {
+                *entry = glacier.to_pte(0x100_0000) | flags::U_RWO;
+            } else if *entry & flags::VALID == 0 {
+                let next = host_table();
+                *entry = glacier.to_pte(next) | flags::NEXT;
+                table = next;
+            } else {
+                table = glacier.pte_pa(*entry);
+            }
+        }
+    }
+
+    #[test]
+    fn teardown_visits_every_user_table() {
+        G_CFG.call_once(|| RvmCfg { psz: BPage::Size4kiB, va_bits: 48, pa_bits: 52 });
+        // Not initialised, so dropping it frees nothing
+        let glacier = Glacier { root_table: host_table(), table_ty: RAMType::UserPTable, is_init: false };
+
+        // A kernel table in the higher half must be left alone
+        let kernel = host_table();
+        let hihalf_idx = glacier.cfg().ent_cnt(0) >> 1;
+        unsafe { *(glacier.root_table as *mut usize).add(hihalf_idx) = glacier.to_pte(kernel) | flags::NEXT; }
+
+        // Two pages sharing every table, and one at the top of the lower half
+        for va in [0x40_0000, 0x40_1000, 0x7fff_ffff_f000] {
+            map(&glacier, va);
+        }
+
+        let mut tables = Vec::new();
+        glacier.for_tables(glacier.root_table, 0, &mut |table| tables.push(table));
+        assert_eq!(tables.len(), 7); // The root and three levels under each of its two entries
+        assert_eq!(tables.last(), Some(&glacier.root_table));
+        assert!(!tables.contains(&kernel));
+    }
+
+    #[test]
+    fn sv39_indices() {
+        let sv39 = RvmCfg { psz: BPage::Size4kiB, va_bits: 39, pa_bits: 56 };
+        assert_eq!(sv39.levels(), 3);
+        assert_eq!((0..3).map(|level| sv39.ent_cnt(level)).collect::<Vec<_>>(), [512, 512, 512]);
+
+        // VPN[2], VPN[1] and VPN[0] in bits 38:30, 29:21 and 20:12
+        let va = 5 << 30 | 0x1a3 << 21 | 0x7f << 12 | 0x123;
+        let idx = (0..3).map(|level| sv39.get_index(level, va)).collect::<Vec<_>>();
+        assert_eq!(idx, [5, 0x1a3, 0x7f]);
+
+        // Sign extended bits above 38 are not part of any index
+        let va = 0xffff_ffc0_0000_0000 | 0x1ff << 21;
+        let idx = (0..3).map(|level| sv39.get_index(level, va)).collect::<Vec<_>>();
+        assert_eq!(idx, [256, 0x1ff, 0]);
+    }
+
+    #[test]
+    fn out_of_tables_is_an_error() {
+        G_CFG.call_once(|| RvmCfg { psz: BPage::Size4kiB, va_bits: 48, pa_bits: 52 });
+        let mut glacier = Glacier::empty();
+        glacier.table_ty = RAMType::UserPTable;
+        assert_eq!(unsafe { glacier.init() }, Err(GlacierErr::Failed2Alloc));
+
+        // The root and two of the three tables under it
+        TABLE_BUDGET.set(3);
+        unsafe { glacier.init().unwrap(); }
+        assert_eq!(glacier.map_page(0x40_0000, 0x100_0000, flags::U_RWO), Err(GlacierErr::Failed2Alloc));
+        assert_eq!(TABLE_BUDGET.get(), 0);
+        assert_eq!(glacier.get_pa(0x40_0000), None);
+        glacier.is_init = false; // Host tables are not PHYS_ALLOC's to free
+    }
+}
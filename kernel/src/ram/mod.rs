@@ -2,21 +2,27 @@ pub mod glacier;
 pub mod mutex;
 pub mod physalloc;
 pub mod reloc;
+pub mod tlb;
 
 use crate::{
     arch::rvm::flags,
     kargs::{AP_LIST, KINFO, RAMType},
-    printk, printlnk,
+    arch::stack_ptr,
+    printk, proc, warn,
     ram::{
         glacier::{GLACIER, hihalf, page_size},
+        mutex::IpiSpin,
         physalloc::{AllocParams, OwnedPtr, PHYS_ALLOC}
     }
 };
 
 use core::{
     alloc::Layout,
-    ops::{Deref, DerefMut}
+    fmt::Write,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, Ordering as AtomOrd}
 };
+use alloc::string::String;
 use spin::Mutex;
 use talc::{OomHandler, Span, Talc, Talck};
 
@@ -82,35 +88,71 @@ pub fn gleam_base() -> usize {
 }
 
 // For DMA or other physical page-aligned buffers
-pub struct PhysPageBuf(OwnedPtr);
+// The slice covers the requested size, the allocation is rounded up past it
+pub struct PhysPageBuf {
+    ptr: OwnedPtr,
+    len: usize
+}
 
 impl PhysPageBuf {
+    #[cfg(not(test))]
     pub fn new(size: usize) -> Option<Self> {
         let ptr = PHYS_ALLOC.alloc(
             AllocParams::new(size)
                 .align(page_size())
                 .as_type(RAMType::KernelData)
         )?;
-        return Some(Self(ptr));
+        return Some(Self { ptr, len: size });
+    }
+
+    // Host tests have no PHYS_ALLOC, the global allocator stands in
+    #[cfg(test)]
+    pub fn new(size: usize) -> Option<Self> {
+        let layout = Layout::from_size_align(align_up(size.max(1), PAGE_4KIB), PAGE_4KIB).ok()?;
+        let ptr = unsafe { alloc::alloc::alloc(layout) };
+        if ptr.is_null() { return None; }
+        return Some(Self { ptr: unsafe { OwnedPtr::from_raw(ptr, layout.size()) }, len: size });
+    }
+
+    // The whole allocation, slack included, so nothing stale reaches a device
+    pub fn new_zeroed(size: usize) -> Option<Self> {
+        let buf = Self::new(size)?;
+        unsafe { buf.ptr::<u8>().write_bytes(0, buf.capacity()); }
+        return Some(buf);
+    }
+
+    pub fn ptr<T>(&self) -> *mut T {
+        return self.ptr.ptr();
+    }
+
+    pub fn capacity(&self) -> usize {
+        return self.ptr.size();
     }
 }
 
 impl Drop for PhysPageBuf {
+    #[cfg(not(test))]
     fn drop(&mut self) {
-        PHYS_ALLOC.free(unsafe { self.0.clone() });
+        PHYS_ALLOC.free(unsafe { self.ptr.clone() });
+    }
+
+    #[cfg(test)]
+    fn drop(&mut self) {
+        let layout = Layout::from_size_align(self.capacity(), PAGE_4KIB).unwrap();
+        unsafe { alloc::alloc::dealloc(self.ptr(), layout); }
     }
 }
 
 impl Deref for PhysPageBuf {
     type Target = [u8];
     fn deref(&self) -> &Self::Target {
-        return self.0.into_slice();
+        return &self.ptr.into_slice()[..self.len];
     }
 }
 
 impl DerefMut for PhysPageBuf {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        return self.0.into_slice_mut();
+        return &mut self.ptr.into_slice_mut()[..self.len];
     }
 }
 
@@ -242,8 +284,8 @@ impl OomHandler for KheapHandler {
     }
 }
 
-#[global_allocator]
-pub static KHEAP: Talck<Mutex<()>, KheapHandler> = Talc::new(KheapHandler::new()).lock();
+#[cfg_attr(not(test), global_allocator)]
+pub static KHEAP: Talck<IpiSpin<Mutex<()>>, KheapHandler> = Talc::new(KheapHandler::new()).lock();
 
 pub fn align_down(val: usize, align: usize) -> usize {
     if align == 0 { return val; }
@@ -262,27 +304,34 @@ pub fn size_align(val: usize) -> usize {
     return align_up(val, page_size());
 }
 
-pub fn dump_bytes(buf: &[u8]) {
+// 16 bytes a line with an ASCII gutter, offsets counted from base
+pub fn hexdump(buf: &[u8], base: usize) -> String {
     const LINE: usize = 16;
-    let mut offset = 0;
+    let mut out = String::new();
+    let mut offset = base;
     for line in buf.chunks(LINE) {
-        printk!("{:08x}  ", offset);
+        let _ = write!(out, "{:08x}  ", offset);
         for (i, byte) in line.iter().enumerate() {
-            if i == LINE / 2 { printk!(" "); }
-            printk!("{:02x} ", byte);
+            if i == LINE / 2 { out.push(' '); }
+            let _ = write!(out, "{:02x} ", byte);
         }
         for i in line.len()..LINE {
-            if i == LINE / 2 { printk!(" "); }
-            printk!("   ");
+            if i == LINE / 2 { out.push(' '); }
+            out.push_str("   ");
         }
-        printk!("   |");
-        for byte in line { printk!("{}",
+        out.push_str("   |");
+        out.extend(line.iter().map(|byte|
             if (0x20..0x7f).contains(byte) { *byte as char } else { '.' }
-        ); }
-        printlnk!("|");
+        ));
+        out.push_str("|\n");
         offset += line.len();
     }
-    printlnk!("{:08x}", offset);
+    let _ = writeln!(out, "{:08x}", offset);
+    return out;
+}
+
+pub fn dump_bytes(buf: &[u8]) {
+    printk!("{}", hexdump(buf, 0));
 }
 
 pub fn init_heap() {
@@ -296,3 +345,122 @@ pub fn init_heap() {
 pub fn stack_top() -> usize {
     return gleam_base() - (AP_LIST.virtid_self() * per_cpu_data());
 }
+
+pub const STACK_PAINT: u8 = 0xcc;
+const PAINT_RUN: usize = 16;
+const PAINT_MARGIN: usize = 0x200; // Left alone below sp for the fill itself
+
+// Fills the unused part of this CPU's stack, call early on each CPU
+pub fn paint_stack() {
+    let base = stack_top() - stack_size();
+    let end = (stack_ptr() as usize).saturating_sub(PAINT_MARGIN);
+    if end <= base { return; }
+    unsafe { core::ptr::write_bytes(base as *mut u8, STACK_PAINT, end - base); }
+}
+
+// Bytes from the base that still hold the paint, counted in whole runs
+// so stray pattern bytes in the deepest frame round usage up, not down
+fn untouched_len(stack: &[u8]) -> usize {
+    return stack.chunks(PAINT_RUN)
+        .take_while(|run| run.len() == PAINT_RUN && run.iter().all(|&b| b == STACK_PAINT))
+        .count() * PAINT_RUN;
+}
+
+// Paint left under a previous `mark`, looked at from the mark down so a call only
+// covers what was used since the last one. The first whole run of paint ends it,
+// so a frame that skips a run under the mark shows up only once the run is written.
+pub fn untouched_below(stack: &[u8], mark: usize) -> usize {
+    let mut len = mark.min(stack.len()) / PAINT_RUN * PAINT_RUN;
+    while len > 0 && !stack[len - PAINT_RUN..len].iter().all(|&b| b == STACK_PAINT) {
+        len -= PAINT_RUN;
+    }
+    return len;
+}
+
+// Peak usage of this CPU's stack since paint_stack
+pub fn stack_high_water() -> usize {
+    let base = stack_top() - stack_size();
+    let stack = unsafe { core::slice::from_raw_parts(base as *const u8, stack_size()) };
+    return stack_size() - untouched_len(stack);
+}
+
+static STACK_WARNED: AtomicBool = AtomicBool::new(false);
+
+// Called from the timer tick, warns once past 75%
+pub fn check_stack() {
+    let Some(used) = proc::kstack_high_water() else { return; };
+    if used * 4 > stack_size() * 3 && !STACK_WARNED.swap(true, AtomOrd::Relaxed) {
+        warn!("Kernel stack high water: {} / {} bytes", used, stack_size());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn canonical_memory_managers() {
+        // The kernel has one page table and one physical allocator, both under ram
+        let glacier: &mutex::KRwLock<glacier::Glacier> = &GLACIER;
+        let phys: &physalloc::PhysAllocGlob = &PHYS_ALLOC;
+        assert!(core::any::type_name_of_val(glacier).ends_with("::ram::glacier::Glacier>"));
+        assert!(core::any::type_name_of_val(phys).ends_with("::ram::physalloc::PhysAllocGlob"));
+    }
+
+    #[test]
+    fn scan_finds_deepest_use() {
+        let mut stack = vec![STACK_PAINT; 0x400];
+        assert_eq!(untouched_len(&stack), 0x400);
+        stack[0x300..].fill(0);
+        assert_eq!(untouched_len(&stack), 0x300);
+
+        // A short run of live bytes equal to the paint still counts as used
+        stack[0x2f8..0x300].fill(0);
+        stack[0x2f0] = 0;
+        assert_eq!(untouched_len(&stack), 0x2f0);
+    }
+
+    #[test]
+    fn scan_from_mark_only_goes_down() {
+        let mut stack = vec![STACK_PAINT; 0x400];
+        assert_eq!(untouched_below(&stack, 0x400), 0x400);
+        stack[0x380..].fill(0);
+        assert_eq!(untouched_below(&stack, 0x400), 0x380);
+
+        // Bytes above the mark are not looked at again
+        stack[0x380..].fill(STACK_PAINT);
+        assert_eq!(untouched_below(&stack, 0x380), 0x380);
+        stack[0x205..0x380].fill(0);
+        assert_eq!(untouched_below(&stack, 0x380), 0x200);
+        assert_eq!(untouched_below(&stack, 0x385), 0x200);
+
+        // A painted gap under the mark hides deeper use until the gap is written
+        stack[0x300..0x380].fill(STACK_PAINT);
+        assert_eq!(untouched_below(&stack, 0x380), 0x380);
+    }
+
+    #[test]
+    fn page_buf_is_the_requested_size() {
+        let mut buf = PhysPageBuf::new(100).unwrap();
+        assert_eq!((buf.len(), buf.capacity()), (100, 0x1000));
+        buf.fill(0xaa);
+        assert_eq!(buf.ptr::<u8>() as usize % 0x1000, 0);
+
+        let buf = PhysPageBuf::new_zeroed(0x1001).unwrap();
+        assert_eq!((buf.len(), buf.capacity()), (0x1001, 0x2000));
+        let whole = unsafe { core::slice::from_raw_parts(buf.ptr::<u8>(), buf.capacity()) };
+        assert!(whole.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn hexdump_layout() {
+        let dump = hexdump(b"Hello, world!\x00\x01\x02\xffABC", 0x1000);
+        assert_eq!(dump, concat!(
+            "00001000  48 65 6c 6c 6f 2c 20 77  6f 72 6c 64 21 00 01 02    |Hello, world!...|\n",
+            "00001010  ff 41 42 43                                         |.ABC|\n",
+            "00001014\n"
+        ));
+        assert_eq!(hexdump(&[], 0x20), "00000020\n");
+    }
+}
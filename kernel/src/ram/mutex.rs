@@ -1,7 +1,79 @@
-use crate::arch::exc;
+use crate::{arch, ram::tlb};
+
+use core::{hint::spin_loop, mem::ManuallyDrop, ops::{Deref, DerefMut}};
+use lock_api::{GuardSend, RawMutex, RawRwLock};
+
+// Default kernel locks. Interrupts stay off while the guard lives and come
+// back only when the outermost guard on this CPU drops, in whatever order.
+// Anything an IRQ handler or the page fault path can take must use these:
+// GLACIER (faults, shootdowns), PHYS_ALLOC (faults), PCI_DEVICES and
+// BLOCK_DEVICES (driver interrupts).
+pub type KMutex<T> = IntLock<IpiSpin<spin::Mutex<()>>, T>;
+pub type KRwLock<T> = IntRwLock<IpiSpin<spin::RwLock<()>>, T>;
+// For locks the caller already holds with interrupts off (PROCS)
+pub type SpinRwLock<T> = lock_api::RwLock<IpiSpin<spin::RwLock<()>>, T>;
+
+// Spins like R but answers TLB shootdowns while waiting. A core spinning with
+// interrupts off never takes the IPI, and the core it waits on may be stuck in
+// tlb_shootdown waiting for it. handle_ipi takes no lock, so any lock may use this.
+pub struct IpiSpin<R>(R);
+
+unsafe impl<R: RawMutex> RawMutex for IpiSpin<R> {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: Self = Self(R::INIT);
+    type GuardMarker = GuardSend;
+
+    fn lock(&self) {
+        while !self.0.try_lock() {
+            tlb::handle_ipi();
+            spin_loop();
+        }
+    }
+
+    fn try_lock(&self) -> bool {
+        return self.0.try_lock();
+    }
+
+    unsafe fn unlock(&self) {
+        unsafe { self.0.unlock(); }
+    }
+}
+
+unsafe impl<R: RawRwLock> RawRwLock for IpiSpin<R> {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: Self = Self(R::INIT);
+    type GuardMarker = GuardSend;
+
+    fn lock_shared(&self) {
+        while !self.0.try_lock_shared() {
+            tlb::handle_ipi();
+            spin_loop();
+        }
+    }
+
+    fn try_lock_shared(&self) -> bool {
+        return self.0.try_lock_shared();
+    }
+
+    unsafe fn unlock_shared(&self) {
+        unsafe { self.0.unlock_shared(); }
+    }
+
+    fn lock_exclusive(&self) {
+        while !self.0.try_lock_exclusive() {
+            tlb::handle_ipi();
+            spin_loop();
+        }
+    }
 
-use core::{mem::ManuallyDrop, ops::{Deref, DerefMut}};
-use lock_api::{RawMutex, RawRwLock};
+    fn try_lock_exclusive(&self) -> bool {
+        return self.0.try_lock_exclusive();
+    }
+
+    unsafe fn unlock_exclusive(&self) {
+        unsafe { self.0.unlock_exclusive(); }
+    }
+}
 
 pub struct IntLock<R: RawMutex, T> {
     mutex: lock_api::Mutex<R, T>
@@ -13,22 +85,16 @@ impl<R: RawMutex, T> IntLock<R, T> {
     }
 
     pub fn lock(&self) -> IntLockGuard<'_, R, T> {
-        let exc_flag = exc::get();
-        exc::set(false);
-        return IntLockGuard {
-            guard: ManuallyDrop::new(self.mutex.lock()), exc_flag
-        };
+        arch::push_cli();
+        return IntLockGuard { guard: ManuallyDrop::new(self.mutex.lock()) };
     }
 
     // pub fn try_lock(&self) -> Option<IntLockGuard<'_, R, T>> {
-    //     let exc_flag = exc_flag::get();
-    //     exc_flag::set(false);
+    //     arch::push_cli();
     //     return match self.mutex.try_lock() {
-    //         Some(guard) => Some(IntLockGuard {
-    //             guard: ManuallyDrop::new(guard), exc_flag
-    //         }),
+    //         Some(guard) => Some(IntLockGuard { guard: ManuallyDrop::new(guard) }),
     //         None => {
-    //             exc_flag::set(exc_flag);
+    //             arch::pop_sti();
     //             None
     //         }
     //     }
@@ -36,8 +102,7 @@ impl<R: RawMutex, T> IntLock<R, T> {
 }
 
 pub struct IntLockGuard<'a, R: RawMutex, T> {
-    guard: ManuallyDrop<lock_api::MutexGuard<'a, R, T>>,
-    exc_flag: bool
+    guard: ManuallyDrop<lock_api::MutexGuard<'a, R, T>>
 }
 
 impl<R: RawMutex, T> Deref for IntLockGuard<'_, R, T> {
@@ -52,7 +117,7 @@ impl<R: RawMutex, T> DerefMut for IntLockGuard<'_, R, T> {
 impl<R: RawMutex, T> Drop for IntLockGuard<'_, R, T> {
     fn drop(&mut self) {
         unsafe { ManuallyDrop::drop(&mut self.guard); }
-        exc::set(self.exc_flag);
+        arch::pop_sti();
     }
 }
 
@@ -66,25 +131,18 @@ impl<R: RawRwLock, T> IntRwLock<R, T> {
     }
 
     pub fn read(&self) -> IntRwReadGuard<'_, R, T> {
-        let exc_flag = exc::get();
-        exc::set(false);
-        return IntRwReadGuard {
-            guard: ManuallyDrop::new(self.mutex.read()), exc_flag
-        };
+        arch::push_cli();
+        return IntRwReadGuard { guard: ManuallyDrop::new(self.mutex.read()) };
     }
 
     pub fn write(&self) -> IntRwWriteGuard<'_, R, T> {
-        let exc_flag = exc::get();
-        exc::set(false);
-        return IntRwWriteGuard {
-            guard: ManuallyDrop::new(self.mutex.write()), exc_flag
-        };
+        arch::push_cli();
+        return IntRwWriteGuard { guard: ManuallyDrop::new(self.mutex.write()) };
     }
 }
 
 pub struct IntRwReadGuard<'a, R: RawRwLock, T> {
-    guard: ManuallyDrop<lock_api::RwLockReadGuard<'a, R, T>>,
-    exc_flag: bool
+    guard: ManuallyDrop<lock_api::RwLockReadGuard<'a, R, T>>
 }
 
 impl<R: RawRwLock, T> Deref for IntRwReadGuard<'_, R, T> {
@@ -95,13 +153,12 @@ impl<R: RawRwLock, T> Deref for IntRwReadGuard<'_, R, T> {
 impl<R: RawRwLock, T> Drop for IntRwReadGuard<'_, R, T> {
     fn drop(&mut self) {
         unsafe { ManuallyDrop::drop(&mut self.guard); }
-        exc::set(self.exc_flag);
+        arch::pop_sti();
     }
 }
 
 pub struct IntRwWriteGuard<'a, R: RawRwLock, T> {
-    guard: ManuallyDrop<lock_api::RwLockWriteGuard<'a, R, T>>,
-    exc_flag: bool
+    guard: ManuallyDrop<lock_api::RwLockWriteGuard<'a, R, T>>
 }
 
 impl<R: RawRwLock, T> Deref for IntRwWriteGuard<'_, R, T> {
@@ -116,6 +173,47 @@ impl<R: RawRwLock, T> DerefMut for IntRwWriteGuard<'_, R, T> {
 impl<R: RawRwLock, T> Drop for IntRwWriteGuard<'_, R, T> {
     fn drop(&mut self) {
         unsafe { ManuallyDrop::drop(&mut self.guard); }
-        exc::set(self.exc_flag);
+        arch::pop_sti();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::host;
+
+    #[test]
+    fn guard_restores_the_interrupt_flag() {
+        let lock = KMutex::new(0);
+        host::set(true);
+        {
+            let mut guard = lock.lock();
+            *guard += 1;
+            assert!(!host::get());
+        }
+        assert!(host::get());
+
+        // Off before stays off after
+        host::set(false);
+        drop(lock.lock());
+        assert!(!host::get());
+    }
+
+    #[test]
+    fn nested_guards_restore_on_the_outermost() {
+        let (mutex, rwlock) = (KMutex::new(()), KRwLock::new(()));
+        host::set(true);
+
+        let outer = mutex.lock();
+        let reader = rwlock.read();
+        drop(reader);
+        assert!(!host::get());
+        let writer = rwlock.write();
+
+        // Dropped out of order, the last guard standing turns them back on
+        drop(outer);
+        assert!(!host::get());
+        drop(writer);
+        assert!(host::get());
     }
 }
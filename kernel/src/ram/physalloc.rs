@@ -5,13 +5,13 @@ use crate::{
         efi_ram_layout, efi_ram_layout_mut, elf_segments
     },
     ram::{
-        PAGE_4KIB, align_up, glacier::page_size, mutex::IntLock, size_align
+        PAGE_4KIB, align_up, glacier::page_size, mutex::KMutex, size_align
     },
     sort::HeaplessSort
 };
 
 // use core::cmp::Ordering;
-use spin::Mutex;
+use alloc::vec::Vec;
 
 #[repr(C)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -19,15 +19,21 @@ pub struct RAMBlock {
     addr: usize,
     size: usize,
     ty: RAMType,
-    used: bool
+    used: bool,
+    // Neighbours in the same free bucket by address, which stays put while the array shifts
+    next_free: usize,
+    prev_free: usize
 }
 
 impl RAMBlock {
     pub fn new(addr: usize, size: usize, ty: RAMType, used: bool) -> Self {
-        return Self { addr, size, ty, used };
+        return Self { addr, size, ty, used, next_free: NO_BLOCK, prev_free: NO_BLOCK };
     }
     pub const fn new_invalid() -> Self {
-        return Self { addr: 0, size: 0, ty: RAMType::Reserved, used: false };
+        return Self {
+            addr: 0, size: 0, ty: RAMType::Reserved, used: false,
+            next_free: NO_BLOCK, prev_free: NO_BLOCK
+        };
     }
 
     pub fn addr(&self) -> usize    { self.addr }
@@ -52,6 +58,8 @@ impl RAMBlock {
         let (mut b0, mut b1) = (*self, *other);
         b0.set_addr(0); b1.set_addr(0);
         b0.set_size(0); b1.set_size(0);
+        (b0.next_free, b0.prev_free) = (NO_BLOCK, NO_BLOCK);
+        (b1.next_free, b1.prev_free) = (NO_BLOCK, NO_BLOCK);
         if b0 != b1 { return 0; }
         return if self.end() == other.addr() { -1 } // self is before other
         else   if other.end() == self.addr() {  1 } // self is after other
@@ -97,6 +105,7 @@ impl OwnedPtr {
     pub fn size(&self) -> usize { self.size }
     pub fn end(&self) -> usize { self.ptr + self.size }
     pub unsafe fn clone(&self) -> Self { Self::new_bytes(self.addr(), self.size()) }
+    pub unsafe fn from_raw(ptr: *mut u8, size: usize) -> Self { Self::new_bytes(ptr as usize, size) }
 
     pub fn merge(&mut self, other: Self) -> Result<(), Self> {
         if self.end() == other.addr() {
@@ -152,19 +161,48 @@ impl AllocParams {
     }
 }
 
+// Free Conv blocks bucketed by floor(log2(size)), doubly linked by block address.
+// Only an index over the block array: built in one pass on first use, then kept
+// current by insert, remove and the merges in add.
+#[derive(Debug)]
+struct FreeBuckets {
+    heads: [usize; usize::BITS as usize],
+    dirty: bool
+}
+
+impl FreeBuckets {
+    const fn new() -> Self {
+        return Self { heads: [NO_BLOCK; usize::BITS as usize], dirty: true };
+    }
+
+    fn bucket(size: usize) -> usize {
+        return size.max(1).ilog2() as usize;
+    }
+}
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct PhysAlloc {
     ptr: OwnedPtr,
     max: usize,
-    is_init: bool
+    is_init: bool,
+    free: FreeBuckets
 }
 
-pub struct PhysAllocGlob(pub IntLock<Mutex<()>, PhysAlloc>);
+pub struct PhysAllocGlob(pub KMutex<PhysAlloc>);
 
 const BASE_RB_SIZE: usize = 128;
+const NO_BLOCK: usize = usize::MAX;
 const MIN_REQ: usize = 4;
 
+// Free RAM below this is typed LowMemory and only handed out to callers asking for it.
+// x86 keeps real mode structures and the AP trampoline under 1 MiB, elsewhere only
+// page 0 is held back so that a null physical address never names an allocation.
+#[cfg(target_arch = "x86_64")]
+pub const LOW_MEM_END: usize = 0x100000;
+#[cfg(not(target_arch = "x86_64"))]
+pub const LOW_MEM_END: usize = PAGE_4KIB;
+
 static mut RB_EMBEDDED: [RAMBlock; BASE_RB_SIZE] = [RAMBlock::new_invalid(); BASE_RB_SIZE];
 pub static PHYS_ALLOC: PhysAllocGlob = PhysAllocGlob::empty();
 
@@ -172,7 +210,8 @@ impl PhysAlloc {
     const fn empty() -> Self {
         Self {
             ptr: OwnedPtr::null(),
-            is_init: false, max: 0
+            is_init: false, max: 0,
+            free: FreeBuckets::new()
         }
     }
 
@@ -188,16 +227,8 @@ impl PhysAlloc {
             let efi_ram = efi_ram_layout_mut();
             efi_ram.sort_noheap_by_key(|desc| desc.page_count);
             for desc in efi_ram.iter().rev() {
-                if desc.ty == RAMType::Conv {
-                    let size = desc.page_count as usize * PAGE_4KIB;
-                    let addr = desc.phys_start as usize;
-
-                    let ty = cfg!(target_arch = "x86_64").then(|| {
-                        if addr < 0x100000 { RAMType::Reserved } else { desc.ty }
-                    }).unwrap_or(desc.ty);
-
-                    let block = RAMBlock::new(addr, size, ty, false);
-                    self.add(block);
+                if desc.ty() == RAMType::Conv {
+                    self.claim_desc(desc);
                 }
             }
 
@@ -228,46 +259,116 @@ impl PhysAlloc {
         SYSINFO.write().layout_ptr = efi_ptr.addr();
         KINFO.write().seg_ptr = elf_ptr.addr();
 
+        // The initrd and the command line sit in LOADER_DATA, which is reclaimed later
+        let (rd_ptr, rd_len, cl_ptr, cl_len) = {
+            let sys = SYSINFO.read();
+            (sys.initrd_ptr, sys.initrd_len, sys.cmdline_ptr, sys.cmdline_len)
+        };
+        let rd_ptr = self.keep_loader_data(rd_ptr, rd_len);
+        let cl_ptr = self.keep_loader_data(cl_ptr, cl_len);
+        {
+            let mut sys = SYSINFO.write();
+            sys.initrd_ptr = rd_ptr;
+            sys.cmdline_ptr = cl_ptr;
+            if rd_ptr == 0 { sys.initrd_len = 0; }
+            if cl_ptr == 0 { sys.cmdline_len = 0; }
+        }
+
         {
             let efi_ram = efi_ram_layout_mut();
             efi_ram.sort_noheap_by_key(|desc| desc.phys_start);
             for desc in efi_ram.iter() {
-                if desc.ty != RAMType::Conv {
-                    let size = desc.page_count as usize * PAGE_4KIB;
-                    let addr = desc.phys_start as usize;
-                    let mut ty = desc.ty;
+                if desc.ty() != RAMType::Conv {
+                    self.claim_desc(desc);
+                }
+            }
+        }
 
-                    #[cfg(target_arch = "x86_64")]
-                    if addr < 0x100000 { ty = RAMType::Reserved; }
+        // The original layout array, the boot stack and the loader image all sit in
+        // Reclaimable blocks, which nothing allocates from until reclaim() after the
+        // jump to the relocated kernel. The copies made above are used blocks.
 
-                    if RECLAMABLE.contains(&desc.ty) {
-                        ty = RAMType::Reclaimable;
-                    }
+        self.is_init = true;
+    }
+
+    // Firmware ranges split at LOW_MEM_END. Below it free RAM turns into LowMemory and
+    // anything the firmware owns into Reserved, loader memory stays reclaimable.
+    fn claim_desc(&mut self, desc: &RAMDescriptor) {
+        let addr = desc.phys_start as usize;
+        let end = addr + desc.page_count as usize * PAGE_4KIB;
+        let split = LOW_MEM_END.clamp(addr, end);
+
+        let (low, high) = match desc.ty() {
+            RAMType::Conv => (RAMType::LowMemory, RAMType::Conv),
+            ty if RECLAMABLE.contains(&ty) => (RAMType::Reclaimable, RAMType::Reclaimable),
+            ty => (RAMType::Reserved, ty)
+        };
+        self.claim(addr, split - addr, low);
+        self.claim(split, end - split, high);
+    }
 
-                    let block = RAMBlock::new(addr, size, ty, false);
-                    self.add(block);
+    // Adds the parts of the range no block covers yet. Where it overlaps a free block
+    // and `ty` isn't free RAM, the firmware's claim wins and the overlap is retyped, so
+    // the loader's own image stays out of reach too. Used blocks are the kernel's own
+    // and are left alone.
+    fn claim(&mut self, addr: usize, size: usize, ty: RAMType) {
+        let is_free = |ty: RAMType| matches!(ty, RAMType::Conv | RAMType::LowMemory);
+        let end = addr + size;
+        let mut cur = addr;
+        while cur < end {
+            let blocks = self.blocks();
+            let idx = blocks.partition_point(|block| block.end() <= cur);
+            match blocks.get(idx).copied() {
+                Some(block) if block.addr() <= cur => {
+                    let stop = block.end().min(end);
+                    if block.not_used() && is_free(block.ty()) && !is_free(ty) {
+                        self.carve(cur, stop - cur, ty);
+                    }
+                    cur = stop;
+                }
+                next => {
+                    let stop = next.map_or(end, |block| block.addr().min(end));
+                    self.add(RAMBlock::new(cur, stop - cur, ty, false));
+                    cur = stop;
                 }
             }
         }
+    }
 
-        self.is_init = true;
+    // Retypes a free range lying inside a single block, the rest keeps the old type
+    fn carve(&mut self, addr: usize, size: usize, ty: RAMType) {
+        let Some(idx) = self.locate(addr) else { return; };
+        let from = self.blocks()[idx];
+        if from.used() || addr + size > from.end() { return; }
+
+        self.remove(idx);
+        self.add(RAMBlock::new(from.addr(), addr - from.addr(), from.ty(), false));
+        self.add(RAMBlock::new(addr + size, from.end() - addr - size, from.ty(), false));
+        self.add(RAMBlock::new(addr, size, ty, false));
+    }
+
+    // Copies a boot loader buffer into kernel memory, 0 if there is none
+    fn keep_loader_data(&mut self, ptr: usize, len: usize) -> usize {
+        if ptr == 0 || len == 0 { return 0; }
+        let Some(new) = self.alloc(
+            AllocParams::new(len).as_type(RAMType::KernelData)
+        ) else { return 0; };
+        unsafe { core::ptr::copy(ptr as *const u8, new.ptr(), len); }
+        return new.addr();
     }
 
     fn reclaim(&mut self) {
         loop { // O(n^2) but called only once.
-            let pair = self.blocks_raw().iter().enumerate()
-                .find(|(_, blk)| blk.valid() && blk.ty() == RAMType::Reclaimable)
+            let pair = self.blocks().iter().enumerate()
+                .find(|(_, blk)| blk.ty() == RAMType::Reclaimable)
                 .map(|(idx, &blk)| (idx, blk));
 
             if let Some((idx, blk)) = pair {
-                self.blocks_raw_mut()[idx].invalidate();
-                let new_blk = RAMBlock::new(
-                    blk.addr(), blk.size(),
-                    RAMType::Conv, false
-                );
-                // SAFETY: self.count() before invalidation is guaranteed to be
-                // greater than or equal to after addition. thus, panic can never be triggered.
-                self.add(new_blk);
+                self.remove(idx);
+                // Same split as claim_desc, add() grows the array if the low part needs a slot
+                let split = LOW_MEM_END.clamp(blk.addr(), blk.end());
+                self.add(RAMBlock::new(blk.addr(), split - blk.addr(), RAMType::LowMemory, false));
+                self.add(RAMBlock::new(split, blk.end() - split, RAMType::Conv, false));
             } else { break; }
         }
     }
@@ -280,6 +381,89 @@ impl PhysAlloc {
         return unsafe { core::slice::from_raw_parts_mut(self.ptr.ptr() as *mut RAMBlock, self.max) };
     }
 
+    // Valid blocks are kept in front, sorted by address, unused slots follow
+    fn len(&self) -> usize {
+        return self.blocks_raw().partition_point(|block| block.valid());
+    }
+
+    fn blocks(&self) -> &[RAMBlock] {
+        return &self.blocks_raw()[..self.len()];
+    }
+
+    // Index of the block containing `addr`
+    fn locate(&self, addr: usize) -> Option<usize> {
+        let blocks = self.blocks();
+
+        // Checking the order costs a full scan, so only debug builds do
+        #[cfg(debug_assertions)]
+        if !blocks.is_sorted_by_key(|block| block.addr()) {
+            return blocks.iter().position(|block| block.addr() <= addr && addr < block.end());
+        }
+
+        let idx = match blocks.binary_search_noheap_by_key(&addr, |block| block.addr()) {
+            Ok(idx) => idx,
+            Err(0) => return None,
+            Err(idx) => idx - 1
+        };
+        return (addr < blocks[idx].end()).then_some(idx);
+    }
+
+    fn locate_free(&self, addr: usize) -> Option<usize> {
+        if addr == NO_BLOCK { return None; }
+        return self.locate(addr);
+    }
+
+    fn is_indexed(&self, block: &RAMBlock) -> bool {
+        return !self.free.dirty && block.not_used() && block.ty() == RAMType::Conv;
+    }
+
+    // Puts a free Conv block at the front of its bucket, anything else is ignored
+    fn link(&mut self, idx: usize) {
+        let block = self.blocks()[idx];
+        if !self.is_indexed(&block) { return; }
+
+        let bucket = FreeBuckets::bucket(block.size());
+        let head = self.free.heads[bucket];
+        if let Some(head_idx) = self.locate_free(head) {
+            self.blocks_raw_mut()[head_idx].prev_free = block.addr();
+        }
+        let slot = &mut self.blocks_raw_mut()[idx];
+        (slot.next_free, slot.prev_free) = (head, NO_BLOCK);
+        self.free.heads[bucket] = block.addr();
+    }
+
+    // Must run before the block's address, size, type or use changes
+    fn unlink(&mut self, idx: usize) {
+        let block = self.blocks()[idx];
+        if !self.is_indexed(&block) { return; }
+
+        match self.locate_free(block.prev_free) {
+            Some(prev) => self.blocks_raw_mut()[prev].next_free = block.next_free,
+            None => self.free.heads[FreeBuckets::bucket(block.size())] = block.next_free
+        }
+        if let Some(next) = self.locate_free(block.next_free) {
+            self.blocks_raw_mut()[next].prev_free = block.prev_free;
+        }
+    }
+
+    // Caller guarantees a free slot
+    fn insert(&mut self, new_block: RAMBlock) {
+        let len = self.len();
+        let idx = self.blocks().partition_point(|block| block.addr() < new_block.addr());
+        let blocks = self.blocks_raw_mut();
+        blocks[idx..=len].rotate_right(1);
+        blocks[idx] = new_block;
+        self.link(idx);
+    }
+
+    fn remove(&mut self, idx: usize) {
+        self.unlink(idx);
+        let len = self.len();
+        let blocks = self.blocks_raw_mut();
+        blocks[idx..len].rotate_left(1);
+        blocks[len - 1].invalidate();
+    }
+
     fn blocks_iter(&self) -> impl Iterator<Item = &RAMBlock> {
         return self.blocks_raw().iter().filter(|&block| block.valid());
     }
@@ -299,6 +483,15 @@ impl PhysAlloc {
             .map(|block| block.size()).sum();
     }
 
+    pub fn count_filter(&self, filter: impl Fn(&RAMBlock) -> bool) -> usize {
+        return self.blocks_iter().filter(|&block| filter(block)).count();
+    }
+
+    pub fn largest_block(&self, ty: RAMType) -> Option<RAMBlock> {
+        return self.blocks_iter().filter(|block| block.ty() == ty)
+            .max_by_key(|block| block.size()).copied();
+    }
+
     // fn sort(&mut self) {
     //     self.blocks_raw_mut().sort_noheap_by(|a, b|
     //         match (a.valid(), b.valid()) {
@@ -314,8 +507,48 @@ impl PhysAlloc {
         return self.blocks_iter_mut().find(|block| f(block));
     }
 
+    // Rebuilds every bucket from the block array, for when it was rewritten wholesale
+    fn reindex(&mut self) {
+        let len = self.len();
+        let mut heads = [NO_BLOCK; usize::BITS as usize];
+        let mut head_idx = [0; usize::BITS as usize];
+
+        // Walking backwards leaves every bucket in address order
+        for idx in (0..len).rev() {
+            let block = self.blocks()[idx];
+            if block.used() || block.ty() != RAMType::Conv { continue; }
+
+            let bucket = FreeBuckets::bucket(block.size());
+            if heads[bucket] != NO_BLOCK {
+                self.blocks_raw_mut()[head_idx[bucket]].prev_free = block.addr();
+            }
+            let block = &mut self.blocks_raw_mut()[idx];
+            (block.next_free, block.prev_free) = (heads[bucket], NO_BLOCK);
+            (heads[bucket], head_idx[bucket]) = (block.addr(), idx);
+        }
+        self.free = FreeBuckets { heads, dirty: false };
+    }
+
     fn find_free_ram(&mut self, args: AllocParams) -> Option<OwnedPtr> {
         let args = args.build();
+        if args.from_type == RAMType::Conv {
+            if self.free.dirty { self.reindex(); }
+
+            // Blocks from the size's own bucket up are large enough unless alignment says otherwise
+            for bucket in FreeBuckets::bucket(args.size)..usize::BITS as usize {
+                let mut next = self.free.heads[bucket];
+                while let Some(idx) = self.locate_free(next) {
+                    let block = &self.blocks()[idx];
+                    let aligned = align_up(block.addr(), args.align);
+                    if aligned + args.size <= block.end() {
+                        return Some(OwnedPtr::new_bytes(aligned, args.size));
+                    }
+                    next = block.next_free;
+                }
+            }
+            return None;
+        }
+
         return self.find(|block| {
             let aligned = align_up(block.addr(), args.align);
 
@@ -353,18 +586,14 @@ impl PhysAlloc {
             ptr.addr() >= block.addr() && ptr.end() <= block.end()
         };
 
-        let (from, to) = self.blocks_iter_mut().find(|block|
-            filter(block)
-        ).and_then(|block| {
-            if block.ty() == args.as_type && !args.used {
-                return None; // Allocation lost its purpose
-            }
+        let idx = self.locate(ptr.addr()).filter(|&idx| filter(&self.blocks()[idx]))?;
+        let from = self.blocks()[idx];
+        if from.ty() == args.as_type && !args.used {
+            return None; // Allocation lost its purpose
+        }
 
-            let from = *block;
-            let to = RAMBlock::new(ptr.addr(), ptr.size(), args.as_type, args.used);
-            block.invalidate();
-            return Some((from, to));
-        })?;
+        let to = RAMBlock::new(ptr.addr(), ptr.size(), args.as_type, args.used);
+        self.remove(idx);
 
         let before_block = RAMBlock::new(
             from.addr(), ptr.addr() - from.addr(),
@@ -382,17 +611,16 @@ impl PhysAlloc {
     }
 
     fn free(&mut self, ptr: OwnedPtr) {
-        let (mut before, mut after) = (None, None);
-        for block in self.blocks_iter_mut() {
-            if block.addr() < ptr.end() && block.end() > ptr.addr() {
-                if block.addr() < ptr.addr() {
-                    before = Some(*block);
-                }
-                if block.end() > ptr.end() {
-                    after = Some(*block);
-                }
-                block.invalidate();
-            }
+        // Overlapping blocks are contiguous, only the outer two can stick out
+        let blocks = self.blocks();
+        let lo = blocks.partition_point(|block| block.end() <= ptr.addr());
+        let hi = blocks.partition_point(|block| block.addr() < ptr.end());
+
+        let before = blocks.get(lo).filter(|b| lo < hi && b.addr() < ptr.addr()).copied();
+        let after = hi.checked_sub(1).and_then(|i| blocks.get(i))
+            .filter(|a| lo < hi && a.end() > ptr.end()).copied();
+        for _ in lo..hi {
+            self.remove(lo);
         }
 
         before.map(|b| {
@@ -415,30 +643,48 @@ impl PhysAlloc {
 
     fn add(&mut self, new_block: RAMBlock) {
         if new_block.invalid() { return; }
-        let (mut before, mut after) = (None, None);
-        for block in self.blocks_iter_mut() {
-            match new_block.is_mergable(&block) {
-                -1 => { after = Some(block); },
-                1 => { before = Some(block); },
-                _ => continue
-            }
-        }
+
+        // Only the neighbours around the insertion point can merge
+        let blocks = self.blocks();
+        let idx = blocks.partition_point(|block| block.addr() < new_block.addr());
+        let before = idx.checked_sub(1).filter(|&i| new_block.is_mergable(&blocks[i]) == 1);
+        let after = Some(idx).filter(|&i| i < blocks.len() && new_block.is_mergable(&blocks[i]) == -1);
+
+        // A block landing on top of its neighbours means the caller lost track of it
+        debug_assert!(idx == 0 || blocks[idx - 1].end() <= new_block.addr(), "RAMBlock overlaps its predecessor");
+        debug_assert!(idx == blocks.len() || new_block.end() <= blocks[idx].addr(), "RAMBlock overlaps its successor");
 
         match (before, after) {
-            (Some(before_block), Some(after_block)) => {
-                before_block.set_size(before_block.size() + new_block.size() + after_block.size());
-                after_block.invalidate();
+            (Some(before_idx), Some(after_idx)) => {
+                let (before_block, after_block) = (self.blocks()[before_idx], self.blocks()[after_idx]);
+                assert!(
+                    before_block.end() == new_block.addr() && new_block.end() == after_block.addr(),
+                    "Three-way merge of RAMBlocks that are not contiguous"
+                );
+
+                let after_size = after_block.size();
+                self.unlink(before_idx);
+                self.remove(after_idx);
+                let before_block = &mut self.blocks_raw_mut()[before_idx];
+                before_block.set_size(before_block.size() + new_block.size() + after_size);
+                self.link(before_idx);
                 if self.count() <= self.max >> 2 {
                     let new_size = (self.max >> 1).max(BASE_RB_SIZE);
                     self.shrink(new_size);
                 }
             },
-            (Some(before_block), None) => {
+            (Some(before_idx), None) => {
+                self.unlink(before_idx);
+                let before_block = &mut self.blocks_raw_mut()[before_idx];
                 before_block.set_size(before_block.size() + new_block.size());
+                self.link(before_idx);
             },
-            (None, Some(after_block)) => {
+            (None, Some(after_idx)) => {
+                self.unlink(after_idx);
+                let after_block = &mut self.blocks_raw_mut()[after_idx];
                 after_block.set_addr(new_block.addr());
                 after_block.set_size(after_block.size() + new_block.size());
+                self.link(after_idx);
             },
             (None, None) => {
                 let prereq = new_block.into_owned_ptr();
@@ -446,15 +692,20 @@ impl PhysAlloc {
                     let new_size = (self.max << 1).max(self.max + MIN_REQ);
                     self.expand(new_size, prereq).expect("Failed to expand RAMBlocks");
                 }
-
-                let blocks = self.blocks_raw_mut();
-                for block in blocks {
-                    if block.valid() { continue; }
-                    *block = new_block;
-                    break;
-                }
+                self.insert(new_block);
             }
         }
+
+        #[cfg(debug_assertions)]
+        self.validate();
+    }
+
+    // Sorted and disjoint, gaps are fine while an allocation is half way through
+    #[cfg(debug_assertions)]
+    fn validate(&self) {
+        for pair in self.blocks().windows(2) {
+            assert!(pair[0].end() <= pair[1].addr(), "RAMBlocks overlap: {:x?}", pair);
+        }
     }
 
     fn expand(&mut self, new_max: usize, prereq: OwnedPtr) -> Option<()> {
@@ -551,20 +802,33 @@ impl PhysAlloc {
 
 impl PhysAllocGlob {
     const fn empty() -> Self {
-        return Self(IntLock::new(PhysAlloc::empty()));
+        return Self(KMutex::new(PhysAlloc::empty()));
     }
 
     pub fn init(&self) { self.0.lock().init(); }
     pub fn reclaim(&self) { self.0.lock().reclaim(); }
 
+    // Bytes in the blocks matching the filter, MMIO and reserved ranges left out.
+    // The filter runs under the allocator lock, so it must not allocate.
     pub fn filtsize(&self, filter: impl Fn(&RAMBlock) -> bool) -> usize {
         return self.0.lock().filtsize(filter);
     }
 
+    // Same as filtsize but over every block, NON_RAM types included
     pub fn filtsize_raw(&self, filter: impl Fn(&RAMBlock) -> bool) -> usize {
         return self.0.lock().filtsize_raw(filter);
     }
 
+    // Number of blocks matching the filter, NON_RAM types included
+    pub fn count_filter(&self, filter: impl Fn(&RAMBlock) -> bool) -> usize {
+        return self.0.lock().count_filter(filter);
+    }
+
+    // Used and free blocks alike, check used() on the result when it matters
+    pub fn largest_block(&self, ty: RAMType) -> Option<RAMBlock> {
+        return self.0.lock().largest_block(ty);
+    }
+
     pub fn available(&self) -> usize {
         return self.0.lock().filtsize(|block| block.not_used() && block.ty() == RAMType::Conv);
     }
@@ -575,6 +839,19 @@ impl PhysAllocGlob {
 
     // pub fn sort(&self) { self.0.lock().sort(); }
 
+    // Copy of the valid blocks. The heap grows through this allocator, so the
+    // buffer is sized beforehand and only filled, never grown, under the lock.
+    pub fn snapshot(&self) -> Vec<RAMBlock> {
+        loop {
+            let mut blocks = Vec::with_capacity(self.0.lock().count() + 8);
+            let pa = self.0.lock();
+            if pa.count() <= blocks.capacity() {
+                blocks.extend(pa.blocks_iter().copied());
+                return blocks;
+            }
+        }
+    }
+
     pub fn with_blocks<F, R>(&self, f: F) -> R
     where F: FnOnce(&dyn Iterator<Item = &RAMBlock>) -> R {
         f(&self.0.lock().blocks_iter())
@@ -596,3 +873,202 @@ impl PhysAllocGlob {
         self.free(OwnedPtr::new_bytes(ptr as usize, size));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ram::glacier::{BPage, G_CFG, RvmCfg};
+    use alloc::vec;
+
+    const BASE: usize = 0x100000;
+    const SPAN: usize = 0x4000_0000;
+
+    // Never dereferenced. BASE_RB_SIZE slots keep both expand and shrink from running.
+    fn blank() -> PhysAlloc {
+        G_CFG.call_once(|| RvmCfg { psz: BPage::Size4kiB, va_bits: 48, pa_bits: 52 });
+        let rb = vec![RAMBlock::new_invalid(); BASE_RB_SIZE].leak();
+        let mut pa = PhysAlloc::empty();
+        (pa.ptr, pa.max) = (OwnedPtr::from_slice(rb), rb.len());
+        return pa;
+    }
+
+    fn fresh() -> PhysAlloc {
+        let mut pa = blank();
+        pa.add(RAMBlock::new(BASE, SPAN, RAMType::Conv, false));
+        return pa;
+    }
+
+    // Every free Conv block is linked exactly once, in its own bucket
+    fn check(pa: &PhysAlloc) {
+        let mut linked = 0;
+        for (bucket, &head) in pa.free.heads.iter().enumerate() {
+            let (mut prev, mut next) = (NO_BLOCK, head);
+            while let Some(idx) = pa.locate_free(next) {
+                let block = pa.blocks()[idx];
+                assert_eq!(block.addr(), next);
+                assert!(block.not_used() && block.ty() == RAMType::Conv);
+                assert_eq!(FreeBuckets::bucket(block.size()), bucket);
+                assert_eq!(block.prev_free, prev);
+                (prev, next) = (next, block.next_free);
+                linked += 1;
+            }
+            assert_eq!(next, NO_BLOCK);
+        }
+        assert_eq!(linked, pa.count_filter(|b| b.not_used() && b.ty() == RAMType::Conv));
+    }
+
+    #[test]
+    fn buckets_follow_alloc_and_free() {
+        let mut pa = fresh();
+        let mut held: Vec<OwnedPtr> = Vec::new();
+        let mut seed = 0x2545f4914f6cdd1du64;
+        let mut rand = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            return seed as usize;
+        };
+
+        for _ in 0..5000 {
+            if held.len() < 40 && (held.is_empty() || rand() % 3 != 0) {
+                let size = (rand() % 64 + 1) * PAGE_4KIB;
+                let align = PAGE_4KIB << (rand() % 5);
+                let ptr = pa.alloc(AllocParams::new(size).align(align)).expect("Out of test RAM");
+                assert_eq!(ptr.addr() % align, 0);
+                held.push(ptr);
+            } else {
+                let ptr = held.swap_remove(rand() % held.len());
+                pa.free(ptr);
+            }
+            check(&pa);
+        }
+
+        for ptr in held.drain(..) {
+            pa.free(ptr);
+        }
+        check(&pa);
+        assert_eq!(pa.blocks(), &[RAMBlock::new(BASE, SPAN, RAMType::Conv, false)]);
+    }
+
+    #[test]
+    fn churn_leaves_no_overlaps_or_gaps() {
+        let mut pa = fresh();
+        let mut held: Vec<OwnedPtr> = Vec::new();
+        let mut seed = 0x2545f4914f6cdd1du64;
+        let mut rand = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            return seed as usize;
+        };
+
+        for _ in 0..3000 {
+            if held.len() < 60 && (held.is_empty() || rand() % 2 == 0) {
+                let size = (rand() % 16 + 1) * PAGE_4KIB;
+                held.push(pa.alloc(AllocParams::new(size)).expect("Out of test RAM"));
+            } else {
+                let ptr = held.swap_remove(rand() % held.len());
+                pa.free(ptr);
+            }
+
+            // The blocks tile the region, and no two free neighbours were left unmerged
+            let blocks = pa.blocks();
+            assert_eq!(blocks.first().map(|b| b.addr()), Some(BASE));
+            assert_eq!(blocks.last().map(|b| b.end()), Some(BASE + SPAN));
+            for pair in blocks.windows(2) {
+                assert_eq!(pair[0].end(), pair[1].addr(), "{:x?}", pair);
+                assert!(!(pair[0].not_used() && pair[1].not_used()), "{:x?}", pair);
+            }
+        }
+    }
+
+    #[test]
+    fn locate_finds_the_containing_block() {
+        let mut pa = fresh();
+        let far = BASE + SPAN + 0x10000;
+        pa.add(RAMBlock::new(far, 0x4000, RAMType::Reserved, false));
+
+        assert_eq!(pa.locate(BASE), Some(0));
+        assert_eq!(pa.locate(BASE + SPAN - 1), Some(0));
+        assert_eq!(pa.locate(far + 0x3fff), Some(1));
+        // Below, between and past the blocks, the unused slots behind them included
+        assert_eq!(pa.locate(BASE - 1), None);
+        assert_eq!(pa.locate(BASE + SPAN), None);
+        assert_eq!(pa.locate(far + 0x4000), None);
+        assert_eq!(pa.locate(0), None);
+    }
+
+    #[test]
+    fn queries_by_type() {
+        let mut pa = fresh();
+        let far = BASE + SPAN;
+        // Gaps between them keep equal neighbours from merging
+        pa.add(RAMBlock::new(far + 0x10000, 0x4000, RAMType::Reserved, false));
+        pa.add(RAMBlock::new(far + 0x20000, 0x8000, RAMType::Reserved, false));
+        pa.add(RAMBlock::new(far + 0x30000, 0x1000, RAMType::MMIO, false));
+        pa.add(RAMBlock::new(far + 0x40000, 0x3000, RAMType::KernelPTable, true));
+        pa.add(RAMBlock::new(far + 0x50000, 0x2000, RAMType::KernelPTable, true));
+        pa.add(RAMBlock::new(far + 0x60000, 0x10000, RAMType::Kernel, true));
+
+        let ptable = |b: &RAMBlock| b.ty() == RAMType::KernelPTable;
+        assert_eq!(pa.filtsize(ptable), 0x5000);
+        assert_eq!(pa.count_filter(ptable), 2);
+        assert_eq!(pa.filtsize(|b| b.ty() == RAMType::Kernel), 0x10000);
+        assert_eq!(pa.count_filter(|b| b.used()), 3);
+
+        // Reserved and MMIO are not RAM, only the raw sum sees them
+        let reserved = |b: &RAMBlock| b.ty() == RAMType::Reserved;
+        assert_eq!(pa.filtsize(reserved), 0);
+        assert_eq!(pa.filtsize_raw(reserved), 0xc000);
+        assert_eq!(pa.count_filter(reserved), 2);
+        assert_eq!(pa.filtsize(|_| true), SPAN + 0x15000);
+        assert_eq!(pa.filtsize_raw(|_| true), SPAN + 0x22000);
+        assert_eq!(pa.count_filter(|_| true), 7);
+
+        assert_eq!(pa.largest_block(RAMType::Reserved),
+            Some(RAMBlock::new(far + 0x20000, 0x8000, RAMType::Reserved, false)));
+        assert_eq!(pa.largest_block(RAMType::Conv).map(|b| b.size()), Some(SPAN));
+        assert_eq!(pa.largest_block(RAMType::UserPTable), None);
+    }
+
+    fn desc(ty: RAMType, start: usize, pages: usize) -> RAMDescriptor {
+        return RAMDescriptor {
+            ty: ty as u32, reserved: 0,
+            phys_start: start as u64, virt_start: 0,
+            page_count: pages as u64, attr: 0, padding: 0
+        };
+    }
+
+    #[test]
+    fn firmware_claims_are_never_handed_out() {
+        let mut pa = blank();
+
+        // The kernel image and a reserved page both lie inside a Conv range
+        let image = 0x400000..0x600000;
+        let hole = 0x800000..0x801000;
+        let layout = [
+            desc(RAMType::Conv, PAGE_4KIB, LOW_MEM_END / PAGE_4KIB - 1),
+            desc(RAMType::Conv, LOW_MEM_END, 0x1f00),
+            desc(RAMType::LoaderData, image.start, 0x200),
+            desc(RAMType::Reserved, hole.start, 1)
+        ];
+        // Same order as init: Conv largest first, then everything else by address
+        for d in layout.iter().rev().filter(|d| d.ty() == RAMType::Conv) { pa.claim_desc(d); }
+        for d in layout.iter().filter(|d| d.ty() != RAMType::Conv) { pa.claim_desc(d); }
+
+        assert_eq!(pa.blocks()[pa.locate(image.start).unwrap()].ty(), RAMType::Reclaimable);
+        assert_eq!(pa.blocks()[pa.locate(hole.start).unwrap()].ty(), RAMType::Reserved);
+
+        let mut total = 0;
+        while let Some(ptr) = pa.alloc(AllocParams::new(0x10000)) {
+            let got = ptr.addr()..ptr.end();
+            assert!(got.start >= LOW_MEM_END, "{:x?}", got);
+            assert!(got.end <= image.start || got.start >= image.end, "{:x?}", got);
+            assert!(got.end <= hole.start || got.start >= hole.end, "{:x?}", got);
+            total += ptr.size();
+        }
+        // Everything else in the Conv range above LOW_MEM_END, less the alignment tail
+        let free = 0x1f00 * PAGE_4KIB - (image.end - image.start) - (hole.end - hole.start);
+        assert!(total <= free && free - total < 0x10000, "{:#x} of {:#x}", total, free);
+    }
+}
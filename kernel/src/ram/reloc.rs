@@ -1,5 +1,5 @@
 use crate::{
-    arch::{R_REL, R_SYM, move_stack, rvm::flags},
+    arch::{R_REL, R_SYM, jump_with_stack, rvm::flags, sync_icache},
     kargs::{
         DT_NULL, DT_RELA, DT_RELASZ,
         AP_LIST, KBASE, KINFO,
@@ -14,7 +14,7 @@ use crate::{
 };
 
 use core::{
-    mem::{size_of, transmute},
+    mem::size_of,
     sync::atomic::{AtomicUsize, Ordering as AtomOrd}
 };
 
@@ -95,20 +95,63 @@ pub fn reloc() -> ! {
     };
 
     // Relocation
+    let image = unsafe { core::slice::from_raw_parts_mut(new_kbase.ptr::<u8>(), kinfo.size) };
+    apply_rela(image, rela, delta);
+    sync_icache(new_kbase.addr(), kinfo.size);
+
+    // JUMP
+    // ALL STACK VARIABLES ARE VOID BEYOND THIS POINT.
+    // The target is read before sp moves and handed over in a register.
+    unsafe { jump_with_stack(stack_va, SPARK_PTR.load(AtomOrd::SeqCst)); }
+}
+
+// Shifts every absolute address in `image` by `delta`, entries outside the image are skipped.
+// The bootloader already resolved them against the old base. Returns how many were patched.
+pub fn apply_rela(image: &mut [u8], rela: &[RelaEntry], delta: usize) -> usize {
+    let mut patched = 0;
     for entry in rela.iter() {
         let ty = entry.info & 0xffffffff;
-        if R_REL == ty || R_SYM.contains(&ty) {
-            let addr = (new_kbase.addr() + entry.offset) as *mut usize;
-            unsafe { *addr += delta; }
-        }
+        if R_REL != ty && !R_SYM.contains(&ty) { continue; }
+
+        let Some(slot) = entry.offset.checked_add(size_of::<usize>())
+            .and_then(|end| image.get_mut(entry.offset..end))
+        else { continue; };
+
+        let ptr = slot.as_mut_ptr() as *mut usize;
+        unsafe { ptr.write_unaligned(ptr.read_unaligned().wrapping_add(delta)); }
+        patched += 1;
     }
+    return patched;
+}
 
-    // JUMP
-    unsafe {
-        // ALL STACK VARIABLES ARE VOID BEYOND THIS POINT.
-        move_stack(stack_va);
-        transmute::<usize, extern "C" fn() -> !>(
-            SPARK_PTR.load(AtomOrd::SeqCst)
-        )();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rela(offset: usize, ty: usize) -> RelaEntry {
+        return RelaEntry { offset, info: ty, addend: 0 };
+    }
+
+    #[test]
+    fn relocations_shift_by_delta() {
+        let mut image = [0u8; 64];
+        image[8..16].copy_from_slice(&0x1000usize.to_le_bytes());
+        image[19..27].copy_from_slice(&0x2345usize.to_le_bytes()); // Unaligned
+        image[32..40].copy_from_slice(&0x3000usize.to_le_bytes());
+        let table = [
+            rela(8, R_REL),
+            rela(19, R_SYM[0] | 7 << 32), // Symbol index above the type
+            rela(32, 0), // R_NONE
+            rela(60, R_REL), // Runs off the end
+            rela(usize::MAX - 2, R_REL)
+        ];
+
+        let delta = 0xffff_8000_0000_0000;
+        assert_eq!(apply_rela(&mut image, &table, delta), 2);
+        let word = |at: usize| usize::from_le_bytes(image[at..at + 8].try_into().unwrap());
+        assert_eq!(word(8), 0xffff_8000_0000_1000);
+        assert_eq!(word(19), 0xffff_8000_0000_2345);
+        assert_eq!(word(32), 0x3000);
+        assert_eq!(image[60..], [0; 4]);
     }
 }
@@ -0,0 +1,90 @@
+use crate::{
+    arch::{intc, percpu::try_this_cpu, rvm::flush_local},
+    device::cpu::CPU_ONLINE,
+    ram::glacier::page_size
+};
+
+use core::{
+    hint::spin_loop,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering as AtomOrd}
+};
+use spin::Mutex;
+
+// Payload of the request in flight: page aligned base and page count
+static SHOOT_VA: AtomicUsize = AtomicUsize::new(0);
+static SHOOT_PAGES: AtomicUsize = AtomicUsize::new(0);
+// Virtual CPU ids that have not invalidated yet
+static SHOOT_PENDING: AtomicU64 = AtomicU64::new(0);
+static SHOOT_LOCK: Mutex<()> = Mutex::new(());
+
+fn encode_range(va: usize, size: usize) -> (usize, usize) {
+    let psz = page_size();
+    let start = va & !(psz - 1);
+    let end = (va + size).next_multiple_of(psz);
+    return (start, (end - start) / psz);
+}
+
+// From the per-CPU block, never AP_LIST: this runs inside lock spin loops and
+// must not take a lock itself. A core without one yet is the BSP in early boot.
+fn self_bit() -> u64 {
+    let virtid = try_this_cpu().map_or(0, |cpu| cpu.virtid());
+    return 1 << (virtid % 64);
+}
+
+// Runs on every other core, from the IPI or from any kernel lock spin loop
+pub fn handle_ipi() {
+    // Cheap bail out first, lock spinners call this on every iteration
+    let pending = SHOOT_PENDING.load(AtomOrd::Acquire);
+    if pending == 0 { return; }
+    let bit = self_bit();
+    if pending & bit == 0 { return; }
+
+    let va = SHOOT_VA.load(AtomOrd::Relaxed);
+    for i in 0..SHOOT_PAGES.load(AtomOrd::Relaxed) {
+        flush_local(va + i * page_size());
+    }
+    SHOOT_PENDING.fetch_and(!bit, AtomOrd::Release);
+}
+
+// Invalidates [va, va + size) on all other online cores and waits for them
+pub fn tlb_shootdown(va: usize, size: usize) {
+    let others = CPU_ONLINE.load(AtomOrd::Acquire) & !self_bit();
+    if others == 0 { return; }
+
+    // Whoever holds the lock may be waiting on this core
+    let _guard = loop {
+        if let Some(guard) = SHOOT_LOCK.try_lock() { break guard; }
+        handle_ipi();
+        spin_loop();
+    };
+
+    let (start, pages) = encode_range(va, size);
+    SHOOT_VA.store(start, AtomOrd::Relaxed);
+    SHOOT_PAGES.store(pages, AtomOrd::Relaxed);
+    SHOOT_PENDING.store(others, AtomOrd::Release);
+
+    intc::send_ipi_others(intc::IPI_TLB);
+    while SHOOT_PENDING.load(AtomOrd::Acquire) != 0 {
+        handle_ipi();
+        spin_loop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ram::glacier::{BPage, G_CFG, RvmCfg};
+
+    #[test]
+    fn range_covers_whole_pages() {
+        G_CFG.call_once(|| RvmCfg { psz: BPage::Size4kiB, va_bits: 48, pa_bits: 52 });
+
+        assert_eq!(encode_range(0x40_0000, 0x1000), (0x40_0000, 1));
+        assert_eq!(encode_range(0x40_0000, 0x3000), (0x40_0000, 3));
+        // Partial pages at either end are invalidated whole
+        assert_eq!(encode_range(0x40_0800, 0x1000), (0x40_0000, 2));
+        assert_eq!(encode_range(0x40_0fff, 2), (0x40_0000, 2));
+        assert_eq!(encode_range(0xffff_8000_0000_1234, 1), (0xffff_8000_0000_1000, 1));
+        assert_eq!(encode_range(0x40_0000, 0), (0x40_0000, 0));
+    }
+}
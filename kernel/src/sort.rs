@@ -5,6 +5,48 @@ pub trait HeaplessSort<T> {
     fn sort_noheap(&mut self) where T: Ord;
     fn sort_noheap_by<F>(&mut self, cmp: F) where F: Fn(&T, &T) -> Ordering;
     fn sort_noheap_by_key<F, K>(&mut self, f: F) where F: Fn(&T) -> K, K: Ord;
+    fn sort_noheap_stable_by<F>(&mut self, cmp: F) where F: Fn(&T, &T) -> Ordering;
+    fn sort_noheap_stable_by_key<F, K>(&mut self, f: F) where F: Fn(&T) -> K, K: Ord;
+    fn binary_search_noheap_by_key<F, K>(&self, key: &K, f: F) -> Result<usize, usize>
+        where F: Fn(&T) -> K, K: Ord;
+}
+
+// Merges the sorted runs v[..mid] and v[mid..] without a buffer.
+// Right elements only jump ahead of strictly greater left ones, so equal keys keep their order.
+fn merge_rotate<T, F>(v: &mut [T], mid: usize, cmp: &F) where F: Fn(&T, &T) -> Ordering {
+    let (mut lo, mut mid) = (0, mid);
+    while lo < mid && mid < v.len() {
+        if cmp(&v[mid], &v[lo]) != Ordering::Less {
+            lo += 1;
+            continue;
+        }
+
+        let mut end = mid + 1;
+        while end < v.len() && cmp(&v[end], &v[lo]) == Ordering::Less {
+            end += 1;
+        }
+
+        // v[mid..end] all go before v[lo], which then sits right after them
+        v[lo..end].rotate_right(end - mid);
+        lo += end - mid + 1;
+        mid = end;
+    }
+}
+
+// Bottom-up, doubling the run width each pass
+fn merge_sort<T, F>(v: &mut [T], cmp: F) where F: Fn(&T, &T) -> Ordering {
+    let len = v.len();
+    let mut width = 1;
+    while width < len {
+        for start in (0..len).step_by(width * 2) {
+            let mid = (start + width).min(len);
+            let end = (start + width * 2).min(len);
+            if mid < end {
+                merge_rotate(&mut v[start..end], mid - start, &cmp);
+            }
+        }
+        width *= 2;
+    }
 }
 
 impl<T> HeaplessSort<T> for [T] {
@@ -19,4 +61,85 @@ impl<T> HeaplessSort<T> for [T] {
     fn sort_noheap_by_key<F, K>(&mut self, f: F) where F: Fn(&T) -> K, K: Ord {
         wikisort(self, |a, b| f(a).cmp(&f(b)));
     }
+
+    fn sort_noheap_stable_by<F>(&mut self, cmp: F) where F: Fn(&T, &T) -> Ordering {
+        merge_sort(self, cmp);
+    }
+
+    fn sort_noheap_stable_by_key<F, K>(&mut self, f: F) where F: Fn(&T) -> K, K: Ord {
+        merge_sort(self, |a, b| f(a).cmp(&f(b)));
+    }
+
+    // Ok(index) on a hit, Err(insertion point) otherwise, like the slice method
+    fn binary_search_noheap_by_key<F, K>(&self, key: &K, f: F) -> Result<usize, usize>
+        where F: Fn(&T) -> K, K: Ord {
+        let (mut lo, mut hi) = (0, self.len());
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            match f(&self[mid]).cmp(key) {
+                Ordering::Less => lo = mid + 1,
+                Ordering::Greater => hi = mid,
+                Ordering::Equal => return Ok(mid)
+            }
+        }
+        return Err(lo);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    // Keys collide a lot, the index shows whether equal keys kept their order
+    fn pairs(len: usize, seed: &mut u64) -> Vec<(u8, usize)> {
+        return (0..len).map(|i| {
+            *seed ^= *seed << 13;
+            *seed ^= *seed >> 7;
+            *seed ^= *seed << 17;
+            return ((*seed % 5) as u8, i);
+        }).collect();
+    }
+
+    #[test]
+    fn merge_rotate_edges() {
+        let by_key = |a: &(u8, usize), b: &(u8, usize)| a.0.cmp(&b.0);
+        let mut v = [(1, 0), (3, 1), (0, 2), (1, 3), (3, 4)];
+        merge_rotate(&mut v, 2, &by_key);
+        assert_eq!(v, [(0, 2), (1, 0), (1, 3), (3, 1), (3, 4)]);
+
+        for mid in [0, 3] {
+            let mut v = [(1, 0), (2, 1), (3, 2)];
+            merge_rotate(&mut v, mid, &by_key);
+            assert_eq!(v, [(1, 0), (2, 1), (3, 2)]);
+        }
+    }
+
+    #[test]
+    fn stable_sort_matches_std() {
+        let mut seed = 0x2545f4914f6cdd1du64;
+        for len in (0..70).chain([255, 256, 257, 1000]) {
+            let mut v = pairs(len, &mut seed);
+            let mut want = v.clone();
+            want.sort_by_key(|p| p.0);
+            v.sort_noheap_stable_by_key(|p| p.0);
+            assert_eq!(v, want);
+        }
+    }
+
+    #[test]
+    fn binary_search_hits_and_misses() {
+        let v = [(10, 'a'), (20, 'b'), (30, 'c'), (40, 'd')];
+        let key = |p: &(i32, char)| p.0;
+        assert_eq!(v.binary_search_noheap_by_key(&10, key), Ok(0));
+        assert_eq!(v.binary_search_noheap_by_key(&40, key), Ok(3));
+        assert_eq!(v.binary_search_noheap_by_key(&25, key), Err(2));
+        assert_eq!(v.binary_search_noheap_by_key(&5, key), Err(0));
+        assert_eq!(v.binary_search_noheap_by_key(&50, key), Err(4));
+        assert_eq!([].binary_search_noheap_by_key(&10, key), Err(0));
+
+        for k in 0..50 {
+            assert_eq!(v.binary_search_noheap_by_key(&k, key), v.binary_search_by_key(&k, key));
+        }
+    }
 }
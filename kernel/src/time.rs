@@ -0,0 +1,328 @@
+use crate::{
+    arch::{self, exc, intc, percpu::this_cpu},
+    ram::mutex::KMutex
+};
+
+use core::{hint::spin_loop, sync::atomic::{AtomicBool, AtomicU64, Ordering as AtomOrd}};
+use alloc::{boxed::Box, collections::btree_map::BTreeMap, sync::Arc};
+
+#[cfg(target_arch = "aarch64")]
+use crate::{
+    arch::rvm::flags,
+    device::DEVICETREE,
+    ram::glacier::{GLACIER, page_size}
+};
+
+#[cfg(target_arch = "aarch64")]
+use spin::Once;
+
+pub fn is_leap(year: i64) -> bool {
+    return year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+}
+
+pub fn bcd_to_bin(val: u8) -> u8 {
+    return (val >> 4) * 10 + (val & 0x0f);
+}
+
+// Days since 1970-01-01 of a proleptic Gregorian date
+pub fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    const CUM_DAYS: [i64; 12] = [0, 31, 59, 90, 120, 151, 181, 212, 243, 273, 304, 334];
+
+    let prev = year - 1;
+    let leaps = prev / 4 - prev / 100 + prev / 400 - (1969 / 4 - 1969 / 100 + 1969 / 400);
+    let mut days = (year - 1970) * 365 + leaps;
+
+    let month = month.clamp(1, 12) as usize;
+    days += CUM_DAYS[month - 1];
+    if month > 2 && is_leap(year) { days += 1; }
+    return days + day.max(1) as i64 - 1;
+}
+
+// Inverse of days_from_civil, as (year, month, day)
+pub fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let z = days + 719468; // Days since 0000-03-01
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153; // Month counted from March
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    return (year, month, day);
+}
+
+pub fn unix_time(year: i64, month: u8, day: u8, hour: u8, min: u8, sec: u8) -> u64 {
+    let days = days_from_civil(year, month, day);
+    let secs = days * 86400 + hour as i64 * 3600 + min as i64 * 60 + sec as i64;
+    return secs.max(0) as u64;
+}
+
+#[cfg(all(target_arch = "x86_64", not(test)))]
+mod rtc {
+    use super::{bcd_to_bin, unix_time};
+    use core::arch::asm;
+
+    const CMOS_ADDR: u16 = 0x70;
+    const CMOS_DATA: u16 = 0x71;
+
+    fn cmos_read(reg: u8) -> u8 {
+        let val: u8;
+        unsafe {
+            asm!("out dx, al", in("dx") CMOS_ADDR, in("al") reg | 0x80, options(nomem, nostack)); // NMI stays off
+            asm!("in al, dx", in("dx") CMOS_DATA, out("al") val, options(nomem, nostack));
+        }
+        return val;
+    }
+
+    fn snapshot() -> [u8; 6] {
+        while cmos_read(0x0a) & 0x80 != 0 {} // Update in progress
+        return [0x00, 0x02, 0x04, 0x07, 0x08, 0x09].map(cmos_read);
+    }
+
+    pub fn read() -> u64 {
+        // Read until two snapshots agree so no update slipped in between
+        let mut regs = snapshot();
+        loop {
+            let again = snapshot();
+            if again == regs { break; }
+            regs = again;
+        }
+
+        let status_b = cmos_read(0x0b);
+        let binary = status_b & 0x04 != 0;
+        let h24 = status_b & 0x02 != 0;
+
+        let pm = regs[2] & 0x80 != 0;
+        regs[2] &= 0x7f;
+        if !binary { regs = regs.map(bcd_to_bin); }
+
+        let [sec, min, mut hour, day, month, year] = regs;
+        if !h24 {
+            hour %= 12;
+            if pm { hour += 12; }
+        }
+
+        return unix_time(2000 + year as i64, month, day, hour, min, sec);
+    }
+}
+
+#[cfg(all(target_arch = "aarch64", not(test)))]
+mod rtc {
+    use super::*;
+
+    const RTCDR: usize = 0x000;
+
+    static PL031_BASE: Once<Option<usize>> = Once::new();
+
+    fn base() -> Option<usize> {
+        return *PL031_BASE.call_once(|| {
+            let dtb = DEVICETREE.read();
+            let node = dtb.as_ref()?.find_compatible(&["arm,pl031"])?;
+            let base = node.reg()?.next()?.starting_address as usize;
+            GLACIER.write().map_range(base, base, page_size(), flags::D_RW).ok()?;
+            return Some(base);
+        });
+    }
+
+    pub fn read() -> u64 {
+        let Some(base) = base() else { return 0; };
+        return unsafe { ((base + RTCDR) as *const u32).read_volatile() } as u64;
+    }
+}
+
+// Host tests have no CMOS to read
+#[cfg(test)]
+mod rtc {
+    pub fn read() -> u64 { return 0; }
+}
+
+// Wall clock as seconds since the Unix epoch, 0 if there is no RTC
+pub fn now() -> u64 {
+    return rtc::read();
+}
+
+pub fn ticks_to_ns(ticks: u64, freq: u64) -> u64 {
+    if freq == 0 { return 0; }
+    return (ticks as u128 * 1_000_000_000 / freq as u128) as u64;
+}
+
+// Monotonic, 0 until the counter frequency is known
+pub fn uptime_ns() -> u64 {
+    return ticks_to_ns(intc::counter(), intc::counter_freq());
+}
+
+pub fn busy_wait_ns(ns: u64) {
+    let freq = intc::counter_freq();
+    if freq == 0 { return; }
+
+    let start = intc::counter();
+    let ticks = (ns as u128 * freq as u128 / 1_000_000_000) as u64;
+    while intc::counter().wrapping_sub(start) < ticks {
+        core::hint::spin_loop();
+    }
+}
+
+pub type TimerId = u64;
+type Callback = Box<dyn FnOnce() + Send>;
+type TimerQueue = BTreeMap<(u64, TimerId), Callback>;
+
+const IDLE_MS: u64 = 1000; // Longest the hardware timer is armed for
+
+// One-shot callbacks per CPU by virtid, in deadline order.
+// Each CPU's hardware timer only covers its own queue.
+static TIMERS: KMutex<BTreeMap<usize, TimerQueue>> = KMutex::new(BTreeMap::new());
+static NEXT_TIMER: AtomicU64 = AtomicU64::new(1);
+
+fn wait_us(queue: &TimerQueue, now: u64) -> u64 {
+    let wait = match queue.keys().next() {
+        Some(&(deadline, _)) => deadline.saturating_sub(now),
+        None => IDLE_MS * 1_000_000
+    };
+    return (wait / 1000).clamp(1, IDLE_MS * 1000);
+}
+
+fn program(queue: &TimerQueue) {
+    intc::timer_set_us(wait_us(queue, uptime_ns()));
+}
+
+// The nearest callback, if it is due by `now`
+fn take_due(queue: &mut TimerQueue, now: u64) -> Option<Callback> {
+    let entry = queue.first_entry()?;
+    if entry.key().0 > now { return None; }
+    return Some(entry.remove());
+}
+
+// Runs `callback` from this CPU's timer IRQ once `ms` have passed
+pub fn after_ms(ms: u64, callback: impl FnOnce() + Send + 'static) -> TimerId {
+    let id = NEXT_TIMER.fetch_add(1, AtomOrd::Relaxed);
+    let deadline = uptime_ns() + ms * 1_000_000;
+
+    let mut timers = TIMERS.lock();
+    let queue = timers.entry(this_cpu().virtid()).or_default();
+    let nearest = queue.keys().next().is_none_or(|&(first, _)| deadline < first);
+    queue.insert((deadline, id), Box::new(callback));
+    if nearest { program(queue); }
+    return id;
+}
+
+// False if it already fired or was set on another CPU
+pub fn cancel(id: TimerId) -> bool {
+    let mut timers = TIMERS.lock();
+    let Some(queue) = timers.get_mut(&this_cpu().virtid()) else { return false; };
+    let Some(key) = queue.keys().find(|&&(_, tid)| tid == id).copied() else { return false; };
+    queue.remove(&key);
+    return true;
+}
+
+// Arms the hardware timer for this CPU's nearest deadline
+pub fn rearm() {
+    program(TIMERS.lock().entry(this_cpu().virtid()).or_default());
+}
+
+// Timer IRQ, runs what is due with the queue unlocked and then rearms.
+// Always leaves the timer armed, a level-triggered one would fire again otherwise.
+pub fn handle_irq() {
+    loop {
+        let due = {
+            let mut timers = TIMERS.lock();
+            let queue = timers.entry(this_cpu().virtid()).or_default();
+            let due = take_due(queue, uptime_ns());
+            if due.is_none() { program(queue); }
+            due
+        };
+        let Some(callback) = due else { break; };
+        callback();
+    }
+}
+
+// Halts between interrupts if the caller had them on, spins otherwise.
+// The deadline is checked as well, in case this CPU's timer is not running.
+pub fn sleep_ms(ms: u64) {
+    if intc::counter_freq() == 0 { return; }
+
+    let done = Arc::new(AtomicBool::new(false));
+    let flag = done.clone();
+    after_ms(ms, move || flag.store(true, AtomOrd::Release));
+
+    let deadline = uptime_ns() + ms * 1_000_000;
+    let halt = exc::get();
+    while !done.load(AtomOrd::Acquire) && uptime_ns() < deadline {
+        if halt { arch::wfi(); } else { spin_loop(); }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn bcd_to_binary() {
+        assert_eq!(bcd_to_bin(0x00), 0);
+        assert_eq!(bcd_to_bin(0x09), 9);
+        assert_eq!(bcd_to_bin(0x10), 10);
+        assert_eq!(bcd_to_bin(0x59), 59);
+        assert_eq!(bcd_to_bin(0x99), 99);
+    }
+
+    #[test]
+    fn leap_days_are_counted() {
+        assert!(is_leap(2000) && is_leap(2024));
+        assert!(!is_leap(1900) && !is_leap(2100) && !is_leap(2023));
+
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 3, 1) - days_from_civil(2000, 2, 28), 2);
+        assert_eq!(days_from_civil(2100, 3, 1) - days_from_civil(2100, 2, 28), 1);
+        assert_eq!(days_from_civil(2025, 1, 1) - days_from_civil(2024, 1, 1), 366);
+        assert_eq!(unix_time(2000, 1, 1, 0, 0, 0), 946684800);
+        assert_eq!(unix_time(2024, 2, 29, 12, 34, 56), 1709210096);
+    }
+
+    #[test]
+    fn days_round_trip() {
+        // From 0001-01-01 on, earlier years are out of the RTC's reach anyway
+        for days in (-719_162..800_000).step_by(97) {
+            let (y, m, d) = civil_from_days(days);
+            assert_eq!(days_from_civil(y, m, d), days);
+        }
+    }
+
+    #[test]
+    fn ticks_scale_to_nanoseconds() {
+        assert_eq!(ticks_to_ns(1, 1_000_000_000), 1);
+        assert_eq!(ticks_to_ns(62_500_000, 62_500_000), 1_000_000_000); // Generic timer on QEMU
+        assert_eq!(ticks_to_ns(3, 24_000_000), 125);
+        assert_eq!(ticks_to_ns(2_999_999_999, 3_000_000_000), 999_999_999); // Truncated, never rounded up
+        // A day at 3 GHz overflows 64 bits before the division
+        assert_eq!(ticks_to_ns(86_400 * 3_000_000_000, 3_000_000_000), 86_400 * 1_000_000_000);
+        assert_eq!(ticks_to_ns(12345, 0), 0);
+    }
+
+    #[test]
+    fn deadlines_fire_in_order_and_rearm() {
+        const MS: u64 = 1_000_000;
+        let fired = Arc::new(KMutex::new(Vec::new()));
+        let mut queue = TimerQueue::new();
+        // Inserted out of order, with a tie broken by id
+        for (deadline, id) in [(30 * MS, 1), (10 * MS, 2), (20 * MS, 3), (10 * MS, 4)] {
+            let fired = fired.clone();
+            queue.insert((deadline, id), Box::new(move || fired.lock().push(id)));
+        }
+        assert_eq!(wait_us(&queue, 0), 10_000);
+
+        // At 15 ms both 10 ms timers run and the next arm is 5 ms out
+        while let Some(callback) = take_due(&mut queue, 15 * MS) { callback(); }
+        assert_eq!(*fired.lock(), [2, 4]);
+        assert_eq!(wait_us(&queue, 15 * MS), 5_000);
+
+        // Running late leaves the shortest wait, not zero
+        while let Some(callback) = take_due(&mut queue, 25 * MS) { callback(); }
+        assert_eq!(wait_us(&queue, 31 * MS), 1);
+        while let Some(callback) = take_due(&mut queue, 31 * MS) { callback(); }
+        assert_eq!(*fired.lock(), [2, 4, 3, 1]);
+
+        // Nothing queued, the timer still ticks at the idle rate
+        assert_eq!(wait_us(&queue, 31 * MS), IDLE_MS * 1000);
+    }
+}